@@ -0,0 +1,127 @@
+// Compare the copying receive path with the pooled, in-place receive path
+//
+// Run with: cargo run --release --example packet_pool_bench
+//
+// The byte counts are the figure that matters on the nRF52840. On the host,
+// `critical_section` is a global mutex rather than masking interrupts, which
+// makes reference counting look far more expensive than it is on the MCU,
+// while memcpy is much cheaper.
+use std::hint::black_box;
+use std::mem::size_of;
+use std::time::Instant;
+
+use meshtassy_net::header::{Header, HeaderFlags};
+use meshtassy_net::pool::PacketBuffer;
+use meshtassy_net::{
+    ChannelSet, DecodedPacket, Encrypted, OwnedData, Packet, PacketHandle, PacketPool, PortNum,
+    ReceivedPacket,
+};
+
+const ITERATIONS: u32 = 100_000;
+// Number of tasks receiving each packet (node database and USB forwarder in the firmware)
+const SUBSCRIBERS: usize = 2;
+
+static POOL: PacketPool<4> = PacketPool::new();
+
+fn frame(channels: &ChannelSet, buffer: &mut [u8]) -> usize {
+    let channel = channels.primary().unwrap();
+    let header = Header::new(
+        0xFFFF_FFFF,
+        0x1234_5678,
+        0x0000_BEEF,
+        HeaderFlags {
+            hop_limit: 3,
+            want_ack: false,
+            via_mqtt: false,
+            hop_start: 3,
+        },
+        channel.hash(),
+        0x00,
+        0x78,
+    );
+    let data = OwnedData::new(PortNum::TextMessageApp, &[b'x'; 200]).unwrap();
+    DecodedPacket::new(header, data)
+        .encode()
+        .unwrap()
+        .encrypt(channel.key())
+        .unwrap()
+        .to_bytes(buffer)
+        .unwrap()
+}
+
+/// The receive path before the pool: every stage works on its own copy
+fn copying_receive(channels: &ChannelSet, bytes: &[u8]) -> usize {
+    let packet = Packet::<Encrypted>::from_bytes(bytes, -80, 5).unwrap();
+    let (_, decoded) = channels.decode(&packet).unwrap();
+    // PubSubChannel clones the message for the publisher and every subscriber
+    let mut received = 0;
+    for _ in 0..=SUBSCRIBERS {
+        received += black_box(decoded.clone()).payload_data().len();
+    }
+    received
+}
+
+/// The pooled receive path: one copy into the pool, then handles
+fn pooled_receive(channels: &ChannelSet, bytes: &[u8]) -> usize {
+    let mut handle = POOL.alloc_from_bytes(bytes, -80, 5).unwrap();
+    channels.decode_in_place(handle.get_mut().unwrap()).unwrap();
+    let mut received = 0;
+    for _ in 0..=SUBSCRIBERS {
+        let subscriber = black_box(handle.clone());
+        received += subscriber.payload_data().len();
+    }
+    received
+}
+
+fn bench(name: &str, mut f: impl FnMut() -> usize) -> f64 {
+    let start = Instant::now();
+    let mut total = 0;
+    for _ in 0..ITERATIONS {
+        total += f();
+    }
+    black_box(total);
+    let ns = start.elapsed().as_nanos() as f64 / ITERATIONS as f64;
+    println!("{:<10} {:>8.0} ns/packet", name, ns);
+    ns
+}
+
+fn main() {
+    let channels = ChannelSet::with_default_channel();
+    let mut buffer = [0u8; 256];
+    let len = frame(&channels, &mut buffer);
+    let bytes = &buffer[..len];
+
+    println!("Type sizes:");
+    println!(
+        "  Packet<Encrypted>  {:>4} bytes",
+        size_of::<Packet<Encrypted>>()
+    );
+    println!(
+        "  DecodedPacket      {:>4} bytes",
+        size_of::<DecodedPacket>()
+    );
+    println!(
+        "  PacketBuffer       {:>4} bytes",
+        size_of::<PacketBuffer>()
+    );
+    println!(
+        "  PacketHandle       {:>4} bytes",
+        size_of::<PacketHandle<'static>>()
+    );
+
+    // Copies of packet-sized values made per received packet
+    let copying = size_of::<Packet<Encrypted>>() * 3 // from_bytes, clone per channel, decrypt
+        + size_of::<DecodedPacket>() * (2 + SUBSCRIBERS); // decode, publish, one per subscriber
+    let pooled = len + size_of::<PacketHandle<'static>>() * (1 + SUBSCRIBERS);
+    println!("Bytes copied per {} byte frame:", len);
+    println!("  copying  {:>5}", copying);
+    println!("  pooled   {:>5}", pooled);
+
+    println!(
+        "Receive path ({} iterations, {} subscribers):",
+        ITERATIONS, SUBSCRIBERS
+    );
+    let copy_ns = bench("copying", || copying_receive(&channels, bytes));
+    let pool_ns = bench("pooled", || pooled_receive(&channels, bytes));
+    println!("Time ratio (pooled / copying): {:.2}", pool_ns / copy_ns);
+}
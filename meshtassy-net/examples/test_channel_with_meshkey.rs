@@ -1,6 +1,6 @@
 // Example showing the updated channel hash generation using MeshKey
-use meshtastic_crypto::channel::generate_channel_hash;
-use meshtastic_crypto::key::MeshKey;
+use meshtassy_net::channel::generate_channel_hash;
+use meshtassy_net::key::MeshKey;
 
 fn main() {
     println!("Testing updated channel hash generation with MeshKey");
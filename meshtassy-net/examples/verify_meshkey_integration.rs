@@ -1,6 +1,6 @@
 // Simple verification that the key functionality works correctly
-use meshtastic_crypto::key::MeshKey;
-use meshtastic_crypto::channel::generate_channel_hash;
+use meshtassy_net::key::MeshKey;
+use meshtassy_net::channel::generate_channel_hash;
 
 fn main() {
     println!("=== Verifying Channel Hash with MeshKey ===");
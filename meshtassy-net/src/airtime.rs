@@ -0,0 +1,352 @@
+//! Airtime accounting and duty cycle limiting
+//!
+//! Every LoRa frame occupies the channel for a time that depends on the
+//! modulation and the frame length ([`time_on_air_ms`]). [`AirtimeTracker`]
+//! adds up the airtime of the frames we send and receive over the last minute
+//! and the last hour, which gives the `channel_utilization` and `air_util_tx`
+//! reported in `DeviceMetrics`.
+//!
+//! The tracker also enforces the region's duty cycle (10% or 1% in parts of
+//! EU868) with [`AirtimeTracker::tx_permission`], and implements Meshtastic's
+//! politeness rules: optional broadcasts are skipped when the channel is busy
+//! or when we used up half of our duty cycle.
+
+use crate::radio_config::{ModemSettings, RadioParams};
+
+/// Channel utilization above which no optional broadcasts are sent
+pub const MAX_CHANNEL_UTIL_PERCENT: f32 = 40.0;
+
+/// Channel utilization above which polite nodes skip optional broadcasts
+pub const POLITE_CHANNEL_UTIL_PERCENT: f32 = 25.0;
+
+/// Share of the duty cycle optional broadcasts may use, in percent
+pub const POLITE_DUTY_CYCLE_PERCENT: u8 = 50;
+
+// The minute window is split in 6 periods of 10 seconds, the hour in 60 periods of a minute
+const MINUTE_PERIODS: usize = 6;
+const MINUTE_PERIOD_MS: u64 = 10_000;
+const HOUR_PERIODS: usize = 60;
+const HOUR_PERIOD_MS: u64 = 60_000;
+
+/// Time on air of a frame, rounded up to the millisecond
+///
+/// Uses the formula of the Semtech datasheets for frames with an explicit
+/// header and a CRC, as Meshtastic sends them. Low data rate optimization is
+/// assumed on when a symbol lasts longer than 16 ms, like the radio drivers do.
+pub fn time_on_air_ms(modem: &ModemSettings, preamble_len: u16, payload_len: usize) -> u32 {
+    let sf = u64::from(modem.spreading_factor);
+    let bandwidth_hz = u64::from(modem.bandwidth_hz.max(1));
+    let symbol_us = (1_000_000u64 << sf) / bandwidth_hz;
+    let low_data_rate = symbol_us > 16_000;
+
+    // Payload symbols: 8 + ceil((8 PL - 4 SF + 28 + 16) / (4 (SF - 2 DE))) * CR
+    let numerator = (8 * payload_len as i64) - (4 * sf as i64) + 28 + 16;
+    let denominator = 4 * (sf as i64 - if low_data_rate { 2 } else { 0 });
+    let blocks = if numerator > 0 {
+        (numerator + denominator - 1) / denominator
+    } else {
+        0
+    };
+    let payload_symbols = 8 + blocks as u64 * u64::from(modem.coding_rate);
+
+    // Counted in quarter symbols, the preamble is followed by 4.25 symbols of sync word
+    let quarter_symbols = 4 * u64::from(preamble_len) + 17 + 4 * payload_symbols;
+    let airtime_us = ((quarter_symbols * 1_000_000u64) << sf) / (4 * bandwidth_hz);
+    airtime_us.div_ceil(1000) as u32
+}
+
+/// Whether a transmission fits in the duty cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxPermission {
+    /// The frame can be sent now
+    Allowed,
+    /// The frame can be sent once enough airtime left the one-hour window
+    Delayed { wait_ms: u64 },
+    /// The frame is longer than the whole hourly budget and can never be sent
+    Refused,
+}
+
+// Airtime of the last N periods, each stored at its period number modulo N
+#[derive(Debug, Clone)]
+struct Window<const N: usize> {
+    period_ms: u64,
+    tx_ms: [u32; N],
+    rx_ms: [u32; N],
+    // Most recent period written
+    last_period: u64,
+}
+
+impl<const N: usize> Window<N> {
+    const fn new(period_ms: u64) -> Self {
+        Self {
+            period_ms,
+            tx_ms: [0; N],
+            rx_ms: [0; N],
+            last_period: 0,
+        }
+    }
+
+    fn add(&mut self, now_ms: u64, airtime_ms: u32, tx: bool) {
+        let period = now_ms / self.period_ms;
+        if period > self.last_period {
+            // Clear the periods we skipped, they are about to be reused
+            let skipped = (period - self.last_period).min(N as u64);
+            for p in (period + 1 - skipped)..=period {
+                let index = (p % N as u64) as usize;
+                self.tx_ms[index] = 0;
+                self.rx_ms[index] = 0;
+            }
+            self.last_period = period;
+        }
+        let index = (period % N as u64) as usize;
+        let bucket = if tx {
+            &mut self.tx_ms[index]
+        } else {
+            &mut self.rx_ms[index]
+        };
+        *bucket = bucket.saturating_add(airtime_ms);
+    }
+
+    // Periods still in the window at `now_ms`, oldest first
+    fn periods(&self, now_ms: u64) -> impl Iterator<Item = u64> {
+        let now_period = (now_ms / self.period_ms).max(self.last_period);
+        let first = (now_period + 1).saturating_sub(N as u64);
+        first..=self.last_period
+    }
+
+    fn totals(&self, now_ms: u64) -> (u32, u32) {
+        self.periods(now_ms).fold((0u32, 0u32), |(tx, rx), p| {
+            let index = (p % N as u64) as usize;
+            (
+                tx.saturating_add(self.tx_ms[index]),
+                rx.saturating_add(self.rx_ms[index]),
+            )
+        })
+    }
+
+    fn span_ms(&self) -> u64 {
+        self.period_ms * N as u64
+    }
+}
+
+/// TX and RX airtime over the last minute and hour
+#[derive(Debug, Clone)]
+pub struct AirtimeTracker {
+    minute: Window<MINUTE_PERIODS>,
+    hour: Window<HOUR_PERIODS>,
+    duty_cycle_percent: u8,
+}
+
+impl Default for AirtimeTracker {
+    fn default() -> Self {
+        Self::new(100)
+    }
+}
+
+impl AirtimeTracker {
+    /// Create a tracker enforcing `duty_cycle_percent`, 100 means no limit
+    pub fn new(duty_cycle_percent: u8) -> Self {
+        Self {
+            minute: Window::new(MINUTE_PERIOD_MS),
+            hour: Window::new(HOUR_PERIOD_MS),
+            duty_cycle_percent: duty_cycle_percent.clamp(1, 100),
+        }
+    }
+
+    /// Create a tracker enforcing the duty cycle of the radio parameters
+    pub fn for_radio(params: &RadioParams) -> Self {
+        Self::new(params.duty_cycle_percent)
+    }
+
+    /// Duty cycle being enforced
+    pub fn duty_cycle_percent(&self) -> u8 {
+        self.duty_cycle_percent
+    }
+
+    /// Enforce another duty cycle, for example after a region change, keeping the airtime history
+    pub fn set_duty_cycle_percent(&mut self, duty_cycle_percent: u8) {
+        self.duty_cycle_percent = duty_cycle_percent.clamp(1, 100);
+    }
+
+    /// Record a frame we sent
+    pub fn record_tx(&mut self, now_ms: u64, airtime_ms: u32) {
+        self.minute.add(now_ms, airtime_ms, true);
+        self.hour.add(now_ms, airtime_ms, true);
+    }
+
+    /// Record a frame we received
+    pub fn record_rx(&mut self, now_ms: u64, airtime_ms: u32) {
+        self.minute.add(now_ms, airtime_ms, false);
+        self.hour.add(now_ms, airtime_ms, false);
+    }
+
+    /// TX and RX airtime over the last minute, in milliseconds
+    pub fn last_minute_ms(&self, now_ms: u64) -> (u32, u32) {
+        self.minute.totals(now_ms)
+    }
+
+    /// TX and RX airtime over the last hour, in milliseconds
+    pub fn last_hour_ms(&self, now_ms: u64) -> (u32, u32) {
+        self.hour.totals(now_ms)
+    }
+
+    /// Share of the last minute the channel was busy with frames we sent or received
+    pub fn channel_utilization_percent(&self, now_ms: u64) -> f32 {
+        let (tx, rx) = self.last_minute_ms(now_ms);
+        percent(u64::from(tx) + u64::from(rx), self.minute.span_ms())
+    }
+
+    /// Share of the last hour we spent transmitting
+    pub fn air_util_tx_percent(&self, now_ms: u64) -> f32 {
+        let (tx, _) = self.last_hour_ms(now_ms);
+        percent(u64::from(tx), self.hour.span_ms())
+    }
+
+    /// Whether a frame of `airtime_ms` may be sent now without breaking the duty cycle
+    pub fn tx_permission(&self, now_ms: u64, airtime_ms: u32) -> TxPermission {
+        if self.duty_cycle_percent >= 100 {
+            return TxPermission::Allowed;
+        }
+        let budget_ms = self.hour.span_ms() * u64::from(self.duty_cycle_percent) / 100;
+        let airtime_ms = u64::from(airtime_ms);
+        if airtime_ms > budget_ms {
+            return TxPermission::Refused;
+        }
+
+        let (used_ms, _) = self.last_hour_ms(now_ms);
+        let mut used_ms = u64::from(used_ms);
+        if used_ms + airtime_ms <= budget_ms {
+            return TxPermission::Allowed;
+        }
+        // Wait for the oldest periods to leave the window until the frame fits
+        for p in self.hour.periods(now_ms) {
+            used_ms -= u64::from(self.hour.tx_ms[(p % HOUR_PERIODS as u64) as usize]);
+            if used_ms + airtime_ms <= budget_ms {
+                let expires_ms = (p + HOUR_PERIODS as u64) * HOUR_PERIOD_MS;
+                return TxPermission::Delayed {
+                    wait_ms: expires_ms.saturating_sub(now_ms),
+                };
+            }
+        }
+        TxPermission::Delayed {
+            wait_ms: self.hour.span_ms(),
+        }
+    }
+
+    /// Whether the channel is quiet enough for an optional broadcast
+    ///
+    /// `polite` nodes (all but routers in Meshtastic) give up sooner.
+    pub fn is_tx_allowed_channel_util(&self, now_ms: u64, polite: bool) -> bool {
+        let limit = if polite {
+            POLITE_CHANNEL_UTIL_PERCENT
+        } else {
+            MAX_CHANNEL_UTIL_PERCENT
+        };
+        self.channel_utilization_percent(now_ms) < limit
+    }
+
+    /// Whether optional broadcasts still fit in the polite share of the duty cycle
+    pub fn is_tx_allowed_air_util(&self, now_ms: u64) -> bool {
+        if self.duty_cycle_percent >= 100 {
+            return true;
+        }
+        let limit =
+            f32::from(self.duty_cycle_percent) * f32::from(POLITE_DUTY_CYCLE_PERCENT) / 100.0;
+        self.air_util_tx_percent(now_ms) < limit
+    }
+}
+
+fn percent(part_ms: u64, span_ms: u64) -> f32 {
+    (part_ms as f32) * 100.0 / (span_ms as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_FAST: ModemSettings = ModemSettings {
+        bandwidth_hz: 250_000,
+        spreading_factor: 11,
+        coding_rate: 5,
+    };
+
+    #[test]
+    fn test_time_on_air() {
+        // 20.25 preamble symbols and 58 payload symbols of 8.192 ms
+        assert_eq!(time_on_air_ms(&LONG_FAST, 16, 50), 642);
+        let very_long_slow = ModemSettings {
+            bandwidth_hz: 62_500,
+            spreading_factor: 12,
+            coding_rate: 8,
+        };
+        // Low data rate optimization: 20.25 + 8 + 10 * 8 symbols of 65.536 ms
+        assert_eq!(time_on_air_ms(&very_long_slow, 16, 50), 7_095);
+        let short_turbo = ModemSettings {
+            bandwidth_hz: 500_000,
+            spreading_factor: 7,
+            coding_rate: 5,
+        };
+        assert!(time_on_air_ms(&short_turbo, 16, 50) < 100);
+    }
+
+    #[test]
+    fn test_utilization_windows() {
+        let mut tracker = AirtimeTracker::new(100);
+        tracker.record_tx(0, 3_000);
+        tracker.record_rx(5_000, 6_000);
+        assert_eq!(tracker.last_minute_ms(10_000), (3_000, 6_000));
+        assert!((tracker.channel_utilization_percent(10_000) - 15.0).abs() < 0.01);
+        assert!((tracker.air_util_tx_percent(10_000) - 3_000.0 / 36_000.0).abs() < 0.001);
+
+        // A minute later the frames left the short window but not the long one
+        assert_eq!(tracker.last_minute_ms(61_000), (0, 0));
+        assert_eq!(tracker.last_hour_ms(61_000), (3_000, 6_000));
+        tracker.record_tx(61_000, 1_000);
+        assert_eq!(tracker.last_minute_ms(61_000), (1_000, 0));
+        assert_eq!(tracker.last_hour_ms(3_600_000), (1_000, 0));
+        assert_eq!(tracker.last_hour_ms(3_700_000), (0, 0));
+    }
+
+    #[test]
+    fn test_duty_cycle_limiter() {
+        // 1% of an hour is 36 seconds of TX
+        let mut tracker = AirtimeTracker::new(1);
+        assert_eq!(tracker.tx_permission(0, 40_000), TxPermission::Refused);
+        tracker.record_tx(0, 20_000);
+        tracker.record_tx(120_000, 15_000);
+        assert_eq!(tracker.tx_permission(130_000, 1_000), TxPermission::Allowed);
+        // Needs the first frame to leave the window
+        assert_eq!(
+            tracker.tx_permission(130_000, 2_000),
+            TxPermission::Delayed {
+                wait_ms: 3_600_000 - 130_000
+            }
+        );
+        assert_eq!(
+            tracker.tx_permission(3_600_000, 2_000),
+            TxPermission::Allowed
+        );
+
+        // Lifting the limit keeps what was already sent
+        tracker.set_duty_cycle_percent(100);
+        assert_eq!(tracker.tx_permission(130_000, 2_000), TxPermission::Allowed);
+        tracker.set_duty_cycle_percent(1);
+        assert_eq!(tracker.last_hour_ms(130_000), (35_000, 0));
+    }
+
+    #[test]
+    fn test_politeness() {
+        let mut tracker = AirtimeTracker::new(10);
+        tracker.record_rx(0, 18_000);
+        // 30% utilization: too busy for polite nodes only
+        assert!(!tracker.is_tx_allowed_channel_util(1_000, true));
+        assert!(tracker.is_tx_allowed_channel_util(1_000, false));
+
+        // Half of a 10% duty cycle is 180 seconds per hour
+        assert!(tracker.is_tx_allowed_air_util(1_000));
+        tracker.record_tx(1_000, 180_000);
+        assert!(!tracker.is_tx_allowed_air_util(2_000));
+        assert!(AirtimeTracker::new(100).is_tx_allowed_air_util(0));
+    }
+}
@@ -0,0 +1,828 @@
+//! Typed application payloads
+//!
+//! The `payload` of a `Data` message is itself a protobuf whose type depends
+//! on the port number. [`AppPayload`] decodes the payloads of the core
+//! Meshtastic apps into owned, heapless types so they can outlive the receive
+//! buffer and be passed between tasks.
+//!
+//! Use [`DecodedPacket::app_payload`](crate::DecodedPacket::app_payload) to
+//! decode a received packet.
+
+use heapless::{String, Vec};
+use meshtastic_protobufs::meshtastic::{position, routing, telemetry, PortNum};
+
+use crate::error::PacketError;
+use crate::node_database::User;
+use crate::DATA_PAYLOAD_LEN;
+
+/// Maximum number of hops recorded in a route (matches the Meshtastic firmware)
+pub const MAX_ROUTE_LEN: usize = 8;
+
+/// Maximum number of neighbors in a NeighborInfo message
+pub const MAX_NEIGHBORS: usize = 10;
+
+/// Maximum length of a waypoint name
+pub const MAX_WAYPOINT_NAME_LEN: usize = 30;
+
+/// Maximum length of a waypoint description
+pub const MAX_WAYPOINT_DESCRIPTION_LEN: usize = 100;
+
+/// Decoded application payload
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AppPayload {
+    /// TEXT_MESSAGE_APP
+    Text(String<DATA_PAYLOAD_LEN>),
+    /// POSITION_APP
+    Position(Position),
+    /// NODEINFO_APP
+    NodeInfo(User),
+    /// TELEMETRY_APP
+    Telemetry(Telemetry),
+    /// ROUTING_APP
+    Routing(Routing),
+    /// TRACEROUTE_APP
+    Traceroute(RouteDiscovery),
+    /// NEIGHBORINFO_APP
+    NeighborInfo(NeighborInfo),
+    /// WAYPOINT_APP
+    Waypoint(Waypoint),
+    /// Any other port, with the raw payload bytes
+    Unknown(femtopb::EnumValue<PortNum>, Vec<u8, DATA_PAYLOAD_LEN>),
+}
+
+impl AppPayload {
+    /// Decode `payload` according to its port number
+    pub fn decode(
+        portnum: femtopb::EnumValue<PortNum>,
+        payload: &[u8],
+    ) -> Result<Self, PacketError> {
+        use meshtastic_protobufs::meshtastic as pb;
+
+        let payload = match portnum {
+            femtopb::EnumValue::Known(PortNum::TextMessageApp) => {
+                let text =
+                    core::str::from_utf8(payload).map_err(|_| PacketError::InvalidUtf8)?;
+                let mut owned = String::new();
+                // A Data payload never exceeds DATA_PAYLOAD_LEN, so this only truncates bad input
+                for ch in text.chars() {
+                    if owned.push(ch).is_err() {
+                        break;
+                    }
+                }
+                AppPayload::Text(owned)
+            }
+            femtopb::EnumValue::Known(PortNum::PositionApp) => {
+                AppPayload::Position(Position::from_protobuf(&decode::<pb::Position>(payload)?))
+            }
+            femtopb::EnumValue::Known(PortNum::NodeinfoApp) => {
+                AppPayload::NodeInfo(User::from_protobuf(&decode::<pb::User>(payload)?))
+            }
+            femtopb::EnumValue::Known(PortNum::TelemetryApp) => {
+                AppPayload::Telemetry(Telemetry::from_protobuf(&decode::<pb::Telemetry>(payload)?))
+            }
+            femtopb::EnumValue::Known(PortNum::RoutingApp) => {
+                AppPayload::Routing(Routing::from_protobuf(&decode::<pb::Routing>(payload)?))
+            }
+            femtopb::EnumValue::Known(PortNum::TracerouteApp) => AppPayload::Traceroute(
+                RouteDiscovery::from_protobuf(&decode::<pb::RouteDiscovery>(payload)?),
+            ),
+            femtopb::EnumValue::Known(PortNum::NeighborinfoApp) => AppPayload::NeighborInfo(
+                NeighborInfo::from_protobuf(&decode::<pb::NeighborInfo>(payload)?),
+            ),
+            femtopb::EnumValue::Known(PortNum::WaypointApp) => {
+                AppPayload::Waypoint(Waypoint::from_protobuf(&decode::<pb::Waypoint>(payload)?))
+            }
+            _ => {
+                let mut bytes = Vec::new();
+                let len = payload.len().min(DATA_PAYLOAD_LEN);
+                // Cannot fail, the length is clamped to the capacity
+                let _ = bytes.extend_from_slice(&payload[..len]);
+                AppPayload::Unknown(portnum, bytes)
+            }
+        };
+        Ok(payload)
+    }
+
+    /// Port number this payload is sent on
+    pub fn portnum(&self) -> femtopb::EnumValue<PortNum> {
+        let portnum = match self {
+            AppPayload::Text(_) => PortNum::TextMessageApp,
+            AppPayload::Position(_) => PortNum::PositionApp,
+            AppPayload::NodeInfo(_) => PortNum::NodeinfoApp,
+            AppPayload::Telemetry(_) => PortNum::TelemetryApp,
+            AppPayload::Routing(_) => PortNum::RoutingApp,
+            AppPayload::Traceroute(_) => PortNum::TracerouteApp,
+            AppPayload::NeighborInfo(_) => PortNum::NeighborinfoApp,
+            AppPayload::Waypoint(_) => PortNum::WaypointApp,
+            AppPayload::Unknown(portnum, _) => return *portnum,
+        };
+        femtopb::EnumValue::Known(portnum)
+    }
+}
+
+fn decode<'a, M: femtopb::Message<'a>>(payload: &'a [u8]) -> Result<M, PacketError> {
+    M::decode(payload).map_err(|_| PacketError::Protobuf)
+}
+
+/// Copy a string, truncating it to the capacity of the destination
+fn truncated<const N: usize>(s: &str) -> String<N> {
+    let mut owned = String::new();
+    for ch in s.chars() {
+        if owned.push(ch).is_err() {
+            break;
+        }
+    }
+    owned
+}
+
+/// Copy a packed field, dropping entries that are invalid or beyond the capacity
+fn collect_packed<'a, T, E, const N: usize>(packed: femtopb::packed::Packed<'a, T, E>) -> Vec<T, N>
+where
+    E: femtopb::item_encoding::ItemEncoding<'a, T>,
+    T: Copy,
+{
+    let mut out = Vec::new();
+    for value in packed.iter().flatten() {
+        if out.push(value).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+/// Position report
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Position {
+    pub latitude_i: Option<i32>,  // latitude in 1e-7 degrees
+    pub longitude_i: Option<i32>, // longitude in 1e-7 degrees
+    pub altitude: Option<i32>,    // altitude in meters above MSL
+    pub time: u32,                // Unix timestamp
+    pub location_source: femtopb::EnumValue<position::LocSource>,
+    pub altitude_source: femtopb::EnumValue<position::AltSource>,
+    pub timestamp: u32, // Unix timestamp of the GPS fix
+    pub altitude_hae: Option<i32>,
+    pub pdop: u32,
+    pub hdop: u32,
+    pub vdop: u32,
+    pub gps_accuracy: u32,
+    pub ground_speed: Option<u32>, // m/s
+    pub ground_track: Option<u32>, // 1e-5 degrees
+    pub fix_quality: u32,
+    pub fix_type: u32,
+    pub sats_in_view: u32,
+    pub seq_number: u32,
+    pub precision_bits: u32,
+}
+
+impl Position {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::Position) -> Self {
+        Self {
+            latitude_i: pb.latitude_i,
+            longitude_i: pb.longitude_i,
+            altitude: pb.altitude,
+            time: pb.time,
+            location_source: pb.location_source,
+            altitude_source: pb.altitude_source,
+            timestamp: pb.timestamp,
+            altitude_hae: pb.altitude_hae,
+            pdop: pb.pdop,
+            hdop: pb.hdop,
+            vdop: pb.vdop,
+            gps_accuracy: pb.gps_accuracy,
+            ground_speed: pb.ground_speed,
+            ground_track: pb.ground_track,
+            fix_quality: pb.fix_quality,
+            fix_type: pb.fix_type,
+            sats_in_view: pb.sats_in_view,
+            seq_number: pb.seq_number,
+            precision_bits: pb.precision_bits,
+        }
+    }
+
+    /// Convert to the protobuf representation
+    pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::Position<'static> {
+        meshtastic_protobufs::meshtastic::Position {
+            latitude_i: self.latitude_i,
+            longitude_i: self.longitude_i,
+            altitude: self.altitude,
+            time: self.time,
+            location_source: self.location_source,
+            altitude_source: self.altitude_source,
+            timestamp: self.timestamp,
+            altitude_hae: self.altitude_hae,
+            pdop: self.pdop,
+            hdop: self.hdop,
+            vdop: self.vdop,
+            gps_accuracy: self.gps_accuracy,
+            ground_speed: self.ground_speed,
+            ground_track: self.ground_track,
+            fix_quality: self.fix_quality,
+            fix_type: self.fix_type,
+            sats_in_view: self.sats_in_view,
+            seq_number: self.seq_number,
+            precision_bits: self.precision_bits,
+            ..Default::default()
+        }
+    }
+}
+
+// The telemetry messages only hold optional scalars, so their owned versions
+// are field for field copies of the protobuf
+macro_rules! owned_metrics {
+    ($(#[$meta:meta])* $name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        pub struct $name {
+            $(pub $field: Option<$ty>,)*
+        }
+
+        impl $name {
+            /// Convert from the protobuf representation
+            pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::$name) -> Self {
+                Self {
+                    $($field: pb.$field,)*
+                }
+            }
+
+            /// Convert to the protobuf representation
+            pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::$name<'static> {
+                meshtastic_protobufs::meshtastic::$name {
+                    $($field: self.$field,)*
+                    ..Default::default()
+                }
+            }
+        }
+    };
+}
+
+owned_metrics! {
+    /// Battery and airtime metrics of a node
+    DeviceMetrics {
+        battery_level: u32,
+        voltage: f32,
+        channel_utilization: f32,
+        air_util_tx: f32,
+        uptime_seconds: u32,
+    }
+}
+
+owned_metrics! {
+    /// Environmental sensor readings
+    EnvironmentMetrics {
+        temperature: f32,
+        relative_humidity: f32,
+        barometric_pressure: f32,
+        gas_resistance: f32,
+        voltage: f32,
+        current: f32,
+        iaq: u32,
+        distance: f32,
+        lux: f32,
+        white_lux: f32,
+        ir_lux: f32,
+        uv_lux: f32,
+        wind_direction: u32,
+        wind_speed: f32,
+        weight: f32,
+        wind_gust: f32,
+        wind_lull: f32,
+        radiation: f32,
+        rainfall_1h: f32,
+        rainfall_24h: f32,
+        soil_moisture: u32,
+        soil_temperature: f32,
+    }
+}
+
+owned_metrics! {
+    /// Voltage and current readings of up to 8 power channels
+    PowerMetrics {
+        ch1_voltage: f32,
+        ch1_current: f32,
+        ch2_voltage: f32,
+        ch2_current: f32,
+        ch3_voltage: f32,
+        ch3_current: f32,
+        ch4_voltage: f32,
+        ch4_current: f32,
+        ch5_voltage: f32,
+        ch5_current: f32,
+        ch6_voltage: f32,
+        ch6_current: f32,
+        ch7_voltage: f32,
+        ch7_current: f32,
+        ch8_voltage: f32,
+        ch8_current: f32,
+    }
+}
+
+owned_metrics! {
+    /// Particulate matter and gas readings
+    AirQualityMetrics {
+        pm10_standard: u32,
+        pm25_standard: u32,
+        pm100_standard: u32,
+        pm10_environmental: u32,
+        pm25_environmental: u32,
+        pm100_environmental: u32,
+        particles_03um: u32,
+        particles_05um: u32,
+        particles_10um: u32,
+        particles_25um: u32,
+        particles_50um: u32,
+        particles_100um: u32,
+        co2: u32,
+        co2_temperature: f32,
+        co2_humidity: f32,
+        form_formaldehyde: f32,
+        form_humidity: f32,
+        form_temperature: f32,
+        pm40_standard: u32,
+        particles_40um: u32,
+        pm_temperature: f32,
+        pm_humidity: f32,
+        pm_voc_idx: f32,
+        pm_nox_idx: f32,
+        particles_tps: f32,
+    }
+}
+
+/// The kind of measurements carried by a telemetry message
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TelemetryMetrics {
+    Device(DeviceMetrics),
+    Environment(EnvironmentMetrics),
+    Power(PowerMetrics),
+    AirQuality(AirQualityMetrics),
+    /// A telemetry variant we do not decode (local stats, health, host metrics)
+    Other,
+    /// The message carried no measurements
+    None,
+}
+
+/// Telemetry report
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Telemetry {
+    pub time: u32, // Unix timestamp
+    pub metrics: TelemetryMetrics,
+}
+
+impl Telemetry {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::Telemetry) -> Self {
+        let metrics = match &pb.variant {
+            Some(telemetry::Variant::DeviceMetrics(m)) => {
+                TelemetryMetrics::Device(DeviceMetrics::from_protobuf(m))
+            }
+            Some(telemetry::Variant::EnvironmentMetrics(m)) => {
+                TelemetryMetrics::Environment(EnvironmentMetrics::from_protobuf(m))
+            }
+            Some(telemetry::Variant::PowerMetrics(m)) => {
+                TelemetryMetrics::Power(PowerMetrics::from_protobuf(m))
+            }
+            Some(telemetry::Variant::AirQualityMetrics(m)) => {
+                TelemetryMetrics::AirQuality(AirQualityMetrics::from_protobuf(m))
+            }
+            Some(_) => TelemetryMetrics::Other,
+            None => TelemetryMetrics::None,
+        };
+        Self {
+            time: pb.time,
+            metrics,
+        }
+    }
+}
+
+/// Route taken by a traceroute request and its reply
+///
+/// SNR values are in units of 0.25 dB.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RouteDiscovery {
+    pub route: Vec<u32, MAX_ROUTE_LEN>,
+    pub snr_towards: Vec<i32, MAX_ROUTE_LEN>,
+    pub route_back: Vec<u32, MAX_ROUTE_LEN>,
+    pub snr_back: Vec<i32, MAX_ROUTE_LEN>,
+}
+
+impl RouteDiscovery {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::RouteDiscovery) -> Self {
+        Self {
+            route: collect_packed(pb.route),
+            snr_towards: collect_packed(pb.snr_towards),
+            route_back: collect_packed(pb.route_back),
+            snr_back: collect_packed(pb.snr_back),
+        }
+    }
+
+    /// Borrow as a protobuf RouteDiscovery, ready to be encoded
+    pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::RouteDiscovery<'_> {
+        meshtastic_protobufs::meshtastic::RouteDiscovery {
+            route: femtopb::packed::Packed::from_slice(&self.route),
+            snr_towards: femtopb::packed::Packed::from_slice(&self.snr_towards),
+            route_back: femtopb::packed::Packed::from_slice(&self.route_back),
+            snr_back: femtopb::packed::Packed::from_slice(&self.snr_back),
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+/// Routing control message
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Routing {
+    RouteRequest(RouteDiscovery),
+    RouteReply(RouteDiscovery),
+    /// Delivery report, `Error::None` is a successful ACK
+    Error(femtopb::EnumValue<routing::Error>),
+}
+
+impl Routing {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::Routing) -> Self {
+        match &pb.variant {
+            Some(routing::Variant::RouteRequest(route)) => {
+                Routing::RouteRequest(RouteDiscovery::from_protobuf(route))
+            }
+            Some(routing::Variant::RouteReply(route)) => {
+                Routing::RouteReply(RouteDiscovery::from_protobuf(route))
+            }
+            Some(routing::Variant::ErrorReason(error)) => Routing::Error(*error),
+            // An empty Routing message is an ACK: the error reason defaults to NONE
+            _ => Routing::Error(femtopb::EnumValue::Known(routing::Error::None)),
+        }
+    }
+}
+
+/// A neighbor heard directly by the reporting node
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Neighbor {
+    pub node_id: u32,
+    pub snr: f32,
+    pub last_rx_time: u32,
+    pub node_broadcast_interval_secs: u32,
+}
+
+impl Neighbor {
+    /// Convert to the protobuf representation
+    pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::Neighbor<'static> {
+        meshtastic_protobufs::meshtastic::Neighbor {
+            node_id: self.node_id,
+            snr: self.snr,
+            last_rx_time: self.last_rx_time,
+            node_broadcast_interval_secs: self.node_broadcast_interval_secs,
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+/// List of a node's direct neighbors
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NeighborInfo {
+    pub node_id: u32,
+    pub last_sent_by_id: u32,
+    pub node_broadcast_interval_secs: u32,
+    pub neighbors: Vec<Neighbor, MAX_NEIGHBORS>,
+}
+
+impl NeighborInfo {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::NeighborInfo) -> Self {
+        let mut neighbors = Vec::new();
+        for neighbor in pb.neighbors.iter().flatten() {
+            let neighbor = Neighbor {
+                node_id: neighbor.node_id,
+                snr: neighbor.snr,
+                last_rx_time: neighbor.last_rx_time,
+                node_broadcast_interval_secs: neighbor.node_broadcast_interval_secs,
+            };
+            if neighbors.push(neighbor).is_err() {
+                break;
+            }
+        }
+        Self {
+            node_id: pb.node_id,
+            last_sent_by_id: pb.last_sent_by_id,
+            node_broadcast_interval_secs: pb.node_broadcast_interval_secs,
+            neighbors,
+        }
+    }
+}
+
+/// Waypoint shared on the map
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Waypoint {
+    pub id: u32,
+    pub latitude_i: Option<i32>,
+    pub longitude_i: Option<i32>,
+    pub expire: u32,    // Unix timestamp, 0 means never
+    pub locked_to: u32, // Node allowed to edit the waypoint, 0 means anyone
+    pub name: String<MAX_WAYPOINT_NAME_LEN>,
+    pub description: String<MAX_WAYPOINT_DESCRIPTION_LEN>,
+    pub icon: u32, // Unicode code point of the icon
+}
+
+impl Waypoint {
+    /// Convert from the protobuf representation
+    pub fn from_protobuf(pb: &meshtastic_protobufs::meshtastic::Waypoint) -> Self {
+        Self {
+            id: pb.id,
+            latitude_i: pb.latitude_i,
+            longitude_i: pb.longitude_i,
+            expire: pb.expire,
+            locked_to: pb.locked_to,
+            name: truncated(pb.name),
+            description: truncated(pb.description),
+            icon: pb.icon,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femtopb::Message as _;
+    use meshtastic_protobufs::meshtastic as pb;
+
+    fn encode<'a, M: femtopb::Message<'a>>(message: &M, buffer: &'a mut [u8]) -> &'a [u8] {
+        let len = message.encoded_len();
+        message.encode(&mut &mut buffer[..len]).unwrap();
+        &buffer[..len]
+    }
+
+    fn known(portnum: PortNum) -> femtopb::EnumValue<PortNum> {
+        femtopb::EnumValue::Known(portnum)
+    }
+
+    #[test]
+    fn test_text_message() {
+        let payload = AppPayload::decode(known(PortNum::TextMessageApp), "héllo".as_bytes());
+        assert_eq!(
+            payload,
+            Ok(AppPayload::Text(String::try_from("héllo").unwrap()))
+        );
+
+        assert_eq!(
+            AppPayload::decode(known(PortNum::TextMessageApp), &[0xFF, 0xFE]),
+            Err(PacketError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn test_position() {
+        let position = pb::Position {
+            latitude_i: Some(473_977_000),
+            longitude_i: Some(85_432_000),
+            altitude: Some(408),
+            time: 1_700_000_000,
+            sats_in_view: 9,
+            precision_bits: 32,
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 128];
+        let bytes = encode(&position, &mut buffer);
+
+        let Ok(AppPayload::Position(decoded)) =
+            AppPayload::decode(known(PortNum::PositionApp), bytes)
+        else {
+            panic!("expected a position");
+        };
+        assert_eq!(decoded.latitude_i, Some(473_977_000));
+        assert_eq!(decoded.longitude_i, Some(85_432_000));
+        assert_eq!(decoded.altitude, Some(408));
+        assert_eq!(decoded.time, 1_700_000_000);
+        assert_eq!(decoded.sats_in_view, 9);
+        assert_eq!(decoded.precision_bits, 32);
+        assert_eq!(decoded.ground_speed, None);
+    }
+
+    #[test]
+    fn test_nodeinfo() {
+        let user = pb::User {
+            long_name: "Meshtassy node",
+            short_name: "MSHY",
+            public_key: &[0x42; 32],
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 128];
+        let bytes = encode(&user, &mut buffer);
+
+        let Ok(AppPayload::NodeInfo(decoded)) =
+            AppPayload::decode(known(PortNum::NodeinfoApp), bytes)
+        else {
+            panic!("expected a user");
+        };
+        assert_eq!(decoded.long_name.as_str(), "Meshtassy node");
+        assert_eq!(decoded.short_name.as_str(), "MSHY");
+        assert_eq!(decoded.public_key, Some([0x42; 32]));
+    }
+
+    #[test]
+    fn test_telemetry_variants() {
+        let mut buffer = [0u8; 128];
+
+        let device = pb::Telemetry {
+            time: 10,
+            variant: Some(telemetry::Variant::DeviceMetrics(
+                DeviceMetrics {
+                    battery_level: Some(87),
+                    voltage: Some(4.1),
+                    ..Default::default()
+                }
+                .to_protobuf(),
+            )),
+            ..Default::default()
+        };
+        let Ok(AppPayload::Telemetry(decoded)) =
+            AppPayload::decode(known(PortNum::TelemetryApp), encode(&device, &mut buffer))
+        else {
+            panic!("expected telemetry");
+        };
+        assert_eq!(decoded.time, 10);
+        let TelemetryMetrics::Device(metrics) = decoded.metrics else {
+            panic!("expected device metrics");
+        };
+        assert_eq!(metrics.battery_level, Some(87));
+        assert_eq!(metrics.voltage, Some(4.1));
+        assert_eq!(metrics.uptime_seconds, None);
+
+        let environment = EnvironmentMetrics {
+            temperature: Some(21.5),
+            relative_humidity: Some(40.0),
+            barometric_pressure: Some(1013.2),
+            ..Default::default()
+        };
+        let message = pb::Telemetry {
+            variant: Some(telemetry::Variant::EnvironmentMetrics(
+                environment.to_protobuf(),
+            )),
+            ..Default::default()
+        };
+        let decoded = Telemetry::from_protobuf(
+            &pb::Telemetry::decode(encode(&message, &mut buffer)).unwrap(),
+        );
+        assert_eq!(decoded.metrics, TelemetryMetrics::Environment(environment));
+
+        let power = PowerMetrics {
+            ch1_voltage: Some(12.6),
+            ch1_current: Some(0.35),
+            ..Default::default()
+        };
+        let message = pb::Telemetry {
+            variant: Some(telemetry::Variant::PowerMetrics(power.to_protobuf())),
+            ..Default::default()
+        };
+        let decoded = Telemetry::from_protobuf(
+            &pb::Telemetry::decode(encode(&message, &mut buffer)).unwrap(),
+        );
+        assert_eq!(decoded.metrics, TelemetryMetrics::Power(power));
+
+        let air_quality = AirQualityMetrics {
+            pm25_standard: Some(12),
+            co2: Some(650),
+            ..Default::default()
+        };
+        let message = pb::Telemetry {
+            variant: Some(telemetry::Variant::AirQualityMetrics(
+                air_quality.to_protobuf(),
+            )),
+            ..Default::default()
+        };
+        let decoded = Telemetry::from_protobuf(
+            &pb::Telemetry::decode(encode(&message, &mut buffer)).unwrap(),
+        );
+        assert_eq!(decoded.metrics, TelemetryMetrics::AirQuality(air_quality));
+    }
+
+    #[test]
+    fn test_routing_and_traceroute() {
+        let route = [0x1111_1111u32, 0x2222_2222];
+        let snr = [24i32, -8, 12];
+        let discovery = pb::RouteDiscovery {
+            route: femtopb::packed::Packed::from_slice(&route),
+            snr_towards: femtopb::packed::Packed::from_slice(&snr),
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 128];
+
+        let Ok(AppPayload::Traceroute(decoded)) = AppPayload::decode(
+            known(PortNum::TracerouteApp),
+            encode(&discovery, &mut buffer),
+        ) else {
+            panic!("expected a traceroute");
+        };
+        assert_eq!(&decoded.route[..], &route);
+        assert_eq!(&decoded.snr_towards[..], &snr);
+        assert!(decoded.route_back.is_empty());
+
+        let reply = pb::Routing {
+            variant: Some(routing::Variant::RouteReply(discovery)),
+            ..Default::default()
+        };
+        let Ok(AppPayload::Routing(Routing::RouteReply(decoded))) =
+            AppPayload::decode(known(PortNum::RoutingApp), encode(&reply, &mut buffer))
+        else {
+            panic!("expected a route reply");
+        };
+        assert_eq!(&decoded.route[..], &route);
+
+        let nak = pb::Routing {
+            variant: Some(routing::Variant::ErrorReason(femtopb::EnumValue::Known(
+                routing::Error::NoChannel,
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(
+            AppPayload::decode(known(PortNum::RoutingApp), encode(&nak, &mut buffer)),
+            Ok(AppPayload::Routing(Routing::Error(
+                femtopb::EnumValue::Known(routing::Error::NoChannel)
+            )))
+        );
+    }
+
+    #[test]
+    fn test_neighbor_info() {
+        let neighbors = [
+            pb::Neighbor {
+                node_id: 0xAAAA,
+                snr: 6.5,
+                ..Default::default()
+            },
+            pb::Neighbor {
+                node_id: 0xBBBB,
+                snr: -3.25,
+                ..Default::default()
+            },
+        ];
+        let info = pb::NeighborInfo {
+            node_id: 0x1234,
+            node_broadcast_interval_secs: 900,
+            neighbors: femtopb::repeated::Repeated::from_slice(&neighbors),
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 128];
+
+        let Ok(AppPayload::NeighborInfo(decoded)) =
+            AppPayload::decode(known(PortNum::NeighborinfoApp), encode(&info, &mut buffer))
+        else {
+            panic!("expected neighbor info");
+        };
+        assert_eq!(decoded.node_id, 0x1234);
+        assert_eq!(decoded.node_broadcast_interval_secs, 900);
+        assert_eq!(decoded.neighbors.len(), 2);
+        assert_eq!(decoded.neighbors[1].node_id, 0xBBBB);
+        assert_eq!(decoded.neighbors[1].snr, -3.25);
+    }
+
+    #[test]
+    fn test_waypoint() {
+        let waypoint = pb::Waypoint {
+            id: 7,
+            latitude_i: Some(1),
+            longitude_i: Some(-1),
+            name: "Trailhead",
+            description: "Parking and water",
+            icon: 0x1F6BB,
+            ..Default::default()
+        };
+        let mut buffer = [0u8; 128];
+
+        let Ok(AppPayload::Waypoint(decoded)) =
+            AppPayload::decode(known(PortNum::WaypointApp), encode(&waypoint, &mut buffer))
+        else {
+            panic!("expected a waypoint");
+        };
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.name.as_str(), "Trailhead");
+        assert_eq!(decoded.description.as_str(), "Parking and water");
+        assert_eq!(decoded.icon, 0x1F6BB);
+    }
+
+    #[test]
+    fn test_unknown_port_and_bad_protobuf() {
+        let payload = AppPayload::decode(known(PortNum::PrivateApp), &[1, 2, 3]).unwrap();
+        assert_eq!(payload.portnum(), known(PortNum::PrivateApp));
+        let AppPayload::Unknown(_, bytes) = payload else {
+            panic!("expected an unknown payload");
+        };
+        assert_eq!(&bytes[..], &[1, 2, 3]);
+
+        let payload = AppPayload::decode(femtopb::EnumValue::Unknown(1234), &[9]).unwrap();
+        assert_eq!(payload.portnum(), femtopb::EnumValue::Unknown(1234));
+
+        assert_eq!(
+            AppPayload::decode(known(PortNum::PositionApp), &[0xFF, 0xFF, 0xFF]),
+            Err(PacketError::Protobuf)
+        );
+    }
+}
@@ -3,8 +3,29 @@
 //! This module provides functions to generate channel hashes using the same
 //! algorithm as the Meshtastic firmware, which combines the channel name
 //! and the channel PSK (pre-shared key) using XOR operations.
+//!
+//! It also provides [`ChannelSet`], the table of channels this node is a
+//! member of, which is used to find the right key for a received packet.
+
+use femtopb::EnumValue;
+use heapless::{String, Vec};
+pub use meshtastic_protobufs::meshtastic::channel::Role as ChannelRole;
+use meshtastic_protobufs::meshtastic::config::lo_ra_config::ModemPreset;
+use meshtastic_protobufs::meshtastic::{ChannelSettings, ModuleSettings};
+
+use crate::channel_url::preset_channel_name;
+use crate::key::{ChannelKey, MeshKey};
+use crate::pool::PacketBuffer;
+use crate::{DecodedPacket, Encrypted, Packet, PacketError};
+
+/// Maximum number of channels a node can be a member of
+pub const MAX_CHANNELS: usize = 8;
 
-use crate::key::MeshKey;
+/// Maximum length of a channel name in bytes
+pub const MAX_CHANNEL_NAME_LEN: usize = 11;
+
+/// Position precision of the default channel, about 2.9 km (same as the Meshtastic firmware)
+pub const DEFAULT_POSITION_PRECISION: u32 = 13;
 
 /// Compute XOR hash of a byte slice
 ///
@@ -39,8 +60,8 @@ pub fn xor_hash(data: &[u8]) -> u8 {
 ///
 /// # Examples
 /// ```
-/// use meshtastic_crypto::channel::generate_channel_hash;
-/// use meshtastic_crypto::key::MeshKey;
+/// use meshtassy_net::channel::generate_channel_hash;
+/// use meshtassy_net::key::MeshKey;
 ///
 /// let key = MeshKey::new(&[0x01]).unwrap();
 /// let hash = generate_channel_hash("LongFast", &key).unwrap();
@@ -65,6 +86,344 @@ pub fn generate_channel_hash(channel_name: &str, channel_key: &MeshKey) -> Optio
     Some(name_hash ^ key_hash)
 }
 
+/// Errors that can occur when building a channel table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChannelError {
+    /// Channel index is outside `0..MAX_CHANNELS`
+    InvalidIndex,
+    /// Channel name is longer than `MAX_CHANNEL_NAME_LEN`
+    InvalidName,
+    /// PSK is not 0, 1, 16 or 32 bytes long
+    InvalidKey,
+}
+
+/// A single channel: its name, PSK and precomputed channel hash
+///
+/// A channel left unnamed goes by the name of the modem preset, like the
+/// stock default channel, so its hash follows [`Channel::set_modem_preset`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel {
+    index: u8,
+    name: String<MAX_CHANNEL_NAME_LEN>,
+    preset_name: &'static str,
+    psk: MeshKey,
+    key: ChannelKey,
+    role: ChannelRole,
+    hash: u8,
+    position_precision: u32,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Channel {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Channel {{ index: {}, name: {}, role: {:?}, hash: 0x{:02X} }}",
+            self.index,
+            self.name(),
+            self.role,
+            self.hash
+        )
+    }
+}
+
+impl Channel {
+    /// Create a channel from its name and raw PSK bytes
+    ///
+    /// An empty name leaves the channel unnamed, it takes the name of the
+    /// default modem preset until [`Channel::set_modem_preset`].
+    pub fn new(index: u8, name: &str, psk: &[u8], role: ChannelRole) -> Result<Self, ChannelError> {
+        if index as usize >= MAX_CHANNELS {
+            return Err(ChannelError::InvalidIndex);
+        }
+        let name: String<MAX_CHANNEL_NAME_LEN> =
+            String::try_from(name).map_err(|_| ChannelError::InvalidName)?;
+        let psk = MeshKey::new(psk).map_err(|_| ChannelError::InvalidKey)?;
+        let key_bytes = psk.as_bytes();
+        let key = ChannelKey::from_bytes(key_bytes, key_bytes.len()).map_err(|_| ChannelError::InvalidKey)?;
+
+        let mut channel = Self {
+            index,
+            name,
+            preset_name: preset_channel_name(ModemPreset::default()),
+            psk,
+            key,
+            role,
+            hash: 0,
+            position_precision: 0,
+        };
+        channel.update_hash();
+        Ok(channel)
+    }
+
+    // The preset name stands in for an empty name, so there is always a hash
+    fn update_hash(&mut self) {
+        if let Some(hash) = generate_channel_hash(self.name(), &self.psk) {
+            self.hash = hash;
+        }
+    }
+
+    /// Index of this channel in the channel table
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Channel name, the modem preset's name for an unnamed channel
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() {
+            self.preset_name
+        } else {
+            &self.name
+        }
+    }
+
+    /// Whether the channel was left unnamed and goes by the modem preset's name
+    pub fn is_unnamed(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    /// Name as configured, empty for an unnamed channel
+    pub fn configured_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Follow the modem preset, which names (and hashes) an unnamed channel
+    pub fn set_modem_preset(&mut self, preset: ModemPreset) {
+        self.preset_name = preset_channel_name(preset);
+        self.update_hash();
+    }
+
+    /// Channel PSK
+    pub fn psk(&self) -> &MeshKey {
+        &self.psk
+    }
+
+    /// Key used to encrypt and decrypt packets on this channel
+    pub fn key(&self) -> &ChannelKey {
+        &self.key
+    }
+
+    /// How this channel is being used
+    pub fn role(&self) -> ChannelRole {
+        self.role
+    }
+
+    /// Channel hash sent in `Header::channel_hash`
+    pub fn hash(&self) -> u8 {
+        self.hash
+    }
+
+    /// Number of latitude/longitude bits kept in positions sent on this channel
+    ///
+    /// 0 means our position is not shared on this channel, 32 means full precision.
+    pub fn position_precision(&self) -> u32 {
+        self.position_precision
+    }
+
+    /// Set the position precision, values above 32 mean full precision
+    pub fn set_position_precision(&mut self, bits: u32) {
+        self.position_precision = bits.min(32);
+    }
+
+    /// Returns true unless the channel is disabled
+    pub fn is_enabled(&self) -> bool {
+        self.role != ChannelRole::Disabled
+    }
+
+    /// The channel as a `Channel` protobuf, for example to keep it in flash
+    pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::Channel<'_> {
+        meshtastic_protobufs::meshtastic::Channel {
+            index: i32::from(self.index),
+            settings: Some(ChannelSettings {
+                psk: self.psk.psk(),
+                name: self.configured_name(),
+                module_settings: Some(ModuleSettings {
+                    position_precision: self.position_precision,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            role: EnumValue::Known(self.role),
+            ..Default::default()
+        }
+    }
+
+    /// Restore a channel from [`Channel::to_protobuf`]
+    ///
+    /// An unknown role reads as disabled.
+    pub fn from_protobuf(
+        channel: &meshtastic_protobufs::meshtastic::Channel<'_>,
+    ) -> Result<Self, ChannelError> {
+        let index = u8::try_from(channel.index).map_err(|_| ChannelError::InvalidIndex)?;
+        let role = match channel.role {
+            EnumValue::Known(role) => role,
+            EnumValue::Unknown(_) => ChannelRole::Disabled,
+        };
+        let settings = channel.settings.clone().unwrap_or_default();
+        let mut restored = Self::new(index, settings.name, settings.psk, role)?;
+        if let Some(module_settings) = settings.module_settings {
+            restored.set_position_precision(module_settings.position_precision);
+        }
+        Ok(restored)
+    }
+}
+
+/// Table of up to [`MAX_CHANNELS`] channels, ordered by index
+#[derive(Debug, Clone, Default)]
+pub struct ChannelSet {
+    channels: Vec<Channel, MAX_CHANNELS>,
+    modem_preset: ModemPreset,
+}
+
+impl ChannelSet {
+    /// Create an empty channel table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a table containing only the default primary channel
+    ///
+    /// Like the stock default channel it is unnamed, so it is "LongFast" on
+    /// the LongFast preset and follows [`ChannelSet::set_modem_preset`].
+    pub fn with_default_channel() -> Self {
+        let mut set = Self::new();
+        // The default channel uses the well-known 1-byte PSK, which is always valid
+        if let Ok(mut channel) = Channel::new(0, "", &[0x01], ChannelRole::Primary) {
+            channel.set_position_precision(DEFAULT_POSITION_PRECISION);
+            set.set(channel);
+        }
+        set
+    }
+
+    /// Modem preset naming the unnamed channels
+    pub fn modem_preset(&self) -> ModemPreset {
+        self.modem_preset
+    }
+
+    /// Switch to another modem preset, renaming and rehashing the unnamed channels
+    pub fn set_modem_preset(&mut self, preset: ModemPreset) {
+        self.modem_preset = preset;
+        for channel in self.channels.iter_mut() {
+            channel.set_modem_preset(preset);
+        }
+    }
+
+    /// Add a channel, replacing any existing channel with the same index
+    ///
+    /// An unnamed channel takes the name of the table's modem preset.
+    pub fn set(&mut self, mut channel: Channel) {
+        channel.set_modem_preset(self.modem_preset);
+        match self.channels.iter().position(|c| c.index >= channel.index) {
+            Some(pos) if self.channels[pos].index == channel.index => self.channels[pos] = channel,
+            // Indices are limited to MAX_CHANNELS, so there is always room for a new one
+            Some(pos) => {
+                let _ = self.channels.insert(pos, channel);
+            }
+            None => {
+                let _ = self.channels.push(channel);
+            }
+        }
+    }
+
+    /// Remove the channel with the given index, returning it if it existed
+    pub fn remove(&mut self, index: u8) -> Option<Channel> {
+        let pos = self.channels.iter().position(|c| c.index == index)?;
+        Some(self.channels.remove(pos))
+    }
+
+    /// Get the channel with the given index
+    pub fn get(&self, index: u8) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.index == index)
+    }
+
+    /// Get the primary channel, if there is one
+    pub fn primary(&self) -> Option<&Channel> {
+        self.channels
+            .iter()
+            .find(|c| c.role == ChannelRole::Primary)
+    }
+
+    /// Iterate over all channels in index order
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter()
+    }
+
+    /// Number of channels in the table
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Returns true if the table has no channels
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Enabled channels whose hash matches `channel_hash`
+    ///
+    /// The hash is a single byte, so more than one channel may match.
+    pub fn candidates(&self, channel_hash: u8) -> impl Iterator<Item = &Channel> {
+        self.channels
+            .iter()
+            .filter(move |c| c.is_enabled() && c.hash == channel_hash)
+    }
+
+    /// Decrypt and decode a received packet with the first matching channel
+    ///
+    /// Every channel whose hash matches `Header::channel_hash` is tried in index
+    /// order until the payload decodes as a `Data` message. Returns the index of
+    /// the channel that worked along with the decoded packet, or the error of the
+    /// last channel tried. `PacketError::UnknownChannel` means no channel matched.
+    pub fn decode(&self, packet: &Packet<Encrypted>) -> Result<(u8, DecodedPacket), PacketError> {
+        let mut error = PacketError::UnknownChannel;
+        for channel in self.candidates(packet.header.channel_hash) {
+            let decoded = packet
+                .clone()
+                .decrypt(channel.key())
+                .and_then(|decrypted| decrypted.decode());
+            match decoded {
+                Ok(decoded) => {
+                    #[cfg(feature = "defmt")]
+                    defmt::trace!(
+                        "Decoded packet 0x{:08X} on channel {} ({})",
+                        packet.header.packet_id,
+                        channel.index,
+                        channel.name()
+                    );
+                    return Ok((channel.index, decoded));
+                }
+                Err(e) => error = e,
+            }
+        }
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "No channel could decode packet 0x{:08X} with hash 0x{:02X}",
+            packet.header.packet_id,
+            packet.header.channel_hash
+        );
+        Err(error)
+    }
+
+    /// Decrypt and decode a pooled packet in place with the first matching channel
+    ///
+    /// Like [`ChannelSet::decode`], but without copying the payload: a channel
+    /// whose key does not produce a valid `Data` message is undone before the
+    /// next one is tried. Returns the index of the channel that worked.
+    pub fn decode_in_place(&self, packet: &mut PacketBuffer) -> Result<u8, PacketError> {
+        let mut error = PacketError::UnknownChannel;
+        for channel in self.candidates(packet.header.channel_hash) {
+            packet.decrypt_in_place(channel.key())?;
+            match packet.decode_in_place() {
+                Ok(_) => return Ok(channel.index),
+                Err(e) => error = e,
+            }
+            packet.encrypt_in_place(channel.key())?;
+        }
+        Err(error)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -215,4 +574,197 @@ mod tests {
         let result = generate_channel_hash("LongFast", &key);
         assert_eq!(result, Some(0x08));
     }
+
+    fn encrypted_packet(channel: &Channel, message: &[u8]) -> Packet<Encrypted> {
+        use crate::header::{Header, HeaderFlags};
+        use crate::{OwnedData, PortNum};
+
+        let header = Header::new(
+            0xFFFF_FFFF,
+            0x1234_5678,
+            0x0000_BEEF,
+            HeaderFlags {
+                hop_limit: 3,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: 3,
+            },
+            channel.hash(),
+            0x00,
+            0x78,
+        );
+        let data = OwnedData::new(PortNum::TextMessageApp, message).unwrap();
+        DecodedPacket::new(header, data)
+            .encode()
+            .unwrap()
+            .encrypt(channel.key())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_channel_precomputes_hash() {
+        let channel = Channel::new(0, "LongFast", &[0x01], ChannelRole::Primary).unwrap();
+        assert_eq!(channel.hash(), 0x08);
+        assert_eq!(channel.name(), "LongFast");
+        assert_eq!(channel.psk(), &MeshKey::new(&[0x01]).unwrap());
+    }
+
+    #[test]
+    fn test_channel_rejects_invalid_settings() {
+        assert_eq!(
+            Channel::new(8, "LongFast", &[0x01], ChannelRole::Secondary),
+            Err(ChannelError::InvalidIndex)
+        );
+        assert_eq!(
+            Channel::new(1, "ThisNameIsTooLong", &[0x01], ChannelRole::Secondary),
+            Err(ChannelError::InvalidName)
+        );
+        assert_eq!(
+            Channel::new(1, "Private", &[0xAA; 5], ChannelRole::Secondary),
+            Err(ChannelError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn test_channel_set_replaces_by_index() {
+        let mut set = ChannelSet::with_default_channel();
+        set.set(Channel::new(2, "Two", &[0x02], ChannelRole::Secondary).unwrap());
+        set.set(Channel::new(1, "One", &[0x03], ChannelRole::Secondary).unwrap());
+        set.set(Channel::new(2, "Deux", &[0x02], ChannelRole::Secondary).unwrap());
+
+        assert_eq!(set.len(), 3);
+        let indices: heapless::Vec<u8, MAX_CHANNELS> = set.iter().map(|c| c.index()).collect();
+        assert_eq!(&indices[..], &[0, 1, 2]);
+        assert_eq!(set.get(2).unwrap().name(), "Deux");
+        assert_eq!(set.primary().unwrap().name(), "LongFast");
+
+        assert!(set.remove(1).is_some());
+        assert!(set.remove(1).is_none());
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_decode_selects_channel_by_hash() {
+        let mut set = ChannelSet::with_default_channel();
+        let private = Channel::new(1, "Private", &[0x5A; 16], ChannelRole::Secondary).unwrap();
+        set.set(private.clone());
+
+        let (index, decoded) = set.decode(&encrypted_packet(&private, b"secret")).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(decoded.payload_data(), b"secret");
+
+        let longfast = set.get(0).unwrap().clone();
+        let (index, decoded) = set.decode(&encrypted_packet(&longfast, b"public")).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(decoded.payload_data(), b"public");
+    }
+
+    #[test]
+    fn test_decode_tries_every_colliding_channel() {
+        // Same name and same key hash, different keys: both channels share a hash
+        let mut key_a = [0u8; 16];
+        key_a[0] = 0x11;
+        key_a[1] = 0x22;
+        let mut key_b = [0u8; 16];
+        key_b[0] = 0x22;
+        key_b[1] = 0x11;
+        let a = Channel::new(0, "Mesh", &key_a, ChannelRole::Primary).unwrap();
+        let b = Channel::new(3, "Mesh", &key_b, ChannelRole::Secondary).unwrap();
+        assert_eq!(a.hash(), b.hash());
+
+        let mut set = ChannelSet::new();
+        set.set(a);
+        set.set(b.clone());
+
+        let (index, decoded) = set.decode(&encrypted_packet(&b, b"collision")).unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(decoded.payload_data(), b"collision");
+    }
+
+    #[test]
+    fn test_decode_in_place_selects_channel_by_hash() {
+        use crate::pool::PacketPool;
+        use crate::ReceivedPacket;
+
+        let mut set = ChannelSet::with_default_channel();
+        let private = Channel::new(1, "Private", &[0x5A; 16], ChannelRole::Secondary).unwrap();
+        set.set(private.clone());
+
+        let mut bytes = [0u8; 256];
+        let len = encrypted_packet(&private, b"secret")
+            .to_bytes(&mut bytes)
+            .unwrap();
+        let pool: PacketPool<1> = PacketPool::new();
+        let mut handle = pool.alloc_from_bytes(&bytes[..len], 0, 0).unwrap();
+
+        assert_eq!(set.decode_in_place(handle.get_mut().unwrap()), Ok(1));
+        assert_eq!(handle.payload_data(), b"secret");
+
+        // Same hash as "Private" but a key we do not have: the packet is left as received
+        let other = Channel::new(2, "Private", &[0x5B; 16], ChannelRole::Secondary).unwrap();
+        assert_eq!(other.hash(), private.hash());
+        let len = encrypted_packet(&other, b"other").to_bytes(&mut bytes).unwrap();
+        drop(handle);
+        let mut handle = pool.alloc_from_bytes(&bytes[..len], 0, 0).unwrap();
+        assert!(set.decode_in_place(handle.get_mut().unwrap()).is_err());
+        assert!(!handle.is_decrypted());
+        assert_eq!(handle.payload(), &bytes[16..len]);
+    }
+
+    #[test]
+    fn test_decode_ignores_disabled_and_unknown_channels() {
+        let disabled = Channel::new(1, "Off", &[0x07], ChannelRole::Disabled).unwrap();
+        let mut set = ChannelSet::new();
+        set.set(disabled.clone());
+        assert_eq!(
+            set.decode(&encrypted_packet(&disabled, b"hello")).err(),
+            Some(PacketError::UnknownChannel)
+        );
+
+        let unknown = Channel::new(2, "Elsewhere", &[0x09; 16], ChannelRole::Secondary).unwrap();
+        assert_eq!(
+            ChannelSet::with_default_channel()
+                .decode(&encrypted_packet(&unknown, b"hello"))
+                .err(),
+            Some(PacketError::UnknownChannel)
+        );
+    }
+
+    #[test]
+    fn test_protobuf_round_trip() {
+        let mut channel = Channel::new(2, "Friends", &[0x09; 16], ChannelRole::Secondary).unwrap();
+        channel.set_position_precision(16);
+        assert_eq!(Channel::from_protobuf(&channel.to_protobuf()).as_ref(), Ok(&channel));
+
+        let default = ChannelSet::with_default_channel();
+        let primary = default.primary().unwrap();
+        assert_eq!(primary.to_protobuf().settings.unwrap().psk, [0x01]);
+        assert_eq!(Channel::from_protobuf(&primary.to_protobuf()).as_ref(), Ok(primary));
+
+        let mut unnamed = channel.to_protobuf();
+        unnamed.settings.as_mut().unwrap().name = "";
+        let unnamed = Channel::from_protobuf(&unnamed).unwrap();
+        assert!(unnamed.is_unnamed());
+        assert_eq!(unnamed.name(), "LongFast");
+        assert_eq!(unnamed.to_protobuf().settings.unwrap().name, "");
+    }
+
+    #[test]
+    fn test_unnamed_channel_follows_modem_preset() {
+        let mut channels = ChannelSet::with_default_channel();
+        channels.set(Channel::new(1, "Friends", &[0x09; 16], ChannelRole::Secondary).unwrap());
+        assert_eq!(channels.primary().unwrap().name(), "LongFast");
+        assert_eq!(channels.primary().unwrap().hash(), 0x08);
+
+        channels.set_modem_preset(ModemPreset::MediumFast);
+        let primary = channels.primary().unwrap();
+        assert_eq!(primary.name(), "MediumFast");
+        assert_eq!(primary.configured_name(), "");
+        assert_eq!(
+            Some(primary.hash()),
+            generate_channel_hash("MediumFast", primary.psk())
+        );
+        assert_eq!(channels.candidates(primary.hash()).next(), Some(primary));
+        assert_eq!(channels.get(1).unwrap().name(), "Friends");
+    }
 }
@@ -0,0 +1,481 @@
+//! Meshtastic channel URLs
+//!
+//! The official apps share channel configurations as links of the form
+//! `https://meshtastic.org/e/#<data>`, usually rendered as a QR code. `<data>`
+//! is a base64url encoded `ChannelSet` protobuf containing the settings of
+//! every channel plus the LoRa configuration. Links of the form
+//! `https://meshtastic.org/e/?add=true#<data>` ask the receiver to add the
+//! channels to its existing ones instead of replacing them.
+//!
+//! This module converts between those links and the crate's [`ChannelSet`].
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use femtopb::Message as _;
+use heapless::{String, Vec};
+use meshtastic_protobufs::meshtastic::config::lo_ra_config::{ModemPreset, RegionCode};
+use meshtastic_protobufs::meshtastic::config::LoRaConfig;
+use meshtastic_protobufs::meshtastic::{ChannelSettings, ModuleSettings};
+
+use crate::channel::{Channel, ChannelError, ChannelRole, ChannelSet, MAX_CHANNELS};
+
+/// Prefix of a URL that replaces the receiver's channels
+pub const CHANNEL_URL_PREFIX: &str = "https://meshtastic.org/e/#";
+
+/// Prefix of a URL that adds channels to the receiver's existing ones
+pub const ADD_CHANNELS_URL_PREFIX: &str = "https://meshtastic.org/e/?add=true#";
+
+/// Largest encoded `ChannelSet` protobuf we handle
+pub const MAX_CHANNEL_SET_LEN: usize = 768;
+
+/// Largest channel URL we generate or accept
+pub const MAX_CHANNEL_URL_LEN: usize =
+    ADD_CHANNELS_URL_PREFIX.len() + (MAX_CHANNEL_SET_LEN * 4).div_ceil(3);
+
+/// Errors that can occur when parsing or generating a channel URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChannelUrlError {
+    /// The URL does not point to `meshtastic.org/e/`
+    InvalidUrl,
+    /// The URL fragment is not valid base64
+    InvalidBase64,
+    /// The decoded data is not a valid `ChannelSet` protobuf
+    InvalidProtobuf,
+    /// The URL or its channel data is larger than we can handle
+    TooLong,
+    /// The URL does not contain any channels
+    NoChannels,
+    /// The URL contains more than `MAX_CHANNELS` channels
+    TooManyChannels,
+    /// One of the channels could not be converted
+    Channel(ChannelError),
+}
+
+impl From<ChannelError> for ChannelUrlError {
+    fn from(err: ChannelError) -> Self {
+        ChannelUrlError::Channel(err)
+    }
+}
+
+/// LoRa settings carried in a channel URL
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoraSettings {
+    pub use_preset: bool,
+    pub modem_preset: ModemPreset,
+    pub bandwidth: u32,
+    pub spread_factor: u32,
+    pub coding_rate: u32,
+    pub frequency_offset: f32,
+    pub region: RegionCode,
+    pub hop_limit: u32,
+    pub tx_enabled: bool,
+    pub tx_power: i32,
+    pub channel_num: u32,
+    pub override_duty_cycle: bool,
+    pub sx126x_rx_boosted_gain: bool,
+    pub override_frequency: f32,
+    pub pa_fan_disabled: bool,
+    pub ignore_mqtt: bool,
+    pub config_ok_to_mqtt: bool,
+}
+
+impl Default for LoraSettings {
+    fn default() -> Self {
+        Self::from_protobuf(&LoRaConfig::default())
+    }
+}
+
+impl LoraSettings {
+    /// Convert from the protobuf representation, unknown enum values fall back to their defaults
+    pub fn from_protobuf(config: &LoRaConfig) -> Self {
+        Self {
+            use_preset: config.use_preset,
+            modem_preset: match config.modem_preset {
+                femtopb::EnumValue::Known(preset) => preset,
+                femtopb::EnumValue::Unknown(_) => ModemPreset::default(),
+            },
+            bandwidth: config.bandwidth,
+            spread_factor: config.spread_factor,
+            coding_rate: config.coding_rate,
+            frequency_offset: config.frequency_offset,
+            region: match config.region {
+                femtopb::EnumValue::Known(region) => region,
+                femtopb::EnumValue::Unknown(_) => RegionCode::default(),
+            },
+            hop_limit: config.hop_limit,
+            tx_enabled: config.tx_enabled,
+            tx_power: config.tx_power,
+            channel_num: config.channel_num,
+            override_duty_cycle: config.override_duty_cycle,
+            sx126x_rx_boosted_gain: config.sx126x_rx_boosted_gain,
+            override_frequency: config.override_frequency,
+            pa_fan_disabled: config.pa_fan_disabled,
+            ignore_mqtt: config.ignore_mqtt,
+            config_ok_to_mqtt: config.config_ok_to_mqtt,
+        }
+    }
+
+    /// Convert to the protobuf representation
+    pub fn to_protobuf(&self) -> LoRaConfig<'static> {
+        LoRaConfig {
+            use_preset: self.use_preset,
+            modem_preset: femtopb::EnumValue::Known(self.modem_preset),
+            bandwidth: self.bandwidth,
+            spread_factor: self.spread_factor,
+            coding_rate: self.coding_rate,
+            frequency_offset: self.frequency_offset,
+            region: femtopb::EnumValue::Known(self.region),
+            hop_limit: self.hop_limit,
+            tx_enabled: self.tx_enabled,
+            tx_power: self.tx_power,
+            channel_num: self.channel_num,
+            override_duty_cycle: self.override_duty_cycle,
+            sx126x_rx_boosted_gain: self.sx126x_rx_boosted_gain,
+            override_frequency: self.override_frequency,
+            pa_fan_disabled: self.pa_fan_disabled,
+            ignore_mqtt: self.ignore_mqtt,
+            config_ok_to_mqtt: self.config_ok_to_mqtt,
+            ..Default::default()
+        }
+    }
+}
+
+/// Name used for a channel whose name is left empty
+///
+/// Meshtastic displays (and hashes) such channels using the name of the modem preset.
+pub fn preset_channel_name(preset: ModemPreset) -> &'static str {
+    match preset {
+        ModemPreset::LongFast => "LongFast",
+        ModemPreset::LongSlow => "LongSlow",
+        ModemPreset::VeryLongSlow => "VLongSlow",
+        ModemPreset::MediumSlow => "MediumSlow",
+        ModemPreset::MediumFast => "MediumFast",
+        ModemPreset::ShortSlow => "ShortSlow",
+        ModemPreset::ShortFast => "ShortFast",
+        ModemPreset::LongModerate => "LongMod",
+        ModemPreset::ShortTurbo => "ShortTurbo",
+    }
+}
+
+/// The contents of a channel URL
+#[derive(Debug, Clone)]
+pub struct ChannelUrl {
+    /// The channels, indexed in the order they appear in the URL
+    pub channels: ChannelSet,
+    /// LoRa settings, if the URL carried any
+    pub lora: Option<LoraSettings>,
+    /// True for "add channels" URLs (`/e/?add=true#`)
+    pub add: bool,
+}
+
+impl ChannelUrl {
+    /// Parse a `https://meshtastic.org/e/#...` URL
+    ///
+    /// The first channel becomes the primary channel and the rest are
+    /// secondary, except for "add channels" URLs where every channel is
+    /// secondary. Channels with an empty name stay unnamed and go by the name
+    /// of the modem preset.
+    pub fn parse(url: &str) -> Result<Self, ChannelUrlError> {
+        let (add, fragment) = split_url(url)?;
+
+        let mut normalized: Vec<u8, { MAX_CHANNEL_URL_LEN }> = Vec::new();
+        for c in fragment.bytes() {
+            // Accept the standard alphabet and padding as well as the URL-safe one
+            let c = match c {
+                b'+' => b'-',
+                b'/' => b'_',
+                b'=' => continue,
+                c => c,
+            };
+            normalized.push(c).map_err(|_| ChannelUrlError::TooLong)?;
+        }
+
+        let mut buffer = [0u8; MAX_CHANNEL_SET_LEN];
+        let len = URL_SAFE_NO_PAD
+            .decode_slice(&normalized, &mut buffer)
+            .map_err(|_| ChannelUrlError::InvalidBase64)?;
+
+        let channel_set = meshtastic_protobufs::meshtastic::ChannelSet::decode(&buffer[..len])
+            .map_err(|_| ChannelUrlError::InvalidProtobuf)?;
+
+        let lora = channel_set
+            .lora_config
+            .as_ref()
+            .map(LoraSettings::from_protobuf);
+        let mut channels = ChannelSet::new();
+        channels.set_modem_preset(lora.unwrap_or_default().modem_preset);
+        for (index, settings) in channel_set.settings.iter().enumerate() {
+            let settings = settings.map_err(|_| ChannelUrlError::InvalidProtobuf)?;
+            if index >= MAX_CHANNELS {
+                return Err(ChannelUrlError::TooManyChannels);
+            }
+
+            let role = if index == 0 && !add {
+                ChannelRole::Primary
+            } else {
+                ChannelRole::Secondary
+            };
+            let mut channel = Channel::new(index as u8, settings.name, settings.psk, role)?;
+            if let Some(module_settings) = settings.module_settings.as_ref() {
+                channel.set_position_precision(module_settings.position_precision);
+            }
+            channels.set(channel);
+        }
+
+        if channels.is_empty() {
+            return Err(ChannelUrlError::NoChannels);
+        }
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Parsed channel URL with {} channels (add: {})",
+            channels.len(),
+            add
+        );
+
+        Ok(Self {
+            channels,
+            lora,
+            add,
+        })
+    }
+
+    /// Generate a URL containing every enabled channel, in index order
+    pub fn to_url(&self) -> Result<String<MAX_CHANNEL_URL_LEN>, ChannelUrlError> {
+        let mut settings: Vec<ChannelSettings, MAX_CHANNELS> = Vec::new();
+        for channel in self.channels.iter().filter(|c| c.is_enabled()) {
+            // The channel set holds at most MAX_CHANNELS channels
+            let _ = settings.push(ChannelSettings {
+                psk: channel.psk().psk(),
+                name: channel.configured_name(),
+                module_settings: Some(ModuleSettings {
+                    position_precision: channel.position_precision(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        if settings.is_empty() {
+            return Err(ChannelUrlError::NoChannels);
+        }
+
+        let channel_set = meshtastic_protobufs::meshtastic::ChannelSet {
+            settings: femtopb::repeated::Repeated::from_slice(&settings),
+            lora_config: self.lora.as_ref().map(LoraSettings::to_protobuf),
+            ..Default::default()
+        };
+
+        let encoded_len = channel_set.encoded_len();
+        if encoded_len > MAX_CHANNEL_SET_LEN {
+            return Err(ChannelUrlError::TooLong);
+        }
+        let mut buffer = [0u8; MAX_CHANNEL_SET_LEN];
+        let mut slice = &mut buffer[..encoded_len];
+        channel_set
+            .encode(&mut slice)
+            .map_err(|_| ChannelUrlError::TooLong)?;
+
+        let mut fragment = [0u8; MAX_CHANNEL_URL_LEN];
+        let fragment_len = URL_SAFE_NO_PAD
+            .encode_slice(&buffer[..encoded_len], &mut fragment)
+            .map_err(|_| ChannelUrlError::TooLong)?;
+        // base64 output is always ASCII
+        let fragment = core::str::from_utf8(&fragment[..fragment_len])
+            .map_err(|_| ChannelUrlError::InvalidBase64)?;
+
+        let mut url = String::new();
+        let prefix = if self.add {
+            ADD_CHANNELS_URL_PREFIX
+        } else {
+            CHANNEL_URL_PREFIX
+        };
+        url.push_str(prefix)
+            .and_then(|_| url.push_str(fragment))
+            .map_err(|_| ChannelUrlError::TooLong)?;
+        Ok(url)
+    }
+}
+
+/// Split a channel URL into its "add channels" flag and base64 fragment
+fn split_url(url: &str) -> Result<(bool, &str), ChannelUrlError> {
+    let url = url.trim();
+    let (location, fragment) = url.split_once('#').ok_or(ChannelUrlError::InvalidUrl)?;
+
+    let location = location
+        .strip_prefix("https://")
+        .or_else(|| location.strip_prefix("http://"))
+        .unwrap_or(location);
+    let (host, path) = location
+        .split_once('/')
+        .ok_or(ChannelUrlError::InvalidUrl)?;
+    if !host.eq_ignore_ascii_case("meshtastic.org")
+        && !host.eq_ignore_ascii_case("www.meshtastic.org")
+    {
+        return Err(ChannelUrlError::InvalidUrl);
+    }
+
+    let add = match path {
+        "e/" | "e" => false,
+        "e/?add=true" | "e?add=true" => true,
+        _ => return Err(ChannelUrlError::InvalidUrl),
+    };
+
+    if fragment.is_empty() {
+        return Err(ChannelUrlError::NoChannels);
+    }
+    Ok((add, fragment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::MeshKey;
+
+    // The stock default channel: unnamed, default key, LongFast preset
+    const DEFAULT_CHANNEL_URL: &str = "https://meshtastic.org/e/#CgMSAQESBggBQANIAQ";
+
+    #[test]
+    fn test_parse_default_channel_url() {
+        let parsed = ChannelUrl::parse(DEFAULT_CHANNEL_URL).unwrap();
+        assert!(!parsed.add);
+        assert_eq!(parsed.channels.len(), 1);
+
+        let primary = parsed.channels.primary().unwrap();
+        assert_eq!(primary.index(), 0);
+        assert_eq!(primary.name(), "LongFast");
+        assert_eq!(primary.psk(), &MeshKey::new(&[0x01]).unwrap());
+        assert_eq!(primary.hash(), 0x08);
+
+        let lora = parsed.lora.unwrap();
+        assert!(lora.use_preset);
+        assert_eq!(lora.modem_preset, ModemPreset::LongFast);
+        assert_eq!(lora.region, RegionCode::Unset);
+        assert_eq!(lora.hop_limit, 3);
+        assert!(lora.tx_enabled);
+    }
+
+    #[test]
+    fn test_parse_unnamed_very_long_slow_channel() {
+        // The default channel on the VeryLongSlow preset
+        let parsed = ChannelUrl::parse("https://meshtastic.org/e/#CgMSAQESCAgBEAJAA0gB").unwrap();
+        assert_eq!(parsed.lora.unwrap().modem_preset, ModemPreset::VeryLongSlow);
+        let primary = parsed.channels.primary().unwrap();
+        assert_eq!(primary.name(), "VLongSlow");
+        assert_eq!(
+            primary.hash(),
+            crate::channel::generate_channel_hash("VLongSlow", primary.psk()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trip_multiple_channels() {
+        let mut channels = ChannelSet::with_default_channel();
+        channels.set_modem_preset(ModemPreset::MediumFast);
+        channels.set(Channel::new(1, "Private", &[0x5A; 16], ChannelRole::Secondary).unwrap());
+        let mut admin = Channel::new(2, "Admin", &[0xA5; 32], ChannelRole::Secondary).unwrap();
+        admin.set_position_precision(32);
+        channels.set(admin);
+        let lora = LoraSettings {
+            use_preset: true,
+            modem_preset: ModemPreset::MediumFast,
+            region: RegionCode::Eu868,
+            hop_limit: 5,
+            tx_enabled: true,
+            ..Default::default()
+        };
+        let original = ChannelUrl {
+            channels,
+            lora: Some(lora),
+            add: false,
+        };
+
+        let url = original.to_url().unwrap();
+        assert!(url.starts_with(CHANNEL_URL_PREFIX));
+
+        let parsed = ChannelUrl::parse(&url).unwrap();
+        assert_eq!(parsed.lora, Some(lora));
+        assert_eq!(parsed.channels.len(), 3);
+        for (a, b) in original.channels.iter().zip(parsed.channels.iter()) {
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_add_channels_url() {
+        let mut channels = ChannelSet::new();
+        channels.set(Channel::new(0, "Extra", &[0x33; 16], ChannelRole::Secondary).unwrap());
+        let url = ChannelUrl {
+            channels,
+            lora: None,
+            add: true,
+        }
+        .to_url()
+        .unwrap();
+        assert!(url.starts_with(ADD_CHANNELS_URL_PREFIX));
+
+        let parsed = ChannelUrl::parse(&url).unwrap();
+        assert!(parsed.add);
+        assert!(parsed.lora.is_none());
+        assert!(parsed.channels.primary().is_none());
+        assert_eq!(parsed.channels.get(0).unwrap().name(), "Extra");
+    }
+
+    #[test]
+    fn test_parse_accepts_padding_and_standard_alphabet() {
+        let mut channels = ChannelSet::new();
+        channels.set(Channel::new(0, "Test", &[0xFB; 16], ChannelRole::Primary).unwrap());
+        let url = ChannelUrl {
+            channels,
+            lora: None,
+            add: false,
+        }
+        .to_url()
+        .unwrap();
+
+        let mut standard: String<MAX_CHANNEL_URL_LEN> = String::new();
+        for c in url.chars() {
+            let c = match c {
+                '-' => '+',
+                '_' => '/',
+                c => c,
+            };
+            standard.push(c).unwrap();
+        }
+        while (standard.len() - CHANNEL_URL_PREFIX.len()) % 4 != 0 {
+            standard.push('=').unwrap();
+        }
+
+        let parsed = ChannelUrl::parse(&standard).unwrap();
+        assert_eq!(
+            parsed.channels.get(0).unwrap().psk(),
+            &MeshKey::new(&[0xFB; 16]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            ChannelUrl::parse("https://example.com/e/#CgMSAQE").unwrap_err(),
+            ChannelUrlError::InvalidUrl
+        );
+        assert_eq!(
+            ChannelUrl::parse("https://meshtastic.org/e/CgMSAQE").unwrap_err(),
+            ChannelUrlError::InvalidUrl
+        );
+        assert_eq!(
+            ChannelUrl::parse("https://meshtastic.org/e/#").unwrap_err(),
+            ChannelUrlError::NoChannels
+        );
+        assert_eq!(
+            ChannelUrl::parse("https://meshtastic.org/e/#!!!!").unwrap_err(),
+            ChannelUrlError::InvalidBase64
+        );
+        // A single channel with a 5 byte PSK
+        assert_eq!(
+            ChannelUrl::parse("https://meshtastic.org/e/#CgcSBQECAwQF").unwrap_err(),
+            ChannelUrlError::Channel(ChannelError::InvalidKey)
+        );
+    }
+}
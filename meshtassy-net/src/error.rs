@@ -0,0 +1,194 @@
+//! Errors of the packet pipeline
+//!
+//! Every stage of turning received bytes into a [`DecodedPacket`](crate::DecodedPacket),
+//! and of encoding and encrypting a packet to send, reports why it failed with a
+//! [`PacketError`]. [`PacketErrorCounters`] keeps a
+//! tally per reason so field logs can tell a short frame from a wrong key.
+
+use crate::key::KeyError;
+
+/// Reasons a packet could not be parsed, decrypted, decoded, encoded or encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketError {
+    /// Frame is shorter than the 16-byte header
+    TooShort,
+    /// Header slice is not exactly 16 bytes
+    InvalidHeader,
+    /// Payload does not fit in a LoRa frame
+    PayloadTooLarge,
+    /// Output buffer is too small for the packet
+    BufferTooSmall,
+    /// Packet has no payload
+    EmptyPayload,
+    /// The key could not be used
+    Key(KeyError),
+    /// Payload is not a valid protobuf
+    Protobuf,
+    /// Text message is not valid UTF-8
+    InvalidUtf8,
+    /// No enabled channel matches the channel hash
+    UnknownChannel,
+    /// Payload is still encrypted
+    NotDecrypted,
+    /// Every buffer of the packet pool is in use
+    PoolExhausted,
+}
+
+impl From<KeyError> for PacketError {
+    fn from(error: KeyError) -> Self {
+        PacketError::Key(error)
+    }
+}
+
+impl core::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PacketError::TooShort => write!(f, "frame shorter than header"),
+            PacketError::InvalidHeader => write!(f, "invalid header length"),
+            PacketError::PayloadTooLarge => write!(f, "payload too large"),
+            PacketError::BufferTooSmall => write!(f, "buffer too small"),
+            PacketError::EmptyPayload => write!(f, "empty payload"),
+            PacketError::Key(error) => write!(f, "key error: {}", error),
+            PacketError::Protobuf => write!(f, "invalid protobuf"),
+            PacketError::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            PacketError::UnknownChannel => write!(f, "unknown channel"),
+            PacketError::NotDecrypted => write!(f, "payload not decrypted"),
+            PacketError::PoolExhausted => write!(f, "packet pool exhausted"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PacketError {}
+
+/// Number of failed packets for each [`PacketError`] reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacketErrorCounters {
+    pub too_short: u32,
+    pub invalid_header: u32,
+    pub payload_too_large: u32,
+    pub buffer_too_small: u32,
+    pub empty_payload: u32,
+    pub key: u32,
+    pub protobuf: u32,
+    pub invalid_utf8: u32,
+    pub unknown_channel: u32,
+    pub not_decrypted: u32,
+    pub pool_exhausted: u32,
+}
+
+impl PacketErrorCounters {
+    /// Create a set of counters starting at zero
+    pub const fn new() -> Self {
+        Self {
+            too_short: 0,
+            invalid_header: 0,
+            payload_too_large: 0,
+            buffer_too_small: 0,
+            empty_payload: 0,
+            key: 0,
+            protobuf: 0,
+            invalid_utf8: 0,
+            unknown_channel: 0,
+            not_decrypted: 0,
+            pool_exhausted: 0,
+        }
+    }
+
+    /// Count one failure
+    pub fn record(&mut self, error: PacketError) {
+        let counter = self.counter_mut(error);
+        *counter = counter.saturating_add(1);
+    }
+
+    /// Number of failures recorded for the reason of `error`
+    ///
+    /// All [`PacketError::Key`] errors share one counter.
+    pub fn count(&self, error: PacketError) -> u32 {
+        let mut counters = *self;
+        *counters.counter_mut(error)
+    }
+
+    /// Number of failures recorded for all reasons
+    pub fn total(&self) -> u32 {
+        [
+            self.too_short,
+            self.invalid_header,
+            self.payload_too_large,
+            self.buffer_too_small,
+            self.empty_payload,
+            self.key,
+            self.protobuf,
+            self.invalid_utf8,
+            self.unknown_channel,
+            self.not_decrypted,
+            self.pool_exhausted,
+        ]
+        .iter()
+        .fold(0u32, |total, count| total.saturating_add(*count))
+    }
+
+    /// Reset all counters to zero
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    fn counter_mut(&mut self, error: PacketError) -> &mut u32 {
+        match error {
+            PacketError::TooShort => &mut self.too_short,
+            PacketError::InvalidHeader => &mut self.invalid_header,
+            PacketError::PayloadTooLarge => &mut self.payload_too_large,
+            PacketError::BufferTooSmall => &mut self.buffer_too_small,
+            PacketError::EmptyPayload => &mut self.empty_payload,
+            PacketError::Key(_) => &mut self.key,
+            PacketError::Protobuf => &mut self.protobuf,
+            PacketError::InvalidUtf8 => &mut self.invalid_utf8,
+            PacketError::UnknownChannel => &mut self.unknown_channel,
+            PacketError::NotDecrypted => &mut self.not_decrypted,
+            PacketError::PoolExhausted => &mut self.pool_exhausted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_per_reason() {
+        let mut counters = PacketErrorCounters::new();
+        counters.record(PacketError::TooShort);
+        counters.record(PacketError::Protobuf);
+        counters.record(PacketError::Protobuf);
+        counters.record(PacketError::Key(KeyError::EmptyData));
+        counters.record(PacketError::Key(KeyError::InvalidKeySize));
+
+        assert_eq!(counters.count(PacketError::TooShort), 1);
+        assert_eq!(counters.count(PacketError::Protobuf), 2);
+        assert_eq!(counters.count(PacketError::Key(KeyError::EmptyData)), 2);
+        assert_eq!(counters.count(PacketError::UnknownChannel), 0);
+        assert_eq!(counters.total(), 5);
+
+        counters.clear();
+        assert_eq!(counters, PacketErrorCounters::default());
+    }
+
+    #[test]
+    fn test_display() {
+        use core::fmt::Write;
+
+        fn display(error: PacketError) -> heapless::String<64> {
+            let mut text = heapless::String::new();
+            write!(text, "{}", error).unwrap();
+            text
+        }
+
+        assert_eq!(display(PacketError::TooShort), "frame shorter than header");
+        assert_eq!(
+            display(PacketError::Key(KeyError::InvalidKeySize)),
+            "key error: invalid key size"
+        );
+    }
+}
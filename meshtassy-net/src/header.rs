@@ -1,6 +1,32 @@
 #[cfg(feature = "defmt")]
 use defmt;
 
+use crate::error::PacketError;
+
+/// Destination of packets sent to every node
+pub const BROADCAST_ADDR: u32 = 0xFFFF_FFFF;
+
+/// Node numbers below this one are reserved by Meshtastic
+pub const NUM_RESERVED_NODES: u32 = 4;
+
+/// Derive our node number from the chip's unique ID
+///
+/// Every byte of the ID is folded into the number, so two chips only collide
+/// if their IDs agree on the folded value. Numbers Meshtastic reserves, and
+/// the broadcast address, are moved out of the way by flipping the top bit.
+pub fn node_num_from_unique_id(unique_id: &[u8]) -> u32 {
+    let mut node_num = 0u32;
+    for chunk in unique_id.chunks(4) {
+        let mut bytes = [0u8; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        node_num ^= u32::from_be_bytes(bytes);
+    }
+    if node_num < NUM_RESERVED_NODES || node_num == BROADCAST_ADDR {
+        node_num ^= 0x8000_0000;
+    }
+    node_num
+}
+
 /// Represents a parsed packet header (16 bytes)
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Header {
@@ -87,26 +113,26 @@ impl defmt::Format for Header {
 }
 
 impl Header {    /// Parse a 16-byte header from a byte slice
-    /// Returns None if the slice is not exactly 16 bytes
-    pub fn from_bytes(header_bytes: &[u8]) -> Option<Self> {
+    /// Returns `PacketError::InvalidHeader` if the slice is not exactly 16 bytes
+    pub fn from_bytes(header_bytes: &[u8]) -> Result<Self, PacketError> {
         #[cfg(feature = "defmt")]
         defmt::trace!("Parsing header from {} bytes", header_bytes.len());
         
         if header_bytes.len() != 16 {
             #[cfg(feature = "defmt")]
             defmt::error!("Invalid header length: {} (expected 16)", header_bytes.len());
-            return None;
+            return Err(PacketError::InvalidHeader);
         }
 
-        let destination = u32::from_le_bytes(header_bytes[0..4].try_into().ok()?);
-        let source = u32::from_le_bytes(header_bytes[4..8].try_into().ok()?);
-        let packet_id = u32::from_le_bytes(header_bytes[8..12].try_into().ok()?);
+        let destination = u32::from_le_bytes(header_bytes[0..4].try_into().map_err(|_| PacketError::InvalidHeader)?);
+        let source = u32::from_le_bytes(header_bytes[4..8].try_into().map_err(|_| PacketError::InvalidHeader)?);
+        let packet_id = u32::from_le_bytes(header_bytes[8..12].try_into().map_err(|_| PacketError::InvalidHeader)?);
         let flags = HeaderFlags::from_raw(header_bytes[12]);
         let channel_hash = header_bytes[13];
         let next_hop = header_bytes[14];
         let relay_node = header_bytes[15];
 
-        Some(Header {
+        Ok(Header {
             destination,
             source,
             packet_id,
@@ -220,3 +246,19 @@ impl HeaderFlags {
             | (self.hop_limit & 0b00000111)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_num_from_unique_id() {
+        let id = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        assert_eq!(node_num_from_unique_id(&id), 0x1234_5678 ^ 0x9ABC_DEF0);
+
+        // Reserved numbers and the broadcast address are never ours
+        assert_eq!(node_num_from_unique_id(&[0; 8]), 0x8000_0000);
+        assert_eq!(node_num_from_unique_id(&[0, 0, 0, 3]), 0x8000_0003);
+        assert_eq!(node_num_from_unique_id(&[0xFF; 4]), 0x7FFF_FFFF);
+    }
+}
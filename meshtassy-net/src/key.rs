@@ -30,6 +30,15 @@ pub enum KeyError {
     EmptyData,
 }
 
+impl core::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KeyError::InvalidKeySize => write!(f, "invalid key size"),
+            KeyError::EmptyData => write!(f, "empty data"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(Format))]
 pub enum MeshKey {
@@ -99,6 +108,17 @@ impl MeshKey {
             MeshKey::MeshKey256bit(key) => key,
         }
     }
+
+    /// Get the PSK as it is stored in `ChannelSettings.psk`
+    /// Empty and 1-byte keys are returned in their short form rather than expanded
+    pub fn psk(&self) -> &[u8] {
+        match self {
+            MeshKey::MeshKeyEmpty(_) => &[],
+            MeshKey::MeshKey8bit(key) => &key[15..],
+            MeshKey::MeshKey128bit(key) => key,
+            MeshKey::MeshKey256bit(key) => key,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -111,7 +131,8 @@ pub enum ChannelKey {
 }
 
 impl ChannelKey {    /// Create a ChannelKey from raw bytes
-    pub fn from_bytes(key: &[u8], key_len: usize) -> Option<Self> {
+    /// Returns `KeyError::InvalidKeySize` unless `key_len` is 0, 1, 16 or 32
+    pub fn from_bytes(key: &[u8], key_len: usize) -> Result<Self, KeyError> {
         #[cfg(feature = "defmt")]
         defmt::trace!("Creating ChannelKey from {} bytes (effective length: {})", key.len(), key_len);
         
@@ -120,7 +141,7 @@ impl ChannelKey {    /// Create a ChannelKey from raw bytes
                 #[cfg(feature = "defmt")]
                 defmt::trace!("Using default key for empty key");
                 // Use default key for empty key
-                Some(ChannelKey::AES128(MESHTASTIC_DEFAULT_KEY))
+                Ok(ChannelKey::AES128(MESHTASTIC_DEFAULT_KEY))
             }
             1 => {
                 #[cfg(feature = "defmt")]
@@ -128,35 +149,35 @@ impl ChannelKey {    /// Create a ChannelKey from raw bytes
                 // Use default key with LSB replaced
                 let mut expanded_key = MESHTASTIC_DEFAULT_KEY;
                 expanded_key[15] = key[0];
-                Some(ChannelKey::AES128(expanded_key))
+                Ok(ChannelKey::AES128(expanded_key))
             }
             16 => {
                 #[cfg(feature = "defmt")]
                 defmt::trace!("Using 16-byte AES-128 key: {:02X}", &key[..16]);
                 let mut array = [0u8; 16];
                 array.copy_from_slice(key);
-                Some(ChannelKey::AES128(array))
+                Ok(ChannelKey::AES128(array))
             }
             32 => {
                 #[cfg(feature = "defmt")]
                 defmt::trace!("Using 32-byte AES-256 key: {:02X}", &key[..16]); // Only show first 16 bytes
                 let mut array = [0u8; 32];
                 array.copy_from_slice(key);
-                Some(ChannelKey::AES256(array))
+                Ok(ChannelKey::AES256(array))
             }
             _ => {
                 #[cfg(feature = "defmt")]
                 defmt::error!("Invalid key length: {}", key_len);
-                None
+                Err(KeyError::InvalidKeySize)
             }
         };
 
         #[cfg(feature = "defmt")]
         {
             match &result {
-                Some(ChannelKey::AES128(_)) => defmt::trace!("Created AES-128 ChannelKey"),
-                Some(ChannelKey::AES256(_)) => defmt::trace!("Created AES-256 ChannelKey"),
-                None => defmt::error!("Failed to create ChannelKey"),
+                Ok(ChannelKey::AES128(_)) => defmt::trace!("Created AES-128 ChannelKey"),
+                Ok(ChannelKey::AES256(_)) => defmt::trace!("Created AES-256 ChannelKey"),
+                Err(_) => defmt::error!("Failed to create ChannelKey"),
             }
         }
 
@@ -11,8 +11,35 @@ use defmt::Format;
 // Re-export commonly used types
 pub use meshtastic_protobufs::meshtastic::PortNum;
 
+/// Length of the unencrypted packet header
+pub const HEADER_LEN: usize = 16;
+
+/// Largest frame Meshtastic sends over LoRa (header included)
+pub const MAX_LORA_PACKET_LEN: usize = 255;
+
+/// Largest encrypted payload that fits in a LoRa frame after the header
+pub const MAX_ENCRYPTED_PAYLOAD_LEN: usize = MAX_LORA_PACKET_LEN - HEADER_LEN;
+
+/// Largest application payload carried inside a Data message (Meshtastic's DATA_PAYLOAD_LEN)
+pub const DATA_PAYLOAD_LEN: usize = 233;
+
+// Airtime accounting and duty cycle limiting
+pub mod airtime;
+
+// Typed decoding of application payloads
+pub mod app_payload;
+pub use app_payload::AppPayload;
+
 // Channel hash generation utilities
 pub mod channel;
+pub use channel::ChannelSet;
+
+// Channel URL (QR code) import and export
+pub mod channel_url;
+
+// Packet pipeline errors
+pub mod error;
+pub use error::{PacketError, PacketErrorCounters};
 
 // packet header types
 pub mod header;
@@ -22,9 +49,54 @@ pub use header::Header;
 pub mod key;
 use crate::key::ChannelKey;
 
+// Meshtastic application modules
+pub mod modules;
+
+// Next-hop routes for direct messages
+pub mod next_hop;
+
 // Node database for storing device information
 pub mod node_database;
 
+// Duplicate packet suppression
+pub mod packet_history;
+pub use packet_history::PacketHistory;
+
+// Fixed-capacity packet buffers shared by reference counted handles
+pub mod pool;
+pub use pool::{PacketHandle, PacketPool};
+
+// Public key encryption for direct messages
+pub mod pki;
+
+// Radio abstraction and the RX/TX loop
+pub mod radio;
+
+// LoRa regions, modem presets and frequency slots
+pub mod radio_config;
+
+// Reliable delivery with ACKs and retransmissions
+pub mod reliability;
+pub use reliability::RetransmissionTable;
+
+// Persistent records in flash
+pub mod storage;
+
+// Managed flood routing
+pub mod router;
+pub use router::Router;
+
+// Priority-ordered transmit queue
+pub mod tx_queue;
+pub use tx_queue::TxQueue;
+
+// Listen-before-talk transmit scheduling
+pub mod tx_scheduler;
+
+// Fixtures shared by the unit tests
+#[cfg(test)]
+mod test_util;
+
 /// Marker types to distinguish between encrypted and decrypted packet states
 #[derive(Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -40,7 +112,7 @@ pub struct Decrypted;
 pub struct Decoded;
 
 /// Owned Data payload that doesn't depend on zero-copy lifetimes
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct OwnedData {
     pub portnum: femtopb::EnumValue<meshtastic_protobufs::meshtastic::PortNum>,
@@ -52,9 +124,52 @@ pub struct OwnedData {
     pub request_id: u32,
     pub reply_id: u32,
     pub emoji: u32,
+    pub bitfield: Option<u32>,
 }
 
 impl OwnedData {
+    /// Create a new Data payload for sending on the given port
+    /// Fails if the payload is larger than `DATA_PAYLOAD_LEN`
+    pub fn new(portnum: PortNum, payload: &[u8]) -> Result<Self, PacketError> {
+        if payload.len() > DATA_PAYLOAD_LEN {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Payload too large: {} bytes (maximum {})", payload.len(), DATA_PAYLOAD_LEN);
+            return Err(PacketError::PayloadTooLarge);
+        }
+
+        let mut new_payload = [0u8; 240];
+        new_payload[..payload.len()].copy_from_slice(payload);
+
+        Ok(Self {
+            portnum: femtopb::EnumValue::Known(portnum),
+            payload: new_payload,
+            payload_len: payload.len(),
+            want_response: false,
+            dest: 0,
+            source: 0,
+            request_id: 0,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        })
+    }
+
+    /// Borrow as a protobuf Data message, ready to be encoded
+    pub fn to_protobuf(&self) -> meshtastic_protobufs::meshtastic::Data<'_> {
+        meshtastic_protobufs::meshtastic::Data {
+            portnum: self.portnum,
+            payload: &self.payload[..self.payload_len],
+            want_response: self.want_response,
+            dest: self.dest,
+            source: self.source,
+            request_id: self.request_id,
+            reply_id: self.reply_id,
+            emoji: self.emoji,
+            bitfield: self.bitfield,
+            unknown_fields: Default::default(),
+        }
+    }
+
     /// Convert from protobuf Data to owned data
     pub fn from_protobuf(data: &meshtastic_protobufs::meshtastic::Data) -> Self {
         #[cfg(feature = "defmt")]
@@ -75,6 +190,7 @@ impl OwnedData {
             request_id: data.request_id,
             reply_id: data.reply_id,
             emoji: data.emoji,
+            bitfield: data.bitfield,
         }
     }
 }
@@ -91,7 +207,7 @@ pub struct Packet<S> {
 }
 
 /// A decoded packet with structured data
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DecodedPacket {
     pub header: Header,
@@ -137,14 +253,19 @@ impl<S> Packet<S> {
 impl Packet<Encrypted> {    
     /// Create an encrypted packet from raw bytes
     /// The buffer should contain a 16-byte header followed by encrypted payload
-    pub fn from_bytes(buffer: &[u8], rssi: i8, snr: i8) -> Option<Self> {
+    pub fn from_bytes(buffer: &[u8], rssi: i8, snr: i8) -> Result<Self, PacketError> {
         #[cfg(feature = "defmt")]
         defmt::trace!("Creating encrypted packet from {} bytes (RSSI: {}, SNR: {})", buffer.len(), rssi, snr);
         
         if buffer.len() < 16 {
             #[cfg(feature = "defmt")]
             defmt::error!("Buffer too small: {} bytes (minimum 16 required)", buffer.len());
-            return None;
+            return Err(PacketError::TooShort);
+        }
+        if buffer.len() > MAX_LORA_PACKET_LEN {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Packet too large: {} bytes (maximum {})", buffer.len(), MAX_LORA_PACKET_LEN);
+            return Err(PacketError::PayloadTooLarge);
         }
 
         #[cfg(feature = "defmt")]
@@ -156,13 +277,13 @@ impl Packet<Encrypted> {
         defmt::trace!("Parsed header: {}", header);
         
         let mut payload = [0u8; 240];
-        let payload_len = (buffer.len() - 16).min(240);
+        let payload_len = buffer.len() - 16;
         payload[..payload_len].copy_from_slice(&buffer[16..16 + payload_len]);
 
         #[cfg(feature = "defmt")]
         defmt::trace!("Payload: {} bytes, encrypted: {:02X}", payload_len, &payload[..payload_len]);
 
-        Some(Self {
+        Ok(Self {
             header,
             rssi,
             snr,
@@ -172,10 +293,25 @@ impl Packet<Encrypted> {
         })
     }
 
+    /// Serialize the packet into its on-air form: the 16-byte header followed by the encrypted payload
+    /// Returns the number of bytes written, or `PacketError::BufferTooSmall`
+    pub fn to_bytes(&self, buffer: &mut [u8]) -> Result<usize, PacketError> {
+        let total_len = 16 + self.payload_len;
+        if buffer.len() < total_len {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Buffer too small: {} bytes ({} required)", buffer.len(), total_len);
+            return Err(PacketError::BufferTooSmall);
+        }
+
+        buffer[..16].copy_from_slice(&self.header.to_bytes());
+        buffer[16..total_len].copy_from_slice(&self.payload[..self.payload_len]);
+        Ok(total_len)
+    }
+
     /// Decrypts the packet payload using the provided key
-    /// Returns a DecryptedPacket on success, or None if decryption fails
+    /// Returns a DecryptedPacket on success, or the key error if decryption fails
     /// Consumes the original encrypted packet
-    pub fn decrypt(self, key: &ChannelKey) -> Result<Packet<Decrypted>, ()> {
+    pub fn decrypt(self, key: &ChannelKey) -> Result<Packet<Decrypted>, PacketError> {
         #[cfg(feature = "defmt")]
         defmt::trace!(
             "Starting decryption process - Header: {:?}, Encrypted payload length: {}, Encrypted payload: {:02X}",
@@ -208,25 +344,68 @@ impl Packet<Encrypted> {
                     payload_len: self.payload_len,
                     _marker: core::marker::PhantomData,
                 })
-            }            Err(_e) => {
+            }            Err(e) => {
                 #[cfg(feature = "defmt")]
-                defmt::error!("Decryption failed with error: {:?}", _e);
-                Err(())
+                defmt::error!("Decryption failed with error: {:?}", e);
+                Err(PacketError::Key(e))
             }
         }
     }
 }
 
 impl Packet<Decrypted> {
+    /// Encrypts the packet payload using the provided key
+    /// The IV is derived from the header, so the header must be final before encrypting
+    /// Consumes the original decrypted packet
+    pub fn encrypt(self, key: &ChannelKey) -> Result<Packet<Encrypted>, PacketError> {
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "Starting encryption process - Header: {:?}, Payload length: {}",
+            self.header,
+            self.payload_len
+        );
+
+        if self.payload_len == 0 {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Cannot encrypt packet: payload is empty");
+            return Err(PacketError::EmptyPayload);
+        }
+        if self.payload_len > MAX_ENCRYPTED_PAYLOAD_LEN {
+            #[cfg(feature = "defmt")]
+            defmt::error!(
+                "Cannot encrypt packet: payload is {} bytes (maximum {})",
+                self.payload_len,
+                MAX_ENCRYPTED_PAYLOAD_LEN
+            );
+            return Err(PacketError::PayloadTooLarge);
+        }
+
+        let iv = self.header.create_iv();
+        let mut encrypted_payload = self.payload;
+        key.transform(&mut encrypted_payload[..self.payload_len], &iv)?;
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!("Encrypted payload: {:02X}", encrypted_payload[..self.payload_len]);
+
+        Ok(Packet {
+            header: self.header,
+            rssi: self.rssi,
+            snr: self.snr,
+            payload: encrypted_payload,
+            payload_len: self.payload_len,
+            _marker: core::marker::PhantomData,
+        })
+    }
+
     /// Decode the payload into structured data
-    pub fn decode(self) -> Result<DecodedPacket, ()> {
+    pub fn decode(self) -> Result<DecodedPacket, PacketError> {
         #[cfg(feature = "defmt")]
         defmt::trace!("Starting packet decode process");
         
         if self.payload_len == 0 {
             #[cfg(feature = "defmt")]
             defmt::error!("Cannot decode packet: payload is empty");
-            return Err(());
+            return Err(PacketError::EmptyPayload);
         }
 
         #[cfg(feature = "defmt")]
@@ -241,7 +420,7 @@ impl Packet<Decrypted> {
                 defmt::error!("Failed to decode protobuf Data from payload");
                 defmt::error!("Payload data: {:02X}", &self.payload[..self.payload_len]);
             }
-            return Err(());
+            return Err(PacketError::Protobuf);
         };
         
         #[cfg(feature = "defmt")]
@@ -278,6 +457,46 @@ impl Packet<Decrypted> {
 }
 
 impl DecodedPacket {
+    /// Create a packet for sending from a header and its Data payload
+    pub fn new(header: Header, data: OwnedData) -> Self {
+        Self {
+            header,
+            rssi: 0,
+            snr: 0,
+            data,
+        }
+    }
+
+    /// Encode the Data payload to protobuf, producing a packet ready to be encrypted
+    /// This is the reverse of `Packet::<Decrypted>::decode`
+    pub fn encode(&self) -> Result<Packet<Decrypted>, PacketError> {
+        let data = self.data.to_protobuf();
+
+        let encoded_len = data.encoded_len();
+        if encoded_len > MAX_ENCRYPTED_PAYLOAD_LEN {
+            #[cfg(feature = "defmt")]
+            defmt::error!(
+                "Encoded Data is {} bytes (maximum {})",
+                encoded_len,
+                MAX_ENCRYPTED_PAYLOAD_LEN
+            );
+            return Err(PacketError::PayloadTooLarge);
+        }
+
+        let mut payload = [0u8; 240];
+        let mut slice = &mut payload[..encoded_len];
+        if data.encode(&mut slice).is_err() {
+            #[cfg(feature = "defmt")]
+            defmt::error!("Failed to encode protobuf Data");
+            return Err(PacketError::BufferTooSmall);
+        }
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!("Encoded Data: {:02X}", payload[..encoded_len]);
+
+        Ok(Packet::new(self.header, self.rssi, self.snr, payload, encoded_len))
+    }
+
     /// Get the port number from the decoded packet data
     pub fn port_num(&self) -> femtopb::EnumValue<meshtastic_protobufs::meshtastic::PortNum> {
         self.data.portnum
@@ -288,12 +507,72 @@ impl DecodedPacket {
         &self.data.payload[..self.data.payload_len]
     }
     
+    /// Decode the payload according to its port number
+    pub fn app_payload(&self) -> Result<AppPayload, PacketError> {
+        AppPayload::decode(self.data.portnum, self.payload_data())
+    }
+
     /// Get a reference to the owned data
-    pub fn data(&self) -> Result<&OwnedData, ()> {
+    pub fn data(&self) -> Result<&OwnedData, PacketError> {
         Ok(&self.data)
     }
 }
 
+/// Read access to a received packet whose payload has been decrypted
+///
+/// Implemented by [`DecodedPacket`], which owns a copy of the `Data` message,
+/// and by pooled packets, which decode it from their buffer on demand.
+pub trait ReceivedPacket {
+    /// The unencrypted packet header
+    fn header(&self) -> &Header;
+
+    /// Received signal strength in dBm
+    fn rssi(&self) -> i8;
+
+    /// Signal-to-noise ratio in dB
+    fn snr(&self) -> i8;
+
+    /// The `Data` message, borrowing its payload from the packet
+    fn data_message(&self) -> Result<meshtastic_protobufs::meshtastic::Data<'_>, PacketError>;
+
+    /// Port number of the payload, `UNKNOWN_APP` if the payload does not decode
+    fn port_num(&self) -> femtopb::EnumValue<PortNum>;
+
+    /// The application payload carried by the `Data` message
+    fn payload_data(&self) -> &[u8];
+
+    /// Decode the application payload according to its port number
+    fn app_payload(&self) -> Result<AppPayload, PacketError> {
+        AppPayload::decode(self.port_num(), self.payload_data())
+    }
+}
+
+impl ReceivedPacket for DecodedPacket {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn rssi(&self) -> i8 {
+        self.rssi
+    }
+
+    fn snr(&self) -> i8 {
+        self.snr
+    }
+
+    fn data_message(&self) -> Result<meshtastic_protobufs::meshtastic::Data<'_>, PacketError> {
+        Ok(self.data.to_protobuf())
+    }
+
+    fn port_num(&self) -> femtopb::EnumValue<PortNum> {
+        self.data.portnum
+    }
+
+    fn payload_data(&self) -> &[u8] {
+        &self.data.payload[..self.data.payload_len]
+    }
+}
+
 /// Errors that can occur during cryptographic operations
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(Format))]
@@ -0,0 +1,933 @@
+//! Admin
+//!
+//! Clients read and change a node's configuration with `AdminMessage`s on
+//! ADMIN_APP: the owner (names), the radio and module configuration, the
+//! channels, and actions like reboot or factory reset. The same messages
+//! arrive from our own client over USB, serial or Bluetooth, and from remote
+//! nodes over the mesh.
+//!
+//! Remote commands are only accepted PKI encrypted from one of the admin keys
+//! in the security config, or on the legacy channel named "admin" when that is
+//! enabled. Every get response carries a session passkey that remote set
+//! commands must echo back within [`SESSION_PASSKEY_LIFETIME_SECS`], so a
+//! recorded command cannot be replayed later.
+//!
+//! The module does not own the configuration: changes are applied through the
+//! [`AdminSettings`] trait, which the firmware implements on top of its live
+//! configuration and persistence.
+
+use femtopb::{EnumValue, Message as _};
+use heapless::{String, Vec};
+use meshtastic_protobufs::meshtastic::admin_message::{
+    ConfigType, ModuleConfigType, PayloadVariant,
+};
+use meshtastic_protobufs::meshtastic::{
+    config, routing, AdminMessage, ChannelSettings, Config, ModuleConfig, ModuleSettings, PortNum,
+};
+use sha2::{Digest, Sha256};
+
+use crate::channel::{Channel, ChannelRole, ChannelSet, MAX_CHANNELS};
+use crate::header::{Header, HeaderFlags};
+use crate::node_database::User;
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Length of the session passkey
+pub const SESSION_PASSKEY_LEN: usize = 8;
+
+/// Time a session passkey is accepted after it was generated
+pub const SESSION_PASSKEY_LIFETIME_SECS: u32 = 300;
+
+/// Age after which a get response comes with a new session passkey
+pub const SESSION_PASSKEY_REFRESH_SECS: u32 = 150;
+
+/// Number of admin public keys in the security config
+pub const MAX_ADMIN_KEYS: usize = 3;
+
+/// Name of the legacy admin channel
+pub const ADMIN_CHANNEL_NAME: &str = "admin";
+
+/// Errors that can occur when handling an admin message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdminError {
+    /// The payload is not a valid `AdminMessage`
+    InvalidMessage,
+    /// The sender is not allowed to administer this node
+    NotAuthorized,
+    /// The sender's public key is not one of our admin keys
+    UnauthorizedKey,
+    /// A remote set command did not carry the current session passkey
+    BadSessionPasskey,
+    /// The command is not supported by this node
+    Unsupported,
+    /// The command contains a value we cannot apply (e.g. an invalid channel)
+    InvalidValue,
+    /// The response does not fit in a packet
+    ResponseTooLarge,
+    /// The change was applied but could not be saved
+    Storage,
+}
+
+impl AdminError {
+    /// The routing error to send back to the requester
+    pub fn routing_error(&self) -> routing::Error {
+        match self {
+            AdminError::NotAuthorized => routing::Error::NotAuthorized,
+            AdminError::UnauthorizedKey => routing::Error::AdminPublicKeyUnauthorized,
+            AdminError::BadSessionPasskey => routing::Error::AdminBadSessionKey,
+            AdminError::InvalidMessage
+            | AdminError::Unsupported
+            | AdminError::InvalidValue
+            | AdminError::ResponseTooLarge
+            | AdminError::Storage => routing::Error::BadRequest,
+        }
+    }
+}
+
+/// Where an admin message came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminOrigin<'a> {
+    /// Our own client, over USB, serial or Bluetooth
+    Local,
+    /// A remote node, on the channel with this name
+    Channel(&'a str),
+    /// A remote node, PKI encrypted by the owner of this public key
+    Pki(&'a [u8; 32]),
+}
+
+/// An action requested by an admin message, carried out by the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdminAction {
+    /// Reboot after the delay, a negative delay cancels a pending reboot
+    Reboot { delay_secs: i32 },
+    /// Shut down after the delay, a negative delay cancels a pending shutdown
+    Shutdown { delay_secs: i32 },
+    /// Erase the configuration, keys and node database
+    FactoryResetDevice,
+    /// Erase the configuration but keep the keys
+    FactoryResetConfig,
+    /// Forget every node except ours
+    ResetNodeDb,
+}
+
+/// Result of a handled admin message
+#[derive(Debug, Clone, Default)]
+pub struct AdminOutcome {
+    /// Response to send back to the requester, already addressed to its request
+    pub response: Option<OwnedData>,
+    /// Action the caller has to carry out
+    pub action: Option<AdminAction>,
+}
+
+/// Live configuration changed by admin messages
+///
+/// Setters apply the change right away. They are followed by a call to
+/// [`AdminSettings::save`], batched until the commit when the client wraps its
+/// changes in begin/commit edit messages.
+pub trait AdminSettings {
+    /// Our owner: names, hardware model, role and public key
+    fn owner(&self) -> User;
+
+    /// Replace our owner
+    fn set_owner(&mut self, owner: User);
+
+    /// A config section, `None` if this node does not have it
+    fn config(&self, kind: ConfigType) -> Option<Config<'_>>;
+
+    /// Apply a config section
+    ///
+    /// The security section is handled by [`AdminModule`] and only passed on
+    /// here so it can be persisted.
+    fn set_config(&mut self, config: &Config<'_>) -> Result<(), AdminError>;
+
+    /// A module config section, `None` if this node does not have the module
+    fn module_config(&self, kind: ModuleConfigType) -> Option<ModuleConfig<'_>>;
+
+    /// Apply a module config section
+    fn set_module_config(&mut self, config: &ModuleConfig<'_>) -> Result<(), AdminError>;
+
+    /// Our channels
+    fn channels(&self) -> &ChannelSet;
+
+    /// Add or replace a channel
+    fn set_channel(&mut self, channel: Channel);
+
+    /// Remove the channel at `index`
+    fn remove_channel(&mut self, index: u8);
+
+    /// Persist the current configuration
+    fn save(&mut self) -> Result<(), AdminError>;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SessionPasskey {
+    key: [u8; SESSION_PASSKEY_LEN],
+    created_secs: u32,
+}
+
+/// Handles the admin messages addressed to our node
+#[derive(Debug, Clone)]
+pub struct AdminModule<const K: usize = MAX_ADMIN_KEYS> {
+    node_num: u32,
+    admin_keys: Vec<[u8; 32], K>,
+    admin_channel_enabled: bool,
+    // Passkeys are derived from this secret and a counter
+    secret: [u8; 32],
+    counter: u32,
+    session: Option<SessionPasskey>,
+    editing: bool,
+}
+
+impl<const K: usize> AdminModule<K> {
+    /// Create the module for our node
+    ///
+    /// `secret` seeds the session passkeys, it should come from the board's
+    /// random number generator.
+    pub fn new(node_num: u32, secret: [u8; 32]) -> Self {
+        Self {
+            node_num,
+            admin_keys: Vec::new(),
+            admin_channel_enabled: false,
+            secret,
+            counter: 0,
+            session: None,
+            editing: false,
+        }
+    }
+
+    /// Public keys allowed to send remote admin messages
+    pub fn admin_keys(&self) -> &[[u8; 32]] {
+        &self.admin_keys
+    }
+
+    /// Allow remote admin messages from `public_key`, returns false if the list is full
+    pub fn add_admin_key(&mut self, public_key: [u8; 32]) -> bool {
+        self.admin_keys.contains(&public_key) || self.admin_keys.push(public_key).is_ok()
+    }
+
+    /// Whether remote admin messages are accepted on the legacy admin channel
+    pub fn admin_channel_enabled(&self) -> bool {
+        self.admin_channel_enabled
+    }
+
+    /// Accept or refuse remote admin messages on the legacy admin channel
+    pub fn set_admin_channel_enabled(&mut self, enabled: bool) {
+        self.admin_channel_enabled = enabled;
+    }
+
+    /// Returns true between begin and commit edit messages
+    pub fn is_editing(&self) -> bool {
+        self.editing
+    }
+
+    /// Handle a received packet, returns `None` unless it is an admin message addressed to us
+    pub fn handle_received<S, P>(
+        &mut self,
+        packet: &P,
+        origin: AdminOrigin<'_>,
+        settings: &mut S,
+        now_secs: u32,
+    ) -> Option<Result<AdminOutcome, AdminError>>
+    where
+        S: AdminSettings + ?Sized,
+        P: ReceivedPacket + ?Sized,
+    {
+        let header = packet.header();
+        if header.destination != self.node_num
+            || packet.port_num() != EnumValue::Known(PortNum::AdminApp)
+        {
+            return None;
+        }
+        Some(self.handle(
+            packet.payload_data(),
+            header.packet_id,
+            origin,
+            settings,
+            now_secs,
+        ))
+    }
+
+    /// Handle an encoded `AdminMessage` from `origin`, sent in the packet `request_id`
+    pub fn handle<S: AdminSettings + ?Sized>(
+        &mut self,
+        payload: &[u8],
+        request_id: u32,
+        origin: AdminOrigin<'_>,
+        settings: &mut S,
+        now_secs: u32,
+    ) -> Result<AdminOutcome, AdminError> {
+        self.authorize(origin)?;
+        let message = AdminMessage::decode(payload).map_err(|_| AdminError::InvalidMessage)?;
+        let Some(variant) = message.payload_variant else {
+            return Err(AdminError::InvalidMessage);
+        };
+
+        if is_get_request(&variant) {
+            return self
+                .respond(&variant, settings, now_secs)
+                .map(|response| AdminOutcome {
+                    response: Some(with_request_id(response, request_id)),
+                    action: None,
+                });
+        }
+
+        // Everything else changes the node, remote senders have to prove they
+        // read our current passkey
+        if origin != AdminOrigin::Local && !self.check_passkey(message.session_passkey, now_secs) {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("Admin: rejecting command with a stale session passkey");
+            return Err(AdminError::BadSessionPasskey);
+        }
+
+        let mut outcome = AdminOutcome::default();
+        match variant {
+            PayloadVariant::SetOwner(owner) => {
+                let mut user = settings.owner();
+                if !owner.long_name.is_empty() {
+                    user.long_name =
+                        String::try_from(owner.long_name).map_err(|_| AdminError::InvalidValue)?;
+                }
+                if !owner.short_name.is_empty() {
+                    user.short_name =
+                        String::try_from(owner.short_name).map_err(|_| AdminError::InvalidValue)?;
+                }
+                user.is_licensed = owner.is_licensed;
+                settings.set_owner(user);
+            }
+            PayloadVariant::SetConfig(config) => {
+                if let Some(config::PayloadVariant::Security(security)) = &config.payload_variant {
+                    self.set_security(security)?;
+                }
+                settings.set_config(&config)?;
+            }
+            PayloadVariant::SetModuleConfig(config) => settings.set_module_config(&config)?,
+            PayloadVariant::SetChannel(channel) => set_channel(settings, &channel)?,
+            PayloadVariant::BeginEditSettings(_) => {
+                self.editing = true;
+                return Ok(outcome);
+            }
+            PayloadVariant::CommitEditSettings(_) => self.editing = false,
+            PayloadVariant::RebootSeconds(delay_secs) => {
+                outcome.action = Some(AdminAction::Reboot { delay_secs });
+                return Ok(outcome);
+            }
+            PayloadVariant::ShutdownSeconds(delay_secs) => {
+                outcome.action = Some(AdminAction::Shutdown { delay_secs });
+                return Ok(outcome);
+            }
+            PayloadVariant::FactoryResetDevice(_) => {
+                self.admin_keys.clear();
+                self.admin_channel_enabled = false;
+                outcome.action = Some(AdminAction::FactoryResetDevice);
+                return Ok(outcome);
+            }
+            PayloadVariant::FactoryResetConfig(_) => {
+                outcome.action = Some(AdminAction::FactoryResetConfig);
+                return Ok(outcome);
+            }
+            PayloadVariant::NodedbReset(_) => {
+                outcome.action = Some(AdminAction::ResetNodeDb);
+                return Ok(outcome);
+            }
+            _ => return Err(AdminError::Unsupported),
+        }
+
+        if !self.editing {
+            settings.save()?;
+        }
+        Ok(outcome)
+    }
+
+    /// Build the packet answering `request` with a `response` returned by [`AdminModule::handle_received`]
+    pub fn reply(
+        &self,
+        request: &Header,
+        response: OwnedData,
+        packet_id: u32,
+        hop_limit: u8,
+    ) -> DecodedPacket {
+        let header = Header::new(
+            request.source,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            request.channel_hash,
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        DecodedPacket::new(header, response)
+    }
+
+    fn authorize(&self, origin: AdminOrigin<'_>) -> Result<(), AdminError> {
+        match origin {
+            AdminOrigin::Local => Ok(()),
+            AdminOrigin::Channel(name) => {
+                if self.admin_channel_enabled && name.eq_ignore_ascii_case(ADMIN_CHANNEL_NAME) {
+                    Ok(())
+                } else {
+                    Err(AdminError::NotAuthorized)
+                }
+            }
+            AdminOrigin::Pki(public_key) => {
+                if self.admin_keys.contains(public_key) {
+                    Ok(())
+                } else {
+                    Err(AdminError::UnauthorizedKey)
+                }
+            }
+        }
+    }
+
+    // The current passkey, replaced once it is old enough that a client
+    // could not use it for much longer
+    fn session_passkey(&mut self, now_secs: u32) -> [u8; SESSION_PASSKEY_LEN] {
+        if let Some(session) = self.session {
+            if now_secs.wrapping_sub(session.created_secs) < SESSION_PASSKEY_REFRESH_SECS {
+                return session.key;
+            }
+        }
+        self.counter = self.counter.wrapping_add(1);
+        let digest = Sha256::new()
+            .chain_update(self.secret)
+            .chain_update(self.counter.to_le_bytes())
+            .finalize();
+        let mut key = [0u8; SESSION_PASSKEY_LEN];
+        key.copy_from_slice(&digest[..SESSION_PASSKEY_LEN]);
+        self.session = Some(SessionPasskey {
+            key,
+            created_secs: now_secs,
+        });
+        key
+    }
+
+    fn check_passkey(&self, passkey: &[u8], now_secs: u32) -> bool {
+        self.session.is_some_and(|session| {
+            now_secs.wrapping_sub(session.created_secs) < SESSION_PASSKEY_LIFETIME_SECS
+                && passkey == session.key
+        })
+    }
+
+    fn set_security(&mut self, security: &config::SecurityConfig<'_>) -> Result<(), AdminError> {
+        let mut admin_keys = Vec::new();
+        for key in security.admin_key.iter() {
+            let key = key.map_err(|_| AdminError::InvalidMessage)?;
+            // Clients send empty entries for unused slots
+            if key.is_empty() {
+                continue;
+            }
+            let key: [u8; 32] = key.try_into().map_err(|_| AdminError::InvalidValue)?;
+            admin_keys.push(key).map_err(|_| AdminError::InvalidValue)?;
+        }
+        self.admin_keys = admin_keys;
+        self.admin_channel_enabled = security.admin_channel_enabled;
+        Ok(())
+    }
+
+    fn respond<S: AdminSettings + ?Sized>(
+        &mut self,
+        request: &PayloadVariant<'_>,
+        settings: &S,
+        now_secs: u32,
+    ) -> Result<OwnedData, AdminError> {
+        let passkey = self.session_passkey(now_secs);
+        let owner = settings.owner();
+        let id = super::node_info::user_id(self.node_num);
+        let admin_keys: Vec<&[u8], K> = self.admin_keys.iter().map(|key| &key[..]).collect();
+        let name;
+        let module_settings;
+
+        let variant = match *request {
+            PayloadVariant::GetOwnerRequest(_) => {
+                PayloadVariant::GetOwnerResponse(meshtastic_protobufs::meshtastic::User {
+                    id: &id,
+                    long_name: &owner.long_name,
+                    short_name: &owner.short_name,
+                    hw_model: owner.hw_model,
+                    is_licensed: owner.is_licensed,
+                    role: owner.role,
+                    public_key: owner.public_key.as_ref().map_or(&[], |key| key.as_slice()),
+                    ..Default::default()
+                })
+            }
+            PayloadVariant::GetConfigRequest(EnumValue::Known(ConfigType::SecurityConfig)) => {
+                PayloadVariant::GetConfigResponse(Config {
+                    payload_variant: Some(config::PayloadVariant::Security(
+                        config::SecurityConfig {
+                            public_key: owner.public_key.as_ref().map_or(&[], |key| key.as_slice()),
+                            admin_key: femtopb::repeated::Repeated::from_slice(&admin_keys),
+                            admin_channel_enabled: self.admin_channel_enabled,
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                })
+            }
+            PayloadVariant::GetConfigRequest(EnumValue::Known(kind)) => {
+                PayloadVariant::GetConfigResponse(
+                    settings.config(kind).ok_or(AdminError::Unsupported)?,
+                )
+            }
+            PayloadVariant::GetModuleConfigRequest(EnumValue::Known(kind)) => {
+                PayloadVariant::GetModuleConfigResponse(
+                    settings
+                        .module_config(kind)
+                        .ok_or(AdminError::Unsupported)?,
+                )
+            }
+            PayloadVariant::GetChannelRequest(index_plus_one) => {
+                // Clients ask for index + 1, so that 0 is not a valid request
+                let index = index_plus_one
+                    .checked_sub(1)
+                    .filter(|&index| (index as usize) < MAX_CHANNELS)
+                    .ok_or(AdminError::InvalidValue)? as u8;
+                let channel = settings.channels().get(index);
+                name = channel.map(|channel| channel.configured_name()).unwrap_or("");
+                module_settings = channel.map(|channel| ModuleSettings {
+                    position_precision: channel.position_precision(),
+                    ..Default::default()
+                });
+                PayloadVariant::GetChannelResponse(meshtastic_protobufs::meshtastic::Channel {
+                    index: index as i32,
+                    settings: channel.map(|channel| ChannelSettings {
+                        psk: channel.psk().psk(),
+                        name,
+                        module_settings,
+                        ..Default::default()
+                    }),
+                    role: EnumValue::Known(
+                        channel.map_or(ChannelRole::Disabled, |channel| channel.role()),
+                    ),
+                    ..Default::default()
+                })
+            }
+            _ => return Err(AdminError::Unsupported),
+        };
+
+        let message = AdminMessage {
+            session_passkey: &passkey,
+            payload_variant: Some(variant),
+            ..Default::default()
+        };
+        let payload_len = message.encoded_len();
+        let mut data = OwnedData {
+            portnum: EnumValue::Known(PortNum::AdminApp),
+            payload: [0u8; 240],
+            payload_len,
+            want_response: false,
+            dest: 0,
+            source: 0,
+            request_id: 0,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        };
+        let mut slice = data
+            .payload
+            .get_mut(..payload_len)
+            .ok_or(AdminError::ResponseTooLarge)?;
+        message
+            .encode(&mut slice)
+            .map_err(|_| AdminError::ResponseTooLarge)?;
+        Ok(data)
+    }
+}
+
+fn is_get_request(variant: &PayloadVariant<'_>) -> bool {
+    matches!(
+        variant,
+        PayloadVariant::GetOwnerRequest(_)
+            | PayloadVariant::GetConfigRequest(_)
+            | PayloadVariant::GetModuleConfigRequest(_)
+            | PayloadVariant::GetChannelRequest(_)
+    )
+}
+
+fn with_request_id(mut data: OwnedData, request_id: u32) -> OwnedData {
+    data.request_id = request_id;
+    data
+}
+
+// Apply a channel from a SetChannel message, disabled channels are removed
+fn set_channel<S: AdminSettings + ?Sized>(
+    settings: &mut S,
+    channel: &meshtastic_protobufs::meshtastic::Channel<'_>,
+) -> Result<(), AdminError> {
+    let index = u8::try_from(channel.index).map_err(|_| AdminError::InvalidValue)?;
+    let role = match channel.role {
+        EnumValue::Known(role) => role,
+        EnumValue::Unknown(_) => return Err(AdminError::InvalidValue),
+    };
+    // The primary channel cannot be removed
+    if role == ChannelRole::Disabled && index != 0 {
+        settings.remove_channel(index);
+        return Ok(());
+    }
+
+    let default_settings = ChannelSettings::default();
+    let channel_settings = channel.settings.as_ref().unwrap_or(&default_settings);
+    // An unnamed channel takes the modem preset's name once it joins the channel set
+    let mut new_channel = Channel::new(index, channel_settings.name, channel_settings.psk, role)
+        .map_err(|_| AdminError::InvalidValue)?;
+    if let Some(module_settings) = channel_settings.module_settings.as_ref() {
+        new_channel.set_position_precision(module_settings.position_precision);
+    }
+    settings.set_channel(new_channel);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::header;
+    use meshtastic_protobufs::meshtastic::config::device_config::Role;
+    use meshtastic_protobufs::meshtastic::HardwareModel;
+
+    const OUR_NODE: u32 = 0xDEAD_BEEF;
+    const ADMIN_NODE: u32 = 0x0000_AD00;
+    const ADMIN_KEY: [u8; 32] = [0xAD; 32];
+
+    // femtopb leaves out a oneof holding the enum's zero value, so encode
+    // get_config_request = DEVICE_CONFIG by hand like the official clients do
+    const DEVICE_CONFIG_REQUEST: [u8; 2] = [0x28, 0x00];
+
+    #[derive(Default)]
+    struct TestSettings {
+        owner: User,
+        channels: ChannelSet,
+        device: config::DeviceConfig<'static>,
+        saves: u32,
+    }
+
+    impl AdminSettings for TestSettings {
+        fn owner(&self) -> User {
+            self.owner.clone()
+        }
+
+        fn set_owner(&mut self, owner: User) {
+            self.owner = owner;
+        }
+
+        fn config(&self, kind: ConfigType) -> Option<Config<'_>> {
+            match kind {
+                ConfigType::DeviceConfig => Some(Config {
+                    payload_variant: Some(config::PayloadVariant::Device(self.device.clone())),
+                    ..Default::default()
+                }),
+                _ => None,
+            }
+        }
+
+        fn set_config(&mut self, config: &Config<'_>) -> Result<(), AdminError> {
+            match &config.payload_variant {
+                Some(config::PayloadVariant::Device(device)) => {
+                    self.device.role = device.role;
+                    self.device.node_info_broadcast_secs = device.node_info_broadcast_secs;
+                    Ok(())
+                }
+                Some(config::PayloadVariant::Security(_)) => Ok(()),
+                _ => Err(AdminError::Unsupported),
+            }
+        }
+
+        fn module_config(&self, _kind: ModuleConfigType) -> Option<ModuleConfig<'_>> {
+            None
+        }
+
+        fn set_module_config(&mut self, _config: &ModuleConfig<'_>) -> Result<(), AdminError> {
+            Err(AdminError::Unsupported)
+        }
+
+        fn channels(&self) -> &ChannelSet {
+            &self.channels
+        }
+
+        fn set_channel(&mut self, channel: Channel) {
+            self.channels.set(channel);
+        }
+
+        fn remove_channel(&mut self, index: u8) {
+            self.channels.remove(index);
+        }
+
+        fn save(&mut self) -> Result<(), AdminError> {
+            self.saves += 1;
+            Ok(())
+        }
+    }
+
+    fn settings() -> TestSettings {
+        TestSettings {
+            owner: User {
+                long_name: String::try_from("Ours").unwrap(),
+                short_name: String::try_from("OURS").unwrap(),
+                hw_model: EnumValue::Known(HardwareModel::Unset),
+                role: EnumValue::Known(Role::Client),
+                is_licensed: false,
+                public_key: Some([0x42; 32]),
+            },
+            channels: ChannelSet::with_default_channel(),
+            ..Default::default()
+        }
+    }
+
+    fn encode(message: &AdminMessage<'_>) -> Vec<u8, 240> {
+        let mut buffer = [0u8; 240];
+        let len = message.encoded_len();
+        let mut slice = &mut buffer[..len];
+        message.encode(&mut slice).unwrap();
+        Vec::from_slice(&buffer[..len]).unwrap()
+    }
+
+    fn request(variant: PayloadVariant<'_>, passkey: &[u8]) -> Vec<u8, 240> {
+        encode(&AdminMessage {
+            session_passkey: passkey,
+            payload_variant: Some(variant),
+            ..Default::default()
+        })
+    }
+
+    fn response(outcome: &AdminOutcome) -> AdminMessage<'_> {
+        let data = outcome.response.as_ref().unwrap();
+        AdminMessage::decode(&data.payload[..data.payload_len]).unwrap()
+    }
+
+    #[test]
+    fn test_local_get_and_set_owner() {
+        let mut module: AdminModule = AdminModule::new(OUR_NODE, [7; 32]);
+        let mut settings = settings();
+
+        let outcome = module
+            .handle(
+                &request(PayloadVariant::GetOwnerRequest(true), &[]),
+                42,
+                AdminOrigin::Local,
+                &mut settings,
+                0,
+            )
+            .unwrap();
+        assert_eq!(outcome.response.as_ref().unwrap().request_id, 42);
+        let message = response(&outcome);
+        assert_eq!(message.session_passkey.len(), SESSION_PASSKEY_LEN);
+        let Some(PayloadVariant::GetOwnerResponse(owner)) = message.payload_variant else {
+            panic!("expected an owner response");
+        };
+        assert_eq!(owner.long_name, "Ours");
+        assert_eq!(owner.id, "!deadbeef");
+
+        // Local clients do not need the passkey, empty names are left alone
+        let owner = meshtastic_protobufs::meshtastic::User {
+            long_name: "Renamed",
+            ..Default::default()
+        };
+        let outcome = module
+            .handle(
+                &request(PayloadVariant::SetOwner(owner), &[]),
+                43,
+                AdminOrigin::Local,
+                &mut settings,
+                1,
+            )
+            .unwrap();
+        assert!(outcome.response.is_none());
+        assert_eq!(settings.owner.long_name.as_str(), "Renamed");
+        assert_eq!(settings.owner.short_name.as_str(), "OURS");
+        assert_eq!(settings.saves, 1);
+    }
+
+    #[test]
+    fn test_remote_authorization_and_passkey() {
+        let mut module: AdminModule = AdminModule::new(OUR_NODE, [7; 32]);
+        let mut settings = settings();
+        let get = request(PayloadVariant::GetOwnerRequest(true), &[]);
+        let reboot = |passkey: &[u8]| request(PayloadVariant::RebootSeconds(5), passkey);
+
+        // Unknown keys and ordinary channels are refused
+        assert_eq!(
+            module
+                .handle(&get, 1, AdminOrigin::Pki(&ADMIN_KEY), &mut settings, 0)
+                .unwrap_err(),
+            AdminError::UnauthorizedKey
+        );
+        assert_eq!(
+            module
+                .handle(&get, 1, AdminOrigin::Channel("admin"), &mut settings, 0)
+                .unwrap_err(),
+            AdminError::NotAuthorized
+        );
+        module.set_admin_channel_enabled(true);
+        assert!(module
+            .handle(&get, 1, AdminOrigin::Channel("Admin"), &mut settings, 0)
+            .is_ok());
+        assert!(module
+            .handle(&get, 1, AdminOrigin::Channel("LongFast"), &mut settings, 0)
+            .is_err());
+
+        // An admin key may send commands once it has read our passkey
+        assert!(module.add_admin_key(ADMIN_KEY));
+        let origin = AdminOrigin::Pki(&ADMIN_KEY);
+        assert_eq!(
+            module
+                .handle(&reboot(&[0; 8]), 2, origin, &mut settings, 10)
+                .unwrap_err(),
+            AdminError::BadSessionPasskey
+        );
+        let outcome = module.handle(&get, 3, origin, &mut settings, 10).unwrap();
+        let mut passkey = [0u8; SESSION_PASSKEY_LEN];
+        passkey.copy_from_slice(response(&outcome).session_passkey);
+        let outcome = module
+            .handle(&reboot(&passkey), 4, origin, &mut settings, 20)
+            .unwrap();
+        assert_eq!(outcome.action, Some(AdminAction::Reboot { delay_secs: 5 }));
+
+        // The passkey is renewed after a while and expires later
+        let outcome = module
+            .handle(&get, 5, origin, &mut settings, SESSION_PASSKEY_REFRESH_SECS)
+            .unwrap();
+        assert_ne!(response(&outcome).session_passkey, &passkey[..]);
+        assert_eq!(
+            module
+                .handle(
+                    &reboot(&passkey),
+                    6,
+                    origin,
+                    &mut settings,
+                    SESSION_PASSKEY_REFRESH_SECS
+                )
+                .unwrap_err(),
+            AdminError::BadSessionPasskey
+        );
+    }
+
+    #[test]
+    fn test_config_and_channels_with_edit_batch() {
+        let mut module: AdminModule = AdminModule::new(OUR_NODE, [7; 32]);
+        let mut settings = settings();
+        let local = AdminOrigin::Local;
+
+        module
+            .handle(
+                &request(PayloadVariant::BeginEditSettings(true), &[]),
+                1,
+                local,
+                &mut settings,
+                0,
+            )
+            .unwrap();
+        let device = config::DeviceConfig {
+            role: EnumValue::Known(Role::Router),
+            node_info_broadcast_secs: 3600,
+            ..Default::default()
+        };
+        let set_device = PayloadVariant::SetConfig(Config {
+            payload_variant: Some(config::PayloadVariant::Device(device)),
+            ..Default::default()
+        });
+        module
+            .handle(&request(set_device, &[]), 2, local, &mut settings, 0)
+            .unwrap();
+
+        let admin_keys = [&ADMIN_KEY[..]];
+        let set_security = PayloadVariant::SetConfig(Config {
+            payload_variant: Some(config::PayloadVariant::Security(config::SecurityConfig {
+                admin_key: femtopb::repeated::Repeated::from_slice(&admin_keys),
+                ..Default::default()
+            })),
+            ..Default::default()
+        });
+        module
+            .handle(&request(set_security, &[]), 3, local, &mut settings, 0)
+            .unwrap();
+
+        let set_channel = PayloadVariant::SetChannel(meshtastic_protobufs::meshtastic::Channel {
+            index: 1,
+            settings: Some(ChannelSettings {
+                psk: &[0x5A; 16],
+                name: "Private",
+                ..Default::default()
+            }),
+            role: EnumValue::Known(ChannelRole::Secondary),
+            ..Default::default()
+        });
+        module
+            .handle(&request(set_channel, &[]), 4, local, &mut settings, 0)
+            .unwrap();
+        assert_eq!(settings.saves, 0);
+
+        module
+            .handle(
+                &request(PayloadVariant::CommitEditSettings(true), &[]),
+                5,
+                local,
+                &mut settings,
+                0,
+            )
+            .unwrap();
+        assert_eq!(settings.saves, 1);
+        assert_eq!(settings.device.node_info_broadcast_secs, 3600);
+        assert_eq!(module.admin_keys(), &[ADMIN_KEY]);
+
+        // Channels are read back by index + 1
+        let outcome = module
+            .handle(
+                &request(PayloadVariant::GetChannelRequest(2), &[]),
+                6,
+                local,
+                &mut settings,
+                0,
+            )
+            .unwrap();
+        let Some(PayloadVariant::GetChannelResponse(channel)) = response(&outcome).payload_variant
+        else {
+            panic!("expected a channel response");
+        };
+        assert_eq!(channel.index, 1);
+        assert_eq!(channel.settings.unwrap().name, "Private");
+        assert_eq!(channel.role, EnumValue::Known(ChannelRole::Secondary));
+
+        let outcome = module
+            .handle(&DEVICE_CONFIG_REQUEST, 7, local, &mut settings, 0)
+            .unwrap();
+        let Some(PayloadVariant::GetConfigResponse(Config {
+            payload_variant: Some(config::PayloadVariant::Device(device)),
+            ..
+        })) = response(&outcome).payload_variant
+        else {
+            panic!("expected a device config response");
+        };
+        assert_eq!(device.role, EnumValue::Known(Role::Router));
+    }
+
+    #[test]
+    fn test_handle_received_only_takes_admin_packets_for_us() {
+        let mut module: AdminModule = AdminModule::new(OUR_NODE, [7; 32]);
+        let mut settings = settings();
+        let payload = request(PayloadVariant::GetOwnerRequest(true), &[]);
+        let data = OwnedData::new(PortNum::AdminApp, &payload).unwrap();
+        module.add_admin_key(ADMIN_KEY);
+        let origin = AdminOrigin::Pki(&ADMIN_KEY);
+
+        let broadcast = DecodedPacket::new(header(ADMIN_NODE, 0xFFFF_FFFF, 99), data.clone());
+        assert!(module
+            .handle_received(&broadcast, origin, &mut settings, 0)
+            .is_none());
+
+        let request = DecodedPacket::new(header(ADMIN_NODE, OUR_NODE, 99), data)
+            .encode()
+            .unwrap()
+            .decode()
+            .unwrap();
+        let outcome = module
+            .handle_received(&request, origin, &mut settings, 0)
+            .unwrap()
+            .unwrap();
+        let reply = module.reply(&request.header, outcome.response.unwrap(), 5, 3);
+        assert_eq!(reply.header.destination, ADMIN_NODE);
+        assert_eq!(reply.data.request_id, 99);
+    }
+}
@@ -0,0 +1,26 @@
+//! Meshtastic application modules
+//!
+//! Each module handles the packets of one port number on top of the packet
+//! pipeline: it builds the packets the module sends and interprets the ones it
+//! receives, leaving encryption and transmission to the caller.
+
+// Remote and local configuration (ADMIN_APP)
+pub mod admin;
+
+// Direct neighbor tables (NEIGHBORINFO_APP)
+pub mod neighbor_info;
+
+// Sharing our name and public key (NODEINFO_APP)
+pub mod node_info;
+
+// Sharing our position (POSITION_APP)
+pub mod position;
+
+// Measuring how far packets are heard (RANGE_TEST_APP)
+pub mod range_test;
+
+// Buffering messages for nodes that were out of range (STORE_FORWARD_APP)
+pub mod store_forward;
+
+// Route discovery (TRACEROUTE_APP)
+pub mod traceroute;
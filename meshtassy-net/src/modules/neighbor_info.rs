@@ -0,0 +1,384 @@
+//! Neighbor info
+//!
+//! Every node periodically broadcasts a NEIGHBORINFO_APP packet listing the
+//! nodes it hears directly, with the SNR of the last packet it heard from
+//! each. A packet is heard directly when it has not been relayed yet, that is
+//! when its `hop_limit` still equals its `hop_start`.
+//!
+//! [`NeighborInfoModule`] keeps our own neighbor table, builds our broadcasts
+//! and stores the lists received from other nodes. Together they form a graph
+//! of the mesh, see [`NeighborInfoModule::links`].
+//!
+//! Times are in seconds on a clock chosen by the caller (seconds since boot
+//! on a node without an RTC).
+
+use femtopb::{EnumValue, Message as _};
+use heapless::Vec;
+use meshtastic_protobufs::meshtastic::PortNum;
+
+use crate::app_payload::{Neighbor, NeighborInfo, MAX_NEIGHBORS};
+use crate::error::PacketError;
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::{AppPayload, DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Default time between two of our broadcasts (Meshtastic's default is 6 hours)
+pub const DEFAULT_NEIGHBOR_INFO_INTERVAL_SECS: u32 = 6 * 60 * 60;
+
+/// Default number of other nodes whose neighbor lists are kept
+pub const MAX_NEIGHBOR_REPORTS: usize = 16;
+
+/// A direct radio link between two nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Link {
+    /// Node that transmitted
+    pub from: u32,
+    /// Node that heard it
+    pub to: u32,
+    /// SNR `to` heard `from` with, in dB
+    pub snr: f32,
+}
+
+/// Our neighbors and the neighbor lists of other nodes
+#[derive(Debug, Clone)]
+pub struct NeighborInfoModule<const R: usize = MAX_NEIGHBOR_REPORTS> {
+    node_num: u32,
+    interval_secs: u32,
+    next_broadcast_secs: u32,
+    neighbors: Vec<Neighbor, MAX_NEIGHBORS>,
+    reports: Vec<NeighborInfo, R>,
+}
+
+impl<const R: usize> NeighborInfoModule<R> {
+    /// Create the module for our node, broadcasting every `interval_secs`
+    ///
+    /// The first broadcast is due `interval_secs` after time 0, which leaves
+    /// time to hear the neighbors first.
+    pub fn new(node_num: u32, interval_secs: u32) -> Self {
+        Self {
+            node_num,
+            interval_secs,
+            next_broadcast_secs: interval_secs,
+            neighbors: Vec::new(),
+            reports: Vec::new(),
+        }
+    }
+
+    /// Time between two of our broadcasts
+    pub fn interval_secs(&self) -> u32 {
+        self.interval_secs
+    }
+
+    /// Time our next broadcast is due
+    pub fn next_broadcast_secs(&self) -> u32 {
+        self.next_broadcast_secs
+    }
+
+    /// Change the broadcast interval, the next broadcast is rescheduled from `now_secs`
+    pub fn set_interval_secs(&mut self, interval_secs: u32, now_secs: u32) {
+        self.interval_secs = interval_secs;
+        self.next_broadcast_secs = now_secs.saturating_add(interval_secs);
+    }
+
+    /// Nodes we hear directly
+    pub fn neighbors(&self) -> &[Neighbor] {
+        &self.neighbors
+    }
+
+    /// Neighbor lists received from other nodes
+    pub fn reports(&self) -> &[NeighborInfo] {
+        &self.reports
+    }
+
+    /// The neighbor list last received from `node_num`
+    pub fn report(&self, node_num: u32) -> Option<&NeighborInfo> {
+        self.reports
+            .iter()
+            .find(|report| report.node_id == node_num)
+    }
+
+    /// Record a packet heard at `now_secs`
+    ///
+    /// Returns true if it came straight from its sender, which is then added
+    /// to (or refreshed in) our neighbors. When the table is full the
+    /// neighbor heard least recently makes room.
+    pub fn record_heard(&mut self, header: &Header, snr: i8, now_secs: u32) -> bool {
+        let HeaderFlags {
+            hop_start,
+            hop_limit,
+            via_mqtt,
+            ..
+        } = header.flags;
+        // Firmware before 2.3 leaves hop_start at 0, so relayed copies cannot be told apart
+        if hop_start == 0 || hop_limit != hop_start || via_mqtt || header.source == self.node_num {
+            return false;
+        }
+
+        if let Some(neighbor) = self
+            .neighbors
+            .iter_mut()
+            .find(|neighbor| neighbor.node_id == header.source)
+        {
+            neighbor.snr = snr as f32;
+            neighbor.last_rx_time = now_secs;
+            return true;
+        }
+
+        if self.neighbors.is_full() {
+            if let Some(oldest) = self
+                .neighbors
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, neighbor)| neighbor.last_rx_time)
+                .map(|(index, _)| index)
+            {
+                self.neighbors.swap_remove(oldest);
+            }
+        }
+        let _ = self.neighbors.push(Neighbor {
+            node_id: header.source,
+            snr: snr as f32,
+            last_rx_time: now_secs,
+            node_broadcast_interval_secs: 0,
+        });
+        true
+    }
+
+    /// Handle a received packet: record its sender and keep its neighbor list if it has one
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+        now_secs: u32,
+    ) -> Result<(), PacketError> {
+        self.record_heard(packet.header(), packet.snr(), now_secs);
+        if packet.port_num() != EnumValue::Known(PortNum::NeighborinfoApp) {
+            return Ok(());
+        }
+        let AppPayload::NeighborInfo(report) = packet.app_payload()? else {
+            return Ok(());
+        };
+        if report.node_id == self.node_num {
+            return Ok(());
+        }
+
+        // A neighbor tells us how often it reports, which is how long we may go without hearing it
+        if let Some(neighbor) = self
+            .neighbors
+            .iter_mut()
+            .find(|neighbor| neighbor.node_id == report.node_id)
+        {
+            neighbor.node_broadcast_interval_secs = report.node_broadcast_interval_secs;
+        }
+
+        if let Some(existing) = self
+            .reports
+            .iter_mut()
+            .find(|existing| existing.node_id == report.node_id)
+        {
+            *existing = report;
+        } else {
+            if self.reports.is_full() {
+                self.reports.remove(0);
+            }
+            let _ = self.reports.push(report);
+        }
+        Ok(())
+    }
+
+    /// Forget the neighbors not heard for twice their broadcast interval (or ours if unknown)
+    pub fn expire(&mut self, now_secs: u32) {
+        let interval_secs = self.interval_secs;
+        self.neighbors.retain(|neighbor| {
+            let interval = match neighbor.node_broadcast_interval_secs {
+                0 => interval_secs,
+                interval => interval,
+            };
+            now_secs.saturating_sub(neighbor.last_rx_time) <= interval.saturating_mul(2)
+        });
+    }
+
+    /// Our neighbor list as it would be broadcast
+    pub fn neighbor_info(&self) -> NeighborInfo {
+        NeighborInfo {
+            node_id: self.node_num,
+            last_sent_by_id: self.node_num,
+            node_broadcast_interval_secs: self.interval_secs,
+            neighbors: self.neighbors.clone(),
+        }
+    }
+
+    /// Our broadcast, if it is due at `now_secs`
+    ///
+    /// Expired neighbors are left out. The packet still has to be encoded and
+    /// encrypted with the key of the channel matching `channel_hash`.
+    pub fn poll(
+        &mut self,
+        now_secs: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+    ) -> Option<DecodedPacket> {
+        if now_secs < self.next_broadcast_secs {
+            return None;
+        }
+        self.next_broadcast_secs = now_secs.saturating_add(self.interval_secs);
+        self.expire(now_secs);
+
+        let info = self.neighbor_info();
+        let mut neighbors: Vec<_, MAX_NEIGHBORS> = Vec::new();
+        for neighbor in &info.neighbors {
+            let _ = neighbors.push(neighbor.to_protobuf());
+        }
+        let message = meshtastic_protobufs::meshtastic::NeighborInfo {
+            node_id: info.node_id,
+            last_sent_by_id: info.last_sent_by_id,
+            node_broadcast_interval_secs: info.node_broadcast_interval_secs,
+            neighbors: femtopb::repeated::Repeated::from_slice(&neighbors),
+            unknown_fields: Default::default(),
+        };
+
+        let mut data = OwnedData {
+            portnum: EnumValue::Known(PortNum::NeighborinfoApp),
+            payload: [0u8; 240],
+            payload_len: message.encoded_len(),
+            want_response: false,
+            dest: 0,
+            source: 0,
+            request_id: 0,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        };
+        // Ten neighbors take at most 190 bytes
+        let mut slice = &mut data.payload[..data.payload_len];
+        message.encode(&mut slice).ok()?;
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "NeighborInfo: broadcasting {} neighbors",
+            info.neighbors.len()
+        );
+        let header = Header::new(
+            BROADCAST_ADDR,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            channel_hash,
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        Some(DecodedPacket::new(header, data))
+    }
+
+    /// Every direct link known, from our own neighbors and the lists of other nodes
+    pub fn links(&self) -> impl Iterator<Item = Link> + '_ {
+        let ours = self.neighbors.iter().map(|neighbor| Link {
+            from: neighbor.node_id,
+            to: self.node_num,
+            snr: neighbor.snr,
+        });
+        let theirs = self.reports.iter().flat_map(|report| {
+            report.neighbors.iter().map(|neighbor| Link {
+                from: neighbor.node_id,
+                to: report.node_id,
+                snr: neighbor.snr,
+            })
+        });
+        ours.chain(theirs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{header, OUR_NODE};
+
+    const ALICE: u32 = 0x0000_A11C;
+    const BOB: u32 = 0x0000_0B0B;
+
+    // A broadcast from `source` that took `hop_start - hop_limit` hops to reach us
+    fn heard(source: u32, hop_start: u8, hop_limit: u8) -> Header {
+        let mut header = header(source, BROADCAST_ADDR, 1);
+        header.flags.hop_start = hop_start;
+        header.flags.hop_limit = hop_limit;
+        header
+    }
+
+    #[test]
+    fn test_only_zero_hop_packets_are_neighbors() {
+        let mut module: NeighborInfoModule = NeighborInfoModule::new(OUR_NODE, 900);
+        assert!(module.record_heard(&heard(ALICE, 3, 3), 7, 10));
+        assert!(!module.record_heard(&heard(BOB, 3, 2), 5, 10));
+        assert!(!module.record_heard(&heard(BOB, 0, 0), 5, 10));
+        assert!(!module.record_heard(&heard(OUR_NODE, 3, 3), 5, 10));
+
+        // Hearing a neighbor again refreshes its SNR and time
+        assert!(module.record_heard(&heard(ALICE, 3, 3), -4, 20));
+        assert_eq!(
+            module.neighbors(),
+            &[Neighbor {
+                node_id: ALICE,
+                snr: -4.0,
+                last_rx_time: 20,
+                node_broadcast_interval_secs: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_neighbors_expire() {
+        let mut module: NeighborInfoModule = NeighborInfoModule::new(OUR_NODE, 100);
+        module.record_heard(&heard(ALICE, 3, 3), 7, 0);
+        module.record_heard(&heard(BOB, 3, 3), 7, 150);
+
+        module.expire(200);
+        assert_eq!(module.neighbors().len(), 2);
+        module.expire(201);
+        assert_eq!(module.neighbors().len(), 1);
+        assert_eq!(module.neighbors()[0].node_id, BOB);
+    }
+
+    #[test]
+    fn test_broadcast_round_trip_and_topology() {
+        let mut alice: NeighborInfoModule = NeighborInfoModule::new(ALICE, 900);
+        alice.record_heard(&heard(BOB, 3, 3), 6, 10);
+        assert!(alice.poll(899, 1, 3, 0x08).is_none());
+        let broadcast = alice.poll(900, 1, 3, 0x08).unwrap();
+        assert!(alice.poll(901, 2, 3, 0x08).is_none());
+        assert_eq!(broadcast.header.destination, BROADCAST_ADDR);
+
+        // We hear Alice's broadcast directly
+        let mut received = broadcast.encode().unwrap().decode().unwrap();
+        received.snr = 9;
+        let mut module: NeighborInfoModule = NeighborInfoModule::new(OUR_NODE, 900);
+        module.handle_received(&received, 1000).unwrap();
+
+        assert_eq!(module.neighbors()[0].node_id, ALICE);
+        assert_eq!(module.neighbors()[0].node_broadcast_interval_secs, 900);
+        let report = module.report(ALICE).unwrap();
+        assert_eq!(report.neighbors.len(), 1);
+        assert_eq!(report.neighbors[0].node_id, BOB);
+
+        let links: Vec<Link, 4> = module.links().collect();
+        assert_eq!(
+            links,
+            [
+                Link {
+                    from: ALICE,
+                    to: OUR_NODE,
+                    snr: 9.0
+                },
+                Link {
+                    from: BOB,
+                    to: ALICE,
+                    snr: 6.0
+                },
+            ]
+        );
+    }
+}
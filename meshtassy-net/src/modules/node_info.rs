@@ -0,0 +1,359 @@
+//! Node info
+//!
+//! Nodes learn each other's names, hardware model, role and public key from
+//! NODEINFO_APP packets carrying a `User`. Every node broadcasts its own on a
+//! long interval, answers packets with `want_response` set, and introduces
+//! itself directly to nodes it hears for the first time.
+//!
+//! Replies are rate limited: on a busy mesh, answering every request and
+//! greeting every new node would use a good part of the airtime.
+//!
+//! Answering a received packet is split in two steps:
+//! [`NodeInfoModule::handle_received`] decides whether to reply, and only then
+//! does the caller allocate a packet ID for [`NodeInfoModule::reply`].
+
+use core::fmt::Write as _;
+
+use femtopb::{EnumValue, Message as _};
+use heapless::{String, Vec};
+use meshtastic_protobufs::meshtastic::PortNum;
+
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::node_database::User;
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Default time between two broadcasts (Meshtastic's default is 3 hours)
+pub const DEFAULT_NODE_INFO_BROADCAST_SECS: u32 = 3 * 60 * 60;
+
+/// Minimum time between two replies, requests arriving sooner are ignored
+pub const MIN_REPLY_INTERVAL_SECS: u32 = 5 * 60;
+
+/// Default number of nodes remembered as already heard
+pub const MAX_KNOWN_NODES: usize = 64;
+
+/// User ID of a node, `!` followed by its node number in hex (e.g. `!deadbeef`)
+pub fn user_id(node_num: u32) -> String<9> {
+    let mut id = String::new();
+    // "!" and 8 hex digits always fit
+    let _ = write!(id, "!{:08x}", node_num);
+    id
+}
+
+/// A reply decided by [`NodeInfoModule::handle_received`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NodeInfoReply {
+    /// Node to send our NodeInfo to
+    pub destination: u32,
+    /// ID of the request being answered, 0 for a greeting
+    pub request_id: u32,
+    /// Ask for the destination's NodeInfo in return
+    pub want_response: bool,
+    /// Channel hash of the packet that triggered the reply
+    pub channel_hash: u8,
+}
+
+/// Our `User` and when to send it
+#[derive(Debug, Clone)]
+pub struct NodeInfoModule<const N: usize = MAX_KNOWN_NODES> {
+    node_num: u32,
+    user: User,
+    interval_secs: u32,
+    next_broadcast_secs: u32,
+    last_reply_secs: Option<u32>,
+    // Nodes heard so far, least recently heard first
+    known: Vec<u32, N>,
+}
+
+impl<const N: usize> NodeInfoModule<N> {
+    /// Create the module for our node, broadcasting `user` every `interval_secs`
+    ///
+    /// The first broadcast is due right away so the mesh learns about us on boot.
+    pub fn new(node_num: u32, user: User, interval_secs: u32) -> Self {
+        Self {
+            node_num,
+            user,
+            interval_secs,
+            next_broadcast_secs: 0,
+            last_reply_secs: None,
+            known: Vec::new(),
+        }
+    }
+
+    /// Our user
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// Replace our user, the change is broadcast at the next poll
+    pub fn set_user(&mut self, user: User) {
+        self.user = user;
+        self.next_broadcast_secs = 0;
+    }
+
+    /// Time between two broadcasts
+    pub fn interval_secs(&self) -> u32 {
+        self.interval_secs
+    }
+
+    /// Change the time between two broadcasts, the next one is due one interval after `now_secs`
+    pub fn set_interval_secs(&mut self, interval_secs: u32, now_secs: u32) {
+        self.interval_secs = interval_secs;
+        self.next_broadcast_secs = now_secs.saturating_add(interval_secs);
+    }
+
+    /// Time our next broadcast is due
+    pub fn next_broadcast_secs(&self) -> u32 {
+        self.next_broadcast_secs
+    }
+
+    /// Our broadcast, if it is due at `now_secs`
+    ///
+    /// The packet still has to be encoded and encrypted with the key of the
+    /// channel matching `channel_hash`.
+    pub fn poll(
+        &mut self,
+        now_secs: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+    ) -> Option<DecodedPacket> {
+        if now_secs < self.next_broadcast_secs {
+            return None;
+        }
+        self.next_broadcast_secs = now_secs.saturating_add(self.interval_secs);
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!("NodeInfo: broadcasting {}", self.user.long_name.as_str());
+        self.packet(BROADCAST_ADDR, packet_id, hop_limit, channel_hash, 0, false)
+    }
+
+    /// Handle a received packet, returns the reply to send if one is needed
+    ///
+    /// We reply to NODEINFO_APP packets with `want_response` set that are
+    /// addressed to us or broadcast, and greet nodes we never heard before.
+    /// No reply is sent within [`MIN_REPLY_INTERVAL_SECS`] of the last one.
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+        now_secs: u32,
+    ) -> Option<NodeInfoReply> {
+        let header = packet.header();
+        if header.source == self.node_num {
+            return None;
+        }
+        let is_new = self.remember(header.source);
+
+        let is_node_info = packet.port_num() == EnumValue::Known(PortNum::NodeinfoApp);
+        let for_us = header.destination == self.node_num || header.destination == BROADCAST_ADDR;
+        let requested =
+            is_node_info && for_us && packet.data_message().is_ok_and(|data| data.want_response);
+        if !requested && !is_new {
+            return None;
+        }
+
+        if let Some(last_reply_secs) = self.last_reply_secs {
+            if now_secs.saturating_sub(last_reply_secs) < MIN_REPLY_INTERVAL_SECS {
+                #[cfg(feature = "defmt")]
+                defmt::debug!(
+                    "NodeInfo: not replying to 0x{:08X}, rate limited",
+                    header.source
+                );
+                return None;
+            }
+        }
+        self.last_reply_secs = Some(now_secs);
+
+        Some(NodeInfoReply {
+            destination: header.source,
+            request_id: if requested { header.packet_id } else { 0 },
+            // A new node that did not send its NodeInfo is still unknown to us
+            want_response: !requested && !is_node_info,
+            channel_hash: header.channel_hash,
+        })
+    }
+
+    /// Build the packet for a `reply` returned by [`NodeInfoModule::handle_received`]
+    pub fn reply(
+        &self,
+        reply: &NodeInfoReply,
+        packet_id: u32,
+        hop_limit: u8,
+    ) -> Option<DecodedPacket> {
+        self.packet(
+            reply.destination,
+            packet_id,
+            hop_limit,
+            reply.channel_hash,
+            reply.request_id,
+            reply.want_response,
+        )
+    }
+
+    /// Mark `node_num` as heard, returns true if it was not known yet
+    fn remember(&mut self, node_num: u32) -> bool {
+        let is_new = match self.known.iter().position(|&known| known == node_num) {
+            Some(index) => {
+                self.known.remove(index);
+                false
+            }
+            None => true,
+        };
+        if self.known.is_full() {
+            self.known.remove(0);
+        }
+        let _ = self.known.push(node_num);
+        is_new
+    }
+
+    fn packet(
+        &self,
+        destination: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+        request_id: u32,
+        want_response: bool,
+    ) -> Option<DecodedPacket> {
+        let id = user_id(self.node_num);
+        let message = meshtastic_protobufs::meshtastic::User {
+            id: &id,
+            long_name: &self.user.long_name,
+            short_name: &self.user.short_name,
+            hw_model: self.user.hw_model,
+            is_licensed: self.user.is_licensed,
+            role: self.user.role,
+            public_key: self
+                .user
+                .public_key
+                .as_ref()
+                .map_or(&[], |key| key.as_slice()),
+            ..Default::default()
+        };
+        let mut data = OwnedData {
+            portnum: EnumValue::Known(PortNum::NodeinfoApp),
+            payload: [0u8; 240],
+            payload_len: message.encoded_len(),
+            want_response,
+            dest: 0,
+            source: 0,
+            request_id,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        };
+        // Names and key take at most 100 bytes
+        let mut slice = &mut data.payload[..data.payload_len];
+        message.encode(&mut slice).ok()?;
+
+        let header = Header::new(
+            destination,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            channel_hash,
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        Some(DecodedPacket::new(header, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{OTHER_NODE, OUR_NODE};
+    use crate::AppPayload;
+    use meshtastic_protobufs::meshtastic::config::device_config::Role;
+    use meshtastic_protobufs::meshtastic::HardwareModel;
+
+    fn user(long_name: &str, short_name: &str) -> User {
+        User {
+            long_name: String::try_from(long_name).unwrap(),
+            short_name: String::try_from(short_name).unwrap(),
+            hw_model: EnumValue::Known(HardwareModel::Unset),
+            role: EnumValue::Known(Role::Client),
+            is_licensed: false,
+            public_key: Some([0x42; 32]),
+        }
+    }
+
+    #[test]
+    fn test_user_id() {
+        assert_eq!(user_id(0xDEAD_BEEF).as_str(), "!deadbeef");
+        assert_eq!(user_id(0x0000_00AB).as_str(), "!000000ab");
+    }
+
+    #[test]
+    fn test_broadcast_schedule() {
+        let mut module: NodeInfoModule = NodeInfoModule::new(OUR_NODE, user("Ours", "OURS"), 100);
+        let broadcast = module.poll(0, 1, 3, 0x08).unwrap();
+        assert_eq!(broadcast.header.destination, BROADCAST_ADDR);
+        let Ok(AppPayload::NodeInfo(sent)) = broadcast.app_payload() else {
+            panic!("expected a NodeInfo");
+        };
+        assert_eq!(sent, user("Ours", "OURS"));
+        assert!(module.poll(99, 2, 3, 0x08).is_none());
+        assert!(module.poll(100, 2, 3, 0x08).is_some());
+
+        // A new user is broadcast right away
+        module.set_user(user("Renamed", "RNMD"));
+        assert!(module.poll(101, 3, 3, 0x08).is_some());
+    }
+
+    #[test]
+    fn test_replies_and_rate_limit() {
+        let mut module: NodeInfoModule = NodeInfoModule::new(OUR_NODE, user("Ours", "OURS"), 100);
+        let mut other: NodeInfoModule = NodeInfoModule::new(OTHER_NODE, user("Other", "OTHR"), 100);
+        let mut request = other.poll(0, 77, 3, 0x08).unwrap();
+        request.data.want_response = true;
+        let request = request.encode().unwrap().decode().unwrap();
+
+        // A new node asking for our NodeInfo gets one reply
+        let reply = module.handle_received(&request, 1000).unwrap();
+        assert_eq!(
+            reply,
+            NodeInfoReply {
+                destination: OTHER_NODE,
+                request_id: 77,
+                want_response: false,
+                channel_hash: 0x08,
+            }
+        );
+        let packet = module.reply(&reply, 5, 3).unwrap();
+        assert_eq!(packet.header.destination, OTHER_NODE);
+        assert_eq!(packet.data.request_id, 77);
+
+        // Asking again too soon is ignored
+        assert!(module
+            .handle_received(&request, 1000 + MIN_REPLY_INTERVAL_SECS - 1)
+            .is_none());
+        assert!(module
+            .handle_received(&request, 1000 + MIN_REPLY_INTERVAL_SECS)
+            .is_some());
+    }
+
+    #[test]
+    fn test_greet_new_nodes_once() {
+        let mut module: NodeInfoModule = NodeInfoModule::new(OUR_NODE, user("Ours", "OURS"), 100);
+        let mut other: NodeInfoModule = NodeInfoModule::new(OTHER_NODE, user("Other", "OTHR"), 100);
+        let broadcast = other
+            .poll(0, 77, 3, 0x08)
+            .unwrap()
+            .encode()
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        // Its NodeInfo told us who it is, so we only introduce ourselves
+        let reply = module.handle_received(&broadcast, 0).unwrap();
+        assert_eq!(reply.request_id, 0);
+        assert!(!reply.want_response);
+        assert!(module.handle_received(&broadcast, 10_000).is_none());
+    }
+}
@@ -0,0 +1,474 @@
+//! Position
+//!
+//! Nodes share their position in POSITION_APP packets: broadcast on the
+//! primary channel every `broadcast_interval_secs`, sooner when smart
+//! broadcasting notices that the node moved, and sent directly in answer to a
+//! packet with `want_response` set.
+//!
+//! Each channel chooses how precisely positions are shared on it with its
+//! `position_precision`, the number of high bits of the latitude and longitude
+//! that are kept (see [`reduce_precision`]). A precision of 0 means the
+//! position is not shared on that channel at all.
+//!
+//! [`PositionModule`] holds our position, which comes from the configuration
+//! (a fixed position), a GPS or a client, and decides when to send it.
+
+use femtopb::{EnumValue, Message as _};
+use meshtastic_protobufs::meshtastic::{config, position, PortNum};
+
+use crate::app_payload::Position;
+use crate::channel::Channel;
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Default time between two broadcasts (Meshtastic's default is 15 minutes)
+pub const DEFAULT_POSITION_BROADCAST_SECS: u32 = 15 * 60;
+
+/// Default distance to move before smart broadcasting sends an early update
+pub const DEFAULT_SMART_MIN_DISTANCE_M: u32 = 100;
+
+/// Default minimum time between two smart broadcasts
+pub const DEFAULT_SMART_MIN_INTERVAL_SECS: u32 = 30;
+
+/// Precision of a position sent without any bits removed
+pub const FULL_PRECISION: u32 = 32;
+
+// Mean Earth radius, in meters
+const EARTH_RADIUS_M: f32 = 6_371_000.0;
+
+/// Where our position comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PositionSource {
+    /// Entered in the configuration
+    Fixed,
+    /// Our own GPS
+    Gps,
+    /// A connected client (usually the phone's GPS)
+    Client,
+}
+
+impl PositionSource {
+    fn location_source(self) -> position::LocSource {
+        match self {
+            PositionSource::Fixed => position::LocSource::LocManual,
+            PositionSource::Gps => position::LocSource::LocInternal,
+            PositionSource::Client => position::LocSource::LocExternal,
+        }
+    }
+}
+
+/// When to send our position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PositionConfig {
+    /// Time between two regular broadcasts
+    pub broadcast_interval_secs: u32,
+    /// Broadcast early when we moved at least `smart_min_distance_m`
+    pub smart_broadcast: bool,
+    /// Distance that triggers a smart broadcast
+    pub smart_min_distance_m: u32,
+    /// Minimum time between two broadcasts when smart broadcasting
+    pub smart_min_interval_secs: u32,
+    /// Only accept positions from the configuration, ignore GPS and client updates
+    pub fixed_position: bool,
+}
+
+impl Default for PositionConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_interval_secs: DEFAULT_POSITION_BROADCAST_SECS,
+            smart_broadcast: true,
+            smart_min_distance_m: DEFAULT_SMART_MIN_DISTANCE_M,
+            smart_min_interval_secs: DEFAULT_SMART_MIN_INTERVAL_SECS,
+            fixed_position: false,
+        }
+    }
+}
+
+impl PositionConfig {
+    /// Convert from the protobuf representation, zero intervals and distances mean the defaults
+    pub fn from_protobuf(config: &config::PositionConfig) -> Self {
+        let or_default = |value: u32, default: u32| if value == 0 { default } else { value };
+        Self {
+            broadcast_interval_secs: or_default(
+                config.position_broadcast_secs,
+                DEFAULT_POSITION_BROADCAST_SECS,
+            ),
+            smart_broadcast: config.position_broadcast_smart_enabled,
+            smart_min_distance_m: or_default(
+                config.broadcast_smart_minimum_distance,
+                DEFAULT_SMART_MIN_DISTANCE_M,
+            ),
+            smart_min_interval_secs: or_default(
+                config.broadcast_smart_minimum_interval_secs,
+                DEFAULT_SMART_MIN_INTERVAL_SECS,
+            ),
+            fixed_position: config.fixed_position,
+        }
+    }
+
+    /// Convert to the protobuf representation
+    pub fn to_protobuf(&self) -> config::PositionConfig<'static> {
+        config::PositionConfig {
+            position_broadcast_secs: self.broadcast_interval_secs,
+            position_broadcast_smart_enabled: self.smart_broadcast,
+            broadcast_smart_minimum_distance: self.smart_min_distance_m,
+            broadcast_smart_minimum_interval_secs: self.smart_min_interval_secs,
+            fixed_position: self.fixed_position,
+            ..Default::default()
+        }
+    }
+}
+
+/// `position` as it may be shared on a channel with the given `precision_bits`
+///
+/// Like the Meshtastic firmware, only the `precision_bits` high bits of the
+/// latitude and longitude are kept and the result is moved to the middle of
+/// the area they describe. Returns `None` for a precision of 0, which means
+/// the position must not be shared.
+pub fn reduce_precision(position: &Position, precision_bits: u32) -> Option<Position> {
+    if precision_bits == 0 {
+        return None;
+    }
+    let mut reduced = *position;
+    reduced.precision_bits = precision_bits.min(FULL_PRECISION);
+    if precision_bits < FULL_PRECISION {
+        let mask = u32::MAX << (FULL_PRECISION - precision_bits);
+        let center = 1u32 << (FULL_PRECISION - 1 - precision_bits);
+        let reduce = |value: i32| ((value as u32 & mask).wrapping_add(center)) as i32;
+        reduced.latitude_i = position.latitude_i.map(reduce);
+        reduced.longitude_i = position.longitude_i.map(reduce);
+    }
+    Some(reduced)
+}
+
+/// cos(x) for |x| <= pi/2, enough for latitudes without a float math library
+fn cos(x: f32) -> f32 {
+    let x2 = x * x;
+    1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)))
+}
+
+/// Returns true if `a` and `b` are at least `meters` apart
+///
+/// Uses the equirectangular approximation, which is accurate at the distances
+/// smart broadcasting cares about.
+fn moved_at_least(a: &Position, b: &Position, meters: u32) -> bool {
+    let (Some(lat_a), Some(lon_a), Some(lat_b), Some(lon_b)) =
+        (a.latitude_i, a.longitude_i, b.latitude_i, b.longitude_i)
+    else {
+        // Gaining or losing a fix is worth telling
+        return a.latitude_i.is_some() != b.latitude_i.is_some();
+    };
+    let to_radians = |value: i32| value as f32 * 1e-7 * core::f32::consts::PI / 180.0;
+    let mean_latitude = (to_radians(lat_a) + to_radians(lat_b)) / 2.0;
+    let x = to_radians(lon_b.wrapping_sub(lon_a)) * cos(mean_latitude);
+    let y = to_radians(lat_b.wrapping_sub(lat_a));
+    let distance_squared = (x * x + y * y) * EARTH_RADIUS_M * EARTH_RADIUS_M;
+    distance_squared >= (meters as f32) * (meters as f32)
+}
+
+/// Our position and its broadcast schedule
+#[derive(Debug, Clone)]
+pub struct PositionModule {
+    node_num: u32,
+    config: PositionConfig,
+    position: Option<Position>,
+    // Time and position of our last broadcast
+    last_broadcast: Option<(u32, Position)>,
+    seq_number: u32,
+}
+
+impl PositionModule {
+    /// Create the module for our node, without a position yet
+    pub fn new(node_num: u32, config: PositionConfig) -> Self {
+        Self {
+            node_num,
+            config,
+            position: None,
+            last_broadcast: None,
+            seq_number: 0,
+        }
+    }
+
+    /// Current configuration
+    pub fn config(&self) -> &PositionConfig {
+        &self.config
+    }
+
+    /// Replace the configuration
+    pub fn set_config(&mut self, config: PositionConfig) {
+        self.config = config;
+    }
+
+    /// Our current position, if known
+    pub fn position(&self) -> Option<&Position> {
+        self.position.as_ref()
+    }
+
+    /// Update our position
+    ///
+    /// Returns false if the update was ignored because a fixed position is
+    /// configured and `source` is not [`PositionSource::Fixed`].
+    pub fn set_position(&mut self, mut position: Position, source: PositionSource) -> bool {
+        if self.config.fixed_position && source != PositionSource::Fixed {
+            return false;
+        }
+        position.location_source = EnumValue::Known(source.location_source());
+        self.position = Some(position);
+        true
+    }
+
+    /// Forget our position, for example when the GPS lost its fix
+    pub fn clear_position(&mut self) {
+        self.position = None;
+    }
+
+    /// Time the next regular broadcast is due, `None` without a position
+    ///
+    /// Smart broadcasts can happen earlier, see [`PositionModule::is_due`].
+    pub fn next_broadcast_secs(&self) -> Option<u32> {
+        self.position?;
+        Some(match self.last_broadcast {
+            Some((sent_secs, _)) => sent_secs.saturating_add(self.config.broadcast_interval_secs),
+            None => 0,
+        })
+    }
+
+    /// Returns true if our position should be broadcast at `now_secs`
+    pub fn is_due(&self, now_secs: u32) -> bool {
+        let Some(position) = self.position.as_ref() else {
+            return false;
+        };
+        let Some((sent_secs, sent)) = self.last_broadcast.as_ref() else {
+            return true;
+        };
+        let elapsed = now_secs.saturating_sub(*sent_secs);
+        elapsed >= self.config.broadcast_interval_secs
+            || (self.config.smart_broadcast
+                && elapsed >= self.config.smart_min_interval_secs
+                && moved_at_least(sent, position, self.config.smart_min_distance_m))
+    }
+
+    /// Our broadcast on `channel`, if it is due at `now_secs`
+    ///
+    /// The broadcast is rescheduled even when the channel does not share
+    /// positions, in which case nothing is returned. The packet still has to
+    /// be encoded and encrypted with the channel key.
+    pub fn poll(
+        &mut self,
+        now_secs: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel: &Channel,
+    ) -> Option<DecodedPacket> {
+        if !self.is_due(now_secs) {
+            return None;
+        }
+        let position = self.position?;
+        self.last_broadcast = Some((now_secs, position));
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Position: broadcasting with {} bits of precision",
+            channel.position_precision()
+        );
+        self.packet(BROADCAST_ADDR, packet_id, hop_limit, channel, 0)
+    }
+
+    /// Answer a `request` with `want_response` set on a POSITION_APP packet
+    ///
+    /// `channel` is the channel the request was received on, its precision is
+    /// applied to the reply. Returns `None` if the packet is not a position
+    /// request for us, if we have no position or if the channel does not
+    /// share positions.
+    pub fn reply<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        request: &P,
+        packet_id: u32,
+        hop_limit: u8,
+        channel: &Channel,
+    ) -> Option<DecodedPacket> {
+        let header = request.header();
+        let for_us = header.destination == self.node_num || header.destination == BROADCAST_ADDR;
+        if !for_us
+            || header.source == self.node_num
+            || request.port_num() != EnumValue::Known(PortNum::PositionApp)
+            || !request.data_message().ok()?.want_response
+        {
+            return None;
+        }
+        self.packet(
+            header.source,
+            packet_id,
+            hop_limit,
+            channel,
+            header.packet_id,
+        )
+    }
+
+    fn packet(
+        &mut self,
+        destination: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel: &Channel,
+        request_id: u32,
+    ) -> Option<DecodedPacket> {
+        let mut position = reduce_precision(self.position.as_ref()?, channel.position_precision())?;
+        self.seq_number = self.seq_number.wrapping_add(1);
+        position.seq_number = self.seq_number;
+
+        let message = position.to_protobuf();
+        let mut data = OwnedData {
+            portnum: EnumValue::Known(PortNum::PositionApp),
+            payload: [0u8; 240],
+            payload_len: message.encoded_len(),
+            want_response: false,
+            dest: 0,
+            source: 0,
+            request_id,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        };
+        // A Position message is at most about 120 bytes long
+        let mut slice = &mut data.payload[..data.payload_len];
+        message.encode(&mut slice).ok()?;
+
+        let header = Header::new(
+            destination,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            channel.hash(),
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        Some(DecodedPacket::new(header, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChannelRole;
+    use crate::test_util::OUR_NODE;
+    use crate::AppPayload;
+
+    fn position(latitude_i: i32, longitude_i: i32) -> Position {
+        Position {
+            latitude_i: Some(latitude_i),
+            longitude_i: Some(longitude_i),
+            altitude: Some(42),
+            ..Default::default()
+        }
+    }
+
+    fn channel(precision: u32) -> Channel {
+        let mut channel = Channel::new(0, "LongFast", &[0x01], ChannelRole::Primary).unwrap();
+        channel.set_position_precision(precision);
+        channel
+    }
+
+    #[test]
+    fn test_reduce_precision() {
+        let full = position(453_210_987, -1_226_543_210);
+        assert_eq!(reduce_precision(&full, 0), None);
+        assert_eq!(
+            reduce_precision(&full, 32).unwrap().latitude_i,
+            full.latitude_i
+        );
+
+        // 13 bits keep multiples of 2^19, moved to the middle of the box
+        let reduced = reduce_precision(&full, 13).unwrap();
+        assert_eq!(reduced.precision_bits, 13);
+        assert_eq!(reduced.latitude_i, Some(864 * (1 << 19) + (1 << 18)));
+        assert_eq!(reduced.longitude_i, Some(-2340 * (1 << 19) + (1 << 18)));
+        assert_eq!(reduced.altitude, full.altitude);
+    }
+
+    #[test]
+    fn test_broadcast_schedule() {
+        let config = PositionConfig {
+            broadcast_interval_secs: 900,
+            smart_min_distance_m: 100,
+            smart_min_interval_secs: 30,
+            ..Default::default()
+        };
+        let mut module = PositionModule::new(OUR_NODE, config);
+        let channel = channel(32);
+        assert!(module.poll(0, 1, 3, &channel).is_none());
+
+        module.set_position(position(450_000_000, 90_000_000), PositionSource::Gps);
+        let broadcast = module.poll(10, 1, 3, &channel).unwrap();
+        assert_eq!(broadcast.header.destination, BROADCAST_ADDR);
+        let Ok(AppPayload::Position(sent)) = broadcast.app_payload() else {
+            panic!("expected a position");
+        };
+        assert_eq!(sent.latitude_i, Some(450_000_000));
+        assert_eq!(
+            sent.location_source,
+            EnumValue::Known(position::LocSource::LocInternal)
+        );
+        assert_eq!(module.next_broadcast_secs(), Some(910));
+
+        // Moving about 55 m is not enough for a smart broadcast, 111 m is
+        module.set_position(position(450_005_000, 90_000_000), PositionSource::Gps);
+        assert!(!module.is_due(100));
+        module.set_position(position(450_010_000, 90_000_000), PositionSource::Gps);
+        assert!(!module.is_due(39));
+        assert!(module.poll(40, 2, 3, &channel).is_some());
+
+        // Staying put waits for the regular interval
+        assert!(!module.is_due(939));
+        assert!(module.is_due(940));
+    }
+
+    #[test]
+    fn test_fixed_position_ignores_gps() {
+        let config = PositionConfig {
+            fixed_position: true,
+            ..Default::default()
+        };
+        let mut module = PositionModule::new(OUR_NODE, config);
+        assert!(!module.set_position(position(1, 2), PositionSource::Gps));
+        assert!(module.set_position(position(3, 4), PositionSource::Fixed));
+        assert!(!module.set_position(position(5, 6), PositionSource::Client));
+        assert_eq!(module.position().unwrap().latitude_i, Some(3));
+    }
+
+    #[test]
+    fn test_reply_to_position_request() {
+        let mut module = PositionModule::new(OUR_NODE, PositionConfig::default());
+        module.set_position(position(453_210_987, -1_226_543_210), PositionSource::Fixed);
+
+        let mut request = PositionModule::new(0x0000_BEEF, PositionConfig::default());
+        request.set_position(position(1, 2), PositionSource::Gps);
+        let mut request = request.poll(0, 77, 3, &channel(32)).unwrap();
+        request.header.destination = OUR_NODE;
+
+        // A position without want_response is not a request
+        let received = request.encode().unwrap().decode().unwrap();
+        assert!(module.reply(&received, 5, 3, &channel(13)).is_none());
+
+        request.data.want_response = true;
+        let received = request.encode().unwrap().decode().unwrap();
+        let reply = module.reply(&received, 5, 3, &channel(13)).unwrap();
+        assert_eq!(reply.header.destination, 0x0000_BEEF);
+        assert_eq!(reply.data.request_id, 77);
+        let Ok(AppPayload::Position(sent)) = reply.app_payload() else {
+            panic!("expected a position");
+        };
+        assert_eq!(sent.precision_bits, 13);
+        assert_eq!(sent.latitude_i, Some(864 * (1 << 19) + (1 << 18)));
+
+        // Nothing is shared on a channel with precision 0
+        assert!(module.reply(&received, 6, 3, &channel(0)).is_none());
+    }
+}
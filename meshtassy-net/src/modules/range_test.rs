@@ -0,0 +1,324 @@
+//! Range test
+//!
+//! Used to find out how far a node can be heard, for example while siting a
+//! repeater: a sender broadcasts numbered `seq N` text packets on
+//! RANGE_TEST_APP at a fixed interval, and receivers carried around record
+//! every packet they hear along with its signal quality and the sender's last
+//! known position.
+//!
+//! Each hit becomes a [`RangeTestRecord`], which formats as a CSV line (see
+//! [`CSV_HEADER`]) so a stream of records can be collected over USB and
+//! opened in a spreadsheet or a mapping tool.
+
+use core::fmt;
+use core::fmt::Write as _;
+
+use femtopb::EnumValue;
+use heapless::String;
+use meshtastic_protobufs::meshtastic::PortNum;
+
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::node_database::NodeDatabase;
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Default time between two packets of a sender
+pub const DEFAULT_RANGE_TEST_INTERVAL_SECS: u32 = 60;
+
+/// Longest payload kept in a record, range test payloads are short
+pub const MAX_RANGE_TEST_PAYLOAD_LEN: usize = 32;
+
+/// Column names of the CSV lines produced by [`RangeTestRecord`]
+pub const CSV_HEADER: &str =
+    "time,from,sender name,sender lat,sender long,rssi,snr,hops,seq,payload";
+
+/// Sender role: broadcasts `seq N` packets
+#[derive(Debug, Clone)]
+pub struct RangeTestSender {
+    node_num: u32,
+    interval_secs: u32,
+    next_secs: u32,
+    seq: u32,
+}
+
+impl RangeTestSender {
+    /// Create a sender for our node, sending every `interval_secs`
+    pub fn new(node_num: u32, interval_secs: u32) -> Self {
+        Self {
+            node_num,
+            interval_secs,
+            next_secs: 0,
+            seq: 0,
+        }
+    }
+
+    /// Time the next packet is due
+    pub fn next_secs(&self) -> u32 {
+        self.next_secs
+    }
+
+    /// Number of packets sent so far
+    pub fn sent(&self) -> u32 {
+        self.seq
+    }
+
+    /// The next `seq N` packet, if it is due at `now_secs`
+    ///
+    /// The packet still has to be encoded and encrypted with the key of the
+    /// channel matching `channel_hash`.
+    pub fn poll(
+        &mut self,
+        now_secs: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+    ) -> Option<DecodedPacket> {
+        if now_secs < self.next_secs {
+            return None;
+        }
+        self.next_secs = now_secs.saturating_add(self.interval_secs);
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut text: String<16> = String::new();
+        // "seq " and a u32 always fit
+        let _ = write!(text, "seq {}", self.seq);
+        let data = OwnedData::new(PortNum::RangeTestApp, text.as_bytes()).ok()?;
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!("Range test: sending {}", text.as_str());
+        let header = Header::new(
+            BROADCAST_ADDR,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            channel_hash,
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        Some(DecodedPacket::new(header, data))
+    }
+}
+
+/// One range test packet heard by a receiver
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RangeTestRecord {
+    /// Time the packet was heard
+    pub time_secs: u32,
+    /// Node that sent the packet
+    pub from: u32,
+    /// Long name of the sender, empty if unknown
+    pub sender_name: String<40>,
+    /// Last known latitude of the sender, in 1e-7 degrees
+    pub sender_latitude_i: Option<i32>,
+    /// Last known longitude of the sender, in 1e-7 degrees
+    pub sender_longitude_i: Option<i32>,
+    /// Received signal strength in dBm
+    pub rssi: i8,
+    /// Signal-to-noise ratio in dB
+    pub snr: i8,
+    /// Number of relays the packet went through, `None` if the sender did not set `hop_start`
+    pub hops: Option<u8>,
+    /// Sequence number parsed from a `seq N` payload
+    pub seq: Option<u32>,
+    /// The payload text, cut to [`MAX_RANGE_TEST_PAYLOAD_LEN`]
+    pub payload: String<MAX_RANGE_TEST_PAYLOAD_LEN>,
+}
+
+// Write a value in 1e-7 degrees as decimal degrees, without floats
+fn write_degrees(f: &mut fmt::Formatter<'_>, value_i: Option<i32>) -> fmt::Result {
+    let Some(value_i) = value_i else {
+        return Ok(());
+    };
+    let sign = if value_i < 0 { "-" } else { "" };
+    let value = value_i.unsigned_abs();
+    write!(
+        f,
+        "{}{}.{:07}",
+        sign,
+        value / 10_000_000,
+        value % 10_000_000
+    )
+}
+
+// Write a text field, quoted when it contains CSV special characters
+fn write_text(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    if !text.contains([',', '"', '\n', '\r']) {
+        return f.write_str(text);
+    }
+    f.write_char('"')?;
+    for c in text.chars() {
+        if c == '"' {
+            f.write_char('"')?;
+        }
+        f.write_char(c)?;
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for RangeTestRecord {
+    /// Format as a CSV line matching [`CSV_HEADER`], without the line ending
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},!{:08x},", self.time_secs, self.from)?;
+        write_text(f, &self.sender_name)?;
+        f.write_char(',')?;
+        write_degrees(f, self.sender_latitude_i)?;
+        f.write_char(',')?;
+        write_degrees(f, self.sender_longitude_i)?;
+        write!(f, ",{},{},", self.rssi, self.snr)?;
+        if let Some(hops) = self.hops {
+            write!(f, "{}", hops)?;
+        }
+        f.write_char(',')?;
+        if let Some(seq) = self.seq {
+            write!(f, "{}", seq)?;
+        }
+        f.write_char(',')?;
+        write_text(f, &self.payload)
+    }
+}
+
+/// Receiver role: records the range test packets we hear
+#[derive(Debug, Clone)]
+pub struct RangeTestReceiver {
+    node_num: u32,
+    hits: u32,
+}
+
+impl RangeTestReceiver {
+    /// Create a receiver for our node
+    pub fn new(node_num: u32) -> Self {
+        Self { node_num, hits: 0 }
+    }
+
+    /// Number of range test packets heard so far
+    pub fn hits(&self) -> u32 {
+        self.hits
+    }
+
+    /// Record a received packet, returns `None` unless it is a range test packet from another node
+    ///
+    /// The sender's name and position are looked up in `database`.
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+        database: &NodeDatabase,
+        now_secs: u32,
+    ) -> Option<RangeTestRecord> {
+        let header = packet.header();
+        if header.source == self.node_num
+            || packet.port_num() != EnumValue::Known(PortNum::RangeTestApp)
+        {
+            return None;
+        }
+        self.hits = self.hits.wrapping_add(1);
+
+        let text = core::str::from_utf8(packet.payload_data()).unwrap_or("");
+        let mut payload = String::new();
+        for c in text.chars() {
+            if payload.push(c).is_err() {
+                break;
+            }
+        }
+        let seq = text
+            .strip_prefix("seq ")
+            .and_then(|seq| seq.trim().parse().ok());
+
+        let node = database.get_node(header.source);
+        let sender_name = node
+            .and_then(|node| node.user.as_ref())
+            .map(|user| user.long_name.clone())
+            .unwrap_or_default();
+        let position = node.and_then(|node| node.position.as_ref());
+        let flags = header.flags;
+
+        Some(RangeTestRecord {
+            time_secs: now_secs,
+            from: header.source,
+            sender_name,
+            sender_latitude_i: position.map(|position| position.latitude_i),
+            sender_longitude_i: position.map(|position| position.longitude_i),
+            rssi: packet.rssi(),
+            snr: packet.snr(),
+            hops: (flags.hop_start != 0).then(|| flags.hop_start.saturating_sub(flags.hop_limit)),
+            seq,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node_database::{NodeInfo, Position, User};
+
+    const SENDER: u32 = 0x0000_5E4D;
+    const RECEIVER: u32 = 0x0000_4ECE;
+
+    #[test]
+    fn test_sender_numbers_packets() {
+        let mut sender = RangeTestSender::new(SENDER, 30);
+        let first = sender.poll(0, 1, 3, 0x08).unwrap();
+        assert_eq!(first.header.destination, BROADCAST_ADDR);
+        assert_eq!(first.payload_data(), b"seq 1");
+        assert!(sender.poll(29, 2, 3, 0x08).is_none());
+        assert_eq!(
+            sender.poll(30, 2, 3, 0x08).unwrap().payload_data(),
+            b"seq 2"
+        );
+        assert_eq!(sender.sent(), 2);
+    }
+
+    #[test]
+    fn test_receiver_records_hits_as_csv() {
+        let mut database = NodeDatabase::new();
+        database.add_or_update_node(NodeInfo {
+            num: SENDER,
+            user: Some(User {
+                long_name: String::try_from("Hill, top").unwrap(),
+                ..Default::default()
+            }),
+            position: Some(Position {
+                latitude_i: 453_210_987,
+                longitude_i: -1_226_543_210,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let mut sender = RangeTestSender::new(SENDER, 30);
+        let mut packet = sender.poll(0, 1, 3, 0x08).unwrap();
+        // Relayed once on the way
+        packet.header.flags.hop_limit = 2;
+        let mut received = packet.encode().unwrap().decode().unwrap();
+        received.rssi = -110;
+        received.snr = -7;
+
+        let mut receiver = RangeTestReceiver::new(RECEIVER);
+        let record = receiver
+            .handle_received(&received, &database, 1234)
+            .unwrap();
+        assert_eq!(record.seq, Some(1));
+        assert_eq!(record.hops, Some(1));
+        assert_eq!(receiver.hits(), 1);
+
+        let mut line: String<128> = String::new();
+        write!(line, "{}", record).unwrap();
+        assert_eq!(
+            line.as_str(),
+            "1234,!00005e4d,\"Hill, top\",45.3210987,-122.6543210,-110,-7,1,1,seq 1"
+        );
+
+        // Unknown senders leave the name and position empty
+        let record = receiver
+            .handle_received(&received, &NodeDatabase::new(), 1235)
+            .unwrap();
+        line.clear();
+        write!(line, "{}", record).unwrap();
+        assert_eq!(line.as_str(), "1235,!00005e4d,,,,-110,-7,1,1,seq 1");
+    }
+}
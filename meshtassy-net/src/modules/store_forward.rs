@@ -0,0 +1,995 @@
+//! Store & Forward
+//!
+//! A Store & Forward server is a well placed, always powered node that keeps
+//! the recent text messages it hears. Clients that were out of range ask it
+//! for the messages they missed and the server replays them one by one.
+//! Everything goes over STORE_FORWARD_APP packets holding a `StoreAndForward`
+//! protobuf, whose `rr` field tells what the packet is:
+//!
+//! - the server broadcasts a heartbeat every `heartbeat_interval_secs`,
+//!   which is how clients find it and notice they are back in range;
+//! - a client sends `CLIENT_HISTORY` with the last message index it got, the
+//!   server answers `ROUTER_HISTORY` with the number of messages to come and
+//!   then replays them as `ROUTER_TEXT_DIRECT` / `ROUTER_TEXT_BROADCAST`;
+//! - `CLIENT_STATS` and `CLIENT_PING` are answered with `ROUTER_STATS` and
+//!   `ROUTER_PONG`.
+//!
+//! Replayed messages are sent by the server, with the original sender and
+//! destination in the `source` and `dest` fields of their `Data`.
+//!
+//! The server keeps its messages in a [`MessageStore`], [`InMemoryStore`] is
+//! a ring buffer in RAM.
+
+use femtopb::{EnumValue, Message as _};
+use heapless::{Deque, Vec};
+use meshtastic_protobufs::meshtastic::store_and_forward::{
+    self, Heartbeat, History, RequestResponse, Statistics,
+};
+use meshtastic_protobufs::meshtastic::{Data, PortNum, StoreAndForward};
+
+use crate::error::PacketError;
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Default time between two server heartbeats (same as the Meshtastic firmware)
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u32 = 15 * 60;
+
+/// Default maximum number of messages replayed for one request
+pub const DEFAULT_HISTORY_RETURN_MAX: u32 = 25;
+
+/// Default age of the oldest message replayed, in minutes
+pub const DEFAULT_HISTORY_RETURN_WINDOW_MINS: u32 = 240;
+
+/// Default number of messages kept by an [`InMemoryStore`]
+pub const MAX_STORED_MESSAGES: usize = 32;
+
+/// Longest text kept, limited by the `StoreAndForward.text` field
+pub const MAX_STORED_TEXT_LEN: usize = 233;
+
+/// A text message kept by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StoredMessage {
+    /// Index of the message, counting every message stored since boot (the first is 1)
+    pub sequence: u32,
+    /// Time the message was heard
+    pub time_secs: u32,
+    /// Node that sent the message
+    pub source: u32,
+    /// Node the message was sent to, [`BROADCAST_ADDR`] for channel messages
+    pub destination: u32,
+    /// Channel hash of the channel the message was sent on
+    pub channel_hash: u8,
+    /// Message text
+    pub text: Vec<u8, MAX_STORED_TEXT_LEN>,
+}
+
+/// Storage for the messages of a Store & Forward server
+///
+/// Messages are pushed in sequence order, and the oldest ones are dropped to
+/// make room once the store is full.
+pub trait MessageStore {
+    /// Keep `message`, dropping the oldest message if the store is full
+    fn push(&mut self, message: StoredMessage);
+
+    /// Number of messages kept
+    fn len(&self) -> usize;
+
+    /// Maximum number of messages kept
+    fn capacity(&self) -> usize;
+
+    /// The message at `index`, 0 being the oldest one
+    fn get(&self, index: usize) -> Option<&StoredMessage>;
+
+    /// Returns true if no message is kept
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Ring buffer of the last `N` messages, in RAM
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore<const N: usize = MAX_STORED_MESSAGES> {
+    messages: Deque<StoredMessage, N>,
+}
+
+impl<const N: usize> InMemoryStore<N> {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self {
+            messages: Deque::new(),
+        }
+    }
+}
+
+impl<const N: usize> MessageStore for InMemoryStore<N> {
+    fn push(&mut self, message: StoredMessage) {
+        if self.messages.is_full() {
+            self.messages.pop_front();
+        }
+        let _ = self.messages.push_back(message);
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn get(&self, index: usize) -> Option<&StoredMessage> {
+        self.messages.iter().nth(index)
+    }
+}
+
+/// Server settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ServerConfig {
+    /// Time between two heartbeats, 0 disables them
+    pub heartbeat_interval_secs: u32,
+    /// Maximum number of messages replayed for one request
+    pub history_return_max: u32,
+    /// Age of the oldest message replayed when the client does not choose, in minutes
+    pub history_return_window_mins: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            history_return_max: DEFAULT_HISTORY_RETURN_MAX,
+            history_return_window_mins: DEFAULT_HISTORY_RETURN_WINDOW_MINS,
+        }
+    }
+}
+
+/// Server statistics, as sent in `ROUTER_STATS`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StoreForwardStats {
+    /// Messages stored since boot
+    pub messages_total: u32,
+    /// Messages currently stored
+    pub messages_saved: u32,
+    /// Maximum number of messages stored
+    pub messages_max: u32,
+    /// Server uptime in seconds
+    pub up_time: u32,
+    /// Requests received from clients
+    pub requests: u32,
+    /// History requests received from clients
+    pub requests_history: u32,
+    /// Whether the server sends heartbeats
+    pub heartbeat: bool,
+    /// Maximum number of messages replayed for one request
+    pub return_max: u32,
+    /// Default history window in minutes
+    pub return_window: u32,
+}
+
+impl StoreForwardStats {
+    fn from_protobuf(pb: &Statistics) -> Self {
+        Self {
+            messages_total: pb.messages_total,
+            messages_saved: pb.messages_saved,
+            messages_max: pb.messages_max,
+            up_time: pb.up_time,
+            requests: pb.requests,
+            requests_history: pb.requests_history,
+            heartbeat: pb.heartbeat,
+            return_max: pb.return_max,
+            return_window: pb.return_window,
+        }
+    }
+
+    fn to_protobuf(self) -> Statistics<'static> {
+        Statistics {
+            messages_total: self.messages_total,
+            messages_saved: self.messages_saved,
+            messages_max: self.messages_max,
+            up_time: self.up_time,
+            requests: self.requests,
+            requests_history: self.requests_history,
+            heartbeat: self.heartbeat,
+            return_max: self.return_max,
+            return_window: self.return_window,
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+/// Decode the `StoreAndForward` message of a STORE_FORWARD_APP packet
+fn decode<'a>(data: &Data<'a>) -> Result<StoreAndForward<'a>, PacketError> {
+    StoreAndForward::decode(data.payload).map_err(|_| PacketError::Protobuf)
+}
+
+/// Build a STORE_FORWARD_APP packet from `source` to `destination`
+fn packet(
+    source: u32,
+    destination: u32,
+    packet_id: u32,
+    hop_limit: u8,
+    channel_hash: u8,
+    message: &StoreAndForward,
+) -> Option<DecodedPacket> {
+    let mut data = OwnedData {
+        portnum: EnumValue::Known(PortNum::StoreForwardApp),
+        payload: [0u8; 240],
+        payload_len: message.encoded_len(),
+        want_response: false,
+        dest: 0,
+        source: 0,
+        request_id: 0,
+        reply_id: 0,
+        emoji: 0,
+        bitfield: None,
+    };
+    // The largest message is a text of MAX_STORED_TEXT_LEN bytes, which fits with its tag
+    let mut slice = &mut data.payload[..data.payload_len];
+    message.encode(&mut slice).ok()?;
+
+    let header = Header::new(
+        destination,
+        source,
+        packet_id,
+        HeaderFlags {
+            hop_limit,
+            want_ack: false,
+            via_mqtt: false,
+            hop_start: hop_limit,
+        },
+        channel_hash,
+        0,
+        (source & 0xFF) as u8,
+    );
+    Some(DecodedPacket::new(header, data))
+}
+
+fn message(rr: RequestResponse, variant: Option<store_and_forward::Variant>) -> StoreAndForward {
+    StoreAndForward {
+        rr: EnumValue::Known(rr),
+        variant,
+        unknown_fields: Default::default(),
+    }
+}
+
+// A response waiting for `StoreForwardServer::poll`
+#[derive(Debug, Clone, Copy)]
+struct PendingResponse {
+    client: u32,
+    channel_hash: u8,
+    rr: RequestResponse,
+    // Number of messages announced by ROUTER_HISTORY
+    history_messages: u32,
+}
+
+// A history being replayed to a client
+#[derive(Debug, Clone, Copy)]
+struct Replay {
+    client: u32,
+    channel_hash: u8,
+    next_sequence: u32,
+    last_sequence: u32,
+    since_secs: u32,
+}
+
+impl Replay {
+    fn includes(&self, message: &StoredMessage) -> bool {
+        message.sequence >= self.next_sequence
+            && message.sequence <= self.last_sequence
+            && message.time_secs >= self.since_secs
+            && message.channel_hash == self.channel_hash
+            && message.source != self.client
+            && (message.destination == self.client || message.destination == BROADCAST_ADDR)
+    }
+}
+
+/// The server role: keeps text messages and replays them to clients
+#[derive(Debug, Clone)]
+pub struct StoreForwardServer<S: MessageStore = InMemoryStore> {
+    node_num: u32,
+    config: ServerConfig,
+    store: S,
+    messages_total: u32,
+    requests: u32,
+    requests_history: u32,
+    next_heartbeat_secs: u32,
+    pending: Option<PendingResponse>,
+    replay: Option<Replay>,
+}
+
+impl<S: MessageStore> StoreForwardServer<S> {
+    /// Create a server keeping its messages in `store`
+    ///
+    /// The first heartbeat is due right away so clients find the server on boot.
+    pub fn new(node_num: u32, config: ServerConfig, store: S) -> Self {
+        Self {
+            node_num,
+            config,
+            store,
+            messages_total: 0,
+            requests: 0,
+            requests_history: 0,
+            next_heartbeat_secs: 0,
+            pending: None,
+            replay: None,
+        }
+    }
+
+    /// Messages kept so far
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Current statistics, `now_secs` being the time since boot
+    pub fn stats(&self, now_secs: u32) -> StoreForwardStats {
+        StoreForwardStats {
+            messages_total: self.messages_total,
+            messages_saved: self.store.len() as u32,
+            messages_max: self.store.capacity() as u32,
+            up_time: now_secs,
+            requests: self.requests,
+            requests_history: self.requests_history,
+            heartbeat: self.config.heartbeat_interval_secs != 0,
+            return_max: self.config.history_return_max,
+            return_window: self.config.history_return_window_mins,
+        }
+    }
+
+    /// Returns true if a history is being replayed
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Time the next heartbeat is due, `None` if heartbeats are disabled
+    pub fn next_heartbeat_secs(&self) -> Option<u32> {
+        (self.config.heartbeat_interval_secs != 0).then_some(self.next_heartbeat_secs)
+    }
+
+    /// Handle a received packet
+    ///
+    /// Text messages are stored, requests from clients prepare a response
+    /// that is sent by [`StoreForwardServer::poll`].
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+        now_secs: u32,
+    ) -> Result<(), PacketError> {
+        let header = packet.header();
+        if header.source == self.node_num {
+            return Ok(());
+        }
+        match packet.port_num() {
+            EnumValue::Known(PortNum::TextMessageApp) => {
+                let data = packet.data_message()?;
+                let Ok(text) = Vec::from_slice(data.payload) else {
+                    return Ok(());
+                };
+                self.messages_total = self.messages_total.wrapping_add(1);
+                self.store.push(StoredMessage {
+                    sequence: self.messages_total,
+                    time_secs: now_secs,
+                    source: header.source,
+                    destination: header.destination,
+                    channel_hash: header.channel_hash,
+                    text,
+                });
+            }
+            EnumValue::Known(PortNum::StoreForwardApp) if header.destination == self.node_num => {
+                let data = packet.data_message()?;
+                let request = decode(&data)?;
+                self.handle_request(header, &request, now_secs);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, header: &Header, request: &StoreAndForward, now_secs: u32) {
+        let EnumValue::Known(rr) = request.rr else {
+            return;
+        };
+        self.requests = self.requests.wrapping_add(1);
+        let mut respond = |rr: RequestResponse, history_messages: u32| {
+            self.pending = Some(PendingResponse {
+                client: header.source,
+                channel_hash: header.channel_hash,
+                rr,
+                history_messages,
+            });
+        };
+        match rr {
+            RequestResponse::ClientPing => respond(RequestResponse::RouterPong, 0),
+            RequestResponse::ClientStats => respond(RequestResponse::RouterStats, 0),
+            RequestResponse::ClientAbort
+                if self
+                    .replay
+                    .is_some_and(|replay| replay.client == header.source) =>
+            {
+                self.replay = None;
+            }
+            RequestResponse::ClientHistory => {
+                self.requests_history = self.requests_history.wrapping_add(1);
+                if self
+                    .replay
+                    .is_some_and(|replay| replay.client != header.source)
+                {
+                    respond(RequestResponse::RouterBusy, 0);
+                    return;
+                }
+                let (window_mins, last_request) = match &request.variant {
+                    Some(store_and_forward::Variant::History(history)) if history.window != 0 => {
+                        (history.window, history.last_request)
+                    }
+                    Some(store_and_forward::Variant::History(history)) => {
+                        (self.config.history_return_window_mins, history.last_request)
+                    }
+                    _ => (self.config.history_return_window_mins, 0),
+                };
+                let mut replay = Replay {
+                    client: header.source,
+                    channel_hash: header.channel_hash,
+                    next_sequence: last_request.saturating_add(1),
+                    last_sequence: u32::MAX,
+                    since_secs: now_secs.saturating_sub(window_mins.saturating_mul(60)),
+                };
+                let mut count = 0;
+                let mut last_sequence = last_request;
+                for message in (0..self.store.len()).filter_map(|index| self.store.get(index)) {
+                    if count == self.config.history_return_max {
+                        break;
+                    }
+                    if replay.includes(message) {
+                        count += 1;
+                        last_sequence = message.sequence;
+                    }
+                }
+                replay.last_sequence = last_sequence;
+
+                #[cfg(feature = "defmt")]
+                defmt::debug!(
+                    "Store & Forward: replaying {} messages to 0x{:08X}",
+                    count,
+                    header.source
+                );
+                self.replay = (count > 0).then_some(replay);
+                respond(RequestResponse::RouterHistory, count);
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns true if [`StoreForwardServer::poll`] has something to send at `now_secs`
+    pub fn is_due(&self, now_secs: u32) -> bool {
+        self.pending.is_some()
+            || self.replay.is_some()
+            || self
+                .next_heartbeat_secs()
+                .is_some_and(|heartbeat_secs| now_secs >= heartbeat_secs)
+    }
+
+    /// The next packet to send: a response, a replayed message or a heartbeat
+    ///
+    /// Heartbeats go to the channel matching `channel_hash` (usually the
+    /// primary channel), responses and replayed messages to the channel the
+    /// request came from. Replayed messages keep the channel hash of the
+    /// channel they were heard on, a caller should only replay them on that
+    /// channel. Call repeatedly to go through a whole history, one message
+    /// per call.
+    pub fn poll(
+        &mut self,
+        now_secs: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+    ) -> Option<DecodedPacket> {
+        if let Some(pending) = self.pending.take() {
+            let variant = match pending.rr {
+                RequestResponse::RouterStats => Some(store_and_forward::Variant::Stats(
+                    self.stats(now_secs).to_protobuf(),
+                )),
+                RequestResponse::RouterHistory => {
+                    Some(store_and_forward::Variant::History(History {
+                        history_messages: pending.history_messages,
+                        window: self.config.history_return_window_mins * 60 * 1000,
+                        last_request: self.replay.map_or(0, |replay| replay.last_sequence),
+                        unknown_fields: Default::default(),
+                    }))
+                }
+                _ => None,
+            };
+            return packet(
+                self.node_num,
+                pending.client,
+                packet_id,
+                hop_limit,
+                pending.channel_hash,
+                &message(pending.rr, variant),
+            );
+        }
+
+        if let Some(mut replay) = self.replay {
+            let next = (0..self.store.len())
+                .filter_map(|index| self.store.get(index))
+                .find(|message| replay.includes(message));
+            if let Some(stored) = next {
+                replay.next_sequence = stored.sequence.saturating_add(1);
+                self.replay = Some(replay);
+                let rr = if stored.destination == BROADCAST_ADDR {
+                    RequestResponse::RouterTextBroadcast
+                } else {
+                    RequestResponse::RouterTextDirect
+                };
+                let variant = Some(store_and_forward::Variant::Text(&stored.text));
+                let mut replayed = packet(
+                    self.node_num,
+                    replay.client,
+                    packet_id,
+                    hop_limit,
+                    replay.channel_hash,
+                    &message(rr, variant),
+                )?;
+                replayed.data.source = stored.source;
+                replayed.data.dest = stored.destination;
+                return Some(replayed);
+            }
+            // The remaining messages were dropped from the store meanwhile
+            self.replay = None;
+        }
+
+        let heartbeat_secs = self.next_heartbeat_secs()?;
+        if now_secs < heartbeat_secs {
+            return None;
+        }
+        self.next_heartbeat_secs = now_secs.saturating_add(self.config.heartbeat_interval_secs);
+        let heartbeat = Heartbeat {
+            period: self.config.heartbeat_interval_secs,
+            secondary: 0,
+            unknown_fields: Default::default(),
+        };
+        packet(
+            self.node_num,
+            BROADCAST_ADDR,
+            packet_id,
+            hop_limit,
+            channel_hash,
+            &message(
+                RequestResponse::RouterHeartbeat,
+                Some(store_and_forward::Variant::Heartbeat(heartbeat)),
+            ),
+        )
+    }
+}
+
+/// What a client learned from a received packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientEvent {
+    /// A server was heard for the first time or after being out of reach,
+    /// it is time to ask for the missed messages
+    ServerAvailable {
+        /// Node number of the server
+        server: u32,
+    },
+    /// The server is about to replay `messages` messages
+    History {
+        /// Node number of the server
+        server: u32,
+        /// Number of messages that follow
+        messages: u32,
+    },
+    /// The server is busy with another client, try again later
+    Busy {
+        /// Node number of the server
+        server: u32,
+    },
+    /// The server answered a ping
+    Pong {
+        /// Node number of the server
+        server: u32,
+    },
+    /// The server sent its statistics
+    Stats {
+        /// Node number of the server
+        server: u32,
+        /// The statistics
+        stats: StoreForwardStats,
+    },
+}
+
+/// A message replayed by a server, see [`replayed_text`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReplayedText<'a> {
+    /// Node that originally sent the message
+    pub source: u32,
+    /// Node the message was sent to, [`BROADCAST_ADDR`] for channel messages
+    pub destination: u32,
+    /// Message text
+    pub text: &'a [u8],
+}
+
+/// The text message replayed in a `ROUTER_TEXT_DIRECT` or `ROUTER_TEXT_BROADCAST` packet
+pub fn replayed_text<'a>(data: &Data<'a>) -> Option<ReplayedText<'a>> {
+    let message = decode(data).ok()?;
+    match (message.rr, message.variant) {
+        (
+            EnumValue::Known(
+                RequestResponse::RouterTextDirect | RequestResponse::RouterTextBroadcast,
+            ),
+            Some(store_and_forward::Variant::Text(text)),
+        ) => Some(ReplayedText {
+            source: data.source,
+            destination: data.dest,
+            text,
+        }),
+        _ => None,
+    }
+}
+
+// The server a client uses
+#[derive(Debug, Clone, Copy)]
+struct KnownServer {
+    node_num: u32,
+    channel_hash: u8,
+    last_heard_secs: u32,
+    period_secs: u32,
+}
+
+/// The client role: finds a server and asks it for the messages we missed
+#[derive(Debug, Clone)]
+pub struct StoreForwardClient {
+    node_num: u32,
+    server: Option<KnownServer>,
+    last_request: u32,
+}
+
+impl StoreForwardClient {
+    /// Create a client for our node
+    pub fn new(node_num: u32) -> Self {
+        Self {
+            node_num,
+            server: None,
+            last_request: 0,
+        }
+    }
+
+    /// Node number of the server we use, if one was heard
+    pub fn server(&self) -> Option<u32> {
+        self.server.map(|server| server.node_num)
+    }
+
+    /// Handle a received STORE_FORWARD_APP packet
+    ///
+    /// A server is considered out of reach after missing two heartbeats.
+    /// Hearing it again afterwards returns [`ClientEvent::ServerAvailable`].
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+        now_secs: u32,
+    ) -> Result<Option<ClientEvent>, PacketError> {
+        let header = packet.header();
+        if header.source == self.node_num
+            || packet.port_num() != EnumValue::Known(PortNum::StoreForwardApp)
+        {
+            return Ok(None);
+        }
+        let data = packet.data_message()?;
+        let message = decode(&data)?;
+        let server = header.source;
+
+        let event = match (message.rr, message.variant) {
+            (
+                EnumValue::Known(RequestResponse::RouterHeartbeat),
+                Some(store_and_forward::Variant::Heartbeat(heartbeat)),
+            ) => {
+                let back_in_reach = match self.server {
+                    Some(known) if known.node_num == server => {
+                        let missed = known.period_secs.saturating_mul(2);
+                        now_secs.saturating_sub(known.last_heard_secs) > missed
+                    }
+                    // Stick to the server we already use
+                    Some(_) => return Ok(None),
+                    None => true,
+                };
+                self.server = Some(KnownServer {
+                    node_num: server,
+                    channel_hash: header.channel_hash,
+                    last_heard_secs: now_secs,
+                    period_secs: heartbeat.period,
+                });
+                back_in_reach.then_some(ClientEvent::ServerAvailable { server })
+            }
+            _ if header.destination != self.node_num => None,
+            (
+                EnumValue::Known(RequestResponse::RouterHistory),
+                Some(store_and_forward::Variant::History(history)),
+            ) => {
+                if history.last_request != 0 {
+                    self.last_request = history.last_request;
+                }
+                Some(ClientEvent::History {
+                    server,
+                    messages: history.history_messages,
+                })
+            }
+            (EnumValue::Known(RequestResponse::RouterBusy), _) => {
+                Some(ClientEvent::Busy { server })
+            }
+            (EnumValue::Known(RequestResponse::RouterPong), _) => {
+                Some(ClientEvent::Pong { server })
+            }
+            (
+                EnumValue::Known(RequestResponse::RouterStats),
+                Some(store_and_forward::Variant::Stats(stats)),
+            ) => Some(ClientEvent::Stats {
+                server,
+                stats: StoreForwardStats::from_protobuf(&stats),
+            }),
+            _ => None,
+        };
+
+        if let Some(known) = self.server.as_mut() {
+            if known.node_num == server {
+                known.last_heard_secs = now_secs;
+            }
+        }
+        Ok(event)
+    }
+
+    /// Ask our server for the messages of the last `window_mins` minutes we did not get yet
+    ///
+    /// A window of 0 lets the server choose. Returns `None` if no server was heard.
+    pub fn request_history(
+        &self,
+        window_mins: u32,
+        packet_id: u32,
+        hop_limit: u8,
+    ) -> Option<DecodedPacket> {
+        let history = History {
+            history_messages: 0,
+            window: window_mins,
+            last_request: self.last_request,
+            unknown_fields: Default::default(),
+        };
+        self.request(
+            RequestResponse::ClientHistory,
+            Some(store_and_forward::Variant::History(history)),
+            packet_id,
+            hop_limit,
+        )
+    }
+
+    /// Ask our server for its statistics
+    pub fn request_stats(&self, packet_id: u32, hop_limit: u8) -> Option<DecodedPacket> {
+        self.request(RequestResponse::ClientStats, None, packet_id, hop_limit)
+    }
+
+    fn request(
+        &self,
+        rr: RequestResponse,
+        variant: Option<store_and_forward::Variant>,
+        packet_id: u32,
+        hop_limit: u8,
+    ) -> Option<DecodedPacket> {
+        let server = self.server?;
+        packet(
+            self.node_num,
+            server.node_num,
+            packet_id,
+            hop_limit,
+            server.channel_hash,
+            &message(rr, variant),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::header;
+
+    const SERVER: u32 = 0x5E57_0001;
+    const CLIENT: u32 = 0x0000_C11E;
+    const OTHER: u32 = 0x0000_0123;
+
+    fn text(source: u32, destination: u32, packet_id: u32, text: &str) -> DecodedPacket {
+        let data = OwnedData::new(PortNum::TextMessageApp, text.as_bytes()).unwrap();
+        DecodedPacket::new(header(source, destination, packet_id), data)
+    }
+
+    fn deliver(packet: DecodedPacket) -> DecodedPacket {
+        packet.encode().unwrap().decode().unwrap()
+    }
+
+    #[test]
+    fn test_in_memory_store_is_a_ring_buffer() {
+        let mut store: InMemoryStore<2> = InMemoryStore::new();
+        assert!(store.is_empty());
+        for sequence in 1..=3 {
+            store.push(StoredMessage {
+                sequence,
+                time_secs: 0,
+                source: OTHER,
+                destination: BROADCAST_ADDR,
+                channel_hash: 0x08,
+                text: Vec::new(),
+            });
+        }
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(0).unwrap().sequence, 2);
+        assert_eq!(store.get(1).unwrap().sequence, 3);
+        assert!(store.get(2).is_none());
+    }
+
+    #[test]
+    fn test_client_gets_missed_messages() {
+        let mut server: StoreForwardServer =
+            StoreForwardServer::new(SERVER, ServerConfig::default(), InMemoryStore::new());
+        let mut client = StoreForwardClient::new(CLIENT);
+
+        // The client hears the boot heartbeat and finds the server
+        let heartbeat = deliver(server.poll(0, 1, 3, 0x08).unwrap());
+        assert_eq!(heartbeat.header.destination, BROADCAST_ADDR);
+        assert_eq!(
+            client.handle_received(&heartbeat, 0).unwrap(),
+            Some(ClientEvent::ServerAvailable { server: SERVER })
+        );
+        assert!(!server.is_due(10));
+
+        // Messages sent while the client is away
+        server
+            .handle_received(&deliver(text(OTHER, BROADCAST_ADDR, 10, "hello all")), 100)
+            .unwrap();
+        server
+            .handle_received(&deliver(text(OTHER, CLIENT, 11, "hello you")), 110)
+            .unwrap();
+        server
+            .handle_received(&deliver(text(OTHER, 0x0000_4444, 12, "not yours")), 120)
+            .unwrap();
+        server
+            .handle_received(&deliver(text(CLIENT, BROADCAST_ADDR, 13, "own")), 130)
+            .unwrap();
+        assert_eq!(server.store().len(), 4);
+
+        // Back in reach after missing heartbeats, the client asks for its history
+        let heartbeat = deliver(
+            server
+                .poll(DEFAULT_HEARTBEAT_INTERVAL_SECS * 3, 2, 3, 0x08)
+                .unwrap(),
+        );
+        let now = DEFAULT_HEARTBEAT_INTERVAL_SECS * 3;
+        assert_eq!(
+            client.handle_received(&heartbeat, now).unwrap(),
+            Some(ClientEvent::ServerAvailable { server: SERVER })
+        );
+        let request = deliver(client.request_history(0, 3, 3).unwrap());
+        assert_eq!(request.header.destination, SERVER);
+        server.handle_received(&request, now).unwrap();
+        assert!(server.is_replaying());
+
+        let announce = deliver(server.poll(now, 4, 3, 0x08).unwrap());
+        assert_eq!(
+            client.handle_received(&announce, now).unwrap(),
+            Some(ClientEvent::History {
+                server: SERVER,
+                messages: 2
+            })
+        );
+
+        let first = deliver(server.poll(now, 5, 3, 0x08).unwrap());
+        let data = first.data_message().unwrap();
+        let replayed = replayed_text(&data).unwrap();
+        assert_eq!(replayed.source, OTHER);
+        assert_eq!(replayed.destination, BROADCAST_ADDR);
+        assert_eq!(replayed.text, b"hello all");
+
+        let second = deliver(server.poll(now, 6, 3, 0x08).unwrap());
+        let data = second.data_message().unwrap();
+        assert_eq!(replayed_text(&data).unwrap().text, b"hello you");
+        assert!(server.poll(now, 7, 3, 0x08).is_none());
+        assert!(!server.is_replaying());
+
+        // Asking again returns nothing new
+        let request = deliver(client.request_history(0, 8, 3).unwrap());
+        server.handle_received(&request, now).unwrap();
+        let announce = deliver(server.poll(now, 9, 3, 0x08).unwrap());
+        assert_eq!(
+            client.handle_received(&announce, now).unwrap(),
+            Some(ClientEvent::History {
+                server: SERVER,
+                messages: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_replay_stays_on_its_channel() {
+        const PRIVATE: u8 = 0x2A;
+        let mut server: StoreForwardServer =
+            StoreForwardServer::new(SERVER, ServerConfig::default(), InMemoryStore::new());
+        let mut client = StoreForwardClient::new(CLIENT);
+        let heartbeat = deliver(server.poll(0, 1, 3, 0x08).unwrap());
+        client.handle_received(&heartbeat, 0).unwrap();
+
+        server
+            .handle_received(&deliver(text(OTHER, BROADCAST_ADDR, 10, "public")), 5)
+            .unwrap();
+        let mut secret = text(OTHER, BROADCAST_ADDR, 11, "private");
+        secret.header.channel_hash = PRIVATE;
+        server.handle_received(&deliver(secret), 6).unwrap();
+        assert_eq!(server.store().len(), 2);
+
+        // Asked on the primary channel, only what was heard there is replayed
+        let request = deliver(client.request_history(0, 2, 3).unwrap());
+        server.handle_received(&request, 10).unwrap();
+        let announce = deliver(server.poll(10, 3, 3, 0x08).unwrap());
+        assert_eq!(
+            client.handle_received(&announce, 10).unwrap(),
+            Some(ClientEvent::History {
+                server: SERVER,
+                messages: 1
+            })
+        );
+        let replayed = deliver(server.poll(10, 4, 3, 0x08).unwrap());
+        assert_eq!(replayed.header.channel_hash, 0x08);
+        let data = replayed.data_message().unwrap();
+        assert_eq!(replayed_text(&data).unwrap().text, b"public");
+        assert!(server.poll(10, 5, 3, 0x08).is_none());
+
+        // Asked on the private channel, its message comes back on it
+        let mut request = client.request_history(0, 6, 3).unwrap();
+        request.header.channel_hash = PRIVATE;
+        server.handle_received(&deliver(request), 20).unwrap();
+        server.poll(20, 7, 3, 0x08).unwrap();
+        let replayed = deliver(server.poll(20, 8, 3, 0x08).unwrap());
+        assert_eq!(replayed.header.channel_hash, PRIVATE);
+        let data = replayed.data_message().unwrap();
+        assert_eq!(replayed_text(&data).unwrap().text, b"private");
+        assert!(server.poll(20, 9, 3, 0x08).is_none());
+    }
+
+    #[test]
+    fn test_server_busy_and_stats() {
+        let mut server: StoreForwardServer =
+            StoreForwardServer::new(SERVER, ServerConfig::default(), InMemoryStore::new());
+        let mut client = StoreForwardClient::new(CLIENT);
+        let mut other = StoreForwardClient::new(OTHER);
+        let heartbeat = deliver(server.poll(0, 1, 3, 0x08).unwrap());
+        client.handle_received(&heartbeat, 0).unwrap();
+        other.handle_received(&heartbeat, 0).unwrap();
+        server
+            .handle_received(&deliver(text(0x0000_4444, BROADCAST_ADDR, 10, "hi")), 5)
+            .unwrap();
+
+        // While replaying to one client, the others are told to wait
+        server
+            .handle_received(&deliver(client.request_history(0, 2, 3).unwrap()), 10)
+            .unwrap();
+        server.poll(10, 3, 3, 0x08).unwrap();
+        server
+            .handle_received(&deliver(other.request_history(0, 4, 3).unwrap()), 10)
+            .unwrap();
+        let busy = deliver(server.poll(10, 5, 3, 0x08).unwrap());
+        assert_eq!(
+            other.handle_received(&busy, 10).unwrap(),
+            Some(ClientEvent::Busy { server: SERVER })
+        );
+
+        server
+            .handle_received(&deliver(other.request_stats(6, 3).unwrap()), 20)
+            .unwrap();
+        let stats = deliver(server.poll(20, 7, 3, 0x08).unwrap());
+        let Some(ClientEvent::Stats { stats, .. }) = other.handle_received(&stats, 20).unwrap()
+        else {
+            panic!("expected statistics");
+        };
+        assert_eq!(stats.messages_total, 1);
+        assert_eq!(stats.messages_max, MAX_STORED_MESSAGES as u32);
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.requests_history, 2);
+        assert_eq!(stats.up_time, 20);
+    }
+}
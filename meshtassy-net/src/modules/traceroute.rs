@@ -0,0 +1,470 @@
+//! Traceroute
+//!
+//! A traceroute request is a TRACEROUTE_APP packet carrying a
+//! [`RouteDiscovery`]. Every node that relays the request appends its node
+//! number to `route` and the SNR it received the packet with to
+//! `snr_towards`. The destination adds its own SNR and answers with the
+//! discovery as a reply, on which relays fill in `route_back` and `snr_back`
+//! the same way. Hops that relayed the packet without recording themselves
+//! (no channel key, old firmware) are inserted as [`UNKNOWN_HOP`].
+//!
+//! SNR values are stored in units of 0.25 dB like the Meshtastic firmware.
+//! [`TracerouteTracker`] turns the reply to one of our requests into the
+//! forward and return path with the SNR of every hop.
+
+use femtopb::{EnumValue, Message as _};
+use heapless::Vec;
+use meshtastic_protobufs::meshtastic::PortNum;
+
+use crate::app_payload::{RouteDiscovery, MAX_ROUTE_LEN};
+use crate::error::PacketError;
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::{DecodedPacket, OwnedData, ReceivedPacket};
+
+/// Node number recorded for a hop that did not add itself to the route
+pub const UNKNOWN_HOP: u32 = BROADCAST_ADDR;
+
+/// SNR recorded for a hop whose SNR is unknown
+pub const UNKNOWN_SNR: i32 = i8::MIN as i32;
+
+/// How long to wait for the reply to a traceroute
+pub const DEFAULT_TRACEROUTE_TIMEOUT_MS: u64 = 60_000;
+
+/// Maximum number of traceroutes waiting for a reply
+pub const MAX_PENDING_TRACEROUTES: usize = 4;
+
+/// Convert an SNR in dB to the 0.25 dB units used in a [`RouteDiscovery`]
+pub fn scale_snr(snr_db: i8) -> i32 {
+    snr_db as i32 * 4
+}
+
+/// Which half of the [`RouteDiscovery`] a packet fills in
+fn towards_destination<P: ReceivedPacket + ?Sized>(packet: &P) -> Result<bool, PacketError> {
+    Ok(packet.data_message()?.request_id == 0)
+}
+
+fn discovery<P: ReceivedPacket + ?Sized>(packet: &P) -> Result<RouteDiscovery, PacketError> {
+    let message = meshtastic_protobufs::meshtastic::RouteDiscovery::decode(packet.payload_data())
+        .map_err(|_| PacketError::Protobuf)?;
+    Ok(RouteDiscovery::from_protobuf(&message))
+}
+
+/// Add [`UNKNOWN_HOP`]s for the hops the packet took that are not in the route yet
+fn insert_unknown_hops(discovery: &mut RouteDiscovery, header: &Header, towards: bool) {
+    let HeaderFlags {
+        hop_start,
+        hop_limit,
+        ..
+    } = header.flags;
+    if hop_start == 0 || hop_limit > hop_start {
+        // The number of hops taken is not known
+        return;
+    }
+    let hops_taken = (hop_start - hop_limit) as usize;
+    let (route, snr) = if towards {
+        (&mut discovery.route, &mut discovery.snr_towards)
+    } else {
+        (&mut discovery.route_back, &mut discovery.snr_back)
+    };
+    while route.len() < hops_taken && route.push(UNKNOWN_HOP).is_ok() {}
+    while snr.len() < route.len() && snr.push(UNKNOWN_SNR).is_ok() {}
+}
+
+/// Record our SNR, and unless `snr_only` our node number, silently dropped once the route is full
+fn append_hop(
+    discovery: &mut RouteDiscovery,
+    towards: bool,
+    node_num: u32,
+    snr_db: i8,
+    snr_only: bool,
+) {
+    let (route, snr) = if towards {
+        (&mut discovery.route, &mut discovery.snr_towards)
+    } else {
+        (&mut discovery.route_back, &mut discovery.snr_back)
+    };
+    let _ = snr.push(scale_snr(snr_db));
+    if !snr_only {
+        let _ = route.push(node_num);
+    }
+}
+
+/// Copy of `packet` with its payload replaced by `discovery`
+fn with_discovery<P: ReceivedPacket + ?Sized>(
+    packet: &P,
+    header: Header,
+    discovery: &RouteDiscovery,
+) -> Result<DecodedPacket, PacketError> {
+    let mut data = OwnedData::from_protobuf(&packet.data_message()?);
+    let message = discovery.to_protobuf();
+    data.payload_len = message.encoded_len();
+    let mut slice = &mut data.payload[..data.payload_len];
+    message
+        .encode(&mut slice)
+        .map_err(|_| PacketError::BufferTooSmall)?;
+    Ok(DecodedPacket::new(header, data))
+}
+
+/// The traceroute `packet` with our hop appended, to be relayed in its place
+///
+/// The header is unchanged, so the result encrypts to a payload that can
+/// replace the one of the queued rebroadcast (see `Router::update_rebroadcast`).
+pub fn relay<P: ReceivedPacket + ?Sized>(
+    packet: &P,
+    node_num: u32,
+) -> Result<DecodedPacket, PacketError> {
+    let towards = towards_destination(packet)?;
+    let mut discovery = discovery(packet)?;
+    insert_unknown_hops(&mut discovery, packet.header(), towards);
+    append_hop(&mut discovery, towards, node_num, packet.snr(), false);
+    with_discovery(packet, *packet.header(), &discovery)
+}
+
+/// Answer a traceroute `request` addressed to us
+///
+/// The reply carries the forward route with our SNR added, and starts the
+/// return route that the relays on the way back fill in.
+pub fn reply<P: ReceivedPacket + ?Sized>(
+    request: &P,
+    node_num: u32,
+    packet_id: u32,
+    hop_limit: u8,
+) -> Result<DecodedPacket, PacketError> {
+    let header = request.header();
+    let mut discovery = discovery(request)?;
+    insert_unknown_hops(&mut discovery, header, true);
+    append_hop(&mut discovery, true, node_num, request.snr(), true);
+
+    let reply_header = Header::new(
+        header.source,
+        node_num,
+        packet_id,
+        HeaderFlags {
+            hop_limit,
+            want_ack: false,
+            via_mqtt: false,
+            hop_start: hop_limit,
+        },
+        header.channel_hash,
+        0,
+        (node_num & 0xFF) as u8,
+    );
+    let mut reply = with_discovery(request, reply_header, &discovery)?;
+    reply.data.want_response = false;
+    reply.data.request_id = header.packet_id;
+    Ok(reply)
+}
+
+/// One hop of a traced route
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hop {
+    /// Node that received the packet, [`UNKNOWN_HOP`] if it did not record itself
+    pub node_num: u32,
+    /// SNR this node received the packet with, in dB
+    pub snr_db: Option<f32>,
+}
+
+/// Path of a completed traceroute
+///
+/// Both paths list the nodes that received the packet in order, ending with
+/// the destination for `towards` and with us for `back`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TracerouteResult {
+    /// Id of our request
+    pub packet_id: u32,
+    pub destination: u32,
+    pub towards: Vec<Hop, { MAX_ROUTE_LEN + 1 }>,
+    pub back: Vec<Hop, { MAX_ROUTE_LEN + 1 }>,
+}
+
+fn hops(route: &[u32], snr: &[i32], last: u32) -> Vec<Hop, { MAX_ROUTE_LEN + 1 }> {
+    route
+        .iter()
+        .chain(core::iter::once(&last))
+        .enumerate()
+        .map(|(index, &node_num)| Hop {
+            node_num,
+            snr_db: snr
+                .get(index)
+                .filter(|&&snr| snr != UNKNOWN_SNR)
+                .map(|&snr| snr as f32 / 4.0),
+        })
+        .collect()
+}
+
+/// A traceroute of ours that got no reply in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TracerouteTimeout {
+    pub packet_id: u32,
+    pub destination: u32,
+}
+
+/// Reasons a traceroute cannot be started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TracerouteError {
+    /// Too many traceroutes are waiting for a reply
+    TooManyPending,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingTraceroute {
+    packet_id: u32,
+    destination: u32,
+    deadline_ms: u64,
+}
+
+/// Our traceroutes waiting for a reply
+///
+/// Send the packet returned by [`TracerouteTracker::request`], feed received
+/// TRACEROUTE_APP packets to [`TracerouteTracker::handle_reply`] and call
+/// [`TracerouteTracker::poll`] to learn about traceroutes that timed out.
+#[derive(Debug, Clone)]
+pub struct TracerouteTracker<const N: usize = MAX_PENDING_TRACEROUTES> {
+    node_num: u32,
+    pending: Vec<PendingTraceroute, N>,
+}
+
+impl<const N: usize> TracerouteTracker<N> {
+    /// Create a tracker for the node with the given node number
+    pub fn new(node_num: u32) -> Self {
+        Self {
+            node_num,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Number of traceroutes waiting for a reply
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Start a traceroute to `destination`
+    ///
+    /// Returns the request, still to be encoded and encrypted with the key of
+    /// the channel matching `channel_hash`.
+    pub fn request(
+        &mut self,
+        destination: u32,
+        packet_id: u32,
+        hop_limit: u8,
+        channel_hash: u8,
+        now_ms: u64,
+        timeout_ms: u64,
+    ) -> Result<DecodedPacket, TracerouteError> {
+        self.pending
+            .push(PendingTraceroute {
+                packet_id,
+                destination,
+                deadline_ms: now_ms + timeout_ms,
+            })
+            .map_err(|_| TracerouteError::TooManyPending)?;
+
+        let header = Header::new(
+            destination,
+            self.node_num,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: hop_limit,
+            },
+            channel_hash,
+            0,
+            (self.node_num & 0xFF) as u8,
+        );
+        // The request carries an empty RouteDiscovery, which encodes to nothing
+        let data = OwnedData {
+            portnum: EnumValue::Known(PortNum::TracerouteApp),
+            payload: [0u8; 240],
+            payload_len: 0,
+            want_response: true,
+            dest: 0,
+            source: 0,
+            request_id: 0,
+            reply_id: 0,
+            emoji: 0,
+            bitfield: None,
+        };
+        Ok(DecodedPacket::new(header, data))
+    }
+
+    /// Check a received packet for the reply to one of our traceroutes
+    pub fn handle_reply<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+    ) -> Option<TracerouteResult> {
+        let header = packet.header();
+        if header.destination != self.node_num
+            || packet.port_num() != EnumValue::Known(PortNum::TracerouteApp)
+        {
+            return None;
+        }
+        let request_id = packet.data_message().ok()?.request_id;
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.packet_id == request_id)?;
+        let mut discovery = discovery(packet).ok()?;
+        let pending = self.pending.remove(index);
+
+        // We are the last hop of the way back
+        insert_unknown_hops(&mut discovery, header, false);
+        append_hop(&mut discovery, false, self.node_num, packet.snr(), true);
+
+        Some(TracerouteResult {
+            packet_id: pending.packet_id,
+            destination: pending.destination,
+            towards: hops(
+                &discovery.route,
+                &discovery.snr_towards,
+                pending.destination,
+            ),
+            back: hops(&discovery.route_back, &discovery.snr_back, self.node_num),
+        })
+    }
+
+    /// Take the next traceroute that timed out at `now_ms`
+    ///
+    /// Call repeatedly until it returns `None`.
+    pub fn poll(&mut self, now_ms: u64) -> Option<TracerouteTimeout> {
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.deadline_ms <= now_ms)?;
+        let pending = self.pending.remove(index);
+        Some(TracerouteTimeout {
+            packet_id: pending.packet_id,
+            destination: pending.destination,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppPayload;
+
+    const ORIGIN: u32 = 0x0000_0001;
+    const RELAY: u32 = 0x0000_0002;
+    const DESTINATION: u32 = 0x0000_0003;
+
+    /// Deliver `packet` after `hops` more hops, heard with `snr_db`
+    fn arrive(packet: &DecodedPacket, hops: u8, snr_db: i8) -> DecodedPacket {
+        let mut received = packet.encode().unwrap().decode().unwrap();
+        received.header.flags.hop_limit -= hops;
+        received.snr = snr_db;
+        received
+    }
+
+    #[test]
+    fn test_traceroute_through_one_relay() {
+        let mut tracker: TracerouteTracker = TracerouteTracker::new(ORIGIN);
+        let request = tracker
+            .request(
+                DESTINATION,
+                0x100,
+                3,
+                0x08,
+                0,
+                DEFAULT_TRACEROUTE_TIMEOUT_MS,
+            )
+            .unwrap();
+        assert!(request.data.want_response);
+
+        // The relay hears the request at 6 dB and adds itself
+        let relayed = relay(&arrive(&request, 0, 6), RELAY).unwrap();
+        assert_eq!(relayed.header, request.header);
+        let Ok(AppPayload::Traceroute(discovery)) = relayed.app_payload() else {
+            panic!("expected a traceroute");
+        };
+        assert_eq!(&discovery.route[..], &[RELAY]);
+        assert_eq!(&discovery.snr_towards[..], &[24]);
+
+        // The destination hears it at -2 dB, one hop later, and answers
+        let reply = reply(&arrive(&relayed, 1, -2), DESTINATION, 0x200, 3).unwrap();
+        assert_eq!(reply.header.destination, ORIGIN);
+        assert_eq!(reply.data.request_id, 0x100);
+        assert!(!reply.data.want_response);
+
+        // Same relay on the way back, then the origin hears it at 5 dB
+        let reply = relay(&arrive(&reply, 0, -1), RELAY).unwrap();
+        let result = tracker.handle_reply(&arrive(&reply, 1, 5)).unwrap();
+        assert_eq!(result.packet_id, 0x100);
+        assert_eq!(result.destination, DESTINATION);
+        assert_eq!(
+            &result.towards[..],
+            &[
+                Hop {
+                    node_num: RELAY,
+                    snr_db: Some(6.0)
+                },
+                Hop {
+                    node_num: DESTINATION,
+                    snr_db: Some(-2.0)
+                },
+            ]
+        );
+        assert_eq!(
+            &result.back[..],
+            &[
+                Hop {
+                    node_num: RELAY,
+                    snr_db: Some(-1.0)
+                },
+                Hop {
+                    node_num: ORIGIN,
+                    snr_db: Some(5.0)
+                },
+            ]
+        );
+        assert_eq!(tracker.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_silent_hops_are_unknown() {
+        let mut tracker: TracerouteTracker = TracerouteTracker::new(ORIGIN);
+        let request = tracker
+            .request(DESTINATION, 0x100, 3, 0x08, 0, 1000)
+            .unwrap();
+
+        // Two relays without the channel key forwarded the request
+        let reply = reply(&arrive(&request, 2, 1), DESTINATION, 0x200, 3).unwrap();
+        let Ok(AppPayload::Traceroute(discovery)) = reply.app_payload() else {
+            panic!("expected a traceroute");
+        };
+        assert_eq!(&discovery.route[..], &[UNKNOWN_HOP, UNKNOWN_HOP]);
+        assert_eq!(&discovery.snr_towards[..], &[UNKNOWN_SNR, UNKNOWN_SNR, 4]);
+
+        let result = tracker.handle_reply(&arrive(&reply, 0, 3)).unwrap();
+        assert_eq!(result.towards.len(), 3);
+        assert_eq!(result.towards[0].snr_db, None);
+        assert_eq!(result.towards[2].snr_db, Some(1.0));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut tracker: TracerouteTracker<1> = TracerouteTracker::new(ORIGIN);
+        tracker
+            .request(DESTINATION, 0x100, 3, 0x08, 0, 1000)
+            .unwrap();
+        assert_eq!(
+            tracker.request(RELAY, 0x101, 3, 0x08, 0, 1000).err(),
+            Some(TracerouteError::TooManyPending)
+        );
+
+        assert_eq!(tracker.poll(999), None);
+        assert_eq!(
+            tracker.poll(1000),
+            Some(TracerouteTimeout {
+                packet_id: 0x100,
+                destination: DESTINATION
+            })
+        );
+        assert_eq!(tracker.pending_count(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+//! Next-hop table for direct messages
+//!
+//! Since Meshtastic 2.6 a unicast packet can name the neighbor that should
+//! relay it in `Header::next_hop`, so only that node rebroadcasts it instead of
+//! the whole mesh. Only the last byte of a node number fits in the header, the
+//! same byte nodes write into `Header::relay_node` when they transmit.
+//!
+//! Routes are learned from ACKs and replies: the `relay_node` of a reply from
+//! a node is the neighbor that carried it to us, and so a good next hop back
+//! to that node. A destination without a route is reached by flooding.
+
+use heapless::Vec;
+
+/// `Header::next_hop` value of packets that should be flooded
+pub const NO_NEXT_HOP_PREFERENCE: u8 = 0;
+
+/// Default number of destinations remembered
+pub const MAX_NEXT_HOPS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    destination: u32,
+    next_hop: u8,
+}
+
+/// Learned next hop for each destination, least recently learned evicted first
+#[derive(Debug, Clone, Default)]
+pub struct NextHopTable<const N: usize = MAX_NEXT_HOPS> {
+    routes: Vec<Route, N>,
+}
+
+impl<const N: usize> NextHopTable<N> {
+    /// Create an empty table
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Remember that `destination` is reached through the neighbor with relay byte `next_hop`
+    pub fn learn(&mut self, destination: u32, next_hop: u8) {
+        if next_hop == NO_NEXT_HOP_PREFERENCE {
+            // Sent by firmware that does not fill in relay_node
+            return;
+        }
+        self.forget(destination);
+        if self.routes.is_full() {
+            self.routes.remove(0);
+        }
+        let _ = self.routes.push(Route {
+            destination,
+            next_hop,
+        });
+    }
+
+    /// The next hop towards `destination`, if one was learned
+    pub fn get(&self, destination: u32) -> Option<u8> {
+        self.routes
+            .iter()
+            .find(|route| route.destination == destination)
+            .map(|route| route.next_hop)
+    }
+
+    /// Drop the route to `destination`, returns true if there was one
+    pub fn forget(&mut self, destination: u32) -> bool {
+        let Some(index) = self
+            .routes
+            .iter()
+            .position(|route| route.destination == destination)
+        else {
+            return false;
+        };
+        self.routes.remove(index);
+        true
+    }
+
+    /// Number of destinations with a route
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Returns true if no route is known
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Forget all routes
+    pub fn clear(&mut self) {
+        self.routes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learn_update_and_forget() {
+        let mut table: NextHopTable = NextHopTable::new();
+        assert_eq!(table.get(0xA1B2_C3D4), None);
+
+        table.learn(0xA1B2_C3D4, 0x42);
+        assert_eq!(table.get(0xA1B2_C3D4), Some(0x42));
+        table.learn(0xA1B2_C3D4, 0x17);
+        assert_eq!(table.get(0xA1B2_C3D4), Some(0x17));
+        assert_eq!(table.len(), 1);
+
+        // Relay byte 0 means the relay is unknown
+        table.learn(0x0000_1234, NO_NEXT_HOP_PREFERENCE);
+        assert_eq!(table.get(0x0000_1234), None);
+
+        assert!(table.forget(0xA1B2_C3D4));
+        assert!(!table.forget(0xA1B2_C3D4));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_oldest_route_is_evicted() {
+        let mut table: NextHopTable<2> = NextHopTable::new();
+        table.learn(1, 0x11);
+        table.learn(2, 0x22);
+        // Learning again makes a route the most recent one
+        table.learn(1, 0x11);
+        table.learn(3, 0x33);
+
+        assert_eq!(table.get(1), Some(0x11));
+        assert_eq!(table.get(2), None);
+        assert_eq!(table.get(3), Some(0x33));
+    }
+}
@@ -1,13 +1,16 @@
 #[cfg(feature = "defmt")]
 use defmt;
 
-use femtopb::{self, Message as _};
 use heapless::String;
-use meshtastic_protobufs::meshtastic::{PortNum, Telemetry};
+use meshtastic_protobufs::meshtastic::PortNum;
+
+use crate::app_payload::{self, AppPayload, TelemetryMetrics};
+use crate::error::PacketError;
+use crate::ReceivedPacket;
 
 /// Simplified User struct mimicking UserLite with heapless strings
 /// Only contains essential fields needed for node identification
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct User {
     pub long_name: String<40>, // Max 40 characters for long name
@@ -15,6 +18,7 @@ pub struct User {
     pub hw_model: femtopb::EnumValue<meshtastic_protobufs::meshtastic::HardwareModel>,
     pub role: femtopb::EnumValue<meshtastic_protobufs::meshtastic::config::device_config::Role>,
     pub is_licensed: bool,
+    pub public_key: Option<[u8; 32]>, // X25519 public key used for PKI direct messages
 }
 
 /// Simplified Position struct mimicking PositionLite
@@ -50,6 +54,7 @@ pub struct NodeInfo {
     pub snr: f32,        // Signal-to-noise ratio
     pub last_heard: u32, // Unix timestamp of last message
     pub device_metrics: Option<DeviceMetrics>,
+    pub public_key_mismatch: bool, // Node advertised a different public key than the one we know
 }
 
 /// Node database containing up to 50 nodes
@@ -69,6 +74,7 @@ impl Default for User {
             hw_model: femtopb::EnumValue::Unknown(0),
             role: femtopb::EnumValue::Unknown(0),
             is_licensed: false,
+            public_key: None,
         }
     }
 }
@@ -106,6 +112,7 @@ impl Default for NodeInfo {
             snr: 0.0,
             last_heard: 0,
             device_metrics: None,
+            public_key_mismatch: false,
         }
     }
 }
@@ -142,6 +149,7 @@ impl User {
             hw_model: pb_user.hw_model,
             role: pb_user.role,
             is_licensed: pb_user.is_licensed,
+            public_key: pb_user.public_key.try_into().ok(),
         }
     }
 }
@@ -158,6 +166,17 @@ impl Position {
             location_source: pb_pos.location_source,
         }
     }
+
+    /// Convert from a decoded position payload
+    pub fn from_app_payload(position: &app_payload::Position) -> Self {
+        Self {
+            latitude_i: position.latitude_i.unwrap_or(0),
+            longitude_i: position.longitude_i.unwrap_or(0),
+            altitude: position.altitude.unwrap_or(0),
+            time: position.time,
+            location_source: position.location_source,
+        }
+    }
 }
 
 // Conversion methods for DeviceMetrics
@@ -179,6 +198,17 @@ impl DeviceMetrics {
         }
         None
     }
+
+    /// Convert from decoded device metrics
+    pub fn from_app_payload(metrics: &app_payload::DeviceMetrics) -> Self {
+        Self {
+            battery_level: metrics.battery_level.unwrap_or(0),
+            voltage: metrics.voltage.unwrap_or(0.0),
+            channel_utilization: metrics.channel_utilization.unwrap_or(0.0),
+            air_util_tx: metrics.air_util_tx.unwrap_or(0.0),
+            uptime_seconds: metrics.uptime_seconds.unwrap_or(0),
+        }
+    }
 }
 
 // Conversion methods for NodeInfo
@@ -198,6 +228,7 @@ impl NodeInfo {
             snr: pb_node.snr,
             last_heard: pb_node.last_heard,
             device_metrics: None, // Will be updated separately from telemetry packets
+            public_key_mismatch: false,
         }
     }
 }
@@ -303,6 +334,26 @@ impl NodeDatabase {
         self.get_node(node_num)?.user.as_ref()
     }
 
+    /// Get the PKI public key advertised by a specific node
+    pub fn get_public_key(&self, node_num: u32) -> Option<&[u8; 32]> {
+        self.get_node_user(node_num)?.public_key.as_ref()
+    }
+
+    /// Forget the public key of a node, e.g. after it was legitimately re-keyed
+    /// The next NODEINFO received from the node is trusted again
+    pub fn reset_public_key(&mut self, node_num: u32) -> bool {
+        for node in self.nodes.iter_mut().flatten() {
+            if node.num == node_num {
+                if let Some(user) = node.user.as_mut() {
+                    user.public_key = None;
+                }
+                node.public_key_mismatch = false;
+                return true;
+            }
+        }
+        false
+    }
+
     /// Get the short name for a specific node, or return a default if not available
     pub fn get_node_short_name(&self, node_num: u32) -> &str {
         if let Some(user) = self.get_node_user(node_num) {
@@ -318,115 +369,75 @@ impl NodeDatabase {
         self.nodes.iter().filter_map(|n| n.as_ref())
     }    /// Add or update a node from a received packet
     /// This method handles the packet decoding and node database update
-    pub fn add_or_update_node_from_packet(
+    /// Fails if the payload of a node info, position or telemetry packet cannot be decoded
+    pub fn add_or_update_node_from_packet<P: ReceivedPacket + ?Sized>(
         &mut self,
-        packet: &crate::DecodedPacket,
-    ) -> bool {
-        let node_num = packet.header.source;
-        let port_num = packet.port_num();
-
-        // Get the owned data from the decoded packet
-        let owned_data = match packet.data() {
-            Ok(data) => data,
-            Err(_) => return false,
-        };
-
-        match port_num {
-            femtopb::EnumValue::Known(PortNum::NodeinfoApp) => {
-                // Decode payload as User message
-                if let Ok(user_info) = meshtastic_protobufs::meshtastic::User::decode(
-                    &owned_data.payload[..owned_data.payload_len],
-                ) {
-                    // Create a NodeInfo with the user information
-                    let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
-                        let mut new_node = NodeInfo::default();
-                        new_node.num = node_num;
-                        new_node
-                    });
-
-                    // Update user info using conversion method
-                    node_info.user = Some(User::from_protobuf(&user_info));
-
-                    // Update SNR and add to database
-                    node_info.snr = packet.snr as f32;
-                    self.add_or_update_node(node_info);                    #[cfg(feature = "defmt")]
-                    defmt::info!("Node 0x{:08X} user info updated", node_num);
-                    true
-                } else {
-                    false
+        packet: &P,
+    ) -> Result<(), PacketError> {
+        let node_num = packet.header().source;
+
+        let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
+            let mut new_node = NodeInfo::default();
+            new_node.num = node_num;
+            new_node
+        });
+
+        match packet.app_payload() {
+            Ok(AppPayload::NodeInfo(mut user)) => {
+                // Never silently replace a known public key, a different key may
+                // mean somebody is impersonating the node
+                let known_key = node_info.user.as_ref().and_then(|u| u.public_key);
+                if let Some(known_key) = known_key {
+                    if user.public_key.is_some_and(|key| key != known_key) {
+                        #[cfg(feature = "defmt")]
+                        defmt::warn!(
+                            "Node 0x{:08X} advertised a different public key, keeping the known key",
+                            node_num
+                        );
+                        node_info.public_key_mismatch = true;
+                    }
+                    user.public_key = Some(known_key);
                 }
+                node_info.user = Some(user);
+                #[cfg(feature = "defmt")]
+                defmt::info!("Node 0x{:08X} user info updated", node_num);
             }
-            femtopb::EnumValue::Known(PortNum::PositionApp) => {
-                if let Ok(position) = meshtastic_protobufs::meshtastic::Position::decode(
-                    &owned_data.payload[..owned_data.payload_len],
-                ) {
-                    // Create a NodeInfo with the position information
-                    let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
-                        let mut new_node = NodeInfo::default();
-                        new_node.num = node_num;
-                        new_node
-                    });
-
-                    // Update position info using conversion method
-                    node_info.position = Some(Position::from_protobuf(&position));
-
-                    // Update SNR and add to database
-                    node_info.snr = packet.snr as f32;
-                    self.add_or_update_node(node_info);                    #[cfg(feature = "defmt")]
-                    defmt::info!("Node 0x{:08X} position updated", node_num);
-                    true
-                } else {
-                    false
-                }
+            Ok(AppPayload::Position(position)) => {
+                node_info.position = Some(Position::from_app_payload(&position));
+                #[cfg(feature = "defmt")]
+                defmt::info!("Node 0x{:08X} position updated", node_num);
             }
-            femtopb::EnumValue::Known(PortNum::TelemetryApp) => {
-                if let Ok(telemetry) =
-                    Telemetry::decode(&owned_data.payload[..owned_data.payload_len])
-                {
-                    // Handle telemetry data
-                    if let Some(device_metrics) = DeviceMetrics::from_protobuf(&telemetry) {
-                        // Update or create node with telemetry data
-                        let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
-                            let mut new_node = NodeInfo::default();
-                            new_node.num = node_num;
-                            new_node
-                        });
-
-                        node_info.device_metrics = Some(device_metrics);
-                        node_info.snr = packet.snr as f32;
-                        self.add_or_update_node(node_info);                        #[cfg(feature = "defmt")]
-                        defmt::info!("Node 0x{:08X} telemetry updated", node_num);
-                        true
-                    } else {
-                        // For other telemetry types, just update basic info
-                        let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
-                            let mut new_node = NodeInfo::default();
-                            new_node.num = node_num;
-                            new_node
-                        });
-
-                        node_info.snr = packet.snr as f32;
-                        self.add_or_update_node(node_info);                        #[cfg(feature = "defmt")]
-                        defmt::info!("Node 0x{:08X} basic info updated from telemetry", node_num);
-                        true
-                    }
+            Ok(AppPayload::Telemetry(telemetry)) => {
+                if let TelemetryMetrics::Device(metrics) = telemetry.metrics {
+                    node_info.device_metrics = Some(DeviceMetrics::from_app_payload(&metrics));
+                    #[cfg(feature = "defmt")]
+                    defmt::info!("Node 0x{:08X} telemetry updated", node_num);
                 } else {
-                    false
+                    // For other telemetry types, just update basic info
+                    #[cfg(feature = "defmt")]
+                    defmt::info!("Node 0x{:08X} basic info updated from telemetry", node_num);
                 }
             }
+            // The apps we store must decode, anything else still counts as hearing the node
+            Err(e)
+                if matches!(
+                    packet.port_num(),
+                    femtopb::EnumValue::Known(
+                        PortNum::NodeinfoApp | PortNum::PositionApp | PortNum::TelemetryApp
+                    )
+                ) =>
+            {
+                return Err(e);
+            }
             _ => {
                 // For other packet types, just update the basic info (SNR, last_heard)
-                let mut node_info = self.get_node(node_num).cloned().unwrap_or_else(|| {
-                    let mut new_node = NodeInfo::default();
-                    new_node.num = node_num;
-                    new_node
-                });
-
-                node_info.snr = packet.snr as f32;
-                self.add_or_update_node(node_info);                #[cfg(feature = "defmt")]
-                defmt::info!("Node 0x{:08X} basic info updated (SNR: {})", node_num, packet.snr);
-                true
+                #[cfg(feature = "defmt")]
+                defmt::info!("Node 0x{:08X} basic info updated (SNR: {})", node_num, packet.snr());
             }
         }
+
+        node_info.snr = packet.snr() as f32;
+        self.add_or_update_node(node_info);
+        Ok(())
     }
 }
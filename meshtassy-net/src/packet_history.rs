@@ -0,0 +1,273 @@
+//! Recently seen packet history
+//!
+//! Every copy of a flooded packet arrives several times, once per relaying
+//! node. This module keeps a small, fixed-capacity record of the packets we
+//! have already seen, keyed on `(Header::source, Header::packet_id)`, so that
+//! duplicates can be dropped before they are decoded and published again.
+//!
+//! Entries expire after a configurable time and the oldest entry is evicted
+//! when the history is full.
+
+use heapless::Deque;
+
+use crate::Header;
+
+/// Default number of packets remembered
+pub const DEFAULT_HISTORY_SIZE: usize = 64;
+
+/// Default time a packet is remembered (matches Meshtastic's 10 minute flood expiry)
+pub const DEFAULT_EXPIRY_MS: u64 = 10 * 60 * 1000;
+
+/// Classification of a received packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SeenStatus {
+    /// First time this packet has been heard
+    New,
+    /// Already heard this packet, this copy adds nothing
+    Duplicate,
+    /// Already heard this packet, but this copy took fewer hops to reach us
+    DuplicateBetterHops,
+}
+
+impl SeenStatus {
+    /// Returns true for both kinds of duplicates
+    pub fn is_duplicate(&self) -> bool {
+        !matches!(self, SeenStatus::New)
+    }
+}
+
+/// Counters describing what the history has classified so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HistoryStats {
+    /// Packets heard for the first time
+    pub unique: u32,
+    /// Copies of packets that were already seen
+    pub duplicates: u32,
+    /// Entries evicted before they expired because the history was full
+    pub evictions: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    source: u32,
+    packet_id: u32,
+    hop_limit: u8,
+    seen_at_ms: u64,
+}
+
+/// Fixed-capacity "recently seen" cache of packets
+#[derive(Debug, Clone)]
+pub struct PacketHistory<const N: usize = DEFAULT_HISTORY_SIZE> {
+    entries: Deque<Entry, N>,
+    expiry_ms: u64,
+    stats: HistoryStats,
+}
+
+impl<const N: usize> Default for PacketHistory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PacketHistory<N> {
+    /// Create an empty history using the default expiry time
+    pub fn new() -> Self {
+        Self::with_expiry(DEFAULT_EXPIRY_MS)
+    }
+
+    /// Create an empty history whose entries are forgotten after `expiry_ms`
+    pub fn with_expiry(expiry_ms: u64) -> Self {
+        Self {
+            entries: Deque::new(),
+            expiry_ms,
+            stats: HistoryStats::default(),
+        }
+    }
+
+    /// Classify a received packet and record it
+    ///
+    /// `now_ms` is a monotonic timestamp in milliseconds.
+    pub fn check_and_insert(&mut self, header: &Header, now_ms: u64) -> SeenStatus {
+        self.expire(now_ms);
+
+        let hop_limit = header.flags.hop_limit;
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.source == header.source && e.packet_id == header.packet_id)
+        {
+            self.stats.duplicates = self.stats.duplicates.wrapping_add(1);
+            if hop_limit > entry.hop_limit {
+                entry.hop_limit = hop_limit;
+                return SeenStatus::DuplicateBetterHops;
+            }
+            return SeenStatus::Duplicate;
+        }
+
+        if self.entries.is_full() {
+            self.entries.pop_front();
+            self.stats.evictions = self.stats.evictions.wrapping_add(1);
+        }
+        let _ = self.entries.push_back(Entry {
+            source: header.source,
+            packet_id: header.packet_id,
+            hop_limit,
+            seen_at_ms: now_ms,
+        });
+        self.stats.unique = self.stats.unique.wrapping_add(1);
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "PacketHistory: recorded 0x{:08X} from 0x{:08X} ({} entries)",
+            header.packet_id,
+            header.source,
+            self.entries.len()
+        );
+        SeenStatus::New
+    }
+
+    /// Check whether a packet has been seen recently, without recording anything
+    pub fn was_seen(&self, source: u32, packet_id: u32, now_ms: u64) -> bool {
+        self.entries.iter().any(|e| {
+            e.source == source
+                && e.packet_id == packet_id
+                && now_ms.saturating_sub(e.seen_at_ms) < self.expiry_ms
+        })
+    }
+
+    /// Forget every entry older than the expiry time
+    pub fn expire(&mut self, now_ms: u64) {
+        // Entries are stored in arrival order, so the oldest are at the front
+        while let Some(oldest) = self.entries.front() {
+            if now_ms.saturating_sub(oldest.seen_at_ms) < self.expiry_ms {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    /// Number of packets currently remembered
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no packets are remembered
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Counters describing the traffic classified so far
+    pub fn stats(&self) -> HistoryStats {
+        self.stats
+    }
+
+    /// Forget all entries and reset the counters
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.stats = HistoryStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::HeaderFlags;
+
+    fn header(source: u32, packet_id: u32, hop_limit: u8) -> Header {
+        Header::new(
+            0xFFFF_FFFF,
+            source,
+            packet_id,
+            HeaderFlags {
+                hop_limit,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: 7,
+            },
+            0x08,
+            0x00,
+            0x00,
+        )
+    }
+
+    #[test]
+    fn test_new_then_duplicate() {
+        let mut history: PacketHistory = PacketHistory::new();
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 3), 0),
+            SeenStatus::New
+        );
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 3), 10),
+            SeenStatus::Duplicate
+        );
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 2), 20),
+            SeenStatus::Duplicate
+        );
+        assert!(history.was_seen(1, 100, 30));
+
+        let stats = history.stats();
+        assert_eq!(stats.unique, 1);
+        assert_eq!(stats.duplicates, 2);
+    }
+
+    #[test]
+    fn test_key_includes_source() {
+        let mut history: PacketHistory = PacketHistory::new();
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 3), 0),
+            SeenStatus::New
+        );
+        assert_eq!(
+            history.check_and_insert(&header(2, 100, 3), 0),
+            SeenStatus::New
+        );
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_with_better_hops() {
+        let mut history: PacketHistory = PacketHistory::new();
+        history.check_and_insert(&header(1, 100, 2), 0);
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 4), 10),
+            SeenStatus::DuplicateBetterHops
+        );
+        // The better hop count is remembered
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 4), 20),
+            SeenStatus::Duplicate
+        );
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let mut history: PacketHistory<8> = PacketHistory::with_expiry(1_000);
+        history.check_and_insert(&header(1, 100, 3), 0);
+        assert!(history.was_seen(1, 100, 999));
+        assert!(!history.was_seen(1, 100, 1_000));
+
+        assert_eq!(
+            history.check_and_insert(&header(1, 100, 3), 1_500),
+            SeenStatus::New
+        );
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_oldest_entry_evicted_when_full() {
+        let mut history: PacketHistory<4> = PacketHistory::new();
+        for id in 0..4 {
+            history.check_and_insert(&header(1, id, 3), id as u64);
+        }
+        history.check_and_insert(&header(1, 4, 3), 4);
+
+        assert_eq!(history.len(), 4);
+        assert!(!history.was_seen(1, 0, 5));
+        assert!(history.was_seen(1, 4, 5));
+        assert_eq!(history.stats().evictions, 1);
+    }
+}
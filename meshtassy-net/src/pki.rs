@@ -0,0 +1,712 @@
+//! Public key (PKI) encryption for direct messages
+//!
+//! Since Meshtastic 2.5, direct messages between nodes that know each other's
+//! public keys are encrypted with a key only the two nodes share, instead of
+//! the channel key:
+//!
+//! 1. An X25519 key agreement between our private key and the peer's public key
+//! 2. The shared key is the SHA-256 of the X25519 result
+//! 3. The payload is encrypted with AES-256-CCM using an 8 byte auth tag and a
+//!    13 byte nonce built from the packet id, a random "extra nonce" and the
+//!    sending node number
+//!
+//! On air the payload is `ciphertext || auth tag (8) || extra nonce (4)` and
+//! the header's channel hash is set to [`PKI_CHANNEL_HASH`].
+//!
+//! Each node owns a [`Keypair`] and advertises the public half in the
+//! `User.public_key` field of its NODEINFO.
+
+use aes::Aes256;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::header::BROADCAST_ADDR;
+use crate::node_database::NodeDatabase;
+use crate::pool::PacketBuffer;
+use crate::{Decrypted, Encrypted, Packet, MAX_ENCRYPTED_PAYLOAD_LEN};
+
+type Aes256Ccm = Ccm<Aes256, U8, U13>;
+
+/// Channel hash used by PKI encrypted packets
+pub const PKI_CHANNEL_HASH: u8 = 0;
+
+/// Length of the authentication tag appended to the ciphertext
+pub const PKI_TAG_LEN: usize = 8;
+
+/// Bytes added to the payload by PKI encryption (auth tag and extra nonce)
+pub const PKI_OVERHEAD: usize = PKI_TAG_LEN + 4;
+
+/// Largest plaintext payload that fits in a PKI encrypted packet
+pub const MAX_PKI_PAYLOAD_LEN: usize = MAX_ENCRYPTED_PAYLOAD_LEN - PKI_OVERHEAD;
+
+/// Length of a serialized [`Keypair`]
+pub const KEYPAIR_LEN: usize = 64;
+
+/// Errors that can occur during PKI encryption and decryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PkiError {
+    /// We do not know the public key of the other node
+    UnknownPublicKey,
+    /// The public key produced an all-zero shared secret
+    InvalidPublicKey,
+    /// Broadcast packets cannot be PKI encrypted
+    BroadcastNotSupported,
+    /// Payload is empty
+    EmptyData,
+    /// Payload is too large to fit with the PKI overhead
+    PayloadTooLarge,
+    /// Payload is too short to contain the auth tag and extra nonce
+    PayloadTooShort,
+    /// The auth tag did not match: wrong key or corrupted packet
+    AuthenticationFailed,
+    /// Stored keypair is malformed or its public key does not match the private key
+    InvalidKeypair,
+}
+
+/// This node's X25519 keypair
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keypair {
+    private_key: [u8; 32],
+    public_key: [u8; 32],
+}
+
+// Never print the private key
+impl core::fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Keypair {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Keypair {{ public_key: {:02X} }}", self.public_key)
+    }
+}
+
+impl Keypair {
+    /// Generate a new keypair
+    ///
+    /// `rng` should be the board's hardware random number generator.
+    pub fn generate<R: RngCore>(rng: &mut R) -> Self {
+        loop {
+            let mut private_key = [0u8; 32];
+            rng.fill_bytes(&mut private_key);
+            if let Ok(keypair) = Self::from_private_key(private_key) {
+                return keypair;
+            }
+        }
+    }
+
+    /// Create a keypair from an existing private key
+    pub fn from_private_key(mut private_key: [u8; 32]) -> Result<Self, PkiError> {
+        // Clamp the scalar the same way X25519 does, so the stored key matches the one in use
+        private_key[0] &= 248;
+        private_key[31] &= 127;
+        private_key[31] |= 64;
+        if private_key[..31].iter().all(|&b| b == 0) && private_key[31] == 64 {
+            return Err(PkiError::InvalidKeypair);
+        }
+
+        let public_key = x25519_dalek::x25519(private_key, x25519_dalek::X25519_BASEPOINT_BYTES);
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Our public key, advertised in `User.public_key`
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// Our private key
+    pub fn private_key(&self) -> &[u8; 32] {
+        &self.private_key
+    }
+
+    /// Serialize the keypair for storage: private key followed by public key
+    pub fn to_bytes(&self) -> [u8; KEYPAIR_LEN] {
+        let mut bytes = [0u8; KEYPAIR_LEN];
+        bytes[..32].copy_from_slice(&self.private_key);
+        bytes[32..].copy_from_slice(&self.public_key);
+        bytes
+    }
+
+    /// Restore a keypair written by [`Keypair::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PkiError> {
+        if bytes.len() != KEYPAIR_LEN {
+            return Err(PkiError::InvalidKeypair);
+        }
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&bytes[..32]);
+
+        let keypair = Self::from_private_key(private_key)?;
+        if keypair.public_key[..] != bytes[32..] {
+            return Err(PkiError::InvalidKeypair);
+        }
+        Ok(keypair)
+    }
+
+    /// Derive the key shared with the owner of `public_key`
+    pub fn shared_key(&self, public_key: &[u8; 32]) -> Result<[u8; 32], PkiError> {
+        shared_key(&self.private_key, public_key)
+    }
+}
+
+/// Derive the key shared between our private key and a peer's public key
+pub fn shared_key(private_key: &[u8; 32], public_key: &[u8; 32]) -> Result<[u8; 32], PkiError> {
+    let shared_secret = x25519_dalek::x25519(*private_key, *public_key);
+    // A low order public key yields an all-zero secret that anybody can compute
+    if shared_secret.iter().all(|&b| b == 0) {
+        return Err(PkiError::InvalidPublicKey);
+    }
+    Ok(Sha256::digest(shared_secret).into())
+}
+
+/// Build the CCM nonce for a packet
+///
+/// The packet id is written as a little endian u64, with its upper half
+/// replaced by the extra nonce, followed by the sending node number.
+fn create_nonce(from_node: u32, packet_id: u32, extra_nonce: u32) -> [u8; 13] {
+    let mut nonce = [0u8; 13];
+    nonce[0..4].copy_from_slice(&packet_id.to_le_bytes());
+    nonce[4..8].copy_from_slice(&extra_nonce.to_le_bytes());
+    nonce[8..12].copy_from_slice(&from_node.to_le_bytes());
+    nonce
+}
+
+/// Encrypt `buffer[..plaintext_len]` in place and append the auth tag and extra nonce
+///
+/// Returns the total length of the encrypted payload.
+pub fn encrypt_in_place(
+    shared_key: &[u8; 32],
+    from_node: u32,
+    packet_id: u32,
+    extra_nonce: u32,
+    buffer: &mut [u8],
+    plaintext_len: usize,
+) -> Result<usize, PkiError> {
+    if plaintext_len == 0 {
+        return Err(PkiError::EmptyData);
+    }
+    let total_len = plaintext_len + PKI_OVERHEAD;
+    if total_len > buffer.len() {
+        return Err(PkiError::PayloadTooLarge);
+    }
+
+    let nonce = create_nonce(from_node, packet_id, extra_nonce);
+    let cipher = Aes256Ccm::new(GenericArray::from_slice(shared_key));
+    let tag = cipher
+        .encrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            &[],
+            &mut buffer[..plaintext_len],
+        )
+        .map_err(|_| PkiError::PayloadTooLarge)?;
+
+    buffer[plaintext_len..plaintext_len + PKI_TAG_LEN].copy_from_slice(&tag);
+    buffer[plaintext_len + PKI_TAG_LEN..total_len].copy_from_slice(&extra_nonce.to_le_bytes());
+    Ok(total_len)
+}
+
+/// Verify and decrypt `buffer[..payload_len]` in place
+///
+/// Returns the length of the plaintext, which is left at the start of the buffer.
+pub fn decrypt_in_place(
+    shared_key: &[u8; 32],
+    from_node: u32,
+    packet_id: u32,
+    buffer: &mut [u8],
+    payload_len: usize,
+) -> Result<usize, PkiError> {
+    if payload_len <= PKI_OVERHEAD || payload_len > buffer.len() {
+        return Err(PkiError::PayloadTooShort);
+    }
+    let plaintext_len = payload_len - PKI_OVERHEAD;
+
+    let mut extra_nonce = [0u8; 4];
+    extra_nonce.copy_from_slice(&buffer[plaintext_len + PKI_TAG_LEN..payload_len]);
+    let mut tag = [0u8; PKI_TAG_LEN];
+    tag.copy_from_slice(&buffer[plaintext_len..plaintext_len + PKI_TAG_LEN]);
+
+    let nonce = create_nonce(from_node, packet_id, u32::from_le_bytes(extra_nonce));
+    let cipher = Aes256Ccm::new(GenericArray::from_slice(shared_key));
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            &[],
+            &mut buffer[..plaintext_len],
+            GenericArray::from_slice(&tag),
+        )
+        .map_err(|_| PkiError::AuthenticationFailed)?;
+    Ok(plaintext_len)
+}
+
+impl Packet<Encrypted> {
+    /// Returns true if the header marks this packet as a PKI encrypted direct message
+    pub fn is_pki_encrypted(&self) -> bool {
+        self.header.channel_hash == PKI_CHANNEL_HASH
+            && self.header.destination != BROADCAST_ADDR
+            && self.payload_len > PKI_OVERHEAD
+    }
+
+    /// Decrypt a direct message, looking up the sender's public key in the node database
+    pub fn decrypt_pki(
+        self,
+        private_key: &[u8; 32],
+        node_db: &NodeDatabase,
+    ) -> Result<Packet<Decrypted>, PkiError> {
+        let Some(public_key) = node_db.get_public_key(self.header.source) else {
+            #[cfg(feature = "defmt")]
+            defmt::debug!(
+                "No public key known for 0x{:08X}, cannot decrypt PKI packet",
+                self.header.source
+            );
+            return Err(PkiError::UnknownPublicKey);
+        };
+        self.decrypt_pki_with_key(private_key, public_key)
+    }
+
+    /// Decrypt a direct message sent by the owner of `remote_public_key`
+    pub fn decrypt_pki_with_key(
+        self,
+        private_key: &[u8; 32],
+        remote_public_key: &[u8; 32],
+    ) -> Result<Packet<Decrypted>, PkiError> {
+        let key = shared_key(private_key, remote_public_key)?;
+
+        let mut payload = self.payload;
+        let plaintext_len = decrypt_in_place(
+            &key,
+            self.header.source,
+            self.header.packet_id,
+            &mut payload,
+            self.payload_len,
+        )?;
+        payload[plaintext_len..].fill(0);
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "PKI decrypted payload from 0x{:08X}: {:02X}",
+            self.header.source,
+            payload[..plaintext_len]
+        );
+
+        Ok(Packet::new(
+            self.header,
+            self.rssi,
+            self.snr,
+            payload,
+            plaintext_len,
+        ))
+    }
+}
+
+impl PacketBuffer {
+    /// Returns true if the header marks this packet as a PKI encrypted direct message
+    pub fn is_pki_encrypted(&self) -> bool {
+        !self.is_decrypted()
+            && self.header.channel_hash == PKI_CHANNEL_HASH
+            && self.header.destination != BROADCAST_ADDR
+            && self.payload().len() > PKI_OVERHEAD
+    }
+
+    /// Decrypt a direct message in place, looking up the sender's public key in the node database
+    ///
+    /// The payload is left unusable if authentication fails.
+    pub fn decrypt_pki_in_place(
+        &mut self,
+        private_key: &[u8; 32],
+        node_db: &NodeDatabase,
+    ) -> Result<(), PkiError> {
+        let Some(public_key) = node_db.get_public_key(self.header.source) else {
+            return Err(PkiError::UnknownPublicKey);
+        };
+        let key = shared_key(private_key, public_key)?;
+
+        let (source, packet_id) = (self.header.source, self.header.packet_id);
+        let (payload, payload_len) = self.payload_mut();
+        let plaintext_len = decrypt_in_place(&key, source, packet_id, payload, payload_len)?;
+        self.set_decrypted(plaintext_len);
+        // A message that authenticates but is not a Data message is still usable as raw bytes
+        let _ = self.decode_in_place();
+        Ok(())
+    }
+}
+
+impl Packet<Decrypted> {
+    /// Encrypt a direct message, looking up the recipient's public key in the node database
+    ///
+    /// `extra_nonce` must be random for every packet.
+    pub fn encrypt_pki(
+        self,
+        private_key: &[u8; 32],
+        node_db: &NodeDatabase,
+        extra_nonce: u32,
+    ) -> Result<Packet<Encrypted>, PkiError> {
+        if self.header.destination == BROADCAST_ADDR {
+            return Err(PkiError::BroadcastNotSupported);
+        }
+        let Some(public_key) = node_db.get_public_key(self.header.destination) else {
+            #[cfg(feature = "defmt")]
+            defmt::warn!(
+                "No public key known for 0x{:08X}, cannot send PKI packet",
+                self.header.destination
+            );
+            return Err(PkiError::UnknownPublicKey);
+        };
+        self.encrypt_pki_with_key(private_key, public_key, extra_nonce)
+    }
+
+    /// Encrypt a direct message for the owner of `remote_public_key`
+    ///
+    /// The header's channel hash is set to [`PKI_CHANNEL_HASH`].
+    pub fn encrypt_pki_with_key(
+        self,
+        private_key: &[u8; 32],
+        remote_public_key: &[u8; 32],
+        extra_nonce: u32,
+    ) -> Result<Packet<Encrypted>, PkiError> {
+        if self.header.destination == BROADCAST_ADDR {
+            return Err(PkiError::BroadcastNotSupported);
+        }
+        if self.payload_len > MAX_PKI_PAYLOAD_LEN {
+            return Err(PkiError::PayloadTooLarge);
+        }
+        let key = shared_key(private_key, remote_public_key)?;
+
+        let mut payload = self.payload;
+        let payload_len = encrypt_in_place(
+            &key,
+            self.header.source,
+            self.header.packet_id,
+            extra_nonce,
+            &mut payload,
+            self.payload_len,
+        )?;
+
+        let mut header = self.header;
+        header.channel_hash = PKI_CHANNEL_HASH;
+
+        Ok(Packet::new(
+            header,
+            self.rssi,
+            self.snr,
+            payload,
+            payload_len,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{Header, HeaderFlags};
+    use crate::node_database::{NodeInfo, User};
+    use crate::{DecodedPacket, OwnedData, PortNum};
+    use femtopb::Message as _;
+
+    const ALICE: u32 = 0x0000_0A11;
+    const BOB: u32 = 0x0000_0B0B;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    fn public_key(private_key: &[u8; 32]) -> [u8; 32] {
+        x25519_dalek::x25519(*private_key, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+
+    fn text_packet(from: u32, to: u32, message: &[u8]) -> Packet<Decrypted> {
+        let header = Header::new(
+            to,
+            from,
+            0x1234_5678,
+            HeaderFlags {
+                hop_limit: 3,
+                want_ack: true,
+                via_mqtt: false,
+                hop_start: 3,
+            },
+            0x08,
+            0x00,
+            0x00,
+        );
+        let data = OwnedData::new(PortNum::TextMessageApp, message).unwrap();
+        DecodedPacket::new(header, data).encode().unwrap()
+    }
+
+    fn node_db_with_key(node_num: u32, public_key: [u8; 32]) -> NodeDatabase {
+        let mut db = NodeDatabase::new();
+        db.add_or_update_node(NodeInfo {
+            num: node_num,
+            user: Some(User {
+                public_key: Some(public_key),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        db
+    }
+
+    #[test]
+    fn test_decrypt_meshtastic_firmware_packet() {
+        // Test vector from the Meshtastic firmware's crypto unit tests
+        let private_key: [u8; 32] =
+            hex("a00330633e63522f8a4d81ec6d9d1e6617f6c8ffd3a4c698229537d44e522277");
+        let remote_public_key: [u8; 32] =
+            hex("db18fc50eea47f00251cb784819a3cf5fc361882597f589f0d7ff820e8064457");
+        let radio_bytes: [u8; 38] =
+            hex("8c646d7a2909000062d6b2136b00000040df24abfcc30a17a3d9046726099e796a1c036a792b");
+
+        let shared = shared_key(&private_key, &remote_public_key).unwrap();
+        assert_eq!(shared[..8], hex::<8>("777b1545c9d6f9a2"));
+
+        let packet = Packet::<Encrypted>::from_bytes(&radio_bytes, 0, 0).unwrap();
+        assert!(packet.is_pki_encrypted());
+        assert_eq!(packet.header.source, 0x0929);
+        assert_eq!(packet.header.packet_id, 0x13b2_d662);
+
+        let decrypted = packet
+            .decrypt_pki_with_key(&private_key, &remote_public_key)
+            .unwrap();
+        assert_eq!(
+            decrypted.payload[..decrypted.payload_len],
+            hex::<10>("08011204746573744800")
+        );
+    }
+
+    #[test]
+    fn test_round_trip_between_nodes() {
+        let alice_private = [0x11; 32];
+        let bob_private = [0x22; 32];
+        let alice_db = node_db_with_key(BOB, public_key(&bob_private));
+        let bob_db = node_db_with_key(ALICE, public_key(&alice_private));
+
+        let encrypted = text_packet(ALICE, BOB, b"for bob only")
+            .encrypt_pki(&alice_private, &alice_db, 0xCAFE_F00D)
+            .unwrap();
+        assert_eq!(encrypted.header.channel_hash, PKI_CHANNEL_HASH);
+        assert!(encrypted.is_pki_encrypted());
+
+        let mut buffer = [0u8; 256];
+        let len = encrypted.to_bytes(&mut buffer).unwrap();
+        assert_eq!(&buffer[len - 4..len], &0xCAFE_F00Du32.to_le_bytes());
+
+        let decoded = Packet::<Encrypted>::from_bytes(&buffer[..len], 0, 0)
+            .unwrap()
+            .decrypt_pki(&bob_private, &bob_db)
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(decoded.payload_data(), b"for bob only");
+    }
+
+    #[test]
+    fn test_pooled_packet_decrypts_in_place() {
+        use crate::pool::PacketPool;
+        use crate::ReceivedPacket;
+
+        let alice_private = [0x11; 32];
+        let bob_private = [0x22; 32];
+        let alice_db = node_db_with_key(BOB, public_key(&bob_private));
+        let bob_db = node_db_with_key(ALICE, public_key(&alice_private));
+
+        let mut buffer = [0u8; 256];
+        let len = text_packet(ALICE, BOB, b"pooled")
+            .encrypt_pki(&alice_private, &alice_db, 7)
+            .unwrap()
+            .to_bytes(&mut buffer)
+            .unwrap();
+
+        let pool: PacketPool<1> = PacketPool::new();
+        let mut handle = pool.alloc_from_bytes(&buffer[..len], 0, 0).unwrap();
+        let packet = handle.get_mut().unwrap();
+        assert!(packet.is_pki_encrypted());
+        packet.decrypt_pki_in_place(&bob_private, &bob_db).unwrap();
+        assert!(!packet.is_pki_encrypted());
+        assert_eq!(packet.data_message().unwrap().payload, b"pooled");
+    }
+
+    #[test]
+    fn test_unknown_recipient_key() {
+        let db = NodeDatabase::new();
+        let result = text_packet(ALICE, BOB, b"hello").encrypt_pki(&[0x11; 32], &db, 1);
+        assert!(matches!(result, Err(PkiError::UnknownPublicKey)));
+    }
+
+    #[test]
+    fn test_broadcast_not_supported() {
+        let db = node_db_with_key(BROADCAST_ADDR, public_key(&[0x22; 32]));
+        let result = text_packet(ALICE, BROADCAST_ADDR, b"hello").encrypt_pki(&[0x11; 32], &db, 1);
+        assert!(matches!(result, Err(PkiError::BroadcastNotSupported)));
+    }
+
+    #[test]
+    fn test_tampered_packet_fails_authentication() {
+        let alice_private = [0x11; 32];
+        let bob_private = [0x22; 32];
+
+        let mut encrypted = text_packet(ALICE, BOB, b"integrity")
+            .encrypt_pki_with_key(&alice_private, &public_key(&bob_private), 7)
+            .unwrap();
+        encrypted.payload[0] ^= 0x01;
+
+        let result = encrypted.decrypt_pki_with_key(&bob_private, &public_key(&alice_private));
+        assert!(matches!(result, Err(PkiError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn test_wrong_key_fails_authentication() {
+        let alice_private = [0x11; 32];
+        let bob_private = [0x22; 32];
+        let mallory_private = [0x33; 32];
+
+        let encrypted = text_packet(ALICE, BOB, b"not for mallory")
+            .encrypt_pki_with_key(&alice_private, &public_key(&bob_private), 7)
+            .unwrap();
+        let result = encrypted.decrypt_pki_with_key(&mallory_private, &public_key(&alice_private));
+        assert!(matches!(result, Err(PkiError::AuthenticationFailed)));
+    }
+
+    struct CountingRng(u8);
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_keypair_generation_and_storage() {
+        let keypair = Keypair::generate(&mut CountingRng(0));
+        assert_eq!(keypair.public_key(), &public_key(keypair.private_key()));
+
+        let restored = Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+        assert_eq!(restored, keypair);
+
+        let other = Keypair::generate(&mut CountingRng(100));
+        assert_ne!(other.public_key(), keypair.public_key());
+    }
+
+    #[test]
+    fn test_keypair_from_bytes_rejects_bad_data() {
+        let keypair = Keypair::generate(&mut CountingRng(0));
+        let mut bytes = keypair.to_bytes();
+        bytes[40] ^= 0xFF;
+        assert_eq!(Keypair::from_bytes(&bytes), Err(PkiError::InvalidKeypair));
+        assert_eq!(
+            Keypair::from_bytes(&bytes[..32]),
+            Err(PkiError::InvalidKeypair)
+        );
+    }
+
+    #[test]
+    fn test_keypairs_agree_on_shared_key() {
+        let alice = Keypair::generate(&mut CountingRng(0));
+        let bob = Keypair::generate(&mut CountingRng(50));
+        assert_eq!(
+            alice.shared_key(bob.public_key()).unwrap(),
+            bob.shared_key(alice.public_key()).unwrap()
+        );
+    }
+
+    fn nodeinfo_packet(from: u32, public_key: &[u8]) -> DecodedPacket {
+        let user = meshtastic_protobufs::meshtastic::User {
+            long_name: "Test node",
+            short_name: "TEST",
+            public_key,
+            ..Default::default()
+        };
+        let mut payload = [0u8; 128];
+        let len = user.encoded_len();
+        user.encode(&mut &mut payload[..len]).unwrap();
+
+        let header = Header::new(
+            BROADCAST_ADDR,
+            from,
+            1,
+            HeaderFlags {
+                hop_limit: 3,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: 3,
+            },
+            0x08,
+            0x00,
+            0x00,
+        );
+        let data = OwnedData::new(PortNum::NodeinfoApp, &payload[..len]).unwrap();
+        DecodedPacket::new(header, data)
+    }
+
+    #[test]
+    fn test_node_database_learns_public_key_from_nodeinfo() {
+        let bob = Keypair::generate(&mut CountingRng(50));
+        let mut db = NodeDatabase::new();
+        assert!(db
+            .add_or_update_node_from_packet(&nodeinfo_packet(BOB, bob.public_key()))
+            .is_ok());
+        assert_eq!(db.get_public_key(BOB), Some(bob.public_key()));
+        assert!(!db.get_node(BOB).unwrap().public_key_mismatch);
+    }
+
+    #[test]
+    fn test_node_database_flags_changed_public_key() {
+        let bob = Keypair::generate(&mut CountingRng(50));
+        let mallory = Keypair::generate(&mut CountingRng(150));
+        let mut db = NodeDatabase::new();
+        db.add_or_update_node_from_packet(&nodeinfo_packet(BOB, bob.public_key()))
+            .unwrap();
+
+        // A NODEINFO with a different key keeps the known key and raises the flag
+        db.add_or_update_node_from_packet(&nodeinfo_packet(BOB, mallory.public_key()))
+            .unwrap();
+        assert_eq!(db.get_public_key(BOB), Some(bob.public_key()));
+        assert!(db.get_node(BOB).unwrap().public_key_mismatch);
+
+        // A NODEINFO without a key does not erase the known one
+        db.add_or_update_node_from_packet(&nodeinfo_packet(BOB, &[])).unwrap();
+        assert_eq!(db.get_public_key(BOB), Some(bob.public_key()));
+
+        // Once reset, the next key is accepted
+        assert!(db.reset_public_key(BOB));
+        assert!(!db.get_node(BOB).unwrap().public_key_mismatch);
+        db.add_or_update_node_from_packet(&nodeinfo_packet(BOB, mallory.public_key()))
+            .unwrap();
+        assert_eq!(db.get_public_key(BOB), Some(mallory.public_key()));
+    }
+
+    #[test]
+    fn test_low_order_public_key_rejected() {
+        assert_eq!(
+            shared_key(&[0x11; 32], &[0u8; 32]),
+            Err(PkiError::InvalidPublicKey)
+        );
+    }
+}
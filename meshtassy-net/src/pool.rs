@@ -0,0 +1,599 @@
+//! Fixed-capacity packet pool with reference-counted handles
+//!
+//! Received frames are written once into a slot of a [`PacketPool`] and then
+//! decrypted and decoded in place. The slot is shared through [`PacketHandle`]s:
+//! cloning a handle only bumps a reference count, so handing a packet to
+//! several tasks (for example through an embassy `PubSubChannel`) never copies
+//! the payload. The slot returns to the pool when the last handle is dropped.
+//!
+//! A pool is meant to live in a `static`:
+//!
+//! ```
+//! use meshtassy_net::pool::PacketPool;
+//!
+//! static POOL: PacketPool<4> = PacketPool::new();
+//!
+//! let frame = [0u8; 20];
+//! let packet = POOL.alloc_from_bytes(&frame, -80, 5).unwrap();
+//! let shared = packet.clone();
+//! assert_eq!(POOL.available(), 3);
+//! drop(packet);
+//! drop(shared);
+//! assert_eq!(POOL.available(), 4);
+//! ```
+
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, Range};
+
+use femtopb::Message as _;
+use meshtastic_protobufs::meshtastic::Data;
+
+use crate::error::PacketError;
+use crate::header::{Header, HeaderFlags};
+use crate::key::ChannelKey;
+use crate::{
+    AppPayload, DecodedPacket, PortNum, ReceivedPacket, HEADER_LEN, MAX_ENCRYPTED_PAYLOAD_LEN,
+    MAX_LORA_PACKET_LEN,
+};
+
+/// A received frame, decrypted and decoded in place once the key is known
+pub struct PacketBuffer {
+    pub header: Header,
+    pub rssi: i8,
+    pub snr: i8,
+    payload: [u8; MAX_ENCRYPTED_PAYLOAD_LEN],
+    payload_len: usize,
+    decrypted: bool,
+    // Port number and location of the application payload inside `payload`
+    decoded: Option<(femtopb::EnumValue<PortNum>, Range<usize>)>,
+}
+
+impl PacketBuffer {
+    const fn new() -> Self {
+        Self {
+            header: Header {
+                destination: 0,
+                source: 0,
+                packet_id: 0,
+                flags: HeaderFlags {
+                    hop_limit: 0,
+                    want_ack: false,
+                    via_mqtt: false,
+                    hop_start: 0,
+                },
+                channel_hash: 0,
+                next_hop: 0,
+                relay_node: 0,
+            },
+            rssi: 0,
+            snr: 0,
+            payload: [0; MAX_ENCRYPTED_PAYLOAD_LEN],
+            payload_len: 0,
+            decrypted: false,
+            decoded: None,
+        }
+    }
+
+    /// Parse an on-air frame: the 16-byte header followed by the encrypted payload
+    pub fn fill_from_bytes(&mut self, buffer: &[u8], rssi: i8, snr: i8) -> Result<(), PacketError> {
+        if buffer.len() < HEADER_LEN {
+            return Err(PacketError::TooShort);
+        }
+        if buffer.len() > MAX_LORA_PACKET_LEN {
+            return Err(PacketError::PayloadTooLarge);
+        }
+
+        self.header = Header::from_bytes(&buffer[..HEADER_LEN])?;
+        self.rssi = rssi;
+        self.snr = snr;
+        self.payload_len = buffer.len() - HEADER_LEN;
+        self.payload[..self.payload_len].copy_from_slice(&buffer[HEADER_LEN..]);
+        self.decrypted = false;
+        self.decoded = None;
+        Ok(())
+    }
+
+    /// Hold a packet of our own, decrypted and decoded like a received one
+    pub fn fill_from_decoded(&mut self, packet: &DecodedPacket) -> Result<(), PacketError> {
+        let packet = packet.encode()?;
+        let payload = &packet.payload[..packet.payload_len];
+        self.header = packet.header;
+        self.rssi = packet.rssi;
+        self.snr = packet.snr;
+        self.payload_len = payload.len();
+        self.payload[..payload.len()].copy_from_slice(payload);
+        self.decrypted = true;
+        self.decode_in_place()
+    }
+
+    /// Serialize the frame into its on-air form
+    /// Returns the number of bytes written
+    pub fn to_bytes(&self, buffer: &mut [u8]) -> Result<usize, PacketError> {
+        let total_len = HEADER_LEN + self.payload_len;
+        if buffer.len() < total_len {
+            return Err(PacketError::BufferTooSmall);
+        }
+        buffer[..HEADER_LEN].copy_from_slice(&self.header.to_bytes());
+        buffer[HEADER_LEN..total_len].copy_from_slice(self.payload());
+        Ok(total_len)
+    }
+
+    /// The payload, encrypted or decrypted depending on [`PacketBuffer::is_decrypted`]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_len]
+    }
+
+    /// True once the payload has been decrypted in place
+    pub fn is_decrypted(&self) -> bool {
+        self.decrypted
+    }
+
+    /// True once the decrypted payload has been validated as a `Data` message
+    pub fn is_decoded(&self) -> bool {
+        self.decoded.is_some()
+    }
+
+    /// Validate the decrypted payload as a `Data` message
+    ///
+    /// The port number and the location of the application payload are
+    /// remembered, so [`ReceivedPacket::port_num`] and
+    /// [`ReceivedPacket::payload_data`] do not parse the message again.
+    pub fn decode_in_place(&mut self) -> Result<(), PacketError> {
+        let data = self.data_message()?;
+        let start = if data.payload.is_empty() {
+            0
+        } else {
+            data.payload.as_ptr() as usize - self.payload.as_ptr() as usize
+        };
+        let decoded = (data.portnum, start..start + data.payload.len());
+        self.decoded = Some(decoded);
+        Ok(())
+    }
+
+    /// Decrypt the payload in place with a channel key
+    pub fn decrypt_in_place(&mut self, key: &ChannelKey) -> Result<(), PacketError> {
+        if self.decrypted {
+            return Ok(());
+        }
+        self.transform(key)?;
+        self.decrypted = true;
+        Ok(())
+    }
+
+    /// Encrypt the payload in place with a channel key
+    ///
+    /// Undoes [`PacketBuffer::decrypt_in_place`], for example to try another
+    /// channel after the payload failed to decode.
+    pub fn encrypt_in_place(&mut self, key: &ChannelKey) -> Result<(), PacketError> {
+        if !self.decrypted {
+            return Ok(());
+        }
+        self.transform(key)?;
+        self.decrypted = false;
+        self.decoded = None;
+        Ok(())
+    }
+
+    fn transform(&mut self, key: &ChannelKey) -> Result<(), PacketError> {
+        let iv = self.header.create_iv();
+        key.transform(&mut self.payload[..self.payload_len], &iv)?;
+        Ok(())
+    }
+
+    /// Raw payload storage, for decryption schemes that change the payload length
+    pub(crate) fn payload_mut(&mut self) -> (&mut [u8; MAX_ENCRYPTED_PAYLOAD_LEN], usize) {
+        (&mut self.payload, self.payload_len)
+    }
+
+    /// Mark the first `plaintext_len` bytes of the payload as the decrypted payload
+    pub(crate) fn set_decrypted(&mut self, plaintext_len: usize) {
+        self.payload[plaintext_len..self.payload_len].fill(0);
+        self.payload_len = plaintext_len;
+        self.decrypted = true;
+        self.decoded = None;
+    }
+}
+
+impl ReceivedPacket for PacketBuffer {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn rssi(&self) -> i8 {
+        self.rssi
+    }
+
+    fn snr(&self) -> i8 {
+        self.snr
+    }
+
+    fn data_message(&self) -> Result<Data<'_>, PacketError> {
+        if !self.decrypted {
+            return Err(PacketError::NotDecrypted);
+        }
+        if self.payload_len == 0 {
+            return Err(PacketError::EmptyPayload);
+        }
+        Data::decode(self.payload()).map_err(|_| PacketError::Protobuf)
+    }
+
+    fn port_num(&self) -> femtopb::EnumValue<PortNum> {
+        match &self.decoded {
+            Some((portnum, _)) => *portnum,
+            None => femtopb::EnumValue::Known(PortNum::UnknownApp),
+        }
+    }
+
+    fn payload_data(&self) -> &[u8] {
+        match &self.decoded {
+            Some((_, range)) => &self.payload[range.clone()],
+            None => &[],
+        }
+    }
+
+    fn app_payload(&self) -> Result<AppPayload, PacketError> {
+        match &self.decoded {
+            Some((portnum, range)) => AppPayload::decode(*portnum, &self.payload[range.clone()]),
+            None => Err(PacketError::NotDecrypted),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PacketBuffer {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "PacketBuffer {{ header: {}, rssi: {}, snr: {}, payload_len: {}, decrypted: {}, port: {} }}",
+            self.header,
+            self.rssi,
+            self.snr,
+            self.payload_len,
+            self.decrypted,
+            self.port_num()
+        );
+    }
+}
+
+struct Slot {
+    refs: critical_section::Mutex<Cell<u8>>,
+    buffer: UnsafeCell<PacketBuffer>,
+}
+
+// The buffer is only written through a handle holding the only reference
+// (see `PacketHandle::get_mut`), and the count is updated in a critical section.
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            refs: critical_section::Mutex::new(Cell::new(0)),
+            buffer: UnsafeCell::new(PacketBuffer::new()),
+        }
+    }
+}
+
+/// A static pool of `N` packet buffers
+pub struct PacketPool<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Default for PacketPool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PacketPool<N> {
+    /// Create a pool with every buffer free
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    /// Take a free buffer, or None if every buffer is in use
+    ///
+    /// The buffer keeps the contents of its previous packet until it is filled.
+    pub fn alloc(&self) -> Option<PacketHandle<'_>> {
+        critical_section::with(|cs| {
+            self.slots.iter().find_map(|slot| {
+                let refs = slot.refs.borrow(cs);
+                if refs.get() != 0 {
+                    return None;
+                }
+                refs.set(1);
+                Some(PacketHandle { slot })
+            })
+        })
+    }
+
+    /// Take a free buffer and fill it with a received frame
+    pub fn alloc_from_bytes(
+        &self,
+        buffer: &[u8],
+        rssi: i8,
+        snr: i8,
+    ) -> Result<PacketHandle<'_>, PacketError> {
+        let Some(mut handle) = self.alloc() else {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("Packet pool exhausted ({} buffers in use)", N);
+            return Err(PacketError::PoolExhausted);
+        };
+        // A freshly allocated handle is the only reference to its buffer
+        if let Some(packet) = handle.get_mut() {
+            packet.fill_from_bytes(buffer, rssi, snr)?;
+        }
+        Ok(handle)
+    }
+
+    /// Take a free buffer and fill it with a packet of our own
+    ///
+    /// Lets a locally generated packet, like a delivery failure for the client,
+    /// reach the tasks that consume received packets.
+    pub fn alloc_from_decoded(
+        &self,
+        packet: &DecodedPacket,
+    ) -> Result<PacketHandle<'_>, PacketError> {
+        let Some(mut handle) = self.alloc() else {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("Packet pool exhausted ({} buffers in use)", N);
+            return Err(PacketError::PoolExhausted);
+        };
+        if let Some(packet_buffer) = handle.get_mut() {
+            packet_buffer.fill_from_decoded(packet)?;
+        }
+        Ok(handle)
+    }
+
+    /// Number of free buffers
+    pub fn available(&self) -> usize {
+        critical_section::with(|cs| {
+            self.slots
+                .iter()
+                .filter(|slot| slot.refs.borrow(cs).get() == 0)
+                .count()
+        })
+    }
+
+    /// Total number of buffers
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+/// Shared reference to a packet buffer in a [`PacketPool`]
+///
+/// Cloning is cheap and never copies the packet. The buffer can only be
+/// modified through [`PacketHandle::get_mut`] while no clone exists.
+pub struct PacketHandle<'a> {
+    slot: &'a Slot,
+}
+
+impl PacketHandle<'_> {
+    /// Number of handles sharing this buffer
+    pub fn ref_count(&self) -> u8 {
+        critical_section::with(|cs| self.slot.refs.borrow(cs).get())
+    }
+
+    /// Mutable access to the buffer, only if this is the sole handle
+    pub fn get_mut(&mut self) -> Option<&mut PacketBuffer> {
+        if self.ref_count() != 1 {
+            return None;
+        }
+        // SAFETY: we hold the only handle and borrow it mutably, and a count of one
+        // can only grow by cloning this handle, which the borrow prevents
+        Some(unsafe { &mut *self.slot.buffer.get() })
+    }
+}
+
+impl Deref for PacketHandle<'_> {
+    type Target = PacketBuffer;
+
+    fn deref(&self) -> &PacketBuffer {
+        // SAFETY: mutable access requires the only handle, which this shared borrow excludes
+        unsafe { &*self.slot.buffer.get() }
+    }
+}
+
+impl Clone for PacketHandle<'_> {
+    fn clone(&self) -> Self {
+        critical_section::with(|cs| {
+            let refs = self.slot.refs.borrow(cs);
+            refs.set(
+                refs.get()
+                    .checked_add(1)
+                    .expect("packet handle count overflow"),
+            );
+        });
+        Self { slot: self.slot }
+    }
+}
+
+impl Drop for PacketHandle<'_> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let refs = self.slot.refs.borrow(cs);
+            refs.set(refs.get() - 1);
+        });
+    }
+}
+
+impl ReceivedPacket for PacketHandle<'_> {
+    fn header(&self) -> &Header {
+        &self.deref().header
+    }
+
+    fn rssi(&self) -> i8 {
+        self.deref().rssi
+    }
+
+    fn snr(&self) -> i8 {
+        self.deref().snr
+    }
+
+    fn data_message(&self) -> Result<Data<'_>, PacketError> {
+        self.deref().data_message()
+    }
+
+    fn port_num(&self) -> femtopb::EnumValue<PortNum> {
+        self.deref().port_num()
+    }
+
+    fn payload_data(&self) -> &[u8] {
+        self.deref().payload_data()
+    }
+
+    fn app_payload(&self) -> Result<AppPayload, PacketError> {
+        self.deref().app_payload()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PacketHandle<'_> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", self.deref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OwnedData, PortNum};
+
+    fn frame(payload: &[u8], key: &ChannelKey, buffer: &mut [u8]) -> usize {
+        let header = Header {
+            destination: 0xFFFFFFFF,
+            source: 0x11223344,
+            packet_id: 0x01020304,
+            flags: HeaderFlags {
+                hop_limit: 3,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: 3,
+            },
+            channel_hash: 0x08,
+            next_hop: 0,
+            relay_node: 0x44,
+        };
+        let data = OwnedData::new(PortNum::TextMessageApp, payload).unwrap();
+        DecodedPacket::new(header, data)
+            .encode()
+            .unwrap()
+            .encrypt(key)
+            .unwrap()
+            .to_bytes(buffer)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_alloc_and_release() {
+        let pool: PacketPool<2> = PacketPool::new();
+        assert_eq!(pool.available(), 2);
+
+        let a = pool.alloc().unwrap();
+        let b = pool.alloc().unwrap();
+        assert!(pool.alloc().is_none());
+        assert!(matches!(
+            pool.alloc_from_bytes(&[0u8; 20], 0, 0),
+            Err(PacketError::PoolExhausted)
+        ));
+
+        let a2 = a.clone();
+        drop(a);
+        assert_eq!(pool.available(), 0);
+        drop(a2);
+        assert_eq!(pool.available(), 1);
+        drop(b);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn test_get_mut_requires_unique_handle() {
+        let pool: PacketPool<1> = PacketPool::new();
+        let mut handle = pool.alloc().unwrap();
+        assert!(handle.get_mut().is_some());
+
+        let shared = handle.clone();
+        assert_eq!(handle.ref_count(), 2);
+        assert!(handle.get_mut().is_none());
+
+        drop(shared);
+        assert!(handle.get_mut().is_some());
+    }
+
+    #[test]
+    fn test_rejects_bad_frames_and_frees_buffer() {
+        let pool: PacketPool<1> = PacketPool::new();
+        assert!(matches!(
+            pool.alloc_from_bytes(&[0u8; 10], 0, 0),
+            Err(PacketError::TooShort)
+        ));
+        assert!(matches!(
+            pool.alloc_from_bytes(&[0u8; MAX_LORA_PACKET_LEN + 1], 0, 0),
+            Err(PacketError::PayloadTooLarge)
+        ));
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_decrypt_and_decode_in_place() {
+        let key = ChannelKey::from_bytes(&[0x01], 1).unwrap();
+        let mut bytes = [0u8; MAX_LORA_PACKET_LEN];
+        let len = frame(b"in place", &key, &mut bytes);
+
+        let pool: PacketPool<1> = PacketPool::new();
+        let mut handle = pool.alloc_from_bytes(&bytes[..len], -90, 4).unwrap();
+        assert_eq!(handle.header.source, 0x11223344);
+        assert_eq!(handle.data_message().err(), Some(PacketError::NotDecrypted));
+
+        let packet = handle.get_mut().unwrap();
+        packet.decrypt_in_place(&key).unwrap();
+        packet.decode_in_place().unwrap();
+        let shared = handle.clone();
+        assert_eq!(shared.data_message().unwrap().payload, b"in place");
+        assert_eq!(shared.payload_data(), b"in place");
+        assert_eq!(
+            shared.port_num(),
+            femtopb::EnumValue::Known(PortNum::TextMessageApp)
+        );
+        assert_eq!(shared.snr(), 4);
+
+        // Encrypting again restores the on-air bytes
+        drop(shared);
+        handle.get_mut().unwrap().encrypt_in_place(&key).unwrap();
+        let mut out = [0u8; MAX_LORA_PACKET_LEN];
+        let out_len = handle.to_bytes(&mut out).unwrap();
+        assert_eq!(&out[..out_len], &bytes[..len]);
+    }
+
+    #[test]
+    fn test_alloc_from_decoded() {
+        let header = Header::new(
+            0x11223344,
+            0x11223344,
+            7,
+            HeaderFlags {
+                hop_limit: 0,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: 0,
+            },
+            0x08,
+            0,
+            0x44,
+        );
+        let data = OwnedData::new(PortNum::TextMessageApp, b"local").unwrap();
+        let pool: PacketPool<1> = PacketPool::new();
+        let handle = pool
+            .alloc_from_decoded(&DecodedPacket::new(header, data))
+            .unwrap();
+        assert!(handle.is_decrypted());
+        assert_eq!(handle.header.packet_id, 7);
+        assert_eq!(handle.payload_data(), b"local");
+        assert_eq!(
+            handle.port_num(),
+            femtopb::EnumValue::Known(PortNum::TextMessageApp)
+        );
+    }
+}
@@ -0,0 +1,647 @@
+//! Radio abstraction and the RX/TX loop
+//!
+//! The mesh stack talks to the LoRa chip through the [`MeshRadio`] trait,
+//! which extends [`TxRadio`] (CAD and transmit) with configuration, reception
+//! and sleep. The firmware implements it on top of its radio driver, and
+//! [`MemoryRadio`] implements it in memory so the RX/TX logic can be exercised
+//! in host tests.
+//!
+//! [`Transceiver`] is the half-duplex loop built on that trait: it listens
+//! until the next queued frame is due, hands received frames to the node, and
+//! sends queued frames with listen-before-talk through a [`TxScheduler`]. The
+//! node's queues, clock and airtime accounting stay on the other side of the
+//! [`RadioHost`] trait.
+
+use heapless::{Deque, Vec};
+use rand_core::RngCore;
+
+use crate::airtime::{time_on_air_ms, TxPermission};
+use crate::header::Header;
+use crate::radio_config::{RadioParams, PREAMBLE_LEN};
+use crate::tx_scheduler::{Contention, TxOutcome, TxRadio, TxScheduler};
+use crate::MAX_LORA_PACKET_LEN;
+
+/// Signal of a received frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RxMetadata {
+    /// Length of the frame written to the receive buffer
+    pub len: usize,
+    /// Received signal strength in dBm
+    pub rssi: i16,
+    /// Signal-to-noise ratio in dB
+    pub snr: i16,
+}
+
+/// A LoRa radio the mesh stack can drive
+#[allow(async_fn_in_trait)]
+pub trait MeshRadio: TxRadio {
+    /// Apply the frequency, modem settings and power, the radio is ready to receive afterwards
+    async fn configure(&mut self, params: &RadioParams) -> Result<(), Self::Error>;
+
+    /// Listen for a frame for at most `timeout_ms`, or until one arrives when `None`
+    ///
+    /// Returns `None` when the timeout elapsed without a frame.
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout_ms: Option<u64>,
+    ) -> Result<Option<RxMetadata>, Self::Error>;
+
+    /// Put the radio in its lowest power state until the next call
+    async fn sleep(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A frame the host serialized for the transceiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TxFrame {
+    pub header: Header,
+    /// Length of the frame written to the transmit buffer
+    pub len: usize,
+    /// Contention window the frame uses
+    pub contention: Contention,
+}
+
+/// What the transceiver needs from the rest of the node
+#[allow(async_fn_in_trait)]
+pub trait RadioHost {
+    /// Current time in milliseconds
+    fn now_ms(&self) -> u64;
+
+    /// Channel utilization in percent, sizes the contention window of our own frames
+    fn channel_utilization_percent(&self) -> f32;
+
+    /// How long to listen when no frame is waiting for its slot, `None` to wait for a frame
+    ///
+    /// Called on every step, so it is also the place to queue periodic work.
+    fn idle_wait_ms(&mut self) -> Option<u64>;
+
+    /// Serialize the next frame to send into `buffer`, `None` if nothing is queued
+    ///
+    /// The frame stays queued until [`RadioHost::frame_sent`] or
+    /// [`RadioHost::frame_dropped`] is called for it, so a frame cancelled while
+    /// it waits for its slot is not sent.
+    fn next_frame(&mut self, buffer: &mut [u8]) -> Option<TxFrame>;
+
+    /// Whether a frame of `len` bytes may be sent now, for example within the duty cycle
+    ///
+    /// A delayed frame stays queued, the transceiver asks again once the wait is over.
+    fn may_transmit(&mut self, len: usize) -> TxPermission;
+
+    /// A frame was sent, after `airtime_ms` on air with contention slots of `slot_time_ms`
+    fn frame_sent(&mut self, frame: &TxFrame, airtime_ms: u32, slot_time_ms: u32);
+
+    /// A frame will not be sent: TX is disabled, not allowed or failed
+    fn frame_dropped(&mut self, frame: &TxFrame);
+
+    /// A frame was received after `airtime_ms` on air
+    ///
+    /// Awaited before the transceiver listens again, so the node may wait for
+    /// shared state while it handles the frame.
+    async fn frame_received(&mut self, frame: &[u8], metadata: &RxMetadata, airtime_ms: u32);
+}
+
+/// What one [`Transceiver::step`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioEvent {
+    /// A frame was received and handed to the host
+    Received(RxMetadata),
+    /// Nothing was received and nothing is queued
+    Idle,
+    /// The next frame waits for its contention slot
+    Scheduled { wait_ms: u64 },
+    /// CAD heard another transmission, the frame was given a new slot
+    ChannelBusy { wait_ms: u64 },
+    /// The host delayed the next frame, it stays queued until the wait is over
+    Deferred { wait_ms: u64 },
+    /// A frame was sent
+    Sent(Header),
+    /// A frame was dropped without being sent
+    Dropped(Header),
+}
+
+/// Half-duplex RX/TX loop over a [`MeshRadio`]
+pub struct Transceiver<R> {
+    radio: R,
+    params: RadioParams,
+    scheduler: TxScheduler,
+    deferred_until_ms: Option<u64>,
+    rx_buffer: [u8; MAX_LORA_PACKET_LEN],
+    tx_buffer: [u8; MAX_LORA_PACKET_LEN],
+}
+
+impl<R: MeshRadio> Transceiver<R> {
+    /// Create a transceiver for the radio parameters, `router` gives our relays precedence
+    pub fn new(radio: R, params: RadioParams, router: bool) -> Self {
+        Self {
+            radio,
+            scheduler: TxScheduler::new(&params.modem, router),
+            params,
+            deferred_until_ms: None,
+            rx_buffer: [0; MAX_LORA_PACKET_LEN],
+            tx_buffer: [0; MAX_LORA_PACKET_LEN],
+        }
+    }
+
+    /// Radio parameters in use
+    pub fn params(&self) -> &RadioParams {
+        &self.params
+    }
+
+    /// The radio, for example to put it to sleep
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Configure the radio with the parameters given to [`Transceiver::new`]
+    pub async fn configure(&mut self) -> Result<(), R::Error> {
+        self.radio.configure(&self.params).await
+    }
+
+    /// Apply new radio parameters, a frame waiting for its slot picks a new one
+    pub async fn reconfigure(&mut self, params: RadioParams, router: bool) -> Result<(), R::Error> {
+        self.params = params;
+        self.scheduler = TxScheduler::new(&params.modem, router);
+        self.deferred_until_ms = None;
+        self.radio.configure(&self.params).await
+    }
+
+    /// Listen until the next frame is due, then send it if the channel is clear
+    ///
+    /// Call in a loop. Radio errors are returned after the frame being sent,
+    /// if any, was dropped; the next step listens again.
+    pub async fn step<H: RadioHost, G: RngCore>(
+        &mut self,
+        host: &mut H,
+        rng: &mut G,
+    ) -> Result<RadioEvent, R::Error> {
+        let idle_wait_ms = host.idle_wait_ms();
+        let wait_ms = if self.scheduler.is_scheduled() {
+            self.scheduler.wait_ms(host.now_ms())
+        } else if let Some(deferred_until_ms) = self.deferred_until_ms {
+            let deferred_ms = deferred_until_ms.saturating_sub(host.now_ms());
+            Some(idle_wait_ms.map_or(deferred_ms, |wait_ms| wait_ms.min(deferred_ms)))
+        } else {
+            idle_wait_ms
+        };
+        if let Some(metadata) = self.radio.receive(&mut self.rx_buffer, wait_ms).await? {
+            let len = metadata.len.min(self.rx_buffer.len());
+            let airtime_ms = time_on_air_ms(&self.params.modem, PREAMBLE_LEN, len);
+            host.frame_received(&self.rx_buffer[..len], &metadata, airtime_ms)
+                .await;
+            return Ok(RadioEvent::Received(metadata));
+        }
+
+        // A delayed frame is asked about again once its wait is over
+        let now_ms = host.now_ms();
+        if let Some(deferred_until_ms) = self.deferred_until_ms {
+            if now_ms < deferred_until_ms {
+                return Ok(RadioEvent::Deferred {
+                    wait_ms: deferred_until_ms - now_ms,
+                });
+            }
+            self.deferred_until_ms = None;
+        }
+
+        // The channel stayed quiet, send the next frame once its contention slot comes
+        let Some(frame) = host.next_frame(&mut self.tx_buffer) else {
+            self.scheduler.cancel();
+            return Ok(RadioEvent::Idle);
+        };
+        let permission = if self.params.tx_enabled {
+            host.may_transmit(frame.len)
+        } else {
+            TxPermission::Refused
+        };
+        match permission {
+            TxPermission::Allowed => {}
+            TxPermission::Delayed { wait_ms } => {
+                #[cfg(feature = "defmt")]
+                defmt::debug!("Transceiver: {} delayed by {} ms", frame.header, wait_ms);
+                self.scheduler.cancel();
+                self.deferred_until_ms = Some(now_ms + wait_ms);
+                return Ok(RadioEvent::Deferred { wait_ms });
+            }
+            TxPermission::Refused => {
+                #[cfg(feature = "defmt")]
+                defmt::debug!("Transceiver: not allowed to send {}", frame.header);
+                self.scheduler.cancel();
+                host.frame_dropped(&frame);
+                return Ok(RadioEvent::Dropped(frame.header));
+            }
+        }
+        let channel_utilization = host.channel_utilization_percent();
+        if !self.scheduler.is_scheduled() {
+            let tx_at_ms =
+                self.scheduler
+                    .schedule(now_ms, rng, channel_utilization, frame.contention);
+            if tx_at_ms > now_ms {
+                return Ok(RadioEvent::Scheduled {
+                    wait_ms: tx_at_ms - now_ms,
+                });
+            }
+        }
+
+        let result = self
+            .scheduler
+            .try_transmit(
+                &mut self.radio,
+                rng,
+                now_ms,
+                channel_utilization,
+                &self.tx_buffer[..frame.len],
+            )
+            .await;
+        match result {
+            Ok(TxOutcome::Sent) => {
+                let airtime_ms = time_on_air_ms(&self.params.modem, PREAMBLE_LEN, frame.len);
+                host.frame_sent(&frame, airtime_ms, self.scheduler.slot_time_ms());
+                Ok(RadioEvent::Sent(frame.header))
+            }
+            Ok(TxOutcome::NotDue { wait_ms }) => Ok(RadioEvent::Scheduled { wait_ms }),
+            Ok(TxOutcome::ChannelBusy { wait_ms }) => Ok(RadioEvent::ChannelBusy { wait_ms }),
+            Err(err) => {
+                host.frame_dropped(&frame);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Number of frames [`MemoryRadio`] holds in each direction
+pub const MEMORY_RADIO_FRAMES: usize = 8;
+
+/// Errors of [`MemoryRadio`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MemoryRadioError {
+    /// The radio was used before [`MeshRadio::configure`]
+    NotConfigured,
+    /// No room left for another frame
+    Full,
+    /// The frame is longer than a LoRa frame
+    TooLong,
+    /// Receiving without a timeout while no frame was injected, it would wait forever
+    WouldBlock,
+}
+
+/// In-memory radio for host tests
+///
+/// Frames passed to [`MemoryRadio::inject`] are received in order, frames
+/// sent are kept for [`MemoryRadio::take_sent`]. Receiving never waits: when
+/// no frame was injected the timeout elapses at once, or the call fails with
+/// [`MemoryRadioError::WouldBlock`] if there is no timeout to end it.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRadio {
+    params: Option<RadioParams>,
+    inbound: Deque<(Vec<u8, MAX_LORA_PACKET_LEN>, i16, i16), MEMORY_RADIO_FRAMES>,
+    sent: Deque<Vec<u8, MAX_LORA_PACKET_LEN>, MEMORY_RADIO_FRAMES>,
+    busy_cads: u32,
+    cad_runs: u32,
+    asleep: bool,
+}
+
+impl MemoryRadio {
+    /// Create an unconfigured radio
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parameters given to the last [`MeshRadio::configure`]
+    pub fn params(&self) -> Option<&RadioParams> {
+        self.params.as_ref()
+    }
+
+    /// Queue a frame to be received with the given signal
+    pub fn inject(&mut self, frame: &[u8], rssi: i16, snr: i16) -> Result<(), MemoryRadioError> {
+        let frame = Vec::from_slice(frame).map_err(|_| MemoryRadioError::TooLong)?;
+        self.inbound
+            .push_back((frame, rssi, snr))
+            .map_err(|_| MemoryRadioError::Full)
+    }
+
+    /// Take the oldest frame sent
+    pub fn take_sent(&mut self) -> Option<Vec<u8, MAX_LORA_PACKET_LEN>> {
+        self.sent.pop_front()
+    }
+
+    /// Make the next `count` channel activity detections hear a transmission
+    pub fn set_busy(&mut self, count: u32) {
+        self.busy_cads = count;
+    }
+
+    /// Number of channel activity detections run so far
+    pub fn cad_runs(&self) -> u32 {
+        self.cad_runs
+    }
+
+    /// Whether the radio was put to sleep and not used since
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    fn wake(&mut self) -> Result<(), MemoryRadioError> {
+        if self.params.is_none() {
+            return Err(MemoryRadioError::NotConfigured);
+        }
+        self.asleep = false;
+        Ok(())
+    }
+}
+
+impl TxRadio for MemoryRadio {
+    type Error = MemoryRadioError;
+
+    async fn channel_activity(&mut self) -> Result<bool, MemoryRadioError> {
+        self.wake()?;
+        self.cad_runs += 1;
+        let busy = self.busy_cads > 0;
+        self.busy_cads = self.busy_cads.saturating_sub(1);
+        Ok(busy)
+    }
+
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), MemoryRadioError> {
+        self.wake()?;
+        let frame = Vec::from_slice(frame).map_err(|_| MemoryRadioError::TooLong)?;
+        self.sent
+            .push_back(frame)
+            .map_err(|_| MemoryRadioError::Full)
+    }
+}
+
+impl MeshRadio for MemoryRadio {
+    async fn configure(&mut self, params: &RadioParams) -> Result<(), MemoryRadioError> {
+        self.params = Some(*params);
+        self.asleep = false;
+        Ok(())
+    }
+
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout_ms: Option<u64>,
+    ) -> Result<Option<RxMetadata>, MemoryRadioError> {
+        self.wake()?;
+        let Some((frame, rssi, snr)) = self.inbound.pop_front() else {
+            return match timeout_ms {
+                Some(_) => Ok(None),
+                None => Err(MemoryRadioError::WouldBlock),
+            };
+        };
+        let len = frame.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&frame[..len]);
+        Ok(Some(RxMetadata { len, rssi, snr }))
+    }
+
+    async fn sleep(&mut self) -> Result<(), MemoryRadioError> {
+        self.wake()?;
+        self.asleep = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::BROADCAST_ADDR;
+    use crate::radio_config::{ModemSettings, Region};
+    use crate::test_util::{block_on, packet, FixedRng, OTHER_NODE, OUR_NODE};
+    use crate::tx_queue::TxQueue;
+    use meshtastic_protobufs::meshtastic::config::lo_ra_config::RegionCode;
+    use meshtastic_protobufs::meshtastic::mesh_packet::Priority;
+
+    // A node with a TX queue and a clock the test moves forward
+    #[derive(Default)]
+    struct TestHost {
+        now_ms: u64,
+        queue: TxQueue<4>,
+        received: u32,
+        sent: u32,
+        dropped: u32,
+        rx_airtime_ms: u32,
+        // Answer of the next `may_transmit`, allowed when unset
+        permission: Option<TxPermission>,
+    }
+
+    impl RadioHost for TestHost {
+        fn now_ms(&self) -> u64 {
+            self.now_ms
+        }
+
+        fn channel_utilization_percent(&self) -> f32 {
+            0.0
+        }
+
+        fn idle_wait_ms(&mut self) -> Option<u64> {
+            Some(1_000)
+        }
+
+        fn next_frame(&mut self, buffer: &mut [u8]) -> Option<TxFrame> {
+            let packet = &self.queue.peek()?.packet;
+            let len = packet.to_bytes(buffer).ok()?;
+            let contention = if packet.header.source == OUR_NODE {
+                Contention::Own
+            } else {
+                Contention::Relay { snr: packet.snr }
+            };
+            Some(TxFrame {
+                header: packet.header,
+                len,
+                contention,
+            })
+        }
+
+        fn may_transmit(&mut self, _len: usize) -> TxPermission {
+            self.permission.take().unwrap_or(TxPermission::Allowed)
+        }
+
+        fn frame_sent(&mut self, frame: &TxFrame, _airtime_ms: u32, _slot_time_ms: u32) {
+            self.sent += 1;
+            self.queue
+                .cancel(frame.header.source, frame.header.packet_id);
+        }
+
+        fn frame_dropped(&mut self, frame: &TxFrame) {
+            self.dropped += 1;
+            self.queue
+                .cancel(frame.header.source, frame.header.packet_id);
+        }
+
+        async fn frame_received(&mut self, _frame: &[u8], _metadata: &RxMetadata, airtime_ms: u32) {
+            self.received += 1;
+            self.rx_airtime_ms += airtime_ms;
+        }
+    }
+
+    fn params() -> RadioParams {
+        RadioParams {
+            region: Region::get(RegionCode::Us).unwrap(),
+            modem: ModemSettings {
+                bandwidth_hz: 250_000,
+                spreading_factor: 11,
+                coding_rate: 5,
+            },
+            frequency_hz: 906_875_000,
+            channel_num: Some(19),
+            num_channels: 104,
+            tx_power_dbm: 20,
+            duty_cycle_percent: 100,
+            tx_enabled: true,
+        }
+    }
+
+    fn configured() -> Transceiver<MemoryRadio> {
+        let mut transceiver = Transceiver::new(MemoryRadio::new(), params(), false);
+        block_on(transceiver.configure()).unwrap();
+        assert_eq!(transceiver.radio_mut().params(), Some(&params()));
+        transceiver
+    }
+
+    #[test]
+    fn test_receive_hands_frames_to_host() {
+        let mut transceiver = configured();
+        let mut host = TestHost::default();
+        let mut rng = FixedRng(0);
+
+        let mut frame = [0u8; 64];
+        let len = packet(OTHER_NODE, BROADCAST_ADDR, 1)
+            .to_bytes(&mut frame)
+            .unwrap();
+        transceiver
+            .radio_mut()
+            .inject(&frame[..len], -90, 6)
+            .unwrap();
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Received(RxMetadata {
+                len,
+                rssi: -90,
+                snr: 6
+            }))
+        );
+        assert_eq!(host.received, 1);
+        assert!(host.rx_airtime_ms > 0);
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Idle)
+        );
+    }
+
+    #[test]
+    fn test_queued_frame_waits_for_slot_and_clear_channel() {
+        let mut transceiver = configured();
+        let mut host = TestHost::default();
+        // Slot 3 of the smallest window, 29 ms slots
+        let mut rng = FixedRng(3);
+        host.queue
+            .enqueue(packet(OUR_NODE, BROADCAST_ADDR, 1), Priority::Default);
+
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Scheduled { wait_ms: 87 })
+        );
+        host.now_ms = 87;
+        transceiver.radio_mut().set_busy(1);
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::ChannelBusy { wait_ms: 87 })
+        );
+        assert!(transceiver.radio_mut().take_sent().is_none());
+
+        // A higher priority packet queued meanwhile takes the slot
+        host.queue
+            .enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Ack);
+        host.now_ms = 174;
+        let header = packet(OUR_NODE, BROADCAST_ADDR, 2).header;
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Sent(header))
+        );
+        let sent = transceiver.radio_mut().take_sent().unwrap();
+        assert_eq!(Header::from_bytes(&sent[..16]).unwrap().packet_id, 2);
+        assert_eq!(transceiver.radio_mut().cad_runs(), 2);
+        assert_eq!((host.sent, host.queue.len()), (1, 1));
+    }
+
+    #[test]
+    fn test_cancelled_relay_and_disabled_tx() {
+        let mut transceiver = configured();
+        let mut host = TestHost::default();
+        let mut rng = FixedRng(0);
+
+        // Relays wait for the router slots first
+        host.queue
+            .enqueue(packet(OTHER_NODE, BROADCAST_ADDR, 1), Priority::Default);
+        assert!(matches!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Scheduled { .. })
+        ));
+        // Another node relayed it first
+        host.queue.cancel(OTHER_NODE, 1);
+        host.now_ms = 10_000;
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Idle)
+        );
+        assert!(transceiver.radio_mut().take_sent().is_none());
+
+        let mut disabled = params();
+        disabled.tx_enabled = false;
+        block_on(transceiver.reconfigure(disabled, false)).unwrap();
+        host.queue
+            .enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Default);
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Dropped(
+                packet(OUR_NODE, BROADCAST_ADDR, 2).header
+            ))
+        );
+        assert_eq!((host.dropped, host.queue.len()), (1, 0));
+
+        block_on(transceiver.radio_mut().sleep()).unwrap();
+        assert!(transceiver.radio_mut().is_asleep());
+    }
+
+    #[test]
+    fn test_delayed_frame_stays_queued() {
+        let mut transceiver = configured();
+        let mut host = TestHost::default();
+        let mut rng = FixedRng(0);
+        host.queue
+            .enqueue(packet(OUR_NODE, BROADCAST_ADDR, 1), Priority::Default);
+        host.permission = Some(TxPermission::Delayed { wait_ms: 5_000 });
+
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Deferred { wait_ms: 5_000 })
+        );
+        host.now_ms = 1_000;
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Deferred { wait_ms: 4_000 })
+        );
+        assert_eq!((host.dropped, host.queue.len()), (0, 1));
+
+        // Sent once the wait is over
+        host.now_ms = 5_000;
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Sent(packet(OUR_NODE, BROADCAST_ADDR, 1).header))
+        );
+        assert_eq!((host.sent, host.queue.len()), (1, 0));
+
+        // A frame that can never fit is dropped
+        host.queue
+            .enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Default);
+        host.permission = Some(TxPermission::Refused);
+        assert_eq!(
+            block_on(transceiver.step(&mut host, &mut rng)),
+            Ok(RadioEvent::Dropped(
+                packet(OUR_NODE, BROADCAST_ADDR, 2).header
+            ))
+        );
+        assert_eq!((host.dropped, host.queue.len()), (1, 0));
+    }
+}
@@ -0,0 +1,423 @@
+//! LoRa region and modem preset tables
+//!
+//! Meshtastic radios agree on a frequency without any coordination: each
+//! region defines a band, the modem preset (or custom modem settings) defines
+//! the bandwidth, and the band is split into slots one bandwidth wide. The slot
+//! is picked by hashing the primary channel name with [`channel_hash`], so
+//! every node on "LongFast" in the US ends up on 906.875 MHz, unless the
+//! configuration sets an explicit `channel_num` or `override_frequency`.
+//!
+//! [`RadioParams::from_settings`] turns the LoRa settings of the configuration
+//! into validated radio parameters for the firmware to apply.
+
+use meshtastic_protobufs::meshtastic::config::lo_ra_config::{ModemPreset, RegionCode};
+
+use crate::channel_hash;
+use crate::channel_url::LoraSettings;
+
+/// Transmit power used when a region does not define a limit
+pub const DEFAULT_TX_POWER_DBM: i8 = 17;
+
+/// Preamble length in symbols Meshtastic uses on every preset
+pub const PREAMBLE_LEN: u16 = 16;
+
+/// A regulatory region: the band Meshtastic uses and its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Region {
+    pub code: RegionCode,
+    /// Lowest frequency of the band
+    pub freq_start_hz: u32,
+    /// Highest frequency of the band
+    pub freq_end_hz: u32,
+    /// Share of the time we may transmit, in percent
+    pub duty_cycle_percent: u8,
+    /// Guard band between two slots
+    pub spacing_hz: u32,
+    /// Highest allowed transmit power, 0 if the region sets no limit
+    pub power_limit_dbm: i8,
+    /// 2.4 GHz band, where the presets use wider bandwidths
+    pub wide_lora: bool,
+}
+
+const fn region(
+    code: RegionCode,
+    freq_start_khz: u32,
+    freq_end_khz: u32,
+    duty_cycle_percent: u8,
+    power_limit_dbm: i8,
+    wide_lora: bool,
+) -> Region {
+    Region {
+        code,
+        freq_start_hz: freq_start_khz * 1000,
+        freq_end_hz: freq_end_khz * 1000,
+        duty_cycle_percent,
+        spacing_hz: 0,
+        power_limit_dbm,
+        wide_lora,
+    }
+}
+
+/// Meshtastic's region table
+pub const REGIONS: &[Region] = &[
+    region(RegionCode::Us, 902_000, 928_000, 100, 30, false),
+    region(RegionCode::Eu433, 433_000, 434_000, 10, 10, false),
+    region(RegionCode::Eu868, 869_400, 869_650, 10, 27, false),
+    region(RegionCode::Cn, 470_000, 510_000, 100, 19, false),
+    region(RegionCode::Jp, 920_500, 923_500, 100, 13, false),
+    region(RegionCode::Anz, 915_000, 928_000, 100, 30, false),
+    region(RegionCode::Anz433, 433_050, 434_790, 100, 14, false),
+    region(RegionCode::Ru, 868_700, 869_200, 100, 20, false),
+    region(RegionCode::Kr, 920_000, 923_000, 100, 23, false),
+    region(RegionCode::Tw, 920_000, 925_000, 100, 27, false),
+    region(RegionCode::In, 865_000, 867_000, 100, 30, false),
+    region(RegionCode::Nz865, 864_000, 868_000, 100, 36, false),
+    region(RegionCode::Th, 920_000, 925_000, 100, 16, false),
+    region(RegionCode::Ua433, 433_000, 434_700, 10, 10, false),
+    region(RegionCode::Ua868, 868_000, 868_600, 1, 14, false),
+    region(RegionCode::My433, 433_000, 435_000, 100, 20, false),
+    region(RegionCode::My919, 919_000, 924_000, 100, 27, false),
+    region(RegionCode::Sg923, 917_000, 925_000, 100, 20, false),
+    region(RegionCode::Ph433, 433_000, 434_700, 100, 10, false),
+    region(RegionCode::Ph868, 868_000, 869_400, 100, 14, false),
+    region(RegionCode::Ph915, 915_000, 918_000, 100, 24, false),
+    region(RegionCode::Kz433, 433_075, 434_775, 100, 10, false),
+    region(RegionCode::Kz863, 863_000, 868_000, 100, 30, false),
+    region(RegionCode::Np865, 865_000, 868_000, 100, 30, false),
+    region(RegionCode::Br902, 902_000, 907_500, 100, 30, false),
+    region(RegionCode::Lora24, 2_400_000, 2_483_500, 100, 10, true),
+];
+
+impl Region {
+    /// Look up a region, `None` for `RegionCode::Unset`
+    pub fn get(code: RegionCode) -> Option<&'static Region> {
+        REGIONS.iter().find(|region| region.code == code)
+    }
+
+    /// Width of the band
+    pub fn width_hz(&self) -> u32 {
+        self.freq_end_hz - self.freq_start_hz
+    }
+}
+
+/// Bandwidth, spreading factor and coding rate of a LoRa modulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ModemSettings {
+    pub bandwidth_hz: u32,
+    /// Spreading factor, 7 to 12
+    pub spreading_factor: u8,
+    /// Denominator of the coding rate 4/x, 5 to 8
+    pub coding_rate: u8,
+}
+
+impl ModemSettings {
+    /// Settings of a modem preset, `wide_lora` selects the 2.4 GHz bandwidths
+    pub fn from_preset(preset: ModemPreset, wide_lora: bool) -> Self {
+        let (bandwidth_khz, wide_bandwidth_hz, spreading_factor, coding_rate) = match preset {
+            ModemPreset::ShortTurbo => (500, 1_625_000, 7, 5),
+            ModemPreset::ShortFast => (250, 812_500, 7, 5),
+            ModemPreset::ShortSlow => (250, 812_500, 8, 5),
+            ModemPreset::MediumFast => (250, 812_500, 9, 5),
+            ModemPreset::MediumSlow => (250, 812_500, 10, 5),
+            ModemPreset::LongFast => (250, 812_500, 11, 5),
+            ModemPreset::LongModerate => (125, 406_250, 11, 8),
+            ModemPreset::LongSlow => (125, 406_250, 12, 8),
+            ModemPreset::VeryLongSlow => (62, 203_125, 12, 8),
+        };
+        let bandwidth_hz = match (wide_lora, bandwidth_khz) {
+            (true, _) => wide_bandwidth_hz,
+            (false, 62) => 62_500,
+            (false, khz) => khz * 1000,
+        };
+        Self {
+            bandwidth_hz,
+            spreading_factor,
+            coding_rate,
+        }
+    }
+
+    /// Custom settings from the configuration, `bandwidth_khz` as Meshtastic rounds it
+    ///
+    /// Like the Meshtastic firmware, 31, 62, 200, 400, 800 and 1600 stand for
+    /// the fractional bandwidths 31.25, 62.5, 203.125, 406.25, 812.5 and 1625 kHz.
+    pub fn custom(
+        bandwidth_khz: u32,
+        spreading_factor: u32,
+        coding_rate: u32,
+    ) -> Result<Self, RadioConfigError> {
+        let bandwidth_hz = match bandwidth_khz {
+            0 => return Err(RadioConfigError::InvalidBandwidth),
+            31 => 31_250,
+            62 => 62_500,
+            200 => 203_125,
+            400 => 406_250,
+            800 => 812_500,
+            1600 => 1_625_000,
+            khz => khz
+                .checked_mul(1000)
+                .ok_or(RadioConfigError::InvalidBandwidth)?,
+        };
+        if !(7..=12).contains(&spreading_factor) {
+            return Err(RadioConfigError::InvalidSpreadingFactor);
+        }
+        if !(5..=8).contains(&coding_rate) {
+            return Err(RadioConfigError::InvalidCodingRate);
+        }
+        Ok(Self {
+            bandwidth_hz,
+            spreading_factor: spreading_factor as u8,
+            coding_rate: coding_rate as u8,
+        })
+    }
+}
+
+/// Errors that can occur when computing the radio parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioConfigError {
+    /// No region is configured, the radio must not transmit
+    RegionUnset,
+    /// The custom bandwidth is 0
+    InvalidBandwidth,
+    /// The custom spreading factor is not between 7 and 12
+    InvalidSpreadingFactor,
+    /// The custom coding rate is not between 5 and 8
+    InvalidCodingRate,
+    /// The bandwidth is wider than the region's band
+    BandwidthTooWide,
+    /// The explicit channel number is beyond the region's last slot
+    InvalidChannelNum,
+    /// The frequency offset moves the channel out of the region's band
+    FrequencyOutOfRange,
+}
+
+/// Radio parameters the firmware applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RadioParams {
+    pub region: &'static Region,
+    pub modem: ModemSettings,
+    /// Center frequency
+    pub frequency_hz: u32,
+    /// Slot of the frequency in the region's band, counted from 0, `None` for an override frequency
+    pub channel_num: Option<u32>,
+    /// Number of slots in the region's band
+    pub num_channels: u32,
+    /// Transmit power, within the region's limit
+    pub tx_power_dbm: i8,
+    /// Share of the time we may transmit, 100 when the region has no limit or it is overridden
+    pub duty_cycle_percent: u8,
+    pub tx_enabled: bool,
+}
+
+// MHz as stored in the configuration, to Hz
+fn mhz_to_hz(mhz: f32) -> i64 {
+    let hz = f64::from(mhz) * 1_000_000.0;
+    if hz < 0.0 {
+        (hz - 0.5) as i64
+    } else {
+        (hz + 0.5) as i64
+    }
+}
+
+impl RadioParams {
+    /// Compute the radio parameters from the configuration and the primary channel's name
+    ///
+    /// The frequency slot is `channel_num - 1` when the configuration sets
+    /// one, otherwise the hash of the primary channel name modulo the number
+    /// of slots. `frequency_offset` and `override_frequency` are in MHz.
+    pub fn from_settings(
+        settings: &LoraSettings,
+        primary_channel_name: &str,
+    ) -> Result<Self, RadioConfigError> {
+        let region = Region::get(settings.region).ok_or(RadioConfigError::RegionUnset)?;
+        let modem = if settings.use_preset {
+            ModemSettings::from_preset(settings.modem_preset, region.wide_lora)
+        } else {
+            ModemSettings::custom(
+                settings.bandwidth,
+                settings.spread_factor,
+                settings.coding_rate,
+            )?
+        };
+
+        // Slots are one bandwidth wide plus the guard band, the last one needs no guard band
+        let slot_width_hz = region.spacing_hz + modem.bandwidth_hz;
+        let num_channels =
+            (region.width_hz() + region.spacing_hz + slot_width_hz / 2) / slot_width_hz;
+        if num_channels == 0 {
+            return Err(RadioConfigError::BandwidthTooWide);
+        }
+
+        let slot = if settings.channel_num != 0 {
+            if settings.channel_num > num_channels {
+                return Err(RadioConfigError::InvalidChannelNum);
+            }
+            settings.channel_num - 1
+        } else {
+            channel_hash(primary_channel_name) % num_channels
+        };
+
+        let (frequency_hz, channel_num) = if settings.override_frequency > 0.0 {
+            let frequency_hz = mhz_to_hz(settings.override_frequency);
+            (
+                u32::try_from(frequency_hz).map_err(|_| RadioConfigError::FrequencyOutOfRange)?,
+                None,
+            )
+        } else {
+            let center_hz = i64::from(region.freq_start_hz)
+                + i64::from(modem.bandwidth_hz / 2)
+                + i64::from(slot) * i64::from(slot_width_hz)
+                + mhz_to_hz(settings.frequency_offset);
+            let half_bandwidth_hz = i64::from(modem.bandwidth_hz / 2);
+            if center_hz - half_bandwidth_hz < i64::from(region.freq_start_hz)
+                || center_hz + half_bandwidth_hz > i64::from(region.freq_end_hz)
+            {
+                return Err(RadioConfigError::FrequencyOutOfRange);
+            }
+            (center_hz as u32, Some(slot))
+        };
+
+        let tx_power_dbm = match settings.tx_power {
+            0 if region.power_limit_dbm == 0 => DEFAULT_TX_POWER_DBM,
+            0 => region.power_limit_dbm,
+            power if region.power_limit_dbm != 0 => {
+                power.clamp(i32::from(i8::MIN), i32::from(region.power_limit_dbm)) as i8
+            }
+            power => power.clamp(i32::from(i8::MIN), i32::from(i8::MAX)) as i8,
+        };
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Radio: {} Hz, slot {} of {}, {:?}",
+            frequency_hz,
+            channel_num,
+            num_channels,
+            modem
+        );
+
+        Ok(Self {
+            region,
+            modem,
+            frequency_hz,
+            channel_num,
+            num_channels,
+            tx_power_dbm,
+            duty_cycle_percent: if settings.override_duty_cycle {
+                100
+            } else {
+                region.duty_cycle_percent
+            },
+            tx_enabled: settings.tx_enabled,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(region: RegionCode, preset: ModemPreset) -> LoraSettings {
+        LoraSettings {
+            use_preset: true,
+            modem_preset: preset,
+            region,
+            tx_enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_region_table() {
+        // One entry per region code except Unset
+        assert_eq!(REGIONS.len(), RegionCode::Br902 as usize);
+        for (index, region) in REGIONS.iter().enumerate() {
+            assert_ne!(region.code, RegionCode::Unset);
+            assert!(REGIONS[..index].iter().all(|other| other.code != region.code));
+            assert!(region.freq_start_hz < region.freq_end_hz);
+        }
+        assert!(Region::get(RegionCode::Unset).is_none());
+        assert_eq!(Region::get(RegionCode::Eu868).unwrap().duty_cycle_percent, 10);
+    }
+
+    #[test]
+    fn test_us_long_fast_default_slot() {
+        let params = RadioParams::from_settings(
+            &settings(RegionCode::Us, ModemPreset::LongFast),
+            "LongFast",
+        )
+        .unwrap();
+        assert_eq!(params.num_channels, 104);
+        assert_eq!(params.channel_num, Some(19));
+        assert_eq!(params.frequency_hz, 906_875_000);
+        assert_eq!(
+            params.modem,
+            ModemSettings {
+                bandwidth_hz: 250_000,
+                spreading_factor: 11,
+                coding_rate: 5,
+            }
+        );
+        assert_eq!(params.tx_power_dbm, 30);
+    }
+
+    #[test]
+    fn test_eu_868_and_explicit_channel() {
+        let mut eu = settings(RegionCode::Eu868, ModemPreset::LongFast);
+        eu.tx_power = 30;
+        let params = RadioParams::from_settings(&eu, "LongFast").unwrap();
+        // The band only fits one 250 kHz slot
+        assert_eq!(params.num_channels, 1);
+        assert_eq!(params.frequency_hz, 869_525_000);
+        assert_eq!(params.tx_power_dbm, 27);
+        assert_eq!(params.duty_cycle_percent, 10);
+        eu.override_duty_cycle = true;
+        let params = RadioParams::from_settings(&eu, "LongFast").unwrap();
+        assert_eq!(params.duty_cycle_percent, 100);
+
+        let mut us = settings(RegionCode::Us, ModemPreset::ShortTurbo);
+        us.channel_num = 1;
+        let params = RadioParams::from_settings(&us, "ignored").unwrap();
+        assert_eq!(params.num_channels, 52);
+        assert_eq!(params.frequency_hz, 902_250_000);
+        us.channel_num = 53;
+        assert_eq!(
+            RadioParams::from_settings(&us, "ignored"),
+            Err(RadioConfigError::InvalidChannelNum)
+        );
+    }
+
+    #[test]
+    fn test_custom_modem_and_errors() {
+        let mut custom = settings(RegionCode::Us, ModemPreset::LongFast);
+        custom.use_preset = false;
+        custom.bandwidth = 62;
+        custom.spread_factor = 12;
+        custom.coding_rate = 8;
+        let params = RadioParams::from_settings(&custom, "LongFast").unwrap();
+        assert_eq!(params.modem.bandwidth_hz, 62_500);
+
+        custom.spread_factor = 13;
+        assert_eq!(
+            RadioParams::from_settings(&custom, "LongFast"),
+            Err(RadioConfigError::InvalidSpreadingFactor)
+        );
+        assert_eq!(
+            RadioParams::from_settings(&LoraSettings::default(), "LongFast"),
+            Err(RadioConfigError::RegionUnset)
+        );
+
+        let mut wide = settings(RegionCode::Eu868, ModemPreset::ShortTurbo);
+        assert_eq!(
+            RadioParams::from_settings(&wide, "LongFast"),
+            Err(RadioConfigError::FrequencyOutOfRange)
+        );
+        wide.override_frequency = 869.525;
+        wide.modem_preset = ModemPreset::LongFast;
+        let params = RadioParams::from_settings(&wide, "LongFast").unwrap();
+        assert_eq!(params.channel_num, None);
+        assert_eq!(params.frequency_hz / 1000, 869_525);
+    }
+}
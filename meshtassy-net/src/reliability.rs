@@ -0,0 +1,684 @@
+//! Reliable delivery
+//!
+//! Packets sent with `HeaderFlags::want_ack` are answered by their destination
+//! with a ROUTING_APP message whose `Data::request_id` is the id of the
+//! acknowledged packet: an error reason of `NONE` is an ACK, anything else is
+//! a NAK.
+//!
+//! This module builds those replies for packets addressed to us, and keeps our
+//! own want_ack packets in a bounded [`RetransmissionTable`] until they are
+//! acknowledged. Retry counts and timeouts follow Meshtastic's `ReliableRouter`:
+//! a packet is sent at most [`NUM_RELIABLE_RETX`] times before it is reported
+//! as [`DeliveryStatus::MaxRetransmit`].
+//!
+//! Nobody answers a broadcast with an ACK. Instead, hearing a neighbor relay
+//! one of our broadcasts counts as an implicit ACK, see
+//! [`RetransmissionTable::handle_overheard`].
+
+use femtopb::{EnumValue, Message as _};
+use heapless::Vec;
+use meshtastic_protobufs::meshtastic::{routing, PortNum};
+
+use crate::app_payload::Routing;
+use crate::header::{Header, HeaderFlags, BROADCAST_ADDR};
+use crate::next_hop::NO_NEXT_HOP_PREFERENCE;
+use crate::{AppPayload, DecodedPacket, Encrypted, OwnedData, Packet, ReceivedPacket};
+
+/// Number of times a want_ack packet is transmitted, the first transmission included
+pub const NUM_RELIABLE_RETX: u8 = 3;
+
+/// Maximum number of packets waiting for an ACK
+pub const MAX_PENDING_ACKS: usize = 8;
+
+/// Smallest contention window exponent
+pub const CW_MIN: u8 = 3;
+
+/// Largest contention window exponent
+pub const CW_MAX: u8 = 8;
+
+/// Time allowed for the destination to process a packet and answer
+pub const PROCESSING_TIME_MS: u32 = 4500;
+
+/// Retransmission timeout of a short LongFast packet on an idle channel
+///
+/// Use [`retransmission_timeout_ms`] when the airtime of the packet is known.
+pub const DEFAULT_RETRANSMIT_TIMEOUT_MS: u32 = 10_000;
+
+/// Time to wait for an ACK before retransmitting
+///
+/// Same formula as Meshtastic's `RadioInterface::getRetransmissionMsec`: the
+/// packet and its ACK on air, the longest contention window the channel
+/// utilization calls for, and time for the destination to process the packet.
+pub fn retransmission_timeout_ms(
+    packet_airtime_ms: u32,
+    slot_time_ms: u32,
+    channel_utilization_percent: u8,
+) -> u32 {
+    let utilization = channel_utilization_percent.min(100) as u32;
+    let cw_size = CW_MIN as u32 + (CW_MAX - CW_MIN) as u32 * utilization / 100;
+    let slots = (1 << cw_size) + 2 * CW_MAX as u32 + (1 << ((CW_MAX + CW_MIN) / 2));
+    2 * packet_airtime_ms + slots * slot_time_ms + PROCESSING_TIME_MS
+}
+
+/// Hop limit for a reply, enough to reach the sender of `request` back
+///
+/// Mirrors Meshtastic's `getHopLimitForResponse`: the number of hops the
+/// request used plus two, but never less than that number and never more than
+/// `configured_hop_limit` unless the request itself needed more.
+pub fn hop_limit_for_response(request: &Header, configured_hop_limit: u8) -> u8 {
+    let HeaderFlags {
+        hop_start,
+        hop_limit,
+        ..
+    } = request.flags;
+    if hop_start == 0 {
+        // Old firmware does not record the hops used
+        return configured_hop_limit;
+    }
+    if hop_start < hop_limit {
+        return configured_hop_limit;
+    }
+    let hops_used = hop_start - hop_limit;
+    if hops_used > configured_hop_limit {
+        hops_used
+    } else if hops_used + 2 < configured_hop_limit {
+        hops_used + 2
+    } else {
+        configured_hop_limit
+    }
+}
+
+/// Returns true if `packet` is addressed to `node_num` and asks for an ACK
+///
+/// Routing messages are never acknowledged, so ACKs cannot bounce back and forth.
+pub fn wants_ack_from<P: ReceivedPacket + ?Sized>(packet: &P, node_num: u32) -> bool {
+    let header = packet.header();
+    header.destination == node_num
+        && header.flags.want_ack
+        && packet.port_num() != EnumValue::Known(PortNum::RoutingApp)
+}
+
+/// Build the ROUTING_APP reply to `request`
+///
+/// `reason` is `routing::Error::None` for an ACK, or the reason the packet
+/// could not be handled for a NAK. The reply goes back on the channel the
+/// request came in on and still has to be encoded and encrypted.
+pub fn routing_reply(
+    request: &Header,
+    node_num: u32,
+    packet_id: u32,
+    reason: routing::Error,
+    hop_limit: u8,
+) -> DecodedPacket {
+    let message = meshtastic_protobufs::meshtastic::Routing {
+        variant: Some(routing::Variant::ErrorReason(EnumValue::Known(reason))),
+        ..Default::default()
+    };
+    let mut payload = [0u8; 240];
+    let payload_len = message.encoded_len();
+    // A Routing message holding only an error reason is at most 11 bytes long
+    let mut slice = &mut payload[..payload_len];
+    let _ = message.encode(&mut slice);
+
+    let data = OwnedData {
+        portnum: EnumValue::Known(PortNum::RoutingApp),
+        payload,
+        payload_len,
+        want_response: false,
+        dest: 0,
+        source: 0,
+        request_id: request.packet_id,
+        reply_id: 0,
+        emoji: 0,
+        bitfield: None,
+    };
+    let header = Header::new(
+        request.source,
+        node_num,
+        packet_id,
+        HeaderFlags {
+            hop_limit,
+            want_ack: false,
+            via_mqtt: false,
+            hop_start: hop_limit,
+        },
+        request.channel_hash,
+        0,
+        (node_num & 0xFF) as u8,
+    );
+    DecodedPacket::new(header, data)
+}
+
+/// Build the ROUTING_APP packet that tells our client one of its packets failed
+///
+/// The packet comes from `node_num` and is addressed to it, with the failed
+/// packet's id as `request_id`, the way Meshtastic reports a NAK to its
+/// clients. Returns `None` when the report is a successful delivery.
+pub fn delivery_failure(
+    report: &DeliveryReport,
+    node_num: u32,
+    packet_id: u32,
+) -> Option<DecodedPacket> {
+    let reason = match report.status {
+        DeliveryStatus::MaxRetransmit => routing::Error::MaxRetransmit,
+        DeliveryStatus::NakReceived(EnumValue::Known(reason)) if reason != routing::Error::None => {
+            reason
+        }
+        _ => return None,
+    };
+    let flags = HeaderFlags {
+        hop_limit: 0,
+        want_ack: false,
+        via_mqtt: false,
+        hop_start: 0,
+    };
+    let request = Header::new(node_num, node_num, report.packet_id, flags, 0, 0, 0);
+    Some(routing_reply(&request, node_num, packet_id, reason, 0))
+}
+
+/// Final outcome of a want_ack packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeliveryStatus {
+    /// The destination acknowledged the packet
+    Acked,
+    /// A node answered with a NAK for this reason
+    NakReceived(EnumValue<routing::Error>),
+    /// A neighbor relayed our broadcast, `relay_node` is the relay byte of its copy
+    ImplicitAck { relay_node: u8 },
+    /// No ACK arrived after the last retransmission
+    MaxRetransmit,
+}
+
+/// Delivery status of one of our packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeliveryReport {
+    /// Id of the packet the report is about
+    pub packet_id: u32,
+    /// Destination of that packet
+    pub destination: u32,
+    pub status: DeliveryStatus,
+}
+
+/// Work produced by [`RetransmissionTable::poll`]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RetransmitEvent<'a> {
+    /// No ACK yet, send this packet again
+    Retransmit(&'a Packet<Encrypted>),
+    /// Last attempt: the next hop did not deliver, so this copy is flooded
+    ///
+    /// Forget the route to the destination, e.g. with `Router::forget_next_hop`.
+    Fallback(&'a Packet<Encrypted>),
+    /// The packet was given up on
+    Failed(DeliveryReport),
+}
+
+/// Reasons a packet cannot be tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReliabilityError {
+    /// The packet was not sent with want_ack, no ACK will come back
+    NoAckRequested,
+    /// Every entry of the table is in use
+    TableFull,
+}
+
+#[derive(Clone)]
+struct PendingPacket {
+    packet: Packet<Encrypted>,
+    retransmits_left: u8,
+    timeout_ms: u32,
+    next_tx_ms: u64,
+}
+
+/// Our want_ack packets waiting for an ACK
+///
+/// Hand every want_ack packet to [`RetransmissionTable::track`] once it has
+/// been transmitted, feed received packets to
+/// [`RetransmissionTable::handle_received`] and call
+/// [`RetransmissionTable::poll`] regularly to get the packets that are due for
+/// a retransmission.
+#[derive(Clone)]
+pub struct RetransmissionTable<const N: usize = MAX_PENDING_ACKS> {
+    node_num: u32,
+    pending: Vec<PendingPacket, N>,
+}
+
+impl<const N: usize> RetransmissionTable<N> {
+    /// Create an empty table for the node with the given node number
+    pub fn new(node_num: u32) -> Self {
+        Self {
+            node_num,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Number of packets waiting for an ACK
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns true if the packet with this id is waiting for an ACK
+    pub fn is_pending(&self, packet_id: u32) -> bool {
+        self.position(self.node_num, packet_id).is_some()
+    }
+
+    /// Start waiting for an ACK to a packet that was just transmitted at `now_ms`
+    ///
+    /// The packet is retransmitted every `timeout_ms` until it is acknowledged
+    /// or has been sent [`NUM_RELIABLE_RETX`] times.
+    pub fn track(
+        &mut self,
+        packet: &Packet<Encrypted>,
+        now_ms: u64,
+        timeout_ms: u32,
+    ) -> Result<(), ReliabilityError> {
+        if !packet.header.flags.want_ack {
+            return Err(ReliabilityError::NoAckRequested);
+        }
+        // Sending the same packet again restarts its retries
+        if let Some(index) = self.position(packet.header.source, packet.header.packet_id) {
+            self.pending.remove(index);
+        }
+
+        let entry = PendingPacket {
+            packet: packet.clone(),
+            retransmits_left: NUM_RELIABLE_RETX - 1,
+            timeout_ms,
+            next_tx_ms: now_ms + timeout_ms as u64,
+        };
+        if self.pending.push(entry).is_err() {
+            #[cfg(feature = "defmt")]
+            defmt::warn!(
+                "Reliability: table full, not tracking 0x{:08X}",
+                packet.header.packet_id
+            );
+            return Err(ReliabilityError::TableFull);
+        }
+        Ok(())
+    }
+
+    /// Stop waiting for an ACK to the packet with this id
+    /// Returns true if the packet was pending
+    pub fn cancel(&mut self, packet_id: u32) -> bool {
+        let Some(index) = self.position(self.node_num, packet_id) else {
+            return false;
+        };
+        self.pending.remove(index);
+        true
+    }
+
+    /// Check a received packet for an ACK or NAK of one of our packets
+    ///
+    /// Any packet addressed to us whose `request_id` names a pending packet
+    /// ends its retransmissions: a ROUTING_APP error reason reports an ACK or
+    /// NAK, and any other reply (a traceroute response, for example) is proof
+    /// of delivery as well.
+    pub fn handle_received<P: ReceivedPacket + ?Sized>(
+        &mut self,
+        packet: &P,
+    ) -> Option<DeliveryReport> {
+        if packet.header().destination != self.node_num {
+            return None;
+        }
+        let request_id = packet.data_message().ok()?.request_id;
+        if request_id == 0 {
+            return None;
+        }
+        let index = self.position(self.node_num, request_id)?;
+
+        let status = match packet.app_payload() {
+            Ok(AppPayload::Routing(Routing::Error(EnumValue::Known(routing::Error::None)))) => {
+                DeliveryStatus::Acked
+            }
+            Ok(AppPayload::Routing(Routing::Error(reason))) => DeliveryStatus::NakReceived(reason),
+            _ => DeliveryStatus::Acked,
+        };
+        let entry = self.pending.remove(index);
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Reliability: 0x{:08X} to 0x{:08X}: {:?}",
+            request_id,
+            entry.packet.header.destination,
+            status
+        );
+        Some(DeliveryReport {
+            packet_id: request_id,
+            destination: entry.packet.header.destination,
+            status,
+        })
+    }
+
+    /// Check an overheard packet for a relay of one of our broadcasts
+    ///
+    /// A copy matching a pending broadcast on (`source`, `packet_id`) with a
+    /// lower hop limit than we sent means a neighbor received and rebroadcast
+    /// it, so retrying would only cost airtime. Only the header is needed, so
+    /// this works before (or without) decrypting the packet.
+    pub fn handle_overheard(&mut self, header: &Header) -> Option<DeliveryReport> {
+        let index = self.position(header.source, header.packet_id)?;
+        let sent = &self.pending[index].packet.header;
+        if sent.destination != BROADCAST_ADDR || header.flags.hop_limit >= sent.flags.hop_limit {
+            return None;
+        }
+        self.pending.remove(index);
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Reliability: 0x{:08X} relayed by {:02X}, implicit ACK",
+            header.packet_id,
+            header.relay_node
+        );
+        Some(DeliveryReport {
+            packet_id: header.packet_id,
+            destination: header.destination,
+            status: DeliveryStatus::ImplicitAck {
+                relay_node: header.relay_node,
+            },
+        })
+    }
+
+    /// Take the next retransmission or failure that is due at `now_ms`
+    ///
+    /// Call repeatedly until it returns `None`.
+    pub fn poll(&mut self, now_ms: u64) -> Option<RetransmitEvent<'_>> {
+        let index = self
+            .pending
+            .iter()
+            .position(|entry| entry.next_tx_ms <= now_ms)?;
+
+        if self.pending[index].retransmits_left == 0 {
+            let entry = self.pending.remove(index);
+            #[cfg(feature = "defmt")]
+            defmt::debug!(
+                "Reliability: giving up on 0x{:08X}",
+                entry.packet.header.packet_id
+            );
+            return Some(RetransmitEvent::Failed(DeliveryReport {
+                packet_id: entry.packet.header.packet_id,
+                destination: entry.packet.header.destination,
+                status: DeliveryStatus::MaxRetransmit,
+            }));
+        }
+
+        let entry = &mut self.pending[index];
+        entry.retransmits_left -= 1;
+        entry.next_tx_ms = now_ms + entry.timeout_ms as u64;
+        if entry.retransmits_left == 0 && entry.packet.header.next_hop != NO_NEXT_HOP_PREFERENCE {
+            // The header is not covered by the encryption, so it can change between attempts
+            entry.packet.header.next_hop = NO_NEXT_HOP_PREFERENCE;
+            #[cfg(feature = "defmt")]
+            defmt::debug!(
+                "Reliability: flooding last attempt of 0x{:08X}",
+                entry.packet.header.packet_id
+            );
+            return Some(RetransmitEvent::Fallback(&entry.packet));
+        }
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Reliability: retransmitting 0x{:08X} ({} left)",
+            entry.packet.header.packet_id,
+            entry.retransmits_left
+        );
+        Some(RetransmitEvent::Retransmit(&entry.packet))
+    }
+
+    /// Earliest time at which [`RetransmissionTable::poll`] has work to do
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.pending.iter().map(|entry| entry.next_tx_ms).min()
+    }
+
+    fn position(&self, source: u32, packet_id: u32) -> Option<usize> {
+        self.pending.iter().position(|entry| {
+            entry.packet.header.source == source && entry.packet.header.packet_id == packet_id
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::ChannelKey;
+    use crate::test_util::{header, OTHER_NODE, OUR_NODE};
+
+    fn want_ack(mut header: Header) -> Header {
+        header.flags.want_ack = true;
+        header
+    }
+
+    fn sent_packet(packet_id: u32) -> Packet<Encrypted> {
+        let data = OwnedData::new(PortNum::TextMessageApp, b"hello").unwrap();
+        DecodedPacket::new(want_ack(header(OUR_NODE, OTHER_NODE, packet_id)), data)
+            .encode()
+            .unwrap()
+            .encrypt(&ChannelKey::from_bytes(&[], 0).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_retransmission_timeout() {
+        // 682 ms LongFast packet with a 77 ms slot time on an idle channel
+        assert_eq!(retransmission_timeout_ms(682, 77, 0), 10_176);
+        // A busy channel widens the contention window
+        assert!(retransmission_timeout_ms(682, 77, 100) > retransmission_timeout_ms(682, 77, 50));
+    }
+
+    #[test]
+    fn test_hop_limit_for_response() {
+        let mut request = want_ack(header(OTHER_NODE, OUR_NODE, 1));
+        request.flags.hop_limit = 2;
+        // One hop used, two more for margin
+        assert_eq!(hop_limit_for_response(&request, 7), 3);
+        // Capped at the configured limit
+        assert_eq!(hop_limit_for_response(&request, 3), 3);
+        // Old firmware without hop_start
+        request.flags.hop_start = 0;
+        assert_eq!(hop_limit_for_response(&request, 3), 3);
+    }
+
+    #[test]
+    fn test_ack_reply_round_trip() {
+        let request = want_ack(header(OTHER_NODE, OUR_NODE, 0xCAFE));
+        let ack = routing_reply(&request, OUR_NODE, 77, routing::Error::None, 3);
+
+        assert_eq!(ack.header.destination, OTHER_NODE);
+        assert_eq!(ack.header.source, OUR_NODE);
+        assert_eq!(ack.header.packet_id, 77);
+        assert_eq!(ack.header.channel_hash, request.channel_hash);
+        assert!(!ack.header.flags.want_ack);
+        assert_eq!(ack.data.request_id, 0xCAFE);
+
+        let decoded = ack.encode().unwrap().decode().unwrap();
+        assert_eq!(
+            decoded.app_payload(),
+            Ok(AppPayload::Routing(Routing::Error(EnumValue::Known(
+                routing::Error::None
+            ))))
+        );
+        assert!(!wants_ack_from(&decoded, OTHER_NODE));
+    }
+
+    #[test]
+    fn test_wants_ack_from() {
+        let data = OwnedData::new(PortNum::TextMessageApp, b"hi").unwrap();
+        let to_us = DecodedPacket::new(want_ack(header(OTHER_NODE, OUR_NODE, 1)), data.clone());
+        assert!(wants_ack_from(&to_us, OUR_NODE));
+
+        let no_ack = DecodedPacket::new(header(OTHER_NODE, OUR_NODE, 1), data.clone());
+        assert!(!wants_ack_from(&no_ack, OUR_NODE));
+
+        let to_other = DecodedPacket::new(want_ack(header(OTHER_NODE, 0x5555, 1)), data);
+        assert!(!wants_ack_from(&to_other, OUR_NODE));
+    }
+
+    #[test]
+    fn test_ack_stops_retransmission() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        table.track(&sent_packet(0x100), 0, 1000).unwrap();
+        assert!(table.is_pending(0x100));
+        assert!(table.poll(999).is_none());
+
+        let request = want_ack(header(OUR_NODE, OTHER_NODE, 0x100));
+        let ack = routing_reply(&request, OTHER_NODE, 5, routing::Error::None, 3);
+        assert_eq!(
+            table.handle_received(&ack),
+            Some(DeliveryReport {
+                packet_id: 0x100,
+                destination: OTHER_NODE,
+                status: DeliveryStatus::Acked,
+            })
+        );
+        assert_eq!(table.pending_count(), 0);
+        assert!(table.poll(10_000).is_none());
+
+        // A second copy of the ACK is not reported again
+        assert_eq!(table.handle_received(&ack), None);
+    }
+
+    #[test]
+    fn test_nak_reports_reason() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        table.track(&sent_packet(0x200), 0, 1000).unwrap();
+
+        let request = want_ack(header(OUR_NODE, OTHER_NODE, 0x200));
+        let nak = routing_reply(&request, OTHER_NODE, 6, routing::Error::NoChannel, 3);
+        assert_eq!(
+            table.handle_received(&nak).map(|report| report.status),
+            Some(DeliveryStatus::NakReceived(EnumValue::Known(
+                routing::Error::NoChannel
+            )))
+        );
+        assert!(!table.is_pending(0x200));
+    }
+
+    #[test]
+    fn test_max_retransmit() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        table.track(&sent_packet(0x300), 0, 1000).unwrap();
+        assert_eq!(table.next_deadline(), Some(1000));
+
+        // The first transmission plus NUM_RELIABLE_RETX - 1 retransmissions
+        for attempt in 1..NUM_RELIABLE_RETX as u64 {
+            let Some(RetransmitEvent::Retransmit(packet)) = table.poll(attempt * 1000) else {
+                panic!("expected a retransmission");
+            };
+            assert_eq!(packet.header.packet_id, 0x300);
+            assert!(table.poll(attempt * 1000).is_none());
+        }
+
+        let Some(RetransmitEvent::Failed(report)) = table.poll(NUM_RELIABLE_RETX as u64 * 1000)
+        else {
+            panic!("expected a failure");
+        };
+        assert_eq!(report.status, DeliveryStatus::MaxRetransmit);
+        assert_eq!(report.destination, OTHER_NODE);
+        assert_eq!(table.pending_count(), 0);
+
+        // The client hears about it as a NAK of its packet
+        let failure = delivery_failure(&report, OUR_NODE, 0x301)
+            .unwrap()
+            .encode()
+            .unwrap();
+        assert_eq!(failure.header.source, OUR_NODE);
+        assert_eq!(failure.header.destination, OUR_NODE);
+        let decoded = failure.decode().unwrap();
+        assert_eq!(decoded.data.request_id, 0x300);
+        assert_eq!(
+            decoded.app_payload(),
+            Ok(AppPayload::Routing(Routing::Error(EnumValue::Known(
+                routing::Error::MaxRetransmit
+            ))))
+        );
+
+        let acked = DeliveryReport {
+            status: DeliveryStatus::Acked,
+            ..report
+        };
+        assert!(delivery_failure(&acked, OUR_NODE, 0x302).is_none());
+    }
+
+    #[test]
+    fn test_overheard_relay_is_implicit_ack() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        let mut broadcast = sent_packet(0x400);
+        broadcast.header.destination = BROADCAST_ADDR;
+        table.track(&broadcast, 0, 1000).unwrap();
+
+        // Our own transmission echoed back unchanged is not a relay
+        assert_eq!(table.handle_overheard(&broadcast.header), None);
+        // Neither is somebody else's packet that happens to share the id
+        let mut other = broadcast.header;
+        other.source = OTHER_NODE;
+        other.flags.hop_limit = 1;
+        assert_eq!(table.handle_overheard(&other), None);
+        assert!(table.is_pending(0x400));
+
+        let mut relayed = broadcast.header;
+        relayed.flags.hop_limit = 1;
+        relayed.relay_node = 0x99;
+        assert_eq!(
+            table.handle_overheard(&relayed),
+            Some(DeliveryReport {
+                packet_id: 0x400,
+                destination: BROADCAST_ADDR,
+                status: DeliveryStatus::ImplicitAck { relay_node: 0x99 },
+            })
+        );
+        assert_eq!(table.pending_count(), 0);
+        assert!(table.poll(10_000).is_none());
+    }
+
+    #[test]
+    fn test_relayed_direct_message_waits_for_real_ack() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        let packet = sent_packet(0x500);
+        table.track(&packet, 0, 1000).unwrap();
+
+        let mut relayed = packet.header;
+        relayed.flags.hop_limit = 1;
+        assert_eq!(table.handle_overheard(&relayed), None);
+        assert!(table.is_pending(0x500));
+    }
+
+    #[test]
+    fn test_last_attempt_falls_back_to_flooding() {
+        let mut table: RetransmissionTable = RetransmissionTable::new(OUR_NODE);
+        let mut packet = sent_packet(0x600);
+        packet.header.next_hop = 0x42;
+        table.track(&packet, 0, 1000).unwrap();
+
+        let Some(RetransmitEvent::Retransmit(retry)) = table.poll(1000) else {
+            panic!("expected a retransmission");
+        };
+        assert_eq!(retry.header.next_hop, 0x42);
+
+        let Some(RetransmitEvent::Fallback(flood)) = table.poll(2000) else {
+            panic!("expected a flooded retransmission");
+        };
+        assert_eq!(flood.header.next_hop, NO_NEXT_HOP_PREFERENCE);
+        assert_eq!(flood.payload, packet.payload);
+    }
+
+    #[test]
+    fn test_table_is_bounded() {
+        let mut table: RetransmissionTable<2> = RetransmissionTable::new(OUR_NODE);
+        table.track(&sent_packet(1), 0, 1000).unwrap();
+        table.track(&sent_packet(2), 0, 1000).unwrap();
+        assert_eq!(
+            table.track(&sent_packet(3), 0, 1000),
+            Err(ReliabilityError::TableFull)
+        );
+
+        let mut no_ack = sent_packet(4);
+        no_ack.header.flags.want_ack = false;
+        assert_eq!(
+            table.track(&no_ack, 0, 1000),
+            Err(ReliabilityError::NoAckRequested)
+        );
+        assert!(table.cancel(1));
+        assert!(table.track(&sent_packet(3), 0, 1000).is_ok());
+    }
+}
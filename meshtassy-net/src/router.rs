@@ -0,0 +1,514 @@
+//! Managed flood routing
+//!
+//! This module implements the rebroadcast rules used by Meshtastic's
+//! "managed flood" router. Packets are relayed while they still have hops
+//! left, but a node never relays its own packets and cancels a pending
+//! rebroadcast when it overhears another node relaying the same packet first.
+//!
+//! Routing works on encrypted packets: the header is sent in the clear, so a
+//! node can relay packets for channels it does not have the key for.
+//!
+//! Direct messages that name a `next_hop` are only relayed by that neighbor,
+//! which in turn picks the next hop from its own [`NextHopTable`]. Packets
+//! without a next hop are flooded.
+//!
+//! Duplicate detection is left to [`PacketHistory`](crate::packet_history::PacketHistory),
+//! which is shared with the rest of the receive path.
+
+use heapless::Vec;
+
+use crate::header::{Header, BROADCAST_ADDR};
+use crate::next_hop::{NextHopTable, NO_NEXT_HOP_PREFERENCE};
+use crate::packet_history::SeenStatus;
+use crate::{Encrypted, Packet, MAX_ENCRYPTED_PAYLOAD_LEN};
+
+/// Maximum number of rebroadcasts waiting to be transmitted
+pub const MAX_PENDING_REBROADCASTS: usize = 8;
+
+/// Outcome of handing a received packet to the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RouteDecision {
+    /// A modified copy of the packet was queued for rebroadcast
+    Rebroadcast,
+    /// Another node relayed the packet before us, so our pending rebroadcast was cancelled
+    Cancelled,
+    /// The packet will not be rebroadcast
+    Drop(DropReason),
+}
+
+/// Reasons a packet is not rebroadcast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DropReason {
+    /// The packet was originally sent by this node
+    FromUs,
+    /// The packet is addressed to this node, there is nobody to relay it to
+    AddressedToUs,
+    /// The packet names another node as its next hop
+    NotNextHop,
+    /// The packet has no hops left
+    HopLimitExhausted,
+    /// The packet was already heard (and relayed or dropped) by this node
+    Duplicate,
+    /// The rebroadcast queue is full
+    QueueFull,
+    /// The payload does not fit in a LoRa frame, the frame is malformed
+    PayloadTooLarge,
+}
+
+/// Managed flood router
+///
+/// Feed every received packet to [`Router::handle_received`], then drain the
+/// packets that should go back on air with [`Router::next_rebroadcast`].
+#[derive(Clone)]
+pub struct Router {
+    node_num: u32,
+    pending: Vec<Packet<Encrypted>, MAX_PENDING_REBROADCASTS>,
+    next_hops: NextHopTable,
+}
+
+impl Router {
+    /// Create a router for the node with the given node number
+    pub fn new(node_num: u32) -> Self {
+        Self {
+            node_num,
+            pending: Vec::new(),
+            next_hops: NextHopTable::new(),
+        }
+    }
+
+    /// Our node number
+    pub fn node_num(&self) -> u32 {
+        self.node_num
+    }
+
+    /// The value this node writes into `Header::relay_node` (last byte of the node number)
+    pub fn relay_id(&self) -> u8 {
+        (self.node_num & 0xFF) as u8
+    }
+
+    /// Number of rebroadcasts waiting to be transmitted
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Routes learned so far
+    pub fn next_hops(&self) -> &NextHopTable {
+        &self.next_hops
+    }
+
+    /// The `Header::next_hop` to use for a packet to `destination`
+    ///
+    /// `relay_node` is the relay byte of the copy being forwarded, or our own
+    /// [`Router::relay_id`] for packets we originate. A route leading back to
+    /// the node we got the packet from is not used. Returns
+    /// [`NO_NEXT_HOP_PREFERENCE`] (flood) for broadcasts and unknown routes.
+    pub fn next_hop(&self, destination: u32, relay_node: u8) -> u8 {
+        if destination == BROADCAST_ADDR {
+            return NO_NEXT_HOP_PREFERENCE;
+        }
+        match self.next_hops.get(destination) {
+            Some(next_hop) if next_hop != relay_node => next_hop,
+            _ => NO_NEXT_HOP_PREFERENCE,
+        }
+    }
+
+    /// Learn the route back to the sender of an ACK or reply to one of our packets
+    ///
+    /// The neighbor that relayed the reply to us (`Header::relay_node`) becomes
+    /// the next hop towards `Header::source`.
+    pub fn learn_next_hop(&mut self, reply: &Header) {
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Router: next hop to 0x{:08X} is {:02X}",
+            reply.source,
+            reply.relay_node
+        );
+        self.next_hops.learn(reply.source, reply.relay_node);
+    }
+
+    /// Forget the route to `destination` after it failed to deliver, so it is flooded again
+    pub fn forget_next_hop(&mut self, destination: u32) -> bool {
+        self.next_hops.forget(destination)
+    }
+
+    /// Decide whether a received packet should be rebroadcast
+    ///
+    /// `seen` is the classification returned by `PacketHistory::check_and_insert`
+    /// for this packet. If the packet is eligible, a copy with a decremented hop
+    /// limit and our relay id is queued and can be retrieved with
+    /// [`Router::next_rebroadcast`].
+    pub fn handle_received(
+        &mut self,
+        packet: &Packet<Encrypted>,
+        seen: SeenStatus,
+    ) -> RouteDecision {
+        self.handle_received_frame(
+            &packet.header,
+            &packet.payload[..packet.payload_len],
+            (packet.rssi, packet.snr),
+            seen,
+        )
+    }
+
+    /// Same as [`Router::handle_received`] for a packet held as a header and its encrypted payload
+    ///
+    /// The payload (at most 240 bytes) is only copied when a rebroadcast is
+    /// queued, so pooled packets can be routed before they are decrypted in place.
+    /// The rebroadcast keeps the `(rssi, snr)` the packet was received with,
+    /// which weights its contention window.
+    pub fn handle_received_frame(
+        &mut self,
+        header: &Header,
+        payload: &[u8],
+        (rssi, snr): (i8, i8),
+        seen: SeenStatus,
+    ) -> RouteDecision {
+        if payload.len() > MAX_ENCRYPTED_PAYLOAD_LEN {
+            #[cfg(feature = "defmt")]
+            defmt::warn!(
+                "Router: payload of 0x{:08X} is {} bytes, dropping it",
+                header.packet_id,
+                payload.len()
+            );
+            return RouteDecision::Drop(DropReason::PayloadTooLarge);
+        }
+
+        if header.source == self.node_num {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("Router: ignoring our own packet 0x{:08X}", header.packet_id);
+            return RouteDecision::Drop(DropReason::FromUs);
+        }
+
+        if seen.is_duplicate() {
+            // Hearing a packet we are about to relay means somebody else got there first
+            if self.cancel(header.source, header.packet_id) {
+                #[cfg(feature = "defmt")]
+                defmt::debug!(
+                    "Router: 0x{:08X} from 0x{:08X} relayed by {:02X} first, cancelling rebroadcast",
+                    header.packet_id,
+                    header.source,
+                    header.relay_node
+                );
+                return RouteDecision::Cancelled;
+            }
+            return RouteDecision::Drop(DropReason::Duplicate);
+        }
+
+        if header.destination == self.node_num {
+            return RouteDecision::Drop(DropReason::AddressedToUs);
+        }
+
+        let we_are_next_hop = header.next_hop == self.relay_id();
+        if header.next_hop != NO_NEXT_HOP_PREFERENCE && !we_are_next_hop {
+            #[cfg(feature = "defmt")]
+            defmt::trace!(
+                "Router: 0x{:08X} is for next hop {:02X}",
+                header.packet_id,
+                header.next_hop
+            );
+            return RouteDecision::Drop(DropReason::NotNextHop);
+        }
+
+        if header.flags.hop_limit == 0 {
+            #[cfg(feature = "defmt")]
+            defmt::trace!("Router: hop limit exhausted for 0x{:08X}", header.packet_id);
+            return RouteDecision::Drop(DropReason::HopLimitExhausted);
+        }
+
+        let mut buffer = [0u8; 240];
+        buffer[..payload.len()].copy_from_slice(payload);
+        let mut rebroadcast = Packet::new(*header, rssi, snr, buffer, payload.len());
+        rebroadcast.header.flags.hop_limit -= 1;
+        rebroadcast.header.relay_node = self.relay_id();
+        if we_are_next_hop {
+            rebroadcast.header.next_hop = self.next_hop(header.destination, header.relay_node);
+        }
+
+        if self.pending.push(rebroadcast).is_err() {
+            #[cfg(feature = "defmt")]
+            defmt::warn!(
+                "Router: rebroadcast queue full, dropping 0x{:08X}",
+                header.packet_id
+            );
+            return RouteDecision::Drop(DropReason::QueueFull);
+        }
+
+        #[cfg(feature = "defmt")]
+        defmt::debug!(
+            "Router: queued rebroadcast of 0x{:08X} from 0x{:08X} (hop_limit {} -> {})",
+            header.packet_id,
+            header.source,
+            header.flags.hop_limit,
+            header.flags.hop_limit - 1
+        );
+        RouteDecision::Rebroadcast
+    }
+
+    /// Take the oldest pending rebroadcast, ready to be serialized with `Packet::to_bytes`
+    pub fn next_rebroadcast(&mut self) -> Option<Packet<Encrypted>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(self.pending.remove(0))
+    }
+
+    /// Cancel a pending rebroadcast
+    /// Returns true if a matching packet was waiting to be sent
+    pub fn cancel(&mut self, source: u32, packet_id: u32) -> bool {
+        let Some(index) = self
+            .pending
+            .iter()
+            .position(|p| p.header.source == source && p.header.packet_id == packet_id)
+        else {
+            return false;
+        };
+        self.pending.remove(index);
+        true
+    }
+
+    /// Replace the encrypted payload of a pending rebroadcast
+    ///
+    /// Lets modules that add to the packets they relay, like traceroute, do
+    /// so before the packet goes back on air. Returns false if no rebroadcast
+    /// of the packet is pending or the payload does not fit.
+    pub fn update_rebroadcast(&mut self, source: u32, packet_id: u32, payload: &[u8]) -> bool {
+        let Some(packet) = self
+            .pending
+            .iter_mut()
+            .find(|p| p.header.source == source && p.header.packet_id == packet_id)
+        else {
+            return false;
+        };
+        // The payload buffer is one byte longer than a frame can carry
+        if payload.len() > MAX_ENCRYPTED_PAYLOAD_LEN {
+            return false;
+        }
+        packet.payload[..payload.len()].copy_from_slice(payload);
+        packet.payload_len = payload.len();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_history::PacketHistory;
+    use crate::test_util::{header, packet, OTHER_NODE, OUR_NODE};
+
+    fn receive(
+        router: &mut Router,
+        history: &mut PacketHistory,
+        pkt: &Packet<Encrypted>,
+    ) -> RouteDecision {
+        let seen = history.check_and_insert(&pkt.header, 0);
+        router.handle_received(pkt, seen)
+    }
+
+    #[test]
+    fn test_rebroadcast_decrements_hop_limit_and_sets_relay() {
+        let mut router = Router::new(OUR_NODE);
+        let mut history = PacketHistory::new();
+        let mut pkt = packet(OTHER_NODE, 0xFFFF_FFFF, 1);
+        pkt.header.flags.hop_start = 7;
+
+        assert_eq!(
+            receive(&mut router, &mut history, &pkt),
+            RouteDecision::Rebroadcast
+        );
+
+        let relayed = router.next_rebroadcast().unwrap();
+        assert_eq!(relayed.header.flags.hop_limit, 2);
+        assert_eq!(relayed.header.flags.hop_start, 7);
+        assert_eq!(relayed.header.relay_node, 0x78);
+        assert_eq!(relayed.payload[..4], pkt.payload[..4]);
+        assert!(router.next_rebroadcast().is_none());
+    }
+
+    #[test]
+    fn test_rebroadcast_bytes_round_trip() {
+        let mut router = Router::new(OUR_NODE);
+        router.handle_received(&packet(OTHER_NODE, 0xFFFF_FFFF, 7), SeenStatus::New);
+        let relayed = router.next_rebroadcast().unwrap();
+
+        let mut buffer = [0u8; 256];
+        let len = relayed.to_bytes(&mut buffer).unwrap();
+        assert_eq!(len, 20);
+
+        let parsed = Packet::<Encrypted>::from_bytes(&buffer[..len], 0, 0).unwrap();
+        assert_eq!(parsed.header, relayed.header);
+        assert_eq!(parsed.payload[..4], relayed.payload[..4]);
+    }
+
+    #[test]
+    fn test_no_rebroadcast_at_hop_limit_zero() {
+        let mut router = Router::new(OUR_NODE);
+        let mut pkt = packet(OTHER_NODE, 0xFFFF_FFFF, 1);
+        pkt.header.flags.hop_limit = 0;
+        let decision = router.handle_received(&pkt, SeenStatus::New);
+        assert_eq!(decision, RouteDecision::Drop(DropReason::HopLimitExhausted));
+        assert_eq!(router.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_never_relay_own_packets() {
+        let mut router = Router::new(OUR_NODE);
+        let decision = router.handle_received(&packet(OUR_NODE, 0xFFFF_FFFF, 1), SeenStatus::New);
+        assert_eq!(decision, RouteDecision::Drop(DropReason::FromUs));
+    }
+
+    #[test]
+    fn test_packets_for_us_are_not_relayed() {
+        let mut router = Router::new(OUR_NODE);
+        let decision = router.handle_received(&packet(OTHER_NODE, OUR_NODE, 1), SeenStatus::New);
+        assert_eq!(decision, RouteDecision::Drop(DropReason::AddressedToUs));
+    }
+
+    #[test]
+    fn test_overheard_relay_cancels_pending_rebroadcast() {
+        let mut router = Router::new(OUR_NODE);
+        let mut history = PacketHistory::new();
+        let pkt = packet(OTHER_NODE, 0xFFFF_FFFF, 42);
+        assert_eq!(
+            receive(&mut router, &mut history, &pkt),
+            RouteDecision::Rebroadcast
+        );
+
+        let mut relayed_by_neighbor = pkt.clone();
+        relayed_by_neighbor.header.flags.hop_limit = 2;
+        relayed_by_neighbor.header.relay_node = 0x99;
+        assert_eq!(
+            receive(&mut router, &mut history, &relayed_by_neighbor),
+            RouteDecision::Cancelled
+        );
+        assert_eq!(router.pending_count(), 0);
+
+        // Any further copies are ignored
+        assert_eq!(
+            receive(&mut router, &mut history, &relayed_by_neighbor),
+            RouteDecision::Drop(DropReason::Duplicate)
+        );
+    }
+
+    #[test]
+    fn test_already_relayed_packet_is_not_queued_again() {
+        let mut router = Router::new(OUR_NODE);
+        let mut history = PacketHistory::new();
+        let pkt = packet(OTHER_NODE, 0xFFFF_FFFF, 5);
+        receive(&mut router, &mut history, &pkt);
+        router.next_rebroadcast().unwrap();
+
+        assert_eq!(
+            receive(&mut router, &mut history, &pkt),
+            RouteDecision::Drop(DropReason::Duplicate)
+        );
+    }
+
+    #[test]
+    fn test_only_the_named_next_hop_relays() {
+        let mut router = Router::new(OUR_NODE);
+        let mut direct = packet(OTHER_NODE, 0x0000_5555, 1);
+        direct.header.next_hop = 0x55;
+        assert_eq!(
+            router.handle_received(&direct, SeenStatus::New),
+            RouteDecision::Drop(DropReason::NotNextHop)
+        );
+
+        // We are the next hop but have no route onwards: flood from here
+        direct.header.packet_id = 2;
+        direct.header.next_hop = router.relay_id();
+        assert_eq!(
+            router.handle_received(&direct, SeenStatus::New),
+            RouteDecision::Rebroadcast
+        );
+        assert_eq!(
+            router.next_rebroadcast().unwrap().header.next_hop,
+            NO_NEXT_HOP_PREFERENCE
+        );
+
+        // With a learned route the relayed copy names our next hop
+        let mut reply = header(0x0000_5555, OUR_NODE, 9);
+        reply.relay_node = 0x66;
+        router.learn_next_hop(&reply);
+        direct.header.packet_id = 3;
+        router.handle_received(&direct, SeenStatus::New);
+        assert_eq!(router.next_rebroadcast().unwrap().header.next_hop, 0x66);
+    }
+
+    #[test]
+    fn test_next_hop_selection() {
+        let mut router = Router::new(OUR_NODE);
+        assert_eq!(
+            router.next_hop(OTHER_NODE, router.relay_id()),
+            NO_NEXT_HOP_PREFERENCE
+        );
+
+        let mut reply = header(OTHER_NODE, OUR_NODE, 1);
+        reply.relay_node = 0x42;
+        router.learn_next_hop(&reply);
+        assert_eq!(router.next_hop(OTHER_NODE, router.relay_id()), 0x42);
+        // Never hand a packet back to the node it came from
+        assert_eq!(router.next_hop(OTHER_NODE, 0x42), NO_NEXT_HOP_PREFERENCE);
+        assert_eq!(
+            router.next_hop(BROADCAST_ADDR, router.relay_id()),
+            NO_NEXT_HOP_PREFERENCE
+        );
+
+        assert!(router.forget_next_hop(OTHER_NODE));
+        assert_eq!(
+            router.next_hop(OTHER_NODE, router.relay_id()),
+            NO_NEXT_HOP_PREFERENCE
+        );
+    }
+
+    #[test]
+    fn test_update_pending_rebroadcast() {
+        let mut router = Router::new(OUR_NODE);
+        router.handle_received(&packet(OTHER_NODE, 0xFFFF_FFFF, 1), SeenStatus::New);
+
+        // The payload must still fit in a frame with the header
+        assert!(router.update_rebroadcast(OTHER_NODE, 1, &[0; MAX_ENCRYPTED_PAYLOAD_LEN]));
+        assert!(!router.update_rebroadcast(OTHER_NODE, 1, &[0; MAX_ENCRYPTED_PAYLOAD_LEN + 1]));
+        assert!(router.update_rebroadcast(OTHER_NODE, 1, &[1, 2, 3, 4, 5, 6]));
+        assert!(!router.update_rebroadcast(OTHER_NODE, 2, &[1]));
+
+        let relayed = router.next_rebroadcast().unwrap();
+        assert_eq!(relayed.payload[..relayed.payload_len], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(relayed.header.flags.hop_limit, 2);
+    }
+
+    #[test]
+    fn test_oversized_payload_is_dropped() {
+        let mut router = Router::new(OUR_NODE);
+        let header = header(OTHER_NODE, 0xFFFF_FFFF, 1);
+        assert_eq!(
+            router.handle_received_frame(&header, &[0; 241], (0, 0), SeenStatus::New),
+            RouteDecision::Drop(DropReason::PayloadTooLarge)
+        );
+        assert_eq!(
+            router.handle_received_frame(
+                &header,
+                &[0; MAX_ENCRYPTED_PAYLOAD_LEN],
+                (0, 0),
+                SeenStatus::New
+            ),
+            RouteDecision::Rebroadcast
+        );
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut router = Router::new(OUR_NODE);
+        for id in 0..MAX_PENDING_REBROADCASTS as u32 {
+            assert_eq!(
+                router.handle_received(&packet(OTHER_NODE, 0xFFFF_FFFF, id), SeenStatus::New),
+                RouteDecision::Rebroadcast
+            );
+        }
+        assert_eq!(
+            router.handle_received(&packet(OTHER_NODE, 0xFFFF_FFFF, 100), SeenStatus::New),
+            RouteDecision::Drop(DropReason::QueueFull)
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Persistent records in flash
+//!
+//! State that must survive a reboot, like our PKI keypair and the settings a
+//! client changed, is kept as records in a few erase pages at the end of the
+//! MCU's flash. Each [`Record`] owns one page, so rewriting one record never
+//! puts another at risk.
+//!
+//! A record starts with a header holding a magic number, the payload length
+//! and a Fletcher-16 checksum of the payload. A page that was never written,
+//! or whose write was cut short by a reset, fails those checks and reads as
+//! missing, so the caller falls back to its defaults.
+
+use embedded_storage::nor_flash::NorFlash;
+use femtopb::Message;
+
+/// Largest record payload
+pub const MAX_RECORD_LEN: usize = 1024;
+
+/// Length of the header in front of each record
+pub const RECORD_HEADER_LEN: usize = 8;
+
+// "MSHY" in flash order
+const RECORD_MAGIC: u32 = 0x5948_534D;
+
+/// The records the firmware keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Record {
+    /// Our PKI keypair, see [`Keypair::to_bytes`](crate::pki::Keypair::to_bytes)
+    Keypair,
+    /// The owner, a `User` protobuf
+    Owner,
+    /// The device, position and LoRa configuration, a `LocalConfig` protobuf
+    Config,
+    /// The channels, a `ChannelFile` protobuf
+    Channels,
+}
+
+impl Record {
+    /// Number of records, each takes one erase page
+    pub const COUNT: usize = 4;
+
+    fn page(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Errors that can occur while reading or writing records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageError {
+    /// The flash driver reported an error
+    Flash,
+    /// The record is longer than [`MAX_RECORD_LEN`]
+    TooLarge,
+    /// The stored record does not fit in the buffer
+    BufferTooSmall,
+    /// The storage area does not start on an erase page
+    Misaligned,
+}
+
+// Fletcher-16, enough to notice a torn write or a page of another layout
+fn checksum(data: &[u8]) -> u16 {
+    let (mut a, mut b) = (0u16, 0u16);
+    for &byte in data {
+        a = (a + u16::from(byte)) % 255;
+        b = (b + a) % 255;
+    }
+    (b << 8) | a
+}
+
+/// Records kept in [`Record::COUNT`] erase pages of a flash
+pub struct FlashStore<F> {
+    flash: F,
+    offset: u32,
+}
+
+impl<F: NorFlash> FlashStore<F> {
+    /// Keep records in the pages starting at `offset`, which must start an erase page
+    ///
+    /// The area takes `Record::COUNT * F::ERASE_SIZE` bytes and must not overlap the firmware.
+    pub fn new(flash: F, offset: u32) -> Result<Self, StorageError> {
+        if !offset.is_multiple_of(F::ERASE_SIZE as u32)
+            || F::ERASE_SIZE < RECORD_HEADER_LEN + MAX_RECORD_LEN
+        {
+            return Err(StorageError::Misaligned);
+        }
+        Ok(Self { flash, offset })
+    }
+
+    /// Size of the storage area in bytes
+    pub fn area_len() -> u32 {
+        (Record::COUNT * F::ERASE_SIZE) as u32
+    }
+
+    /// The flash, for example to read the chip's unique ID
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn address(&self, record: Record) -> u32 {
+        self.offset + record.page() * F::ERASE_SIZE as u32
+    }
+
+    /// Read a record into `buffer`, returns its length or `None` if it was never saved
+    pub fn load(
+        &mut self,
+        record: Record,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, StorageError> {
+        let address = self.address(record);
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        self.flash
+            .read(address, &mut header)
+            .map_err(|_| StorageError::Flash)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let len = usize::from(u16::from_le_bytes([header[4], header[5]]));
+        let stored_checksum = u16::from_le_bytes([header[6], header[7]]);
+        if magic != RECORD_MAGIC || len > MAX_RECORD_LEN {
+            return Ok(None);
+        }
+        if len > buffer.len() {
+            return Err(StorageError::BufferTooSmall);
+        }
+
+        self.flash
+            .read(address + RECORD_HEADER_LEN as u32, &mut buffer[..len])
+            .map_err(|_| StorageError::Flash)?;
+        if checksum(&buffer[..len]) != stored_checksum {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("Storage: {} is corrupted, ignoring it", record);
+            return Ok(None);
+        }
+        Ok(Some(len))
+    }
+
+    /// Replace a record
+    pub fn save(&mut self, record: Record, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(StorageError::TooLarge);
+        }
+        let mut page = [0xFFu8; RECORD_HEADER_LEN + MAX_RECORD_LEN];
+        page[..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        page[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        page[6..8].copy_from_slice(&checksum(data).to_le_bytes());
+        page[RECORD_HEADER_LEN..RECORD_HEADER_LEN + data.len()].copy_from_slice(data);
+        let write_len = (RECORD_HEADER_LEN + data.len())
+            .next_multiple_of(F::WRITE_SIZE)
+            .min(page.len());
+
+        self.remove(record)?;
+        let address = self.address(record);
+        self.flash
+            .write(address, &page[..write_len])
+            .map_err(|_| StorageError::Flash)?;
+        #[cfg(feature = "defmt")]
+        defmt::debug!("Storage: saved {} ({} bytes)", record, data.len());
+        Ok(())
+    }
+
+    /// Replace a record with an encoded protobuf message
+    ///
+    /// Read it back with [`FlashStore::load`] and the message's `decode`.
+    pub fn save_message<'a, M: Message<'a>>(
+        &mut self,
+        record: Record,
+        message: &M,
+    ) -> Result<(), StorageError> {
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        let len = message.encoded_len();
+        let mut slice = buffer.get_mut(..len).ok_or(StorageError::TooLarge)?;
+        message
+            .encode(&mut slice)
+            .map_err(|_| StorageError::TooLarge)?;
+        self.save(record, &buffer[..len])
+    }
+
+    /// Erase a record, it reads as missing afterwards
+    pub fn remove(&mut self, record: Record) -> Result<(), StorageError> {
+        let address = self.address(record);
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+    const PAGE: usize = 2048;
+
+    // Flash whose writes can only clear bits, like NOR flash
+    struct RamFlash {
+        data: [u8; PAGE * (Record::COUNT + 1)],
+    }
+
+    #[derive(Debug)]
+    struct RamFlashError;
+
+    impl NorFlashError for RamFlashError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::OutOfBounds
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = RamFlashError;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), RamFlashError> {
+            let start = offset as usize;
+            let source = self
+                .data
+                .get(start..start + bytes.len())
+                .ok_or(RamFlashError)?;
+            bytes.copy_from_slice(source);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), RamFlashError> {
+            self.data
+                .get_mut(from as usize..to as usize)
+                .ok_or(RamFlashError)?
+                .fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), RamFlashError> {
+            assert_eq!(bytes.len() % Self::WRITE_SIZE, 0);
+            let start = offset as usize;
+            let target = self
+                .data
+                .get_mut(start..start + bytes.len())
+                .ok_or(RamFlashError)?;
+            for (cell, byte) in target.iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn store() -> FlashStore<RamFlash> {
+        let flash = RamFlash {
+            data: [0xFF; PAGE * (Record::COUNT + 1)],
+        };
+        FlashStore::new(flash, PAGE as u32).unwrap()
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut store = store();
+        let mut buffer = [0u8; 64];
+        assert_eq!(store.load(Record::Keypair, &mut buffer), Ok(None));
+
+        store.save(Record::Keypair, &[0xA5; 64]).unwrap();
+        store.save(Record::Owner, b"owner").unwrap();
+        assert_eq!(store.load(Record::Keypair, &mut buffer), Ok(Some(64)));
+        assert_eq!(buffer, [0xA5; 64]);
+
+        // Rewriting a record leaves the others alone
+        store.save(Record::Owner, b"new owner").unwrap();
+        assert_eq!(store.load(Record::Owner, &mut buffer), Ok(Some(9)));
+        assert_eq!(&buffer[..9], b"new owner");
+        assert_eq!(store.load(Record::Keypair, &mut buffer), Ok(Some(64)));
+
+        store.remove(Record::Owner).unwrap();
+        assert_eq!(store.load(Record::Owner, &mut buffer), Ok(None));
+        assert_eq!(
+            store.load(Record::Keypair, &mut [0u8; 16]),
+            Err(StorageError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_save_message() {
+        use meshtastic_protobufs::meshtastic::{config, LocalConfig};
+
+        let mut store = store();
+        let saved = LocalConfig {
+            device: Some(config::DeviceConfig {
+                node_info_broadcast_secs: 900,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        store.save_message(Record::Config, &saved).unwrap();
+
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        let len = store.load(Record::Config, &mut buffer).unwrap().unwrap();
+        let loaded = LocalConfig::decode(&buffer[..len]).unwrap();
+        assert_eq!(loaded.device.unwrap().node_info_broadcast_secs, 900);
+    }
+
+    #[test]
+    fn test_corrupted_record_reads_as_missing() {
+        let mut store = store();
+        store.save(Record::Config, &[1, 2, 3, 4, 5]).unwrap();
+        // A bit cleared in the payload
+        let address = store.address(Record::Config) as usize + RECORD_HEADER_LEN;
+        store.flash_mut().data[address] = 0;
+        assert_eq!(store.load(Record::Config, &mut [0u8; 8]), Ok(None));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut store = store();
+        assert_eq!(
+            store.save(Record::Channels, &[0; MAX_RECORD_LEN + 1]),
+            Err(StorageError::TooLarge)
+        );
+        store.save(Record::Channels, &[7; MAX_RECORD_LEN]).unwrap();
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        assert_eq!(
+            store.load(Record::Channels, &mut buffer),
+            Ok(Some(MAX_RECORD_LEN))
+        );
+        assert_eq!(
+            FlashStore::<RamFlash>::area_len(),
+            (PAGE * Record::COUNT) as u32
+        );
+
+        let flash = RamFlash {
+            data: [0xFF; PAGE * (Record::COUNT + 1)],
+        };
+        assert!(matches!(
+            FlashStore::new(flash, 100),
+            Err(StorageError::Misaligned)
+        ));
+    }
+}
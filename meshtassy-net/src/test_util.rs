@@ -0,0 +1,71 @@
+//! Fixtures shared by the unit tests
+
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use rand_core::RngCore;
+
+use crate::header::{Header, HeaderFlags};
+use crate::{Encrypted, Packet};
+
+/// Node number of the node under test
+pub const OUR_NODE: u32 = 0x1234_5678;
+
+/// Node number of another node in the mesh
+pub const OTHER_NODE: u32 = 0xA1B2_C3D4;
+
+/// Always returns the same value, so random delays and slots are predictable
+pub struct FixedRng(pub u32);
+
+impl RngCore for FixedRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0
+    }
+    fn next_u64(&mut self) -> u64 {
+        u64::from(self.0)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.fill(self.0 as u8);
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Run a future to completion, for mock radios whose futures never wait
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+/// Header of a packet sent by `source` on the default channel, three hops left of three
+pub fn header(source: u32, destination: u32, packet_id: u32) -> Header {
+    Header::new(
+        destination,
+        source,
+        packet_id,
+        HeaderFlags {
+            hop_limit: 3,
+            want_ack: false,
+            via_mqtt: false,
+            hop_start: 3,
+        },
+        0x08,
+        0x00,
+        (source & 0xFF) as u8,
+    )
+}
+
+/// Encrypted packet with a four-byte payload and the [`header`] above, heard at -40 dBm
+pub fn packet(source: u32, destination: u32, packet_id: u32) -> Packet<Encrypted> {
+    let mut payload = [0u8; 240];
+    payload[..4].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+    Packet::new(header(source, destination, packet_id), -40, 5, payload, 4)
+}
@@ -0,0 +1,302 @@
+//! Priority-ordered transmit queue
+//!
+//! Every packet that goes on air (ACKs, relays, retransmissions, our own
+//! messages and periodic broadcasts) waits in one bounded [`TxQueue`] for the
+//! half-duplex radio. Packets leave in order of their `MeshPacket.Priority`,
+//! oldest first among packets of the same priority, so an ACK never waits
+//! behind a batch of NodeInfo broadcasts.
+//!
+//! When the queue is full a new packet takes the place of the newest packet
+//! with a lower priority, if there is one. A queued packet can be cancelled,
+//! for example a relay another node sent before us.
+//!
+//! The queue state is reported to the client as a `QueueStatus` after each
+//! packet it sends, like the official firmware does.
+
+use femtopb::EnumValue;
+use heapless::Vec;
+use meshtastic_protobufs::meshtastic::mesh_packet::Priority;
+use meshtastic_protobufs::meshtastic::{PortNum, QueueStatus};
+
+use crate::{DecodedPacket, Encrypted, Packet};
+
+/// Default number of packets waiting for the radio
+pub const TX_QUEUE_LEN: usize = 16;
+
+/// `QueueStatus.res` of a packet that was queued
+pub const ERRNO_OK: i32 = 0;
+
+/// `QueueStatus.res` of a packet that could not be queued
+pub const ERRNO_UNKNOWN: i32 = 32;
+
+/// Priority a packet is queued with unless the sender picked one
+///
+/// ACKs and NAKs go first so the sender stops retransmitting, then packets
+/// that want an ACK, then everything else.
+pub fn default_priority(packet: &DecodedPacket) -> Priority {
+    if packet.data.portnum == EnumValue::Known(PortNum::RoutingApp) && packet.data.request_id != 0 {
+        Priority::Ack
+    } else if packet.header.flags.want_ack {
+        Priority::Reliable
+    } else {
+        Priority::Default
+    }
+}
+
+/// Result of [`TxQueue::enqueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EnqueueResult {
+    /// The packet was queued
+    Queued,
+    /// The queue was full, a lower priority packet was dropped to make room
+    Replaced { source: u32, packet_id: u32 },
+    /// The queue is full of packets with the same or a higher priority, the packet was dropped
+    Full,
+}
+
+impl EnqueueResult {
+    /// Whether the packet is waiting for the radio
+    pub fn is_queued(&self) -> bool {
+        !matches!(self, EnqueueResult::Full)
+    }
+
+    /// Error code reported in `QueueStatus.res`
+    pub fn error_code(&self) -> i32 {
+        if self.is_queued() {
+            ERRNO_OK
+        } else {
+            ERRNO_UNKNOWN
+        }
+    }
+}
+
+/// A packet waiting for the radio
+#[derive(Clone)]
+pub struct QueuedPacket {
+    pub packet: Packet<Encrypted>,
+    pub priority: Priority,
+}
+
+/// Bounded queue of packets waiting for the radio, highest priority first
+#[derive(Clone)]
+pub struct TxQueue<const N: usize = TX_QUEUE_LEN> {
+    // In the order packets were queued
+    entries: Vec<QueuedPacket, N>,
+}
+
+impl<const N: usize> Default for TxQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TxQueue<N> {
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Number of packets waiting
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no packet is waiting
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of packets that can still be queued without dropping one
+    pub fn free(&self) -> usize {
+        N - self.entries.len()
+    }
+
+    /// Maximum number of packets waiting
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Queue a packet, dropping a lower priority one if the queue is full
+    pub fn enqueue(&mut self, packet: Packet<Encrypted>, priority: Priority) -> EnqueueResult {
+        let mut result = EnqueueResult::Queued;
+        if self.entries.is_full() {
+            // Newest of the lowest priority packets
+            let lowest = self
+                .entries
+                .iter()
+                .enumerate()
+                .rev()
+                .min_by_key(|(_, entry)| entry.priority as i32);
+            let Some((index, _)) =
+                lowest.filter(|(_, entry)| (entry.priority as i32) < priority as i32)
+            else {
+                #[cfg(feature = "defmt")]
+                defmt::warn!(
+                    "TX queue: full, dropping 0x{:08X} ({})",
+                    packet.header.packet_id,
+                    priority.as_str_name()
+                );
+                return EnqueueResult::Full;
+            };
+            let dropped = self.entries.remove(index);
+            #[cfg(feature = "defmt")]
+            defmt::debug!(
+                "TX queue: dropping 0x{:08X} ({}) for 0x{:08X} ({})",
+                dropped.packet.header.packet_id,
+                dropped.priority.as_str_name(),
+                packet.header.packet_id,
+                priority.as_str_name()
+            );
+            result = EnqueueResult::Replaced {
+                source: dropped.packet.header.source,
+                packet_id: dropped.packet.header.packet_id,
+            };
+        }
+        // Room was made above
+        let _ = self.entries.push(QueuedPacket { packet, priority });
+        result
+    }
+
+    // Oldest of the highest priority packets
+    fn next_index(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, entry)| entry.priority as i32)
+            .map(|(index, _)| index)
+    }
+
+    /// The packet [`TxQueue::dequeue`] would return
+    pub fn peek(&self) -> Option<&QueuedPacket> {
+        self.next_index().map(|index| &self.entries[index])
+    }
+
+    /// Take the next packet to transmit
+    pub fn dequeue(&mut self) -> Option<QueuedPacket> {
+        self.next_index().map(|index| self.entries.remove(index))
+    }
+
+    /// Whether a packet is waiting
+    pub fn contains(&self, source: u32, packet_id: u32) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.packet.header.source == source && entry.packet.header.packet_id == packet_id
+        })
+    }
+
+    /// Take a waiting packet out of the queue, for example once it was sent
+    pub fn remove(&mut self, source: u32, packet_id: u32) -> Option<QueuedPacket> {
+        let index = self.entries.iter().position(|entry| {
+            entry.packet.header.source == source && entry.packet.header.packet_id == packet_id
+        })?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Drop a waiting packet, returns false if it was not queued
+    pub fn cancel(&mut self, source: u32, packet_id: u32) -> bool {
+        self.remove(source, packet_id).is_some()
+    }
+
+    /// Status reported to the client after it sent the packet `mesh_packet_id`
+    ///
+    /// `res` is [`EnqueueResult::error_code`], or [`ERRNO_UNKNOWN`] when the
+    /// packet could not be encoded in the first place.
+    pub fn status(&self, res: i32, mesh_packet_id: u32) -> QueueStatus<'static> {
+        QueueStatus {
+            res,
+            free: self.free() as u32,
+            maxlen: N as u32,
+            mesh_packet_id,
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::BROADCAST_ADDR;
+    use crate::test_util::{packet, OUR_NODE};
+    use crate::OwnedData;
+
+    fn ids<const N: usize>(queue: &mut TxQueue<N>) -> heapless::Vec<u32, N> {
+        let mut ids = heapless::Vec::new();
+        while let Some(entry) = queue.dequeue() {
+            let _ = ids.push(entry.packet.header.packet_id);
+        }
+        ids
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut queue: TxQueue<4> = TxQueue::new();
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 1), Priority::Background);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Default);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 3), Priority::Ack);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 4), Priority::Default);
+        assert_eq!(queue.peek().unwrap().packet.header.packet_id, 3);
+        assert_eq!(ids(&mut queue).as_slice(), &[3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn test_full_queue_drops_lowest_priority() {
+        let mut queue: TxQueue<3> = TxQueue::new();
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 1), Priority::Background);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Background);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 3), Priority::Reliable);
+        assert_eq!(queue.free(), 0);
+
+        assert_eq!(
+            queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 4), Priority::Background),
+            EnqueueResult::Full
+        );
+        assert_eq!(
+            queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 5), Priority::Ack),
+            EnqueueResult::Replaced {
+                source: OUR_NODE,
+                packet_id: 2
+            }
+        );
+        let status = queue.status(EnqueueResult::Full.error_code(), 4);
+        assert_eq!(
+            (
+                status.res,
+                status.free,
+                status.maxlen,
+                status.mesh_packet_id
+            ),
+            (ERRNO_UNKNOWN, 0, 3, 4)
+        );
+        assert_eq!(ids(&mut queue).as_slice(), &[5, 3, 1]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut queue: TxQueue<4> = TxQueue::new();
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 1), Priority::Default);
+        queue.enqueue(packet(OUR_NODE, BROADCAST_ADDR, 2), Priority::Default);
+        assert!(queue.cancel(OUR_NODE, 1));
+        assert!(!queue.cancel(OUR_NODE, 1));
+        assert!(queue.remove(OUR_NODE, 3).is_none());
+        assert!(!queue.contains(OUR_NODE, 1));
+        assert_eq!(queue.status(ERRNO_OK, 2).free, 3);
+        assert_eq!(ids(&mut queue).as_slice(), &[2]);
+    }
+
+    #[test]
+    fn test_default_priority() {
+        let mut reply = DecodedPacket::new(
+            packet(OUR_NODE, BROADCAST_ADDR, 1).header,
+            OwnedData::new(PortNum::TextMessageApp, b"hi").unwrap(),
+        );
+        assert_eq!(default_priority(&reply), Priority::Default);
+        reply.header.flags.want_ack = true;
+        assert_eq!(default_priority(&reply), Priority::Reliable);
+        reply.data = OwnedData::new(PortNum::RoutingApp, &[]).unwrap();
+        reply.data.request_id = 7;
+        assert_eq!(default_priority(&reply), Priority::Ack);
+    }
+}
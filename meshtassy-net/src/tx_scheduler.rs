@@ -0,0 +1,325 @@
+//! Listen-before-talk transmit scheduling
+//!
+//! Meshtastic nodes avoid collisions like a slotted CSMA/CA: before a frame
+//! goes out the node waits a random number of slots taken from a contention
+//! window, then runs channel activity detection (CAD) and backs off again if
+//! another node is transmitting.
+//!
+//! The window grows with the channel utilization for the packets we
+//! originate. Relays use a window weighted by the SNR the packet was received
+//! with instead, so that the nodes furthest from the sender (lowest SNR)
+//! rebroadcast first and the closer ones can cancel their copy when they
+//! overhear it. Routers get a window of their own, ahead of every other role.
+//!
+//! [`TxScheduler`] only keeps the timing; the radio is reached through the
+//! [`TxRadio`] trait so the scheduler can be driven by a mock radio in tests.
+
+use rand_core::RngCore;
+
+use crate::radio_config::ModemSettings;
+use crate::reliability::{CW_MAX, CW_MIN};
+
+// SNR range mapped onto the contention window for relays, in dB
+const SNR_MIN: i32 = -20;
+const SNR_MAX: i32 = 10;
+
+// Propagation, RX to TX turnaround and MAC processing time added to each slot
+const SLOT_OVERHEAD_US: u32 = 7_600;
+
+/// Radio operations needed to send a frame politely
+#[allow(async_fn_in_trait)]
+pub trait TxRadio {
+    type Error;
+
+    /// Run channel activity detection, true if another node is transmitting
+    async fn channel_activity(&mut self) -> Result<bool, Self::Error>;
+
+    /// Send a frame and wait for the end of the transmission
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Which contention window a frame uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Contention {
+    /// A packet we originate, the window depends on the channel utilization
+    Own,
+    /// A packet we relay, the window depends on the SNR it was received with
+    Relay { snr: i8 },
+}
+
+/// Result of [`TxScheduler::try_transmit`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxOutcome {
+    /// The frame was sent
+    Sent,
+    /// The frame's slot has not come yet
+    NotDue { wait_ms: u64 },
+    /// CAD heard another transmission, the frame was given a new slot
+    ChannelBusy { wait_ms: u64 },
+}
+
+// Linear mapping of `value` from one range to another, clamped like a contention window must be
+fn map_range(value: i32, in_min: i32, in_max: i32, out_min: u8, out_max: u8) -> u8 {
+    let value = value.clamp(in_min, in_max);
+    let out = (value - in_min) * (i32::from(out_max) - i32::from(out_min)) / (in_max - in_min)
+        + i32::from(out_min);
+    out as u8
+}
+
+// A random number of slots in `0..count`
+fn random_slots<R: RngCore>(rng: &mut R, count: u32) -> u32 {
+    rng.next_u32() % count.max(1)
+}
+
+/// When the next frame may be sent
+#[derive(Debug, Clone)]
+pub struct TxScheduler {
+    slot_time_ms: u32,
+    router: bool,
+    tx_at_ms: Option<u64>,
+    backoffs: u32,
+}
+
+impl TxScheduler {
+    /// Create a scheduler for the modem settings in use
+    ///
+    /// A slot lasts the time to run CAD and switch to TX, so that a node
+    /// starting in the next slot hears the earlier one. `router` gives our
+    /// relays precedence over the other roles.
+    pub fn new(modem: &ModemSettings, router: bool) -> Self {
+        let symbol_us =
+            (1_000_000u64 << modem.spreading_factor) / u64::from(modem.bandwidth_hz.max(1));
+        // 2 symbols of CAD and half a symbol to switch
+        let slot_us = symbol_us * 5 / 2 + u64::from(SLOT_OVERHEAD_US);
+        Self {
+            slot_time_ms: slot_us.div_ceil(1000) as u32,
+            router,
+            tx_at_ms: None,
+            backoffs: 0,
+        }
+    }
+
+    /// Length of a contention slot
+    pub fn slot_time_ms(&self) -> u32 {
+        self.slot_time_ms
+    }
+
+    /// Number of times the current frame backed off because the channel was busy
+    pub fn backoffs(&self) -> u32 {
+        self.backoffs
+    }
+
+    /// Contention window for our own packets, growing with the channel utilization
+    pub fn contention_window(channel_util_percent: f32) -> u8 {
+        map_range(channel_util_percent as i32, 0, 100, CW_MIN, CW_MAX)
+    }
+
+    /// Contention window for relays, the worse the SNR the smaller the window
+    pub fn snr_contention_window(snr: i8) -> u8 {
+        map_range(i32::from(snr), SNR_MIN, SNR_MAX, CW_MIN, CW_MAX)
+    }
+
+    /// Random delay before sending one of our packets
+    pub fn tx_delay_ms<R: RngCore>(&self, rng: &mut R, channel_util_percent: f32) -> u32 {
+        let window = Self::contention_window(channel_util_percent);
+        random_slots(rng, 1 << window) * self.slot_time_ms
+    }
+
+    /// Random delay before relaying a packet received with `snr`
+    ///
+    /// Routers pick from the first `2 * CW` slots, the other roles wait for
+    /// the `2 * CW_MAX` slots routers may use before picking their own.
+    pub fn relay_delay_ms<R: RngCore>(&self, rng: &mut R, snr: i8) -> u32 {
+        let window = Self::snr_contention_window(snr);
+        if self.router {
+            random_slots(rng, 2 * u32::from(window)) * self.slot_time_ms
+        } else {
+            (2 * u32::from(CW_MAX) + random_slots(rng, 1 << window)) * self.slot_time_ms
+        }
+    }
+
+    /// Pick the slot of the next frame, returns the time it is due
+    ///
+    /// The frame keeps its slot until it is sent or [`TxScheduler::cancel`] is called.
+    pub fn schedule<R: RngCore>(
+        &mut self,
+        now_ms: u64,
+        rng: &mut R,
+        channel_util_percent: f32,
+        contention: Contention,
+    ) -> u64 {
+        if let Some(tx_at_ms) = self.tx_at_ms {
+            return tx_at_ms;
+        }
+        let delay_ms = match contention {
+            Contention::Own => self.tx_delay_ms(rng, channel_util_percent),
+            Contention::Relay { snr } => self.relay_delay_ms(rng, snr),
+        };
+        let tx_at_ms = now_ms + u64::from(delay_ms);
+        self.tx_at_ms = Some(tx_at_ms);
+        self.backoffs = 0;
+        tx_at_ms
+    }
+
+    /// Whether a frame is waiting for its slot
+    pub fn is_scheduled(&self) -> bool {
+        self.tx_at_ms.is_some()
+    }
+
+    /// Time left until the scheduled frame is due, `None` if no frame is scheduled
+    pub fn wait_ms(&self, now_ms: u64) -> Option<u64> {
+        self.tx_at_ms
+            .map(|tx_at_ms| tx_at_ms.saturating_sub(now_ms))
+    }
+
+    /// Forget the scheduled frame, for example a relay another node sent first
+    pub fn cancel(&mut self) {
+        self.tx_at_ms = None;
+        self.backoffs = 0;
+    }
+
+    /// Send `frame` if its slot has come and the channel is clear
+    ///
+    /// A frame that was not scheduled is due immediately. When CAD hears
+    /// another transmission the frame gets a new slot from the channel
+    /// utilization window. Radio errors drop the frame's slot.
+    pub async fn try_transmit<T: TxRadio, R: RngCore>(
+        &mut self,
+        radio: &mut T,
+        rng: &mut R,
+        now_ms: u64,
+        channel_util_percent: f32,
+        frame: &[u8],
+    ) -> Result<TxOutcome, T::Error> {
+        if let Some(wait_ms) = self.wait_ms(now_ms).filter(|&wait_ms| wait_ms > 0) {
+            return Ok(TxOutcome::NotDue { wait_ms });
+        }
+
+        let busy = match radio.channel_activity().await {
+            Ok(busy) => busy,
+            Err(err) => {
+                self.cancel();
+                return Err(err);
+            }
+        };
+        if busy {
+            let wait_ms = u64::from(self.tx_delay_ms(rng, channel_util_percent));
+            self.tx_at_ms = Some(now_ms + wait_ms);
+            self.backoffs += 1;
+            #[cfg(feature = "defmt")]
+            defmt::debug!(
+                "TX scheduler: channel busy, backing off {} ms (backoff {})",
+                wait_ms,
+                self.backoffs
+            );
+            return Ok(TxOutcome::ChannelBusy { wait_ms });
+        }
+
+        let result = radio.transmit(frame).await;
+        self.cancel();
+        result.map(|()| TxOutcome::Sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, FixedRng};
+
+    const LONG_FAST: ModemSettings = ModemSettings {
+        bandwidth_hz: 250_000,
+        spreading_factor: 11,
+        coding_rate: 5,
+    };
+
+    // Replays scripted CAD results and counts the frames sent
+    struct MockRadio {
+        cad: &'static [bool],
+        cad_runs: usize,
+        sent: usize,
+    }
+
+    impl TxRadio for MockRadio {
+        type Error = ();
+
+        async fn channel_activity(&mut self) -> Result<bool, ()> {
+            let busy = self.cad.get(self.cad_runs).copied().ok_or(())?;
+            self.cad_runs += 1;
+            Ok(busy)
+        }
+
+        async fn transmit(&mut self, _frame: &[u8]) -> Result<(), ()> {
+            self.sent += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_contention_windows() {
+        // 2.5 symbols of 8.192 ms and the 7.6 ms overhead
+        assert_eq!(TxScheduler::new(&LONG_FAST, false).slot_time_ms(), 29);
+
+        assert_eq!(TxScheduler::contention_window(0.0), CW_MIN);
+        assert_eq!(TxScheduler::contention_window(50.0), 5);
+        assert_eq!(TxScheduler::contention_window(120.0), CW_MAX);
+        assert_eq!(TxScheduler::snr_contention_window(-25), CW_MIN);
+        assert_eq!(TxScheduler::snr_contention_window(10), CW_MAX);
+    }
+
+    #[test]
+    fn test_relay_delays_favor_weak_snr_and_routers() {
+        let client = TxScheduler::new(&LONG_FAST, false);
+        let router = TxScheduler::new(&LONG_FAST, true);
+        let mut rng = FixedRng(u32::MAX);
+
+        // Largest slot of each window
+        let far = client.relay_delay_ms(&mut rng, -20);
+        let near = client.relay_delay_ms(&mut rng, 10);
+        assert_eq!(far, (16 + 7) * 29);
+        assert_eq!(near, (16 + 255) * 29);
+        assert!(router.relay_delay_ms(&mut rng, 10) < client.relay_delay_ms(&mut FixedRng(0), -20));
+    }
+
+    #[test]
+    fn test_cad_backs_off_while_busy() {
+        let mut scheduler = TxScheduler::new(&LONG_FAST, false);
+        let mut rng = FixedRng(3);
+        let mut radio = MockRadio {
+            cad: &[true, false],
+            cad_runs: 0,
+            sent: 0,
+        };
+
+        assert_eq!(
+            scheduler.schedule(1_000, &mut rng, 0.0, Contention::Own),
+            1_087
+        );
+        assert_eq!(
+            block_on(scheduler.try_transmit(&mut radio, &mut rng, 1_000, 0.0, b"frame")),
+            Ok(TxOutcome::NotDue { wait_ms: 87 })
+        );
+        assert_eq!(radio.cad_runs, 0);
+
+        assert_eq!(
+            block_on(scheduler.try_transmit(&mut radio, &mut rng, 1_087, 0.0, b"frame")),
+            Ok(TxOutcome::ChannelBusy { wait_ms: 87 })
+        );
+        assert_eq!(scheduler.backoffs(), 1);
+        assert_eq!(
+            block_on(scheduler.try_transmit(&mut radio, &mut rng, 1_174, 0.0, b"frame")),
+            Ok(TxOutcome::Sent)
+        );
+        assert_eq!((radio.cad_runs, radio.sent), (2, 1));
+        assert!(!scheduler.is_scheduled());
+
+        // Radio errors give up the slot
+        scheduler.schedule(2_000, &mut rng, 0.0, Contention::Relay { snr: 0 });
+        assert_eq!(
+            block_on(scheduler.try_transmit(&mut radio, &mut rng, 5_000, 0.0, b"frame")),
+            Err(())
+        );
+        assert_eq!(scheduler.wait_ms(5_000), None);
+    }
+}
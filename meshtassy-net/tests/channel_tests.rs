@@ -1,8 +1,8 @@
 // Test just the channel module
 #[cfg(test)]
 mod channel_tests {
-    use meshtastic_crypto::channel::{generate_channel_hash, xor_hash};
-    use meshtastic_crypto::key::MeshKey;
+    use meshtassy_net::channel::{generate_channel_hash, xor_hash};
+    use meshtassy_net::key::MeshKey;
 
     #[test]
     fn test_generate_channel_hash_with_meshkey() {
@@ -1,15 +1,19 @@
-// Round trip tests for encrypt and decrypt functions
+// Round trip tests for the encode/encrypt and decrypt/decode pipelines
 #[cfg(test)]
 mod round_trip_tests {
-    use meshtastic_crypto::{encrypt_meshtastic_packet, decrypt_meshtastic_packet};
-    use meshtastic_crypto::header::{MeshtasticHeader, MeshtasticHeaderFlags};
+    use meshtassy_net::header::{Header, HeaderFlags};
+    use meshtassy_net::key::{ChannelKey, KeyError};
+    use meshtassy_net::{
+        DecodedPacket, Decrypted, Encrypted, OwnedData, Packet, PacketError, PortNum,
+        DATA_PAYLOAD_LEN,
+    };
 
-    fn create_test_header() -> MeshtasticHeader {
-        MeshtasticHeader {
+    fn create_test_header() -> Header {
+        Header {
             destination: 0x12345678,
             source: 0x87654321,
             packet_id: 0xABCDEF01,
-            flags: MeshtasticHeaderFlags {
+            flags: HeaderFlags {
                 hop_limit: 3,
                 want_ack: true,
                 via_mqtt: false,
@@ -21,252 +25,179 @@ mod round_trip_tests {
         }
     }
 
+    fn create_text_packet(header: Header, message: &[u8]) -> DecodedPacket {
+        let data = OwnedData::new(PortNum::TextMessageApp, message).expect("Payload should fit");
+        DecodedPacket::new(header, data)
+    }
+
+    /// Run a packet through encode -> encrypt -> to_bytes -> from_bytes -> decrypt -> decode
+    fn round_trip(packet: &DecodedPacket, key: &ChannelKey) -> DecodedPacket {
+        let encrypted = packet
+            .encode()
+            .expect("Encoding should succeed")
+            .encrypt(key)
+            .expect("Encryption should succeed");
+
+        let mut buffer = [0u8; 256];
+        let packet_len = encrypted
+            .to_bytes(&mut buffer)
+            .expect("Serialization should succeed");
+        assert_eq!(packet_len, 16 + encrypted.payload_len);
+
+        Packet::<Encrypted>::from_bytes(&buffer[..packet_len], 0, 0)
+            .expect("Parsing should succeed")
+            .decrypt(key)
+            .expect("Decryption should succeed")
+            .decode()
+            .expect("Decoding should succeed")
+    }
+
+    fn assert_round_trip(key: &[u8], message: &[u8]) {
+        let key = ChannelKey::from_bytes(key, key.len()).expect("Key should be valid");
+        let original = create_text_packet(create_test_header(), message);
+
+        let decoded = round_trip(&original, &key);
+
+        assert!(decoded == original);
+        assert_eq!(decoded.payload_data(), message);
+    }
+
     #[test]
     fn test_round_trip_1_byte_key() {
-        let header = create_test_header();
-        let original_payload = b"Hello, Meshtastic!";
-        let key = [0x42u8]; // 1-byte key
-        let key_len = 1;
-        
-        let mut encrypted_packet = [0u8; 256];
-        let mut decrypted_payload = [0u8; 256];
-        
-        // Encrypt
-        let packet_len = encrypt_meshtastic_packet(
-            &header,
-            original_payload,
-            &mut encrypted_packet,
-            &key,
-            key_len,
-        ).expect("Encryption should succeed");
-        
-        assert_eq!(packet_len, 16 + original_payload.len());
-        
-        // Decrypt
-        let decrypted_len = decrypt_meshtastic_packet(
-            &encrypted_packet,
-            packet_len,
-            &mut decrypted_payload,
-            &key,
-            key_len,
-        ).expect("Decryption should succeed");
-        
-        assert_eq!(decrypted_len, original_payload.len());
-        assert_eq!(&decrypted_payload[..decrypted_len], original_payload);
+        assert_round_trip(&[0x42], b"Hello, Meshtastic!");
     }
 
     #[test]
     fn test_round_trip_16_byte_key() {
-        let header = create_test_header();
-        let original_payload = b"This is a longer test message for 16-byte key!";
         let key = [
-            0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
-            0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32, 0x10,
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54,
+            0x32, 0x10,
         ];
-        let key_len = 16;
-        
-        let mut encrypted_packet = [0u8; 256];
-        let mut decrypted_payload = [0u8; 256];
-        
-        // Encrypt
-        let packet_len = encrypt_meshtastic_packet(
-            &header,
-            original_payload,
-            &mut encrypted_packet,
-            &key,
-            key_len,
-        ).expect("Encryption should succeed");
-        
-        assert_eq!(packet_len, 16 + original_payload.len());
-        
-        // Decrypt
-        let decrypted_len = decrypt_meshtastic_packet(
-            &encrypted_packet,
-            packet_len,
-            &mut decrypted_payload,
-            &key,
-            key_len,
-        ).expect("Decryption should succeed");
-        
-        assert_eq!(decrypted_len, original_payload.len());
-        assert_eq!(&decrypted_payload[..decrypted_len], original_payload);
+        assert_round_trip(&key, b"This is a longer test message for 16-byte key!");
     }
 
     #[test]
     fn test_round_trip_32_byte_key() {
-        let header = create_test_header();
-        let original_payload = b"Testing AES-256 with 32-byte key encryption and decryption round trip!";
         let key = [
-            0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF,
-            0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32, 0x10,
-            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
-            0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00,
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54,
+            0x32, 0x10, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC,
+            0xDD, 0xEE, 0xFF, 0x00,
         ];
-        let key_len = 32;
-        
-        let mut encrypted_packet = [0u8; 256];
-        let mut decrypted_payload = [0u8; 256];
-        
-        // Encrypt
-        let packet_len = encrypt_meshtastic_packet(
-            &header,
-            original_payload,
-            &mut encrypted_packet,
+        assert_round_trip(
             &key,
-            key_len,
-        ).expect("Encryption should succeed");
-        
-        assert_eq!(packet_len, 16 + original_payload.len());
-        
-        // Decrypt
-        let decrypted_len = decrypt_meshtastic_packet(
-            &encrypted_packet,
-            packet_len,
-            &mut decrypted_payload,
-            &key,
-            key_len,
-        ).expect("Decryption should succeed");
-        
-        assert_eq!(decrypted_len, original_payload.len());
-        assert_eq!(&decrypted_payload[..decrypted_len], original_payload);
+            b"Testing AES-256 with 32-byte key encryption and decryption round trip!",
+        );
     }
 
     #[test]
     fn test_round_trip_empty_key() {
-        let header = create_test_header();
-        let original_payload = b"Testing with default key";
-        let key = []; // Empty key should use default
-        let key_len = 0;
-        
-        let mut encrypted_packet = [0u8; 256];
-        let mut decrypted_payload = [0u8; 256];
-        
-        // Encrypt
-        let packet_len = encrypt_meshtastic_packet(
-            &header,
-            original_payload,
-            &mut encrypted_packet,
-            &key,
-            key_len,
-        ).expect("Encryption should succeed");
-        
-        assert_eq!(packet_len, 16 + original_payload.len());
-        
-        // Decrypt
-        let decrypted_len = decrypt_meshtastic_packet(
-            &encrypted_packet,
-            packet_len,
-            &mut decrypted_payload,
-            &key,
-            key_len,
-        ).expect("Decryption should succeed");
-        
-        assert_eq!(decrypted_len, original_payload.len());
-        assert_eq!(&decrypted_payload[..decrypted_len], original_payload);
+        assert_round_trip(&[], b"Default key message");
+    }
+
+    #[test]
+    fn test_round_trip_max_payload() {
+        assert_round_trip(&[0x01], &[0x5A; DATA_PAYLOAD_LEN]);
+    }
+
+    #[test]
+    fn test_round_trip_preserves_data_fields() {
+        let key = ChannelKey::from_bytes(&[0x01], 1).unwrap();
+        let mut original = create_text_packet(create_test_header(), b"reply");
+        original.data.want_response = true;
+        original.data.request_id = 0x1111_2222;
+        original.data.reply_id = 0x3333_4444;
+        original.data.emoji = 1;
+        original.data.bitfield = Some(1);
+
+        let decoded = round_trip(&original, &key);
+        assert!(decoded.data == original.data);
     }
 
     #[test]
     fn test_round_trip_different_headers() {
-        // Test that different headers produce different encrypted outputs
-        let key = [0x42u8];
-        let key_len = 1;
-        let payload = b"Same payload";
-        
-        let header1 = MeshtasticHeader {
-            destination: 0x12345678,
-            source: 0x87654321,
-            packet_id: 0x11111111,
-            flags: MeshtasticHeaderFlags {
-                hop_limit: 3,
-                want_ack: true,
-                via_mqtt: false,
-                hop_start: 7,
-            },
-            channel_hash: 0x42,
-            next_hop: 0x55,
-            relay_node: 0xAA,
-        };
-        
-        let header2 = MeshtasticHeader {
-            destination: 0x12345678,
-            source: 0x87654321,
-            packet_id: 0x22222222, // Different packet_id should change IV
-            flags: MeshtasticHeaderFlags {
-                hop_limit: 3,
-                want_ack: true,
-                via_mqtt: false,
-                hop_start: 7,
-            },
-            channel_hash: 0x42,
-            next_hop: 0x55,
-            relay_node: 0xAA,
-        };
-        
-        let mut encrypted1 = [0u8; 256];
-        let mut encrypted2 = [0u8; 256];
-        let mut decrypted1 = [0u8; 256];
-        let mut decrypted2 = [0u8; 256];
-        
-        // Encrypt with both headers
-        let len1 = encrypt_meshtastic_packet(&header1, payload, &mut encrypted1, &key, key_len)
-            .expect("First encryption should succeed");
-        let len2 = encrypt_meshtastic_packet(&header2, payload, &mut encrypted2, &key, key_len)
-            .expect("Second encryption should succeed");
-        
-        assert_eq!(len1, len2);
-        
-        // The encrypted payload portions should be different (due to different IVs)
-        assert_ne!(&encrypted1[16..len1], &encrypted2[16..len2]);
-        
-        // But both should decrypt to the same payload
-        let dec_len1 = decrypt_meshtastic_packet(&encrypted1, len1, &mut decrypted1, &key, key_len)
-            .expect("First decryption should succeed");
-        let dec_len2 = decrypt_meshtastic_packet(&encrypted2, len2, &mut decrypted2, &key, key_len)
-            .expect("Second decryption should succeed");
-        
-        assert_eq!(dec_len1, dec_len2);
-        assert_eq!(&decrypted1[..dec_len1], payload);
-        assert_eq!(&decrypted2[..dec_len2], payload);
+        let key = ChannelKey::from_bytes(&[0x01], 1).unwrap();
+        let payload = b"Same payload, different headers";
+
+        let header1 = create_test_header();
+        let mut header2 = create_test_header();
+        header2.packet_id = 0x11111111;
+
+        let encrypted1 = create_text_packet(header1, payload)
+            .encode()
+            .unwrap()
+            .encrypt(&key)
+            .unwrap();
+        let encrypted2 = create_text_packet(header2, payload)
+            .encode()
+            .unwrap()
+            .encrypt(&key)
+            .unwrap();
+
+        // The IV is derived from the header, so the ciphertexts must differ
+        assert_eq!(encrypted1.payload_len, encrypted2.payload_len);
+        assert_ne!(
+            &encrypted1.payload[..encrypted1.payload_len],
+            &encrypted2.payload[..encrypted2.payload_len]
+        );
+
+        let decoded1 = encrypted1.decrypt(&key).unwrap().decode().unwrap();
+        let decoded2 = encrypted2.decrypt(&key).unwrap().decode().unwrap();
+        assert_eq!(decoded1.payload_data(), payload);
+        assert_eq!(decoded2.payload_data(), payload);
     }
 
     #[test]
     fn test_encryption_changes_payload() {
-        // Verify that encryption actually changes the payload
-        let header = create_test_header();
-        let original_payload = b"This should be encrypted";
-        let key = [0x42u8];
-        let key_len = 1;
-        
-        let mut encrypted_packet = [0u8; 256];
-        
-        let packet_len = encrypt_meshtastic_packet(
-            &header,
-            original_payload,
-            &mut encrypted_packet,
-            &key,
-            key_len,
-        ).expect("Encryption should succeed");
-        
-        // The encrypted payload (after the 16-byte header) should be different from original
-        assert_ne!(&encrypted_packet[16..packet_len], original_payload);
+        let key = ChannelKey::from_bytes(&[0x01], 1).unwrap();
+        let decrypted = create_text_packet(create_test_header(), b"Secret message")
+            .encode()
+            .unwrap();
+        let plaintext = decrypted.payload;
+        let payload_len = decrypted.payload_len;
+
+        let encrypted = decrypted.encrypt(&key).unwrap();
+        assert_ne!(&encrypted.payload[..payload_len], &plaintext[..payload_len]);
     }
 
     #[test]
     fn test_error_cases() {
-        let header = create_test_header();
-        let payload = b"test";
-        let key = [0x42u8];
-        let key_len = 1;
-        
-        // Test empty payload
-        let mut buffer = [0u8; 256];
-        assert!(encrypt_meshtastic_packet(&header, &[], &mut buffer, &key, key_len).is_none());
-        
-        // Test buffer too small
+        let key = ChannelKey::from_bytes(&[0x01], 1).unwrap();
+
+        // Application payload larger than a Data message can carry
+        assert_eq!(
+            OwnedData::new(PortNum::TextMessageApp, &[0u8; DATA_PAYLOAD_LEN + 1]).unwrap_err(),
+            PacketError::PayloadTooLarge
+        );
+
+        // Empty payload cannot be encrypted
+        let empty = Packet::<Decrypted>::new(create_test_header(), 0, 0, [0u8; 240], 0);
+        assert!(matches!(
+            empty.encrypt(&key),
+            Err(PacketError::EmptyPayload)
+        ));
+
+        // Too-small output buffer
+        let encrypted = create_text_packet(create_test_header(), b"Hello")
+            .encode()
+            .unwrap()
+            .encrypt(&key)
+            .unwrap();
         let mut small_buffer = [0u8; 10];
-        assert!(encrypt_meshtastic_packet(&header, payload, &mut small_buffer, &key, key_len).is_none());
-        
-        // Test key length mismatch
-        assert!(encrypt_meshtastic_packet(&header, payload, &mut buffer, &key, 2).is_none());
+        assert_eq!(
+            encrypted.to_bytes(&mut small_buffer),
+            Err(PacketError::BufferTooSmall)
+        );
+
+        // Invalid key length
+        assert_eq!(
+            ChannelKey::from_bytes(&[0x01, 0x02], 2),
+            Err(KeyError::InvalidKeySize)
+        );
+
+        // Frames shorter than the header
+        assert!(matches!(
+            Packet::<Encrypted>::from_bytes(&[0u8; 10], 0, 0),
+            Err(PacketError::TooShort)
+        ));
     }
 }
@@ -2,7 +2,13 @@ fn main() {
     // build the meshtastic protobufs using femtopb
 
     femtopb_build::Config::new()
-        .protos(&["protobufs/meshtastic/mesh.proto", "protobufs/meshtastic/deviceonly.proto"])
+        .protos(&[
+            "protobufs/meshtastic/mesh.proto",
+            "protobufs/meshtastic/deviceonly.proto",
+            "protobufs/meshtastic/apponly.proto",
+            "protobufs/meshtastic/storeforward.proto",
+            "protobufs/meshtastic/admin.proto",
+        ])
         .includes(&["protobufs"])
         .derive_defmt(cfg!(feature = "defmt"))
         .compile()
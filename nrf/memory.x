@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 1024K
+  /* The last 16K hold the records meshtassy-net::storage keeps across reboots */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 1024K - 16K
   RAM : ORIGIN = 0x20000000, LENGTH = 256K
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
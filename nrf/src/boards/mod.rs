@@ -7,7 +7,7 @@ use embassy_nrf::gpio::{Input, Output};
 use embassy_nrf::mode::Blocking;
 use embassy_nrf::spim::Spim;
 use embassy_nrf::twim::Twim;
-use embassy_nrf::{peripherals, rng, usb};
+use embassy_nrf::{nvmc, pac, peripherals, rng, usb};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::Delay;
 use embedded_hal_bus::spi::ExclusiveDevice;
@@ -20,6 +20,23 @@ pub mod seeed_xiao_nrf52840;
 #[cfg(feature = "board-wisblock-rak4631")]
 pub mod wisblock_rak4631;
 
+/// Internal flash, records kept across reboots live in its last pages
+pub type BoardFlash = nvmc::Nvmc<'static>;
+
+/// Size of the nRF52840's internal flash
+pub const FLASH_SIZE: usize = 1024 * 1024;
+
+/// The chip's factory-programmed unique ID, FICR DEVICEID
+pub fn unique_id() -> [u8; 8] {
+    let mut id = [0u8; 8];
+    id[..4].copy_from_slice(&pac::FICR.deviceid(0).read().to_be_bytes());
+    id[4..].copy_from_slice(&pac::FICR.deviceid(1).read().to_be_bytes());
+    id
+}
+
+/// Hardware random number generator
+pub type BoardRng = rng::Rng<'static, peripherals::RNG, Blocking>;
+
 /// Alias sensors on I2C bus
 pub type I2CBus<'dev> = Twim<'dev, peripherals::TWISPI1>;
 
@@ -33,7 +50,9 @@ pub struct BoardPeripherals {
     pub usb_driver:
         usb::Driver<'static, peripherals::USBD, embassy_nrf::usb::vbus_detect::HardwareVbusDetect>,
     /// Random number generator
-    pub rng: rng::Rng<'static, peripherals::RNG, Blocking>,
+    pub rng: BoardRng,
+    /// Internal flash
+    pub flash: BoardFlash,
     /// I2C bus config
     pub i2c: Option<
         &'static mut embassy_sync::mutex::Mutex<
@@ -6,7 +6,7 @@
 use super::{BoardPeripherals, LedPeripherals, LoRaPeripherals};
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
 use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
-use embassy_nrf::{bind_interrupts, pac, peripherals, rng, spim, usb};
+use embassy_nrf::{bind_interrupts, nvmc, pac, peripherals, rng, spim, usb};
 use embassy_time::Delay;
 use embedded_hal_bus::spi::ExclusiveDevice;
 
@@ -72,6 +72,7 @@ pub fn init_board(p: embassy_nrf::Peripherals) -> BoardPeripherals {
         }),
         usb_driver,
         rng,
+        flash: nvmc::Nvmc::new(p.NVMC),
         i2c: None,
     }
 }
@@ -5,7 +5,7 @@
 
 use super::{BoardPeripherals, LoRaPeripherals};
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
-use embassy_nrf::{bind_interrupts, pac, peripherals, rng, spim, usb};
+use embassy_nrf::{bind_interrupts, nvmc, pac, peripherals, rng, spim, usb};
 use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
 use embassy_time::Delay;
 use embedded_hal_bus::spi::ExclusiveDevice;
@@ -63,5 +63,6 @@ pub fn init_board(p: embassy_nrf::Peripherals) -> BoardPeripherals {
         leds: None,  // This board has no LEDs
         usb_driver,
         rng,
+        flash: nvmc::Nvmc::new(p.NVMC),
     }
 }
@@ -7,7 +7,7 @@ use super::{BoardPeripherals, LoRaPeripherals};
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
 use embassy_nrf::twim::Twim;
 use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
-use embassy_nrf::{bind_interrupts, pac, peripherals, rng, spim, twim, usb};
+use embassy_nrf::{bind_interrupts, nvmc, pac, peripherals, rng, spim, twim, usb};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_time::Delay;
@@ -89,6 +89,7 @@ pub fn init_board(p: embassy_nrf::Peripherals) -> BoardPeripherals {
         leds: None,
         usb_driver,
         rng,
+        flash: nvmc::Nvmc::new(p.NVMC),
         i2c: Some(i2c_bus),
     }
 }
@@ -4,6 +4,7 @@
 //! hardware dependencies from the main application logic.
 
 use embassy_rp::clocks;
+use embassy_rp::flash;
 use embassy_rp::gpio::{Input, Output};
 use embassy_rp::i2c;
 use embassy_rp::peripherals;
@@ -18,6 +19,24 @@ use embedded_hal_bus::spi::ExclusiveDevice;
 #[cfg(feature = "board-pico-rp2040")]
 pub mod raspberry_pi_pico;
 
+/// Size of the board's QSPI flash
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// QSPI flash, records kept across reboots live in its last pages
+pub type BoardFlash = flash::Flash<'static, peripherals::FLASH, flash::Blocking, FLASH_SIZE>;
+
+/// The unique ID of the QSPI flash, the RP2040 itself has none
+pub fn unique_id(flash: &mut BoardFlash) -> [u8; 8] {
+    let mut id = [0u8; 8];
+    if flash.blocking_unique_id(&mut id).is_err() {
+        defmt::warn!("Could not read the flash unique ID");
+    }
+    id
+}
+
+/// Random number generator fed by the ring oscillator
+pub type BoardRng = clocks::RoscRng;
+
 /// I2CBus type alias
 pub type I2CBus<'dev> = i2c::I2c<'dev, peripherals::I2C0, i2c::Async>;
 
@@ -30,7 +49,9 @@ pub struct BoardPeripherals {
     /// USB driver
     pub usb_driver: usb::Driver<'static, peripherals::USB>,
     /// Random number generator
-    pub rng: clocks::RoscRng,
+    pub rng: BoardRng,
+    /// QSPI flash
+    pub flash: BoardFlash,
     /// I2C bus config
     pub i2c: Option<
         &'static mut Mutex<
@@ -5,7 +5,7 @@
 
 use super::{BoardPeripherals, LoRaPeripherals};
 use embassy_rp::gpio::{Input, Level, Output, Pull};
-use embassy_rp::{bind_interrupts, i2c, peripherals, spi, usb};
+use embassy_rp::{bind_interrupts, flash, i2c, peripherals, spi, usb};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_time::Delay;
@@ -73,6 +73,7 @@ pub fn init_board(p: embassy_rp::Peripherals) -> BoardPeripherals {
         leds: None, // This board has no LEDs
         usb_driver,
         rng,
+        flash: flash::Flash::new_blocking(p.FLASH),
         i2c: Some(i2c_bus),
     }
 }
@@ -1,6 +1,8 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
 use crate::usb_framer::Framer;
 use defmt::*;
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
@@ -9,13 +11,16 @@ use embassy_rp::peripherals;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::pubsub::{PubSubBehavior, PubSubChannel};
-use embassy_time::Delay;
+use embassy_sync::signal::Signal;
+use embassy_time::{Delay, Instant, Timer};
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
 use femtopb::Message as _;
 use lora_phy::iv::GenericSx126xInterfaceVariant;
 use lora_phy::sx126x::{Sx1262, Sx126x, Sx126xVariant, TcxoCtrlVoltage};
 use lora_phy::{mod_params::*, sx126x};
+use lora_phy::mod_traits::RadioKind;
 use lora_phy::{LoRa, RxMode};
+use embedded_hal_async::delay::DelayNs;
 use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
@@ -26,24 +31,138 @@ use embassy_rp::usb::{Driver, Instance};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config};
 
-use meshtassy_net::header::HeaderFlags;
+use meshtassy_net::airtime::{self, AirtimeTracker, TxPermission};
+use meshtassy_net::header::{node_num_from_unique_id, HeaderFlags};
+use meshtassy_net::channel_url::LoraSettings;
 use meshtassy_net::key::ChannelKey;
-use meshtassy_net::{DecodedPacket, Decrypted, Encrypted, Header, Packet};
+use meshtassy_net::modules::admin::{
+    AdminAction, AdminError, AdminModule, AdminOrigin, AdminSettings, MAX_ADMIN_KEYS,
+};
+use meshtassy_net::modules::neighbor_info::{self, NeighborInfoModule};
+use meshtassy_net::modules::node_info::{self, NodeInfoModule};
+use meshtassy_net::modules::position::{PositionConfig, PositionModule, PositionSource};
+use meshtassy_net::modules::range_test::{self, RangeTestReceiver, RangeTestSender};
+use meshtassy_net::modules::store_forward::{
+    self, ClientEvent, StoreForwardClient, StoreForwardServer,
+};
+use meshtassy_net::modules::traceroute;
+use meshtassy_net::pki::{Keypair, KEYPAIR_LEN};
+use meshtassy_net::pool::PacketBuffer;
+use meshtassy_net::radio::{MeshRadio, RadioEvent, RadioHost, RxMetadata, Transceiver, TxFrame};
+use meshtassy_net::radio_config::{ModemSettings, RadioConfigError, RadioParams, PREAMBLE_LEN};
+use meshtassy_net::storage::{FlashStore, Record, StorageError, MAX_RECORD_LEN};
+use meshtassy_net::reliability::{self, DeliveryReport, RetransmitEvent};
+use meshtassy_net::tx_queue::{self, EnqueueResult, TxQueue, ERRNO_UNKNOWN};
+use meshtassy_net::tx_scheduler::{Contention, TxRadio};
+use meshtassy_net::{
+    AppPayload, ChannelSet, PacketError, PacketErrorCounters, PacketHistory, ReceivedPacket,
+    RetransmissionTable, Router,
+};
+use meshtassy_net::{
+    DecodedPacket, Encrypted, Header, OwnedData, Packet, PacketHandle, PacketPool,
+    MAX_LORA_PACKET_LEN,
+};
+use meshtastic_protobufs::meshtastic::admin_message::{ConfigType, ModuleConfigType};
+use meshtastic_protobufs::meshtastic::config::lo_ra_config::{ModemPreset, RegionCode};
+use meshtastic_protobufs::meshtastic::mesh_packet::Priority;
+use meshtastic_protobufs::meshtastic::{config, module_config, routing};
 use meshtastic_protobufs::meshtastic::{
-    Data, FromRadio, MyNodeInfo, NodeInfo, PortNum, ToRadio, User,
+    FromRadio, MyNodeInfo, NodeInfo, PortNum, ToRadio, User,
 };
 mod usb_framer;
 
 mod boards;
 
-static PACKET_CHANNEL: PubSubChannel<CriticalSectionRawMutex, DecodedPacket, 8, 8, 1> =
-    PubSubChannel::<CriticalSectionRawMutex, DecodedPacket, 8, 8, 1>::new();
+// Received packets live in the pool, the channel only carries handles to them
+static PACKET_POOL: PacketPool<12> = PacketPool::new();
+
+static PACKET_CHANNEL: PubSubChannel<CriticalSectionRawMutex, PacketHandle<'static>, 8, 8, 1> =
+    PubSubChannel::<CriticalSectionRawMutex, PacketHandle<'static>, 8, 8, 1>::new();
 
 static NODE_DATABASE: Mutex<
     CriticalSectionRawMutex,
     Option<meshtassy_net::node_database::NodeDatabase>,
 > = Mutex::new(None);
 
+// Recently seen packets, used to drop duplicate copies of flooded packets
+static PACKET_HISTORY: Mutex<CriticalSectionRawMutex, Option<PacketHistory>> = Mutex::new(None);
+
+// Managed flood router deciding which received packets we relay
+static ROUTER: Mutex<CriticalSectionRawMutex, Option<Router>> = Mutex::new(None);
+
+// Our want_ack packets waiting for an ACK
+static RETRANSMISSIONS: Mutex<CriticalSectionRawMutex, Option<RetransmissionTable>> =
+    Mutex::new(None);
+
+// Our direct neighbors and the neighbor lists other nodes broadcast
+static NEIGHBOR_INFO: Mutex<CriticalSectionRawMutex, Option<NeighborInfoModule>> = Mutex::new(None);
+
+// Our user and when to send it
+static NODE_INFO: Mutex<CriticalSectionRawMutex, Option<NodeInfoModule>> = Mutex::new(None);
+
+// Our position and when to broadcast it
+static POSITION: Mutex<CriticalSectionRawMutex, Option<PositionModule>> = Mutex::new(None);
+
+// Range test sender broadcasting numbered packets, only when RANGE_TEST_SENDER_INTERVAL_SECS is set
+static RANGE_TEST_SENDER: Mutex<CriticalSectionRawMutex, Option<RangeTestSender>> = Mutex::new(None);
+
+// Range test receiver logging the range test packets we hear
+static RANGE_TEST_RECEIVER: Mutex<CriticalSectionRawMutex, Option<RangeTestReceiver>> =
+    Mutex::new(None);
+
+// Store & Forward server keeping text messages for nodes that were out of range
+static STORE_FORWARD_SERVER: Mutex<CriticalSectionRawMutex, Option<StoreForwardServer>> =
+    Mutex::new(None);
+
+// Store & Forward client asking a server for the messages we missed
+static STORE_FORWARD_CLIENT: Mutex<CriticalSectionRawMutex, Option<StoreForwardClient>> =
+    Mutex::new(None);
+
+// Admin messages from our client and from authorized remote nodes
+static ADMIN: Mutex<CriticalSectionRawMutex, Option<AdminModule>> = Mutex::new(None);
+
+// Live configuration changed by admin messages
+static SETTINGS: Mutex<CriticalSectionRawMutex, Option<Settings>> = Mutex::new(None);
+
+// Time a reboot requested by an admin message is due
+static REBOOT_AT_SECS: Mutex<CriticalSectionRawMutex, Option<u32>> = Mutex::new(None);
+
+// Airtime of the frames we send and receive, with the modem settings used to compute it.
+// A blocking mutex, so the duty cycle checks and the TX accounting can never be locked out.
+static AIRTIME: embassy_sync::blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Option<(AirtimeTracker, ModemSettings)>>,
+> = embassy_sync::blocking_mutex::Mutex::new(RefCell::new(None));
+
+// Packets waiting for the radio (ACKs, replies, relays, retransmissions), highest priority first.
+// A blocking mutex, so queueing from synchronous code never finds it busy.
+static TX_QUEUE: embassy_sync::blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<TxQueue>> =
+    embassy_sync::blocking_mutex::Mutex::new(RefCell::new(TxQueue::new()));
+
+// Channels we are a member of, used to pick the key for received packets
+static CHANNELS: Mutex<CriticalSectionRawMutex, Option<ChannelSet>> = Mutex::new(None);
+
+// Our X25519 keypair, used for PKI encrypted direct messages
+static KEYPAIR: Mutex<CriticalSectionRawMutex, Option<Keypair>> = Mutex::new(None);
+
+// Records kept in flash across reboots
+static STORAGE: Mutex<CriticalSectionRawMutex, Option<FlashStore<boards::BoardFlash>>> =
+    Mutex::new(None);
+
+// Why received packets were dropped, one counter per PacketError reason
+static PACKET_ERRORS: Mutex<CriticalSectionRawMutex, PacketErrorCounters> =
+    Mutex::new(PacketErrorCounters::new());
+
+// New radio parameters from an admin message, applied by the main loop between two radio steps
+static RADIO_PARAMS: Signal<CriticalSectionRawMutex, RadioParams> = Signal::new();
+
+// The board's random number generator, shared by the radio loop and the PKI nonces
+static RNG: embassy_sync::blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Option<boards::BoardRng>>> =
+    embassy_sync::blocking_mutex::Mutex::new(RefCell::new(None));
+
+// Our node number, derived from the chip's unique ID before any task starts
+static NODE_NUM: AtomicU32 = AtomicU32::new(0);
+
 // Packet ID counter for USB serial packets
 static PACKET_ID_COUNTER: Mutex<CriticalSectionRawMutex, u32> = Mutex::new(1);
 
@@ -55,16 +174,40 @@ static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
 static STATE: StaticCell<State> = StaticCell::new();
 
 // Meshtastic LoRa parameters
-const LORA_PREAMBLE_LENGTH: u16 = 16;
 const LORA_SYNCWORD: u8 = 0x2B;
 
-// Meshtastic US Default Frequency
-const LORA_FREQUENCY_IN_HZ: u32 = 906_875_000;
+// Region and modem preset, the frequency slot follows from the primary channel name
+const LORA_REGION: RegionCode = RegionCode::Us;
+const LORA_MODEM_PRESET: ModemPreset = ModemPreset::LongFast;
+
+// Highest transmit power of the board's radio, the region may allow less
+const LORA_MAX_TX_POWER_DBM: i8 = 20;
+
+// How long the channel has to stay quiet before we send a pending rebroadcast.
+// Gives other nodes a chance to relay first so we can cancel ours.
+const REBROADCAST_DELAY_MS: u64 = 500;
+
+// Longest the radio listens before the main loop checks for new LoRa settings
+const SETTINGS_POLL_MS: u64 = 1000;
+
+// How long transmissions wait while the airtime tracker is not set up yet
+const AIRTIME_PENDING_WAIT_MS: u64 = 1000;
 
-// Meshtastic LongFast LoRa parameters
-const LORA_SF: SpreadingFactor = SpreadingFactor::_11;
-const LORA_BANDWIDTH: Bandwidth = Bandwidth::_250KHz;
-const LORA_CODINGRATE: CodingRate = CodingRate::_4_5;
+// Hop limit of the packets we originate
+const HOP_LIMIT: u8 = 7;
+
+// Names other nodes and clients show for this node
+const LONG_NAME: &str = "Embassy RP2040";
+const SHORT_NAME: &str = "ERP2";
+
+// Time between two range test packets, 0 to only receive range tests
+const RANGE_TEST_SENDER_INTERVAL_SECS: u32 = 0;
+
+// Act as a Store & Forward server (for nodes on mains power) instead of a client
+const STORE_FORWARD_SERVER_ROLE: bool = false;
+
+// Fixed position of this node as (latitude_i, longitude_i, altitude in meters), `None` if it has none
+const FIXED_POSITION: Option<(i32, i32, i32)> = None;
 
 // This example task processes incoming packets from the Meshtastic radio.
 // It subscribes to the PACKET_CHANNEL and handles each packet as it arrives.
@@ -85,7 +228,10 @@ async fn packet_processor_task() {
         // Add or update the node in the database using the packet
         let mut db_guard = NODE_DATABASE.lock().await;
         if let Some(ref mut db) = *db_guard {
-            let _success = db.add_or_update_node_from_packet(&packet);
+            // Payload errors were already counted when the packet was received
+            if let Err(e) = db.add_or_update_node_from_packet(&packet) {
+                debug!("Node database not updated: {}", e);
+            }
         }
     }
 }
@@ -124,6 +270,22 @@ async fn main(spawner: Spawner) {
     let cdc = CdcAcmClass::new(&mut builder, state, 64);
     let usb = builder.build();
 
+    let mut flash = board.flash;
+    initialize_node_num(&boards::unique_id(&mut flash));
+    initialize_storage(flash).await;
+
+    // Load our PKI keypair before any client can ask for our NodeInfo
+    let mut rng = board.rng;
+    initialize_keypair(|| Keypair::generate(&mut rng)).await;
+    // Seeds the admin session passkeys, a throwaway private key is 32 random bytes
+    let admin_secret = *Keypair::generate(&mut rng).private_key();
+    // From here on the RNG is shared, the PKI nonces need it as well
+    initialize_rng(rng);
+    // Packet IDs continue from a random value, not from 1 on every boot
+    let mut seed = [0u8; 4];
+    fill_random(&mut seed);
+    seed_packet_ids(u32::from_le_bytes(seed)).await;
+
     // LoRa radio setup using board peripherals
     let spi = board.lora.spi;
     let reset = board.lora.reset;
@@ -162,7 +324,7 @@ async fn main(spawner: Spawner) {
     };
     let iv = GenericSx126xInterfaceVariant::new(reset, dio1, busy, None, None).unwrap();
     let radio = Sx126x::new(spi, iv, config);
-    let mut lora = LoRa::with_syncword(radio, LORA_SYNCWORD, Delay)
+    let lora = LoRa::with_syncword(radio, LORA_SYNCWORD, Delay)
         .await
         .unwrap();
 
@@ -186,67 +348,34 @@ async fn main(spawner: Spawner) {
 
     // Initialize the node databases
     initialize_node_database().await;
+    initialize_router().await;
+    initialize_channels().await;
+    initialize_admin(admin_secret).await;
+    load_settings().await;
 
-    info!(
-        "Starting Meshtastic Radio on frequency {} Hz with syncword 0x{:02X}",
-        LORA_FREQUENCY_IN_HZ, LORA_SYNCWORD
-    );
-
-    let mut receiving_buffer = [0u8; 256];
-
-    let mdltn_params = {
-        match lora.create_modulation_params(
-            LORA_SF,
-            LORA_BANDWIDTH,
-            LORA_CODINGRATE,
-            LORA_FREQUENCY_IN_HZ,
-        ) {
-            Ok(mp) => mp,
-            Err(err) => {
-                info!("Radio error = {}", err);
-                return;
-            }
-        }
-    };
-
-    let rx_pkt_params = {
-        match lora.create_rx_packet_params(
-            LORA_PREAMBLE_LENGTH,
-            false,
-            receiving_buffer.len() as u8,
-            true,
-            false,
-            &mdltn_params,
-        ) {
-            Ok(pp) => pp,
-            Err(err) => {
-                info!("Radio error = {}", err);
-                return;
-            }
+    let radio_params = match radio_params().await {
+        Ok(radio_params) => radio_params,
+        Err(err) => {
+            error!("Invalid LoRa configuration: {}", err);
+            return;
         }
     };
+    initialize_airtime(&radio_params);
+    info!(
+        "Starting Meshtastic Radio on frequency {} Hz (slot {:?} of {}) with syncword 0x{:02X}",
+        radio_params.frequency_hz, radio_params.channel_num, radio_params.num_channels, LORA_SYNCWORD
+    );
 
-    let _tx_pkt_params = {
-        match lora.create_tx_packet_params(LORA_PREAMBLE_LENGTH, false, true, false, &mdltn_params)
-        {
-            Ok(pp) => pp,
-            Err(err) => {
-                info!("Radio error = {}", err);
-                return;
-            }
-        }
-    };
-    let mut rng = board.rng;
     let mut bytes = [0u8; 4];
-    rng.fill_bytes(&mut bytes);
+    fill_random(&mut bytes);
     let tx_packet_id = u32::from_le_bytes(bytes); // Create the transmission header
     let tx_header = Header {
-        source: 0xDEADBEEF,
+        source: node_num(),
         destination: 0xFFFFFFFF,
         packet_id: tx_packet_id,
         flags: HeaderFlags {
-            hop_limit: 7,
-            hop_start: 7,
+            hop_limit: HOP_LIMIT,
+            hop_start: HOP_LIMIT,
             want_ack: false,
             via_mqtt: false,
         },
@@ -257,7 +386,7 @@ async fn main(spawner: Spawner) {
 
     info!("TX Header: {}", tx_header);
 
-    // Create and send a test message
+    // Create a test message
     let mut tx_buffer = [0u8; 256];
     if let Some(packet_len) =
         create_text_message_packet(&tx_header, "Hello, world!", &[0x01u8], 1, &mut tx_buffer)
@@ -269,60 +398,50 @@ async fn main(spawner: Spawner) {
         handle_received_packet(
             &tx_buffer, packet_len, 10,  // Mock SNR value
             -50, // Mock RSSI value
-        );
-
-        // match lora
-        //     .prepare_for_tx(
-        //         &mdltn_params,
-        //         &mut tx_pkt_params,
-        //         packet_len as i32,
-        //         &tx_buffer[..packet_len],
-        //     )
-        //     .await
-        // {
-        //     Ok(()) => {
-        //         info!("Radio prepared for TX");
-        //         match lora.tx().await {
-        //             Ok(()) => info!("TX DONE - Packet transmitted successfully!"),
-        //             Err(err) => info!("Radio TX error: {}", err),
-        //         }
-        //     }
-        //     Err(err) => info!("Radio prepare_for_tx error: {}", err),
-        // }
+        )
+        .await;
     } else {
         info!("Failed to create message packet");
     }
 
-    // RX
-    match lora
-        .prepare_for_rx(RxMode::Continuous, &mdltn_params, &rx_pkt_params)
-        .await
-    {
-        Ok(()) => {}
-        Err(err) => {
-            info!("Radio error = {}", err);
-            return;
-        }
-    };
+    // Listen until the head of the TX queue is due, then send it with listen-before-talk
+    let router_role = SETTINGS.lock().await.as_ref().is_some_and(|settings| {
+        settings.device_role == config::device_config::Role::Router
+    });
+    let mut transceiver = Transceiver::new(LoraRadio::new(lora), radio_params, router_role);
+    if let Err(err) = transceiver.configure().await {
+        error!("Radio configuration failed: {}", err);
+        return;
+    }
+    let mut host = FirmwareHost;
 
     loop {
-        receiving_buffer.fill(0);
-
-        match lora.rx(&rx_pkt_params, &mut receiving_buffer).await {
-            Ok((received_len, rx_pkt_status)) => {
-                trace!("rx successful, len = {}, {}", received_len, rx_pkt_status);
-
-                let received_len = received_len as usize;
-                trace!("Received packet: {:02X}", &receiving_buffer[..received_len]); // decode header
-                let _header = Header::from_bytes(&receiving_buffer[..16]).unwrap();
-                handle_received_packet(
-                    &receiving_buffer,
-                    received_len,
-                    rx_pkt_status.snr,
-                    rx_pkt_status.rssi,
-                );
+        // LoRa settings changed by an admin message
+        if let Some(params) = RADIO_PARAMS.try_take() {
+            let router_role = SETTINGS.lock().await.as_ref().is_some_and(|settings| {
+                settings.device_role == config::device_config::Role::Router
+            });
+            match transceiver.reconfigure(params, router_role).await {
+                Ok(()) => {
+                    update_airtime(&params);
+                    info!(
+                        "Radio reconfigured to {} Hz (slot {:?} of {})",
+                        params.frequency_hz, params.channel_num, params.num_channels
+                    );
+                }
+                Err(err) => error!("Radio reconfiguration failed: {}", err),
             }
-            Err(err) => info!("rx unsuccessful = {}", err),
+        }
+
+        match transceiver.step(&mut host, &mut SharedRng).await {
+            Ok(RadioEvent::Scheduled { wait_ms }) => debug!("Packet scheduled in {} ms", wait_ms),
+            Ok(RadioEvent::ChannelBusy { wait_ms }) => {
+                debug!("Channel busy, retrying in {} ms", wait_ms)
+            }
+            Ok(RadioEvent::Deferred { wait_ms }) => debug!("Packet held back for {} ms", wait_ms),
+            Ok(RadioEvent::Dropped(header)) => debug!("Packet not sent: {}", header),
+            Ok(_) => {}
+            Err(err) => info!("Radio error = {}", err),
         }
     }
 }
@@ -350,15 +469,7 @@ fn log_packet_info(
     }
 }
 
-fn handle_received_packet(receiving_buffer: &[u8], received_len: usize, snr: i16, rssi: i16) {
-    // Create channel key from raw bytes (1-byte key with default key + LSB replacement)
-    // @TODO need to replace this with a proper key management system
-    let Some(key) = ChannelKey::from_bytes(&[0x01; 1], 1) else {
-        error!("✗ Failed to create channel key");
-        return;
-    };
-    trace!("✓ Successfully created channel key for decryption");
-
+async fn handle_received_packet(receiving_buffer: &[u8], received_len: usize, snr: i16, rssi: i16) {
     info!("=== Processing received packet ===");
     info!(
         "Received {} bytes, SNR: {}, RSSI: {}",
@@ -367,73 +478,1497 @@ fn handle_received_packet(receiving_buffer: &[u8], received_len: usize, snr: i16
     trace!("Raw packet: {:02X}", &receiving_buffer[..received_len]);
 
     // High Level overview of packet processing:
-    // 1. Packet::<Encrypted>::from_bytes(buffer)  => Packet<Encrypted>
-    // 2. .decrypt(&ChannelKey)                    => Packet<Decrypted>
-    // 3. .decode()                                => DecodedPacket
-    // the decoded packet is equivalent to the `Data` protobuf message, but also has the header, rssi, and snr fields
-
-    // 1. Create encrypted packet from received bytes
-    let Some(encrypted_pkt) =
-        Packet::<Encrypted>::from_bytes(&receiving_buffer[..received_len], rssi as i8, snr as i8)
-    else {
-        warn!("✗ Failed to parse encrypted packet from bytes");
+    // 1. PacketPool::alloc_from_bytes(buffer)     => PacketHandle (the only copy of the frame)
+    // 2. ChannelSet::decode_in_place(&mut buffer) => channel index
+    //    (decrypts and decodes with every channel whose hash matches the header)
+    // the handle is then shared with the other tasks without copying the packet
+
+    // 1. Store the received bytes in a pool buffer
+    let mut packet = match PACKET_POOL.alloc_from_bytes(
+        &receiving_buffer[..received_len],
+        rssi as i8,
+        snr as i8,
+    ) {
+        Ok(packet) => packet,
+        Err(e) => {
+            warn!("✗ Failed to parse encrypted packet from bytes: {}", e);
+            record_packet_error(e);
+            return;
+        }
+    };
+    trace!("✓ Successfully parsed encrypted packet: {:?}", packet);
+
+    // Hearing a neighbor relay one of our broadcasts is an implicit ACK
+    let report = RETRANSMISSIONS
+        .lock()
+        .await
+        .as_mut()
+        .and_then(|table| table.handle_overheard(&packet.header));
+    if let Some(report) = report {
+        info!(
+            "Delivery of 0x{:08X} to 0x{:08X}: {:?}",
+            report.packet_id, report.destination, report.status
+        );
+    }
+
+    // Classify the packet against the recently seen history, waiting for the lock so
+    // that no packet skips deduplication and routing
+    let seen = PACKET_HISTORY.lock().await.as_mut().map(|history| {
+        history.check_and_insert(&packet.header, Instant::now().as_millis())
+    });
+
+    // Let the router decide whether the packet should be relayed
+    if let Some(seen) = seen {
+        if let Some(router) = ROUTER.lock().await.as_mut() {
+            let decision = router.handle_received_frame(
+                &packet.header,
+                packet.payload(),
+                (packet.rssi(), packet.snr()),
+                seen,
+            );
+            debug!("Router decision: {:?}", decision);
+        }
+
+        if seen.is_duplicate() {
+            // Somebody else relayed a packet we were about to relay
+            if packet.header.source != node_num() {
+                let cancelled = TX_QUEUE
+                    .lock(|queue| queue.borrow_mut().cancel(packet.header.source, packet.header.packet_id));
+                if cancelled {
+                    debug!("Cancelled queued relay of {}", packet.header);
+                }
+            }
+            debug!("Dropping duplicate packet: {}", packet.header);
+            return;
+        }
+    }
+
+    // 2. Decrypt and decode the packet in place: direct messages to us may use our PKI key,
+    //    everything else uses the channel whose hash matches
+    let Some(buffer) = packet.get_mut() else {
+        // Nobody else has seen the handle yet, so this cannot happen
+        return;
+    };
+    if buffer.is_pki_encrypted() && buffer.header.destination == node_num() {
+        decrypt_direct_message(buffer).await;
+    }
+    let decoded = if buffer.is_decrypted() {
+        buffer.decode_in_place().map(|()| None)
+    } else {
+        match CHANNELS.lock().await.as_ref() {
+            Some(channels) => channels.decode_in_place(buffer).map(|channel_index| {
+                trace!("✓ Decoded packet on channel {}", channel_index);
+                Some(channel_index)
+            }),
+            None => Err(PacketError::UnknownChannel),
+        }
+    };
+    let channel_index = match decoded {
+        Ok(channel_index) => channel_index,
+        Err(e) => {
+            warn!(
+                "✗ Could not decode packet with hash 0x{:02X}: {}",
+                packet.header.channel_hash,
+                e
+            );
+            record_packet_error(e);
+            if packet.header.destination == node_num() && packet.header.flags.want_ack {
+                // Tell the sender we cannot read it rather than letting it retry
+                let reason = if packet.is_pki_encrypted() {
+                    routing::Error::PkiFailed
+                } else {
+                    routing::Error::NoChannel
+                };
+                send_routing_reply(&packet.header, reason, None);
+            }
+            return;
+        }
+    };
+    trace!("✓ Successfully decoded packet to structured data {:?}", packet);
+
+    // 3. Acknowledge packets addressed to us, and match ACKs to the packets we are waiting on
+    if reliability::wants_ack_from(&packet, node_num()) {
+        send_routing_reply(&packet.header, routing::Error::None, channel_index);
+    }
+    // Let go of the table before waiting for the router, so the TX path is never locked out of it
+    let report = RETRANSMISSIONS
+        .lock()
+        .await
+        .as_mut()
+        .and_then(|table| table.handle_received(&packet));
+    if let Some(report) = report {
+        info!(
+            "Delivery of 0x{:08X} to 0x{:08X}: {:?}",
+            report.packet_id, report.destination, report.status
+        );
+        // The reply found its way back to us, use the same path for our next packets
+        if let Some(router) = ROUTER.lock().await.as_mut() {
+            router.learn_next_hop(&packet.header);
+        }
+    }
+
+    // 4. Let the modules handle their packets
+    if packet.port_num() == femtopb::EnumValue::Known(PortNum::TracerouteApp) {
+        handle_traceroute(&packet, channel_index);
+    }
+    if packet.port_num() == femtopb::EnumValue::Known(PortNum::PositionApp) {
+        answer_position_request(&packet, channel_index);
+    }
+    answer_node_info(&packet, channel_index);
+    handle_store_forward(&packet, channel_index);
+    record_range_test(&packet);
+    handle_admin(&packet, channel_index).await;
+    if let Ok(mut neighbor_info_guard) = NEIGHBOR_INFO.try_lock() {
+        if let Some(module) = neighbor_info_guard.as_mut() {
+            if let Err(err) = module.handle_received(&packet, Instant::now().as_secs() as u32) {
+                debug!("Failed to decode neighbor info: {}", err);
+            }
+        }
+    }
+
+    // Publish a handle to the decoded packet, subscribers share the same buffer
+    PACKET_CHANNEL.publish_immediate(packet.clone());
+
+    let portnum = packet.port_num();
+    match packet.app_payload() {
+        Ok(AppPayload::Text(text)) => info!("Text message: {}", text.as_str()),
+        Ok(payload) => trace!("Decoded payload: {:?}", payload),
+        Err(e) => {
+            warn!("✗ Failed to decode payload: {}", e);
+            record_packet_error(e);
+        }
+    }
+
+    // Log the packet based on port type
+    let port_name = match portnum {
+        femtopb::EnumValue::Known(PortNum::TelemetryApp) => "TELEMETRY",
+        femtopb::EnumValue::Known(PortNum::NodeinfoApp) => "NODEINFO",
+        femtopb::EnumValue::Known(PortNum::PositionApp) => "POSITION",
+        femtopb::EnumValue::Known(PortNum::NeighborinfoApp) => "NEIGHBORINFO",
+        femtopb::EnumValue::Known(PortNum::TextMessageApp) => "TEXT",
+        femtopb::EnumValue::Known(PortNum::RoutingApp) => "ROUTING",
+        femtopb::EnumValue::Known(PortNum::TracerouteApp) => "TRACEROUTE",
+        _ => "OTHER",
+    };
+
+    // Log packet with optional node info from database
+    if let Ok(db_guard) = NODE_DATABASE.try_lock() {
+        let node_info = db_guard
+            .as_ref()
+            .and_then(|db| db.get_node(packet.header.source));
+
+        log_packet_info(&packet.header, node_info, rssi, snr, port_name);
+    } else {
+        log_packet_info(&packet.header, None, rssi, snr, port_name);
+    }
+}
+
+/// Count a dropped packet and periodically log the totals per reason
+fn record_packet_error(error: PacketError) {
+    let Ok(mut counters) = PACKET_ERRORS.try_lock() else {
+        return;
+    };
+    counters.record(error);
+    if counters.total() % 10 == 0 {
+        info!("Packet errors: {:?}", *counters);
+    }
+}
+
+/// Queue an ACK or NAK for a packet addressed to us
+fn send_routing_reply(request: &Header, reason: routing::Error, channel_index: Option<u8>) {
+    let Some(packet_id) = try_next_packet_id() else {
         return;
     };
-    trace!(
-        "✓ Successfully parsed encrypted packet: {:?}",
-        encrypted_pkt
+    let hop_limit = reliability::hop_limit_for_response(request, HOP_LIMIT);
+    let reply = reliability::routing_reply(request, node_num(), packet_id, reason, hop_limit);
+    debug!(
+        "Queueing {:?} for 0x{:08X} from 0x{:08X}",
+        reason, request.packet_id, request.source
     );
+    queue_reply(reply, channel_index);
+}
+
+/// Take part in a traceroute: answer requests for us and add our hop to the ones we relay
+fn handle_traceroute(packet: &PacketHandle<'_>, channel_index: Option<u8>) {
+    if packet.header.destination == node_num() {
+        // Replies to traceroutes of our own need no answer
+        if !packet.data_message().is_ok_and(|data| data.want_response) {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        let hop_limit = reliability::hop_limit_for_response(&packet.header, HOP_LIMIT);
+        match traceroute::reply(packet, node_num(), packet_id, hop_limit) {
+            Ok(reply) => {
+                info!("Answering traceroute from 0x{:08X}", packet.header.source);
+                queue_reply(reply, channel_index);
+            }
+            Err(e) => warn!("✗ Failed to answer traceroute: {}", e),
+        }
+        return;
+    }
+
+    // Add our hop to the copy the router queued for rebroadcast, if there is one
+    let Ok(channels_guard) = CHANNELS.try_lock() else {
+        return;
+    };
+    let Some(channel) = channel_index.and_then(|index| channels_guard.as_ref()?.get(index)) else {
+        return;
+    };
+    let Ok(updated) = traceroute::relay(packet, node_num()) else {
+        return;
+    };
+    let Ok(encrypted) = updated.encode().and_then(|packet| packet.encrypt(channel.key())) else {
+        warn!("Failed to encode traceroute");
+        return;
+    };
+    if let Ok(mut router_guard) = ROUTER.try_lock() {
+        if let Some(router) = router_guard.as_mut() {
+            let payload = &encrypted.payload[..encrypted.payload_len];
+            if router.update_rebroadcast(packet.header.source, packet.header.packet_id, payload) {
+                debug!("Added our hop to traceroute 0x{:08X}", packet.header.packet_id);
+            }
+        }
+    }
+}
+
+/// Encrypt a reply and queue it for transmission
+///
+/// The reply goes out on the channel the request was decoded with, or on the
+/// primary channel when there is none (PKI direct messages, undecodable packets).
+fn queue_reply(reply: DecodedPacket, channel_index: Option<u8>) -> Option<EnqueueResult> {
+    let priority = tx_queue::default_priority(&reply);
+    queue_packet(reply, channel_index, priority)
+}
+
+/// Encrypt a packet with the key of a channel and queue it with `priority`
+///
+/// Returns `None` if the packet could not be encrypted.
+fn queue_packet(
+    mut reply: DecodedPacket,
+    channel_index: Option<u8>,
+    priority: Priority,
+) -> Option<EnqueueResult> {
+    let Ok(channels_guard) = CHANNELS.try_lock() else {
+        return None;
+    };
+    let Some(channel) = channels_guard.as_ref().and_then(|channels| {
+        channel_index
+            .and_then(|index| channels.get(index))
+            .or_else(|| channels.primary())
+    }) else {
+        return None;
+    };
+    reply.header.channel_hash = channel.hash();
+    if let Ok(router_guard) = ROUTER.try_lock() {
+        if let Some(router) = router_guard.as_ref() {
+            reply.header.next_hop = router.next_hop(reply.header.destination, router.relay_id());
+        }
+    }
+
+    let Ok(packet) = reply.encode().and_then(|packet| packet.encrypt(channel.key())) else {
+        warn!("Failed to encode reply");
+        return None;
+    };
+    Some(enqueue_packet(packet, priority))
+}
+
+/// Hand an encrypted packet to the TX queue
+fn enqueue_packet(packet: Packet<Encrypted>, priority: Priority) -> EnqueueResult {
+    let header = packet.header;
+    let result = TX_QUEUE.lock(|queue| queue.borrow_mut().enqueue(packet, priority));
+    match result {
+        EnqueueResult::Queued => {}
+        EnqueueResult::Replaced { source, packet_id } => warn!(
+            "TX queue full, dropped 0x{:08X} from 0x{:08X} for {}",
+            packet_id, source, header
+        ),
+        EnqueueResult::Full => warn!("TX queue full, dropping packet: {}", header),
+    }
+    result
+}
+
+/// Take a packet off the TX queue, once it was sent or given up on
+fn remove_from_tx_queue(header: &Header) -> Option<tx_queue::QueuedPacket> {
+    TX_QUEUE.lock(|queue| queue.borrow_mut().remove(header.source, header.packet_id))
+}
+
+/// Start waiting for an ACK after the first transmission of one of our want_ack packets
+fn track_reliable_packet(packet: &Packet<Encrypted>, airtime_ms: u32, slot_time_ms: u32) {
+    if packet.header.source != node_num() || !packet.header.flags.want_ack {
+        return;
+    }
+    let Ok(mut retransmissions_guard) = RETRANSMISSIONS.try_lock() else {
+        return;
+    };
+    let Some(table) = retransmissions_guard.as_mut() else {
+        return;
+    };
+    // Retransmissions are already tracked
+    if table.is_pending(packet.header.packet_id) {
+        return;
+    }
+    let channel_utilization = airtime_metrics().0 as u8;
+    let timeout_ms =
+        reliability::retransmission_timeout_ms(airtime_ms, slot_time_ms, channel_utilization);
+    if let Err(err) = table.track(packet, Instant::now().as_millis(), timeout_ms) {
+        warn!("Not waiting for an ACK to {}: {:?}", packet.header, err);
+    }
+}
+
+/// Apply an admin message from a remote node and answer its get requests
+///
+/// Packets decoded without a channel were PKI encrypted by their sender, the
+/// reply is then encrypted with the sender's public key as well.
+async fn handle_admin(packet: &PacketHandle<'_>, channel_index: Option<u8>) {
+    if packet.port_num() != femtopb::EnumValue::Known(PortNum::AdminApp)
+        || packet.header.destination != node_num()
+    {
+        return;
+    }
+    let mut channel_name: heapless::String<{ meshtassy_net::channel::MAX_CHANNEL_NAME_LEN }> =
+        heapless::String::new();
+    let mut public_key = [0u8; 32];
+    let origin = match channel_index {
+        Some(index) => {
+            let channels_guard = CHANNELS.lock().await;
+            let Some(channel) = channels_guard.as_ref().and_then(|channels| channels.get(index)) else {
+                return;
+            };
+            // Channel names are at most MAX_CHANNEL_NAME_LEN long
+            let _ = channel_name.push_str(channel.name());
+            AdminOrigin::Channel(&channel_name)
+        }
+        None => {
+            let db_guard = NODE_DATABASE.lock().await;
+            let Some(key) = db_guard
+                .as_ref()
+                .and_then(|database| database.get_public_key(packet.header.source))
+            else {
+                return;
+            };
+            public_key = *key;
+            AdminOrigin::Pki(&public_key)
+        }
+    };
+
+    let result = {
+        let mut admin_guard = ADMIN.lock().await;
+        let mut settings_guard = SETTINGS.lock().await;
+        let (Some(admin), Some(settings)) = (admin_guard.as_mut(), settings_guard.as_mut()) else {
+            return;
+        };
+        let Some(result) =
+            admin.handle_received(packet, origin, settings, Instant::now().as_secs() as u32)
+        else {
+            return;
+        };
+        result.map(|outcome| {
+            let reply = outcome.response.and_then(|response| {
+                let packet_id = try_next_packet_id()?;
+                Some(admin.reply(&packet.header, response, packet_id, HOP_LIMIT))
+            });
+            (reply, outcome.action)
+        })
+    };
+
+    match result {
+        Ok((reply, action)) => {
+            if let Some(reply) = reply {
+                if channel_index.is_some() {
+                    queue_reply(reply, channel_index);
+                } else {
+                    queue_pki_reply(reply).await;
+                }
+            }
+            if let Some(action) = action {
+                run_admin_action(action).await;
+            }
+        }
+        Err(err) => {
+            warn!("Rejected admin message from 0x{:08X}: {}", packet.header.source, err);
+            send_routing_reply(&packet.header, err.routing_error(), channel_index);
+        }
+    }
+}
+
+/// Apply an admin message from our client, returns the response to send back
+async fn handle_local_admin(payload: &[u8], request_id: u32) -> Result<Option<OwnedData>, AdminError> {
+    let mut admin_guard = ADMIN.lock().await;
+    let mut settings_guard = SETTINGS.lock().await;
+    let (Some(admin), Some(settings)) = (admin_guard.as_mut(), settings_guard.as_mut()) else {
+        // Still booting
+        return Err(AdminError::Unsupported);
+    };
+    let outcome = admin.handle(
+        payload,
+        request_id,
+        AdminOrigin::Local,
+        settings,
+        Instant::now().as_secs() as u32,
+    )?;
+    drop(settings_guard);
+    drop(admin_guard);
+    if let Some(action) = outcome.action {
+        run_admin_action(action).await;
+    }
+    Ok(outcome.response)
+}
+
+/// Carry out an action requested by an admin message
+async fn run_admin_action(action: AdminAction) {
+    info!("Admin action: {:?}", action);
+    match action {
+        AdminAction::Reboot { delay_secs } => {
+            *REBOOT_AT_SECS.lock().await = u32::try_from(delay_secs)
+                .ok()
+                .map(|delay_secs| (Instant::now().as_secs() as u32).saturating_add(delay_secs));
+        }
+        AdminAction::Shutdown { .. } => warn!("Shutdown is not supported on this board"),
+        AdminAction::FactoryResetDevice | AdminAction::FactoryResetConfig => {
+            forget_settings().await;
+            if let Some(settings) = SETTINGS.lock().await.as_mut() {
+                settings.reset();
+                if action == AdminAction::FactoryResetDevice {
+                    settings.admin_keys.clear();
+                    settings.admin_channel_enabled = false;
+                } else {
+                    // The admin keys outlive a config reset, save them with the defaults
+                    let _ = settings.save();
+                }
+            }
+            if action == AdminAction::FactoryResetDevice {
+                reset_keypair().await;
+                reset_node_database().await;
+            }
+        }
+        AdminAction::ResetNodeDb => reset_node_database().await,
+    }
+}
+
+/// Forget every node we heard
+async fn reset_node_database() {
+    *NODE_DATABASE.lock().await = Some(meshtassy_net::node_database::NodeDatabase::new());
+}
+
+/// Replace our PKI keypair with a new one, peers learn it from our next NodeInfo
+async fn reset_keypair() {
+    let keypair = Keypair::generate(&mut SharedRng);
+    if let Some(storage) = STORAGE.lock().await.as_mut() {
+        if let Err(err) = storage.save(Record::Keypair, &keypair.to_bytes()) {
+            warn!("Could not save the PKI keypair: {}", err);
+        }
+    }
+    let public_key = *keypair.public_key();
+    *KEYPAIR.lock().await = Some(keypair);
+    if let Some(settings) = SETTINGS.lock().await.as_mut() {
+        let mut owner = settings.owner();
+        owner.public_key = Some(public_key);
+        settings.set_owner(owner);
+    }
+    info!("New PKI public key: {:02X}", public_key);
+}
+
+/// Reset the board once a reboot requested by an admin message is due
+fn reboot_if_due() {
+    let Ok(reboot_guard) = REBOOT_AT_SECS.try_lock() else {
+        return;
+    };
+    if reboot_guard.is_some_and(|reboot_at_secs| Instant::now().as_secs() as u32 >= reboot_at_secs) {
+        info!("Rebooting as requested by an admin message");
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// Encrypt a reply to a PKI direct message with the recipient's public key and queue it
+async fn queue_pki_reply(reply: DecodedPacket) -> Option<EnqueueResult> {
+    let priority = tx_queue::default_priority(&reply);
+    queue_pki_packet(reply, priority).await
+}
+
+/// Encrypt a packet with the recipient's public key and queue it with `priority`
+async fn queue_pki_packet(mut reply: DecodedPacket, priority: Priority) -> Option<EnqueueResult> {
+    if let Some(router) = ROUTER.lock().await.as_ref() {
+        reply.header.next_hop = router.next_hop(reply.header.destination, router.relay_id());
+    }
+
+    let mut extra_nonce = [0u8; 4];
+    fill_random(&mut extra_nonce);
+    let extra_nonce = u32::from_le_bytes(extra_nonce);
+    let encrypted = {
+        let keypair_guard = KEYPAIR.lock().await;
+        let db_guard = NODE_DATABASE.lock().await;
+        let (Some(keypair), Some(database)) = (keypair_guard.as_ref(), db_guard.as_ref()) else {
+            return None;
+        };
+        reply.encode().map_err(|_| ()).and_then(|packet| {
+            packet
+                .encrypt_pki(keypair.private_key(), database, extra_nonce)
+                .map_err(|_| ())
+        })
+    };
+    let Ok(packet) = encrypted else {
+        warn!("Failed to encrypt PKI reply");
+        return None;
+    };
+    Some(enqueue_packet(packet, priority))
+}
+
+/// Live configuration, changed by admin messages
+///
+/// Changes are applied to the modules right away, LoRa changes reach the
+/// radio between two steps of the main loop. `save` keeps the owner, the
+/// configuration and the channels in flash, `load_settings` restores them on boot.
+struct Settings {
+    owner: meshtassy_net::node_database::User,
+    channels: ChannelSet,
+    lora: LoraSettings,
+    device_role: config::device_config::Role,
+    /// Copy of the admin module's security config, kept here to be saved
+    admin_keys: heapless::Vec<[u8; 32], MAX_ADMIN_KEYS>,
+    admin_channel_enabled: bool,
+}
+
+impl Settings {
+    /// Restore the default names, channels and configuration
+    ///
+    /// The security config stays, like the admin module's keys.
+    fn reset(&mut self) {
+        let mut owner = self.owner.clone();
+        owner.long_name = heapless::String::try_from(LONG_NAME).unwrap_or_default();
+        owner.short_name = heapless::String::try_from(SHORT_NAME).unwrap_or_default();
+        owner.is_licensed = false;
+        self.device_role = config::device_config::Role::Client;
+        owner.role = femtopb::EnumValue::Known(self.device_role);
+        self.set_owner(owner);
+        self.lora = default_lora_settings();
+        self.channels = ChannelSet::with_default_channel();
+        self.channels.set_modem_preset(self.lora.modem_preset);
+        if let Ok(mut channels_guard) = CHANNELS.try_lock() {
+            *channels_guard = Some(self.channels.clone());
+        }
+        // The defaults are always valid
+        let _ = self.reconfigure_radio(&self.lora);
+    }
+
+    /// Have the main loop apply `lora` and our primary channel to the radio
+    fn reconfigure_radio(&self, lora: &LoraSettings) -> Result<(), RadioConfigError> {
+        RADIO_PARAMS.signal(radio_params_for(lora, &self.channels)?);
+        Ok(())
+    }
+}
+
+impl AdminSettings for Settings {
+    fn owner(&self) -> meshtassy_net::node_database::User {
+        self.owner.clone()
+    }
+
+    fn set_owner(&mut self, owner: meshtassy_net::node_database::User) {
+        self.owner = owner.clone();
+        if let Ok(mut node_info_guard) = NODE_INFO.try_lock() {
+            if let Some(module) = node_info_guard.as_mut() {
+                module.set_user(owner);
+            }
+        }
+    }
+
+    fn config(&self, kind: ConfigType) -> Option<meshtastic_protobufs::meshtastic::Config<'_>> {
+        let payload_variant = match kind {
+            ConfigType::DeviceConfig => config::PayloadVariant::Device(config::DeviceConfig {
+                role: femtopb::EnumValue::Known(self.device_role),
+                node_info_broadcast_secs: NODE_INFO.try_lock().ok()?.as_ref()?.interval_secs(),
+                ..Default::default()
+            }),
+            ConfigType::PositionConfig => config::PayloadVariant::Position(
+                POSITION.try_lock().ok()?.as_ref()?.config().to_protobuf(),
+            ),
+            ConfigType::LoraConfig => config::PayloadVariant::Lora(self.lora.to_protobuf()),
+            _ => return None,
+        };
+        Some(meshtastic_protobufs::meshtastic::Config {
+            payload_variant: Some(payload_variant),
+            ..Default::default()
+        })
+    }
+
+    fn set_config(&mut self, config: &meshtastic_protobufs::meshtastic::Config<'_>) -> Result<(), AdminError> {
+        let now_secs = Instant::now().as_secs() as u32;
+        match &config.payload_variant {
+            Some(config::PayloadVariant::Device(device)) => {
+                if let femtopb::EnumValue::Known(role) = device.role {
+                    self.device_role = role;
+                    let mut owner = self.owner.clone();
+                    owner.role = device.role;
+                    self.set_owner(owner);
+                }
+                if device.node_info_broadcast_secs != 0 {
+                    if let Ok(mut node_info_guard) = NODE_INFO.try_lock() {
+                        if let Some(module) = node_info_guard.as_mut() {
+                            module.set_interval_secs(device.node_info_broadcast_secs, now_secs);
+                        }
+                    }
+                }
+            }
+            Some(config::PayloadVariant::Position(position)) => {
+                let Ok(mut position_guard) = POSITION.try_lock() else {
+                    return Err(AdminError::Storage);
+                };
+                if let Some(module) = position_guard.as_mut() {
+                    module.set_config(PositionConfig::from_protobuf(position));
+                }
+            }
+            Some(config::PayloadVariant::Lora(lora)) => {
+                let lora = LoraSettings::from_protobuf(lora);
+                if let Err(err) = self.reconfigure_radio(&lora) {
+                    warn!("Rejected LoRa config: {}", err);
+                    return Err(AdminError::InvalidValue);
+                }
+                self.lora = lora;
+                // Unnamed channels are named, and hashed, after the modem preset
+                self.channels.set_modem_preset(lora.modem_preset);
+                if let Ok(mut channels_guard) = CHANNELS.try_lock() {
+                    *channels_guard = Some(self.channels.clone());
+                }
+            }
+            // Applied by the admin module, which already checked the keys
+            Some(config::PayloadVariant::Security(security)) => {
+                self.admin_keys = security
+                    .admin_key
+                    .iter()
+                    .flatten()
+                    .filter_map(|key| <[u8; 32]>::try_from(key).ok())
+                    .collect();
+                self.admin_channel_enabled = security.admin_channel_enabled;
+            }
+            _ => return Err(AdminError::Unsupported),
+        }
+        Ok(())
+    }
+
+    fn module_config(&self, kind: ModuleConfigType) -> Option<meshtastic_protobufs::meshtastic::ModuleConfig<'_>> {
+        let payload_variant = match kind {
+            ModuleConfigType::NeighborinfoConfig => {
+                module_config::PayloadVariant::NeighborInfo(module_config::NeighborInfoConfig {
+                    enabled: true,
+                    update_interval: NEIGHBOR_INFO.try_lock().ok()?.as_ref()?.interval_secs(),
+                    ..Default::default()
+                })
+            }
+            _ => return None,
+        };
+        Some(meshtastic_protobufs::meshtastic::ModuleConfig {
+            payload_variant: Some(payload_variant),
+            ..Default::default()
+        })
+    }
+
+    fn set_module_config(
+        &mut self,
+        config: &meshtastic_protobufs::meshtastic::ModuleConfig<'_>,
+    ) -> Result<(), AdminError> {
+        match &config.payload_variant {
+            Some(module_config::PayloadVariant::NeighborInfo(neighbor_info)) => {
+                if neighbor_info.update_interval != 0 {
+                    let Ok(mut neighbor_info_guard) = NEIGHBOR_INFO.try_lock() else {
+                        return Err(AdminError::Storage);
+                    };
+                    if let Some(module) = neighbor_info_guard.as_mut() {
+                        module.set_interval_secs(
+                            neighbor_info.update_interval,
+                            Instant::now().as_secs() as u32,
+                        );
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(AdminError::Unsupported),
+        }
+    }
+
+    fn channels(&self) -> &ChannelSet {
+        &self.channels
+    }
+
+    fn set_channel(&mut self, channel: meshtassy_net::channel::Channel) {
+        // The primary channel's name picks the frequency slot
+        let primary = channel.role() == meshtassy_net::channel::ChannelRole::Primary;
+        self.channels.set(channel);
+        if let Ok(mut channels_guard) = CHANNELS.try_lock() {
+            *channels_guard = Some(self.channels.clone());
+        }
+        if primary {
+            if let Err(err) = self.reconfigure_radio(&self.lora) {
+                warn!("Radio keeps its frequency: {}", err);
+            }
+        }
+    }
+
+    fn remove_channel(&mut self, index: u8) {
+        self.channels.remove(index);
+        if let Ok(mut channels_guard) = CHANNELS.try_lock() {
+            *channels_guard = Some(self.channels.clone());
+        }
+    }
+
+    fn save(&mut self) -> Result<(), AdminError> {
+        let admin_keys: heapless::Vec<&[u8], MAX_ADMIN_KEYS> =
+            self.admin_keys.iter().map(|key| &key[..]).collect();
+        let owner = User {
+            long_name: &self.owner.long_name,
+            short_name: &self.owner.short_name,
+            hw_model: self.owner.hw_model,
+            is_licensed: self.owner.is_licensed,
+            role: self.owner.role,
+            ..Default::default()
+        };
+        let local_config = meshtastic_protobufs::meshtastic::LocalConfig {
+            device: Some(config::DeviceConfig {
+                role: femtopb::EnumValue::Known(self.device_role),
+                node_info_broadcast_secs: NODE_INFO
+                    .try_lock()
+                    .ok()
+                    .and_then(|guard| guard.as_ref().map(|module| module.interval_secs()))
+                    .unwrap_or(node_info::DEFAULT_NODE_INFO_BROADCAST_SECS),
+                ..Default::default()
+            }),
+            position: POSITION
+                .try_lock()
+                .ok()
+                .and_then(|guard| guard.as_ref().map(|module| module.config().to_protobuf())),
+            lora: Some(self.lora.to_protobuf()),
+            security: Some(config::SecurityConfig {
+                admin_key: femtopb::repeated::Repeated::from_slice(&admin_keys),
+                admin_channel_enabled: self.admin_channel_enabled,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let channels: heapless::Vec<_, { meshtassy_net::channel::MAX_CHANNELS }> =
+            self.channels.iter().map(|channel| channel.to_protobuf()).collect();
+        let channel_file = meshtastic_protobufs::meshtastic::ChannelFile {
+            channels: femtopb::repeated::Repeated::from_slice(&channels),
+            ..Default::default()
+        };
+
+        let Ok(mut storage_guard) = STORAGE.try_lock() else {
+            return Err(AdminError::Storage);
+        };
+        let Some(storage) = storage_guard.as_mut() else {
+            return Err(AdminError::Storage);
+        };
+        storage
+            .save_message(Record::Owner, &owner)
+            .and_then(|()| storage.save_message(Record::Config, &local_config))
+            .and_then(|()| storage.save_message(Record::Channels, &channel_file))
+            .map_err(|err| {
+                warn!("Could not save the settings: {}", err);
+                AdminError::Storage
+            })?;
+        info!("Settings saved to flash");
+        Ok(())
+    }
+}
+
+/// Send our NodeInfo to a node that asked for it or that we never heard before
+fn answer_node_info(packet: &PacketHandle<'_>, channel_index: Option<u8>) {
+    let reply = {
+        let Ok(mut node_info_guard) = NODE_INFO.try_lock() else {
+            return;
+        };
+        let Some(module) = node_info_guard.as_mut() else {
+            return;
+        };
+        let Some(reply) = module.handle_received(packet, Instant::now().as_secs() as u32) else {
+            return;
+        };
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        let hop_limit = reliability::hop_limit_for_response(&packet.header, HOP_LIMIT);
+        module.reply(&reply, packet_id, hop_limit)
+    };
+    if let Some(reply) = reply {
+        info!("Sending our NodeInfo to 0x{:08X}", packet.header.source);
+        queue_reply(reply, channel_index);
+    }
+}
+
+/// Send our position to a node that asked for it with `want_response`
+fn answer_position_request(packet: &PacketHandle<'_>, channel_index: Option<u8>) {
+    if !packet.data_message().is_ok_and(|data| data.want_response) {
+        return;
+    }
+    let reply = {
+        let Ok(channels_guard) = CHANNELS.try_lock() else {
+            return;
+        };
+        let Some(channel) = channels_guard.as_ref().and_then(|channels| {
+            channel_index
+                .and_then(|index| channels.get(index))
+                .or_else(|| channels.primary())
+        }) else {
+            return;
+        };
+        let Ok(mut position_guard) = POSITION.try_lock() else {
+            return;
+        };
+        let Some(module) = position_guard.as_mut() else {
+            return;
+        };
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        let hop_limit = reliability::hop_limit_for_response(&packet.header, HOP_LIMIT);
+        module.reply(packet, packet_id, hop_limit, channel)
+    };
+    if let Some(reply) = reply {
+        info!("Answering position request from 0x{:08X}", packet.header.source);
+        queue_reply(reply, channel_index);
+    }
+}
+
+/// Queue our NodeInfo broadcast on the primary channel when it is due
+fn queue_node_info() {
+    let now_secs = Instant::now().as_secs() as u32;
+    let broadcast = {
+        let Ok(mut node_info_guard) = NODE_INFO.try_lock() else {
+            return;
+        };
+        let Some(module) = node_info_guard.as_mut() else {
+            return;
+        };
+        if now_secs < module.next_broadcast_secs() {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        module.poll(now_secs, packet_id, HOP_LIMIT, 0)
+    };
+    if let Some(broadcast) = broadcast {
+        queue_optional_broadcast(broadcast);
+    }
+}
+
+/// Log a range test hit as a CSV line, see `range_test::CSV_HEADER` for the columns
+fn record_range_test(packet: &PacketHandle<'_>) {
+    let Ok(mut receiver_guard) = RANGE_TEST_RECEIVER.try_lock() else {
+        return;
+    };
+    let Ok(db_guard) = NODE_DATABASE.try_lock() else {
+        return;
+    };
+    let (Some(receiver), Some(database)) = (receiver_guard.as_mut(), db_guard.as_ref()) else {
+        return;
+    };
+    let Some(record) = receiver.handle_received(packet, database, Instant::now().as_secs() as u32)
+    else {
+        return;
+    };
+    let mut line: heapless::String<192> = heapless::String::new();
+    if core::fmt::Write::write_fmt(&mut line, format_args!("{}", record)).is_ok() {
+        info!("Range test: {}", line.as_str());
+    }
+}
+
+/// Keep text messages as a Store & Forward server, or ask a server for our missed messages as a client
+fn handle_store_forward(packet: &PacketHandle<'_>, channel_index: Option<u8>) {
+    let now_secs = Instant::now().as_secs() as u32;
+    if let Ok(mut server_guard) = STORE_FORWARD_SERVER.try_lock() {
+        if let Some(server) = server_guard.as_mut() {
+            if let Err(err) = server.handle_received(packet, now_secs) {
+                debug!("Failed to decode Store & Forward request: {}", err);
+            }
+        }
+    }
+
+    let request = {
+        let Ok(mut client_guard) = STORE_FORWARD_CLIENT.try_lock() else {
+            return;
+        };
+        let Some(client) = client_guard.as_mut() else {
+            return;
+        };
+        match client.handle_received(packet, now_secs) {
+            Ok(Some(ClientEvent::ServerAvailable { server })) => {
+                info!("Store & Forward server 0x{:08X} in reach, requesting history", server);
+                try_next_packet_id().and_then(|packet_id| client.request_history(0, packet_id, HOP_LIMIT))
+            }
+            Ok(Some(event)) => {
+                info!("Store & Forward: {:?}", event);
+                None
+            }
+            Ok(None) => {
+                if let Some(text) = packet
+                    .data_message()
+                    .ok()
+                    .and_then(|data| store_forward::replayed_text(&data))
+                {
+                    info!(
+                        "Missed message from 0x{:08X}: {}",
+                        text.source,
+                        core::str::from_utf8(text.text).unwrap_or("<invalid UTF-8>")
+                    );
+                }
+                None
+            }
+            Err(err) => {
+                debug!("Failed to decode Store & Forward packet: {}", err);
+                None
+            }
+        }
+    };
+    if let Some(request) = request {
+        queue_reply(request, channel_index);
+    }
+}
+
+/// Queue the next Store & Forward response, replayed message or heartbeat
+///
+/// Replayed messages are queued one at a time so they do not crowd out other traffic.
+fn queue_store_forward() {
+    if !TX_QUEUE.lock(|queue| queue.borrow().is_empty()) {
+        return;
+    }
+    let now_secs = Instant::now().as_secs() as u32;
+    let Ok(channels_guard) = CHANNELS.try_lock() else {
+        return;
+    };
+    let Some(channels) = channels_guard.as_ref() else {
+        return;
+    };
+    let Some(primary) = channels.primary() else {
+        return;
+    };
+    let packet = {
+        let Ok(mut server_guard) = STORE_FORWARD_SERVER.try_lock() else {
+            return;
+        };
+        let Some(server) = server_guard.as_mut() else {
+            return;
+        };
+        if !server.is_due(now_secs) {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        server.poll(now_secs, packet_id, HOP_LIMIT, primary.hash())
+    };
+    let Some(packet) = packet else {
+        return;
+    };
+    // Send on the channel the packet belongs to, skip it if that channel is gone
+    let Some(channel_index) = channels
+        .candidates(packet.header.channel_hash)
+        .next()
+        .map(|channel| channel.index())
+    else {
+        return;
+    };
+    drop(channels_guard);
+    queue_reply(packet, Some(channel_index));
+}
+
+/// Queue the next range test packet on the primary channel when it is due
+fn queue_range_test() {
+    let now_secs = Instant::now().as_secs() as u32;
+    let packet = {
+        let Ok(mut sender_guard) = RANGE_TEST_SENDER.try_lock() else {
+            return;
+        };
+        let Some(sender) = sender_guard.as_mut() else {
+            return;
+        };
+        if now_secs < sender.next_secs() {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        sender.poll(now_secs, packet_id, HOP_LIMIT, 0)
+    };
+    if let Some(packet) = packet {
+        queue_reply(packet, None);
+    }
+}
+
+/// Queue our position broadcast on the primary channel when it is due
+fn queue_position() {
+    let now_secs = Instant::now().as_secs() as u32;
+    let broadcast = {
+        let Ok(channels_guard) = CHANNELS.try_lock() else {
+            return;
+        };
+        let Some(channel) = channels_guard.as_ref().and_then(|channels| channels.primary()) else {
+            return;
+        };
+        let Ok(mut position_guard) = POSITION.try_lock() else {
+            return;
+        };
+        let Some(module) = position_guard.as_mut() else {
+            return;
+        };
+        if !module.is_due(now_secs) {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        module.poll(now_secs, packet_id, HOP_LIMIT, channel)
+    };
+    if let Some(broadcast) = broadcast {
+        queue_optional_broadcast(broadcast);
+    }
+}
+
+/// Queue our NeighborInfo broadcast on the primary channel when it is due
+fn queue_neighbor_info() {
+    let now_secs = Instant::now().as_secs() as u32;
+    let broadcast = {
+        let Ok(mut neighbor_info_guard) = NEIGHBOR_INFO.try_lock() else {
+            return;
+        };
+        let Some(module) = neighbor_info_guard.as_mut() else {
+            return;
+        };
+        if now_secs < module.next_broadcast_secs() {
+            return;
+        }
+        let Some(packet_id) = try_next_packet_id() else {
+            return;
+        };
+        module.poll(now_secs, packet_id, HOP_LIMIT, 0)
+    };
+    if let Some(broadcast) = broadcast {
+        queue_optional_broadcast(broadcast);
+    }
+}
+
+/// Queue a periodic broadcast unless the channel is busy or we used our share of the duty cycle
+///
+/// Routers are expected to talk more and keep broadcasting until the channel is 40% busy,
+/// other roles give up at 25%.
+fn queue_optional_broadcast(broadcast: DecodedPacket) {
+    let polite = SETTINGS.try_lock().map_or(true, |guard| {
+        guard
+            .as_ref()
+            .map_or(true, |settings| settings.device_role != config::device_config::Role::Router)
+    });
+    let allowed = AIRTIME.lock(|cell| {
+        cell.borrow().as_ref().is_some_and(|(tracker, _)| {
+            let now_ms = Instant::now().as_millis();
+            tracker.is_tx_allowed_channel_util(now_ms, polite) && tracker.is_tx_allowed_air_util(now_ms)
+        })
+    });
+    if !allowed {
+        info!("Channel busy, skipping broadcast: {}", broadcast.header);
+        return;
+    }
+    queue_packet(broadcast, None, Priority::Background);
+}
+
+/// Add the airtime of a frame we sent or received to the channel utilization
+fn record_airtime(airtime_ms: u32, tx: bool) {
+    AIRTIME.lock(|cell| {
+        let mut airtime = cell.borrow_mut();
+        let Some((tracker, _)) = airtime.as_mut() else {
+            return;
+        };
+        let now_ms = Instant::now().as_millis();
+        if tx {
+            tracker.record_tx(now_ms, airtime_ms);
+        } else {
+            tracker.record_rx(now_ms, airtime_ms);
+        }
+    });
+}
+
+/// Whether a frame of `frame_len` bytes fits in the region's duty cycle right now
+///
+/// Without a tracker we cannot tell, so the frame waits.
+fn duty_cycle_permission(frame_len: usize) -> TxPermission {
+    AIRTIME.lock(|cell| match cell.borrow().as_ref() {
+        Some((tracker, modem)) => {
+            let airtime_ms = airtime::time_on_air_ms(modem, PREAMBLE_LEN, frame_len);
+            tracker.tx_permission(Instant::now().as_millis(), airtime_ms)
+        }
+        None => TxPermission::Delayed {
+            wait_ms: AIRTIME_PENDING_WAIT_MS,
+        },
+    })
+}
+
+/// Channel utilization over the last minute and TX airtime over the last hour, in percent
+fn airtime_metrics() -> (f32, f32) {
+    AIRTIME.lock(|cell| {
+        cell.borrow()
+            .as_ref()
+            .map(|(tracker, _)| {
+                let now_ms = Instant::now().as_millis();
+                (
+                    tracker.channel_utilization_percent(now_ms),
+                    tracker.air_util_tx_percent(now_ms),
+                )
+            })
+            .unwrap_or((0.0, 0.0))
+    })
+}
+
+/// How long to keep listening before a pending transmission is due, `None` if nothing is waiting
+///
+/// Pending frames are held back while a full-size frame would exceed the duty cycle.
+fn tx_wait_ms() -> Option<u64> {
+    reboot_if_due();
+    queue_node_info();
+    queue_neighbor_info();
+    queue_position();
+    queue_range_test();
+    queue_store_forward();
+
+    let duty_cycle_wait_ms = match duty_cycle_permission(MAX_LORA_PACKET_LEN) {
+        TxPermission::Delayed { wait_ms } => wait_ms,
+        TxPermission::Allowed | TxPermission::Refused => 0,
+    };
+
+    fill_tx_queue();
+    if !TX_QUEUE.lock(|queue| queue.borrow().is_empty()) {
+        return Some(REBROADCAST_DELAY_MS.max(duty_cycle_wait_ms));
+    }
+
+    let retransmit_deadline = RETRANSMISSIONS
+        .try_lock()
+        .ok()
+        .and_then(|guard| guard.as_ref().and_then(|table| table.next_deadline()));
+    let broadcast_deadline = NEIGHBOR_INFO.try_lock().ok().and_then(|guard| {
+        guard
+            .as_ref()
+            .map(|module| u64::from(module.next_broadcast_secs()) * 1000)
+    });
+    let node_info_deadline = NODE_INFO.try_lock().ok().and_then(|guard| {
+        guard
+            .as_ref()
+            .map(|module| u64::from(module.next_broadcast_secs()) * 1000)
+    });
+    let position_deadline = POSITION.try_lock().ok().and_then(|guard| {
+        guard
+            .as_ref()
+            .and_then(|module| module.next_broadcast_secs())
+            .map(|secs| u64::from(secs) * 1000)
+    });
+    let heartbeat_deadline = STORE_FORWARD_SERVER.try_lock().ok().and_then(|guard| {
+        guard
+            .as_ref()
+            .and_then(|server| server.next_heartbeat_secs())
+            .map(|secs| u64::from(secs) * 1000)
+    });
+    let range_test_deadline = RANGE_TEST_SENDER.try_lock().ok().and_then(|guard| {
+        guard
+            .as_ref()
+            .map(|sender| u64::from(sender.next_secs()) * 1000)
+    });
+    let reboot_deadline = REBOOT_AT_SECS
+        .try_lock()
+        .ok()
+        .and_then(|guard| guard.map(|secs| u64::from(secs) * 1000));
+    let deadline = retransmit_deadline
+        .into_iter()
+        .chain(reboot_deadline)
+        .chain(broadcast_deadline)
+        .chain(node_info_deadline)
+        .chain(position_deadline)
+        .chain(heartbeat_deadline)
+        .chain(range_test_deadline)
+        .min()?;
+    Some(
+        deadline
+            .saturating_sub(Instant::now().as_millis())
+            .max(REBROADCAST_DELAY_MS)
+            .max(duty_cycle_wait_ms),
+    )
+}
+
+/// Move due retransmissions and pending rebroadcasts into the TX queue
+fn fill_tx_queue() {
+    if let Ok(mut retransmissions_guard) = RETRANSMISSIONS.try_lock() {
+        if let Some(table) = retransmissions_guard.as_mut() {
+            while let Some(event) = table.poll(Instant::now().as_millis()) {
+                match event {
+                    RetransmitEvent::Retransmit(packet) => {
+                        info!("Retransmitting packet: {}", packet.header);
+                        enqueue_packet(packet.clone(), Priority::Reliable);
+                    }
+                    RetransmitEvent::Fallback(packet) => {
+                        info!("Retransmitting packet as a flood: {}", packet.header);
+                        if let Ok(mut router_guard) = ROUTER.try_lock() {
+                            if let Some(router) = router_guard.as_mut() {
+                                router.forget_next_hop(packet.header.destination);
+                            }
+                        }
+                        enqueue_packet(packet.clone(), Priority::Reliable);
+                    }
+                    RetransmitEvent::Failed(report) => {
+                        warn!(
+                            "Delivery of 0x{:08X} to 0x{:08X}: {:?}",
+                            report.packet_id, report.destination, report.status
+                        );
+                        report_delivery_failure(&report);
+                    }
+                }
+            }
+        }
+    }
+
+    let Ok(mut router_guard) = ROUTER.try_lock() else {
+        return;
+    };
+    let Some(router) = router_guard.as_mut() else {
+        return;
+    };
+    while let Some(packet) = router.next_rebroadcast() {
+        info!("Rebroadcasting packet: {}", packet.header);
+        enqueue_packet(packet, Priority::Default);
+    }
+}
+
+/// Tell the client that one of its packets was not delivered
+///
+/// The failure goes out as a ROUTING_APP packet from us, like a NAK from the
+/// destination, so the client can match it to its packet by `request_id`.
+fn report_delivery_failure(report: &DeliveryReport) {
+    let Some(packet_id) = try_next_packet_id() else {
+        return;
+    };
+    let Some(failure) = reliability::delivery_failure(report, node_num(), packet_id) else {
+        return;
+    };
+    match PACKET_POOL.alloc_from_decoded(&failure) {
+        Ok(packet) => PACKET_CHANNEL.publish_immediate(packet),
+        Err(e) => warn!("✗ Failed to report delivery failure: {}", e),
+    }
+}
+
+/// Serialize the packet at the head of the TX queue, it stays queued until it is sent
+///
+/// Also returns the packet's header and the contention window it should use.
+fn next_tx_frame(tx_buffer: &mut [u8]) -> Option<(Header, usize, Contention)> {
+    TX_QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let packet = &queue.peek()?.packet;
+        let header = packet.header;
+        let contention = if header.source == node_num() {
+            Contention::Own
+        } else {
+            Contention::Relay { snr: packet.snr }
+        };
+        let Ok(packet_len) = packet.to_bytes(tx_buffer) else {
+            warn!("Failed to serialize packet, dropping it: {}", header);
+            queue.cancel(header.source, header.packet_id);
+            return None;
+        };
+        Some((header, packet_len, contention))
+    })
+}
+
+/// Modulation and packet parameters applied by `LoraRadio::configure`
+struct LoraConfig {
+    modulation: ModulationParams,
+    rx_packet_params: PacketParams,
+    tx_packet_params: PacketParams,
+}
+
+/// The lora-phy radio as seen by the mesh stack
+struct LoraRadio<RK, DLY> {
+    lora: LoRa<RK, DLY>,
+    config: Option<LoraConfig>,
+    power_dbm: i32,
+    // Whether the radio is in continuous RX since the last configure, CAD or TX
+    listening: bool,
+}
+
+impl<RK, DLY> LoraRadio<RK, DLY> {
+    fn new(lora: LoRa<RK, DLY>) -> Self {
+        Self {
+            lora,
+            config: None,
+            power_dbm: 0,
+            listening: false,
+        }
+    }
+}
+
+/// Errors of the lora-phy radio
+#[derive(Format)]
+enum LoraRadioError {
+    Radio(RadioError),
+    /// The modem settings are not supported by the radio
+    UnsupportedModem,
+    /// The radio was used before it was configured
+    NotConfigured,
+}
+
+impl From<RadioError> for LoraRadioError {
+    fn from(err: RadioError) -> Self {
+        LoraRadioError::Radio(err)
+    }
+}
+
+impl<RK: RadioKind, DLY: DelayNs> TxRadio for LoraRadio<RK, DLY> {
+    type Error = LoraRadioError;
+
+    async fn channel_activity(&mut self) -> Result<bool, LoraRadioError> {
+        let config = self.config.as_ref().ok_or(LoraRadioError::NotConfigured)?;
+        self.listening = false;
+        self.lora.prepare_for_cad(&config.modulation).await?;
+        Ok(self.lora.cad(&config.modulation).await?)
+    }
+
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), LoraRadioError> {
+        let config = self.config.as_mut().ok_or(LoraRadioError::NotConfigured)?;
+        self.listening = false;
+        self.lora
+            .prepare_for_tx(&config.modulation, &mut config.tx_packet_params, self.power_dbm, frame)
+            .await?;
+        Ok(self.lora.tx().await?)
+    }
+}
+
+impl<RK: RadioKind, DLY: DelayNs> MeshRadio for LoraRadio<RK, DLY> {
+    async fn configure(&mut self, params: &RadioParams) -> Result<(), LoraRadioError> {
+        let Some((spreading_factor, bandwidth, coding_rate)) = lora_modulation(&params.modem) else {
+            error!("Modem settings not supported by the radio: {}", params.modem);
+            return Err(LoraRadioError::UnsupportedModem);
+        };
+        let modulation = self.lora.create_modulation_params(
+            spreading_factor,
+            bandwidth,
+            coding_rate,
+            params.frequency_hz,
+        )?;
+        let rx_packet_params = self.lora.create_rx_packet_params(
+            PREAMBLE_LEN,
+            false,
+            MAX_LORA_PACKET_LEN as u8,
+            true,
+            false,
+            &modulation,
+        )?;
+        let tx_packet_params =
+            self.lora
+                .create_tx_packet_params(PREAMBLE_LEN, false, true, false, &modulation)?;
+        self.config = Some(LoraConfig {
+            modulation,
+            rx_packet_params,
+            tx_packet_params,
+        });
+        self.power_dbm = i32::from(params.tx_power_dbm.min(LORA_MAX_TX_POWER_DBM));
+        self.listening = false;
+        Ok(())
+    }
+
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout_ms: Option<u64>,
+    ) -> Result<Option<RxMetadata>, LoraRadioError> {
+        let config = self.config.as_ref().ok_or(LoraRadioError::NotConfigured)?;
+        if !self.listening {
+            self.lora
+                .prepare_for_rx(RxMode::Continuous, &config.modulation, &config.rx_packet_params)
+                .await?;
+            self.listening = true;
+        }
+        let rx = self.lora.rx(&config.rx_packet_params, buffer);
+        let result = match timeout_ms {
+            Some(timeout_ms) => match select(rx, Timer::after_millis(timeout_ms)).await {
+                Either::First(result) => result,
+                Either::Second(()) => return Ok(None),
+            },
+            None => rx.await,
+        };
+        let (received_len, rx_pkt_status) = result.inspect_err(|_| self.listening = false)?;
+        trace!("rx successful, len = {}, {}", received_len, rx_pkt_status);
+        Ok(Some(RxMetadata {
+            len: usize::from(received_len),
+            rssi: rx_pkt_status.rssi,
+            snr: rx_pkt_status.snr,
+        }))
+    }
+
+    async fn sleep(&mut self) -> Result<(), LoraRadioError> {
+        self.listening = false;
+        Ok(self.lora.sleep(false).await?)
+    }
+}
+
+/// The node as seen by the transceiver: TX queue, airtime accounting and packet handling
+struct FirmwareHost;
+
+impl RadioHost for FirmwareHost {
+    fn now_ms(&self) -> u64 {
+        Instant::now().as_millis()
+    }
+
+    fn channel_utilization_percent(&self) -> f32 {
+        airtime_metrics().0
+    }
 
-    // 2. Decrypt the packet
-    let Ok(decrypted_pkt) = encrypted_pkt.decrypt(&key) else {
-        warn!("✗ Failed to decrypt packet");
-        return;
-    };
-    trace!("✓ Successfully decrypted packet: {:?}", decrypted_pkt);
+    fn idle_wait_ms(&mut self) -> Option<u64> {
+        // Wake up now and then so new LoRa settings do not wait for the next frame
+        Some(tx_wait_ms().map_or(SETTINGS_POLL_MS, |wait_ms| wait_ms.min(SETTINGS_POLL_MS)))
+    }
 
-    // 3. Try to decode the packet into structured data
-    let Ok(decoded_pkt) = decrypted_pkt.decode() else {
-        warn!("✗ Failed to decode packet to structured data");
-        return;
-    };
-    trace!(
-        "✓ Successfully decoded packet to structured data {:?}",
-        decoded_pkt
-    );
+    fn next_frame(&mut self, buffer: &mut [u8]) -> Option<TxFrame> {
+        let (header, len, contention) = next_tx_frame(buffer)?;
+        Some(TxFrame {
+            header,
+            len,
+            contention,
+        })
+    }
 
-    // Publish the decoded packet to the channel
-    PACKET_CHANNEL.publish_immediate(decoded_pkt.clone());
+    fn may_transmit(&mut self, len: usize) -> TxPermission {
+        let permission = duty_cycle_permission(len);
+        match permission {
+            TxPermission::Allowed => {}
+            TxPermission::Delayed { wait_ms } => {
+                warn!("Duty cycle limit reached, sending in {} ms", wait_ms)
+            }
+            TxPermission::Refused => warn!("Packet exceeds the hourly airtime budget, dropping it"),
+        }
+        permission
+    }
 
-    // Try to get the owned data for logging
-    let Ok(owned_data) = decoded_pkt.data() else {
-        warn!("✗ Failed to get owned data from decoded packet");
-        return;
-    };
+    fn frame_sent(&mut self, frame: &TxFrame, airtime_ms: u32, slot_time_ms: u32) {
+        info!("TX DONE - Packet transmitted: {}", frame.header);
+        record_airtime(airtime_ms, true);
+        if let Some(sent) = remove_from_tx_queue(&frame.header) {
+            track_reliable_packet(&sent.packet, airtime_ms, slot_time_ms);
+        }
+    }
 
-    trace!("Decoded packet data: {:?}", owned_data);
-    let portnum = owned_data.portnum;
+    fn frame_dropped(&mut self, frame: &TxFrame) {
+        remove_from_tx_queue(&frame.header);
+    }
 
-    // Log the packet based on port type
-    let port_name = match portnum {
-        femtopb::EnumValue::Known(PortNum::TelemetryApp) => "TELEMETRY",
-        femtopb::EnumValue::Known(PortNum::NodeinfoApp) => "NODEINFO",
-        femtopb::EnumValue::Known(PortNum::PositionApp) => "POSITION",
-        femtopb::EnumValue::Known(PortNum::NeighborinfoApp) => "NEIGHBORINFO",
-        femtopb::EnumValue::Known(PortNum::TextMessageApp) => "TEXT",
-        femtopb::EnumValue::Known(PortNum::RoutingApp) => "ROUTING",
-        femtopb::EnumValue::Known(PortNum::TracerouteApp) => "TRACEROUTE",
-        _ => "OTHER",
-    };
+    async fn frame_received(&mut self, frame: &[u8], metadata: &RxMetadata, airtime_ms: u32) {
+        record_airtime(airtime_ms, false);
+        handle_received_packet(frame, frame.len(), metadata.snr, metadata.rssi).await;
+    }
+}
 
-    // Log packet with optional node info from database
-    if let Ok(db_guard) = NODE_DATABASE.try_lock() {
-        let node_info = db_guard
-            .as_ref()
-            .and_then(|db| db.get_node(decoded_pkt.header.source));
+/// Decrypt a PKI direct message in place using our keypair and the sender's key from the node database
+async fn decrypt_direct_message(packet: &mut PacketBuffer) {
+    let keypair_guard = KEYPAIR.lock().await;
+    let db_guard = NODE_DATABASE.lock().await;
+    let (Some(keypair), Some(database)) = (keypair_guard.as_ref(), db_guard.as_ref()) else {
+        return;
+    };
 
-        log_packet_info(&decoded_pkt.header, node_info, rssi, snr, port_name);
-    } else {
-        log_packet_info(&decoded_pkt.header, None, rssi, snr, port_name);
+    match packet.decrypt_pki_in_place(keypair.private_key(), database) {
+        Ok(()) => trace!("✓ Decrypted PKI direct message from 0x{:08X}", packet.header.source),
+        Err(err) => debug!("PKI decryption failed: {:?}", err),
     }
 }
 
@@ -446,79 +1981,32 @@ fn create_text_message_packet(
     key_len: usize,
     tx_buffer: &mut [u8; 256],
 ) -> Option<usize> {
-    use meshtassy_net::key::ChannelKey;
-
-    // Create the data payload
-    let data = Data {
-        portnum: femtopb::EnumValue::Known(PortNum::TextMessageApp),
-        payload: message.as_bytes(),
-        want_response: false,
-        dest: 0,
-        source: 0,
-        request_id: 0,
-        reply_id: 0,
-        emoji: 0,
-        bitfield: Some(0),
-        unknown_fields: Default::default(),
+    let Ok(channel_key) = ChannelKey::from_bytes(key, key_len) else {
+        info!("Failed to create channel key");
+        return None;
     };
 
-    // Encode the data payload to protobuf
-    let mut payload_buffer = [0u8; 240]; // Leave room for header (256 - 16)
-    let buffer_len = payload_buffer.len();
-    let mut slice = payload_buffer.as_mut_slice();
-
-    if let Err(_) = data.encode(&mut slice) {
-        info!("Failed to encode data");
+    let Ok(data) = OwnedData::new(PortNum::TextMessageApp, message.as_bytes()) else {
+        info!("Message too long: {} bytes", message.len());
         return None;
-    }
-
-    let encoded_payload_len = buffer_len - slice.len();
-    info!("Encoded payload length: {} bytes", encoded_payload_len);
-    info!(
-        "Encoded payload: {:02X}",
-        &payload_buffer[..encoded_payload_len]
-    );
-
-    // Create channel key
-    if let Some(channel_key) = ChannelKey::from_bytes(key, key_len) {
-        // Create a decrypted packet first
-        let mut full_payload = [0u8; 240];
-        full_payload[..encoded_payload_len].copy_from_slice(&payload_buffer[..encoded_payload_len]);
-        let _decrypted_packet = Packet::<Decrypted>::new(
-            header.clone(),
-            0, // rssi placeholder
-            0, // snr placeholder
-            full_payload,
-            encoded_payload_len,
-        );
+    };
 
-        // Now we need to encrypt this. For now, let's manually do the encryption
-        // Copy header to output buffer
-        tx_buffer[..16].copy_from_slice(&header.to_bytes());
-
-        // Copy payload to output buffer
-        tx_buffer[16..16 + encoded_payload_len]
-            .copy_from_slice(&payload_buffer[..encoded_payload_len]);
-        // Generate IV from header using the correct Meshtastic protocol format
-        let iv = header.create_iv();
-
-        // Encrypt in place
-        match channel_key.transform(&mut tx_buffer[16..16 + encoded_payload_len], &iv) {
-            Ok(()) => {
-                let total_len = 16 + encoded_payload_len;
-                info!("Successfully encrypted packet! Length: {} bytes", total_len);
-                info!("Encrypted packet: {:02X}", &tx_buffer[..total_len]);
-                Some(total_len)
-            }
-            Err(_) => {
-                info!("Failed to encrypt packet");
-                None
-            }
+    // DecodedPacket -> encode -> Packet<Decrypted> -> encrypt -> Packet<Encrypted>
+    let encrypted_packet = match DecodedPacket::new(*header, data)
+        .encode()
+        .and_then(|packet| packet.encrypt(&channel_key))
+    {
+        Ok(packet) => packet,
+        Err(_) => {
+            info!("Failed to encode and encrypt packet");
+            return None;
         }
-    } else {
-        info!("Failed to create channel key");
-        None
-    }
+    };
+
+    let total_len = encrypted_packet.to_bytes(tx_buffer).ok()?;
+    info!("Successfully encrypted packet! Length: {} bytes", total_len);
+    info!("Encrypted packet: {:02X}", &tx_buffer[..total_len]);
+    Some(total_len)
 }
 
 struct Disconnected {}
@@ -546,14 +2034,21 @@ async fn send_packet_to_usb<'d, T: Instance + 'd>(
         info!("✗ Failed to encode FromRadio packet");
         return Err(Disconnected {});
     };
+    send_encoded_to_usb(class, &buffer[..encoded_len]).await
+}
 
+/// Send an encoded FromRadio packet over USB, framed with its magic bytes and length
+async fn send_encoded_to_usb<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    encoded: &[u8],
+) -> Result<(), Disconnected> {
     info!("Preparing to send FromRadio packet over USB serial...");
 
     // Create header with magic bytes and length
     let mut header = [0u8; 4];
     header[0] = 0x94;
     header[1] = 0xc3;
-    let length_bytes = (encoded_len as u16).to_be_bytes();
+    let length_bytes = (encoded.len() as u16).to_be_bytes();
     header[2] = length_bytes[0];
     header[3] = length_bytes[1];
 
@@ -561,8 +2056,8 @@ async fn send_packet_to_usb<'d, T: Instance + 'd>(
     class.write_packet(&header).await?;
 
     // Send the encoded packet data in 64-byte chunks
-    info!("Sending encoded packet: {:02X}", &buffer[..encoded_len]);
-    for chunk in buffer[..encoded_len].chunks(64) {
+    info!("Sending encoded packet: {:02X}", encoded);
+    for chunk in encoded.chunks(64) {
         class.write_packet(chunk).await?;
     }
 
@@ -618,42 +2113,73 @@ async fn packet_forwarder<'d, T: Instance + 'd>(
                             let from_radio_packet = create_my_node_info_packet(packet_id);
                             send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
 
-                            // Send NodeInfo packet for our own node
-                            let packet_id = get_next_packet_id().await;
-                            let from_radio_packet = create_node_info_packet(packet_id);
-                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            // Send NodeInfo packet for our own node, as the NodeInfo module announces it
+                            let owner = NODE_INFO.lock().await.as_ref().map(|module| module.user().clone());
+                            if let Some(owner) = owner {
+                                let packet_id = get_next_packet_id().await;
+                                let id = node_info::user_id(node_num());
+                                let from_radio_packet = create_node_info_packet(packet_id, &id, &owner);
+                                send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            }
 
-                            // Send NodeInfo packets for all nodes in the database
-                            if let Ok(db_guard) = NODE_DATABASE.try_lock() {
-                                if let Some(ref database) = *db_guard {
-                                    let node_count = database.get_nodes().count();
-                                    info!("Sending NodeInfo for {} nodes from database", node_count);
-
-                                    for node in database.get_nodes() {
-                                        // Skip our own node (already sent above)
-                                        if node.num != 0xDEADBEEF {
-                                            let packet_id = get_next_packet_id().await;
-                                            let from_radio_packet = create_node_info_packet_from_db(packet_id, node);
-                                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
-                                        }
-                                    }
+                            // Send NodeInfo packets for all nodes in the database, one copy at a time so
+                            // the database is not locked while we wait on USB
+                            let node_nums: heapless::Vec<u32, 50> = NODE_DATABASE
+                                .lock()
+                                .await
+                                .as_ref()
+                                .map(|database| database.get_nodes().map(|node| node.num).collect())
+                                .unwrap_or_default();
+                            info!("Sending NodeInfo for {} nodes from database", node_nums.len());
+                            for num in node_nums {
+                                // Skip our own node (already sent above)
+                                if num == node_num() {
+                                    continue;
+                                }
+                                let node = NODE_DATABASE
+                                    .lock()
+                                    .await
+                                    .as_ref()
+                                    .and_then(|database| database.get_node(num).cloned());
+                                if let Some(node) = node {
+                                    let packet_id = get_next_packet_id().await;
+                                    let from_radio_packet = create_node_info_packet_from_db(packet_id, &node);
+                                    send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
                                 }
                             }
 
-                            // Send Config packet  
-                            let packet_id = get_next_packet_id().await;
-                            let from_radio_packet = create_config_packet(packet_id);
-                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            // Send our live configuration, the settings stay locked only while encoding
+                            for kind in CLIENT_CONFIGS {
+                                let packet_id = get_next_packet_id().await;
+                                let encoded_len = SETTINGS.lock().await.as_ref().and_then(|settings| {
+                                    let from_radio_packet = create_config_packet(packet_id, settings.config(kind)?);
+                                    encode_from_radio_packet(&from_radio_packet, &mut encoded_buffer)
+                                });
+                                if let Some(encoded_len) = encoded_len {
+                                    send_encoded_to_usb(class, &encoded_buffer[..encoded_len]).await?;
+                                }
+                            }
 
-                            // Send ModuleConfig packet
-                            let packet_id = get_next_packet_id().await;
-                            let from_radio_packet = create_module_config_packet(packet_id);
-                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            // Send our module configuration
+                            for kind in CLIENT_MODULE_CONFIGS {
+                                let packet_id = get_next_packet_id().await;
+                                let encoded_len = SETTINGS.lock().await.as_ref().and_then(|settings| {
+                                    let from_radio_packet =
+                                        create_module_config_packet(packet_id, settings.module_config(kind)?);
+                                    encode_from_radio_packet(&from_radio_packet, &mut encoded_buffer)
+                                });
+                                if let Some(encoded_len) = encoded_len {
+                                    send_encoded_to_usb(class, &encoded_buffer[..encoded_len]).await?;
+                                }
+                            }
 
-                            // Send Channel packet
-                            let packet_id = get_next_packet_id().await;
-                            let from_radio_packet = create_channel_packet(packet_id);
-                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            // Send every channel we have
+                            let channels = CHANNELS.lock().await.clone().unwrap_or_default();
+                            for channel in channels.iter() {
+                                let packet_id = get_next_packet_id().await;
+                                let from_radio_packet = create_channel_packet(packet_id, channel);
+                                send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                            }
 
                             // Send ConfigComplete packet
                             let packet_id = get_next_packet_id().await;
@@ -661,6 +2187,44 @@ async fn packet_forwarder<'d, T: Instance + 'd>(
                             send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
 
                         },
+                        Some(meshtastic_protobufs::meshtastic::to_radio::PayloadVariant::Packet(mesh_packet)) => {
+                            let Some(meshtastic_protobufs::meshtastic::mesh_packet::PayloadVariant::Decoded(data)) =
+                                &mesh_packet.payload_variant
+                            else {
+                                info!("Received unsupported packet from client");
+                                continue;
+                            };
+                            // Admin messages for our own node are handled locally, everything else goes on air
+                            if data.portnum != femtopb::EnumValue::Known(PortNum::AdminApp)
+                                || (mesh_packet.to != 0 && mesh_packet.to != node_num())
+                            {
+                                let packet_id = match mesh_packet.id {
+                                    0 => get_next_packet_id().await,
+                                    id => id,
+                                };
+                                let status = queue_client_packet(&mesh_packet, data, packet_id).await;
+                                let from_radio_packet =
+                                    create_queue_status_packet(get_next_packet_id().await, status);
+                                send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                                continue;
+                            }
+                            let response = match handle_local_admin(data.payload, mesh_packet.id).await {
+                                Ok(Some(response)) => response,
+                                Ok(None) => continue,
+                                Err(err) => {
+                                    warn!("Rejected admin message from client: {}", err);
+                                    continue;
+                                }
+                            };
+
+                            let packet_id = get_next_packet_id().await;
+                            let from_radio_packet = create_admin_response_packet(packet_id, &response);
+                            if from_radio_packet.encoded_len() > encoded_buffer.len() {
+                                warn!("Admin response too large for USB, dropping it");
+                                continue;
+                            }
+                            send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await?;
+                        },
                         Some(meshtastic_protobufs::meshtastic::to_radio::PayloadVariant::Heartbeat(_)) => {
                             info!("Received heartbeat request - connection kept alive");
                             // Heartbeat requests typically don't require a response
@@ -695,31 +2259,27 @@ async fn packet_forwarder<'d, T: Instance + 'd>(
                             packet.header.source
                         );
 
-                        // Try to get the node from the database and send a NodeInfo packet
-                        if let Ok(db_guard) = NODE_DATABASE.try_lock() {
-                            if let Some(ref database) = *db_guard {
-                                if let Some(node) = database.get_node(packet.header.source) {
-                                    // Generate a unique packet ID for this real-time NodeInfo update
-                                    let packet_id = get_next_packet_id().await;
-                                    let from_radio_packet =
-                                        create_node_info_packet_from_db(packet_id, node);
-
-                                    let mut encoded_buffer = [0u8; 256];
-                                    if let Err(_) = send_packet_to_usb(
-                                        class,
-                                        &from_radio_packet,
-                                        &mut encoded_buffer,
-                                    )
-                                    .await
-                                    {
-                                        info!("Failed to send NodeInfo packet to USB");
-                                    } else {
-                                        info!(
-                                            "Successfully sent NodeInfo packet for node {} to USB",
-                                            packet.header.source
-                                        );
-                                    }
-                                }
+                        // Send a copy of the node from the database, the database stays unlocked while we wait on USB
+                        let node = NODE_DATABASE
+                            .lock()
+                            .await
+                            .as_ref()
+                            .and_then(|database| database.get_node(packet.header.source).cloned());
+                        if let Some(node) = node {
+                            // Generate a unique packet ID for this real-time NodeInfo update
+                            let packet_id = get_next_packet_id().await;
+                            let from_radio_packet = create_node_info_packet_from_db(packet_id, &node);
+
+                            let mut encoded_buffer = [0u8; 256];
+                            if let Err(_) =
+                                send_packet_to_usb(class, &from_radio_packet, &mut encoded_buffer).await
+                            {
+                                info!("Failed to send NodeInfo packet to USB");
+                            } else {
+                                info!(
+                                    "Successfully sent NodeInfo packet for node {} to USB",
+                                    packet.header.source
+                                );
                             }
                         }
                     }
@@ -806,6 +2366,386 @@ async fn initialize_node_database() {
     info!("Node database initialized");
 }
 
+async fn initialize_router() {
+    *PACKET_HISTORY.lock().await = Some(PacketHistory::new());
+
+    let mut router_guard = ROUTER.lock().await;
+    *router_guard = Some(Router::new(node_num()));
+    *RETRANSMISSIONS.lock().await = Some(RetransmissionTable::new(node_num()));
+    *NEIGHBOR_INFO.lock().await = Some(NeighborInfoModule::new(
+        node_num(),
+        neighbor_info::DEFAULT_NEIGHBOR_INFO_INTERVAL_SECS,
+    ));
+
+    let mut position = PositionModule::new(
+        node_num(),
+        PositionConfig {
+            fixed_position: FIXED_POSITION.is_some(),
+            ..Default::default()
+        },
+    );
+    if let Some((latitude_i, longitude_i, altitude)) = FIXED_POSITION {
+        position.set_position(
+            meshtassy_net::app_payload::Position {
+                latitude_i: Some(latitude_i),
+                longitude_i: Some(longitude_i),
+                altitude: Some(altitude),
+                ..Default::default()
+            },
+            PositionSource::Fixed,
+        );
+    }
+    *POSITION.lock().await = Some(position);
+
+    let user = meshtassy_net::node_database::User {
+        long_name: heapless::String::try_from(LONG_NAME).unwrap_or_default(),
+        short_name: heapless::String::try_from(SHORT_NAME).unwrap_or_default(),
+        hw_model: femtopb::EnumValue::Known(meshtastic_protobufs::meshtastic::HardwareModel::Unset),
+        role: femtopb::EnumValue::Known(
+            meshtastic_protobufs::meshtastic::config::device_config::Role::Client,
+        ),
+        is_licensed: false,
+        public_key: KEYPAIR.lock().await.as_ref().map(|keypair| *keypair.public_key()),
+    };
+    if RANGE_TEST_SENDER_INTERVAL_SECS != 0 {
+        *RANGE_TEST_SENDER.lock().await =
+            Some(RangeTestSender::new(node_num(), RANGE_TEST_SENDER_INTERVAL_SECS));
+    }
+    *RANGE_TEST_RECEIVER.lock().await = Some(RangeTestReceiver::new(node_num()));
+    info!("Range test: {}", range_test::CSV_HEADER);
+
+    if STORE_FORWARD_SERVER_ROLE {
+        *STORE_FORWARD_SERVER.lock().await = Some(StoreForwardServer::new(
+            node_num(),
+            store_forward::ServerConfig::default(),
+            store_forward::InMemoryStore::new(),
+        ));
+    } else {
+        *STORE_FORWARD_CLIENT.lock().await = Some(StoreForwardClient::new(node_num()));
+    }
+
+    *NODE_INFO.lock().await = Some(NodeInfoModule::new(
+        node_num(),
+        user,
+        node_info::DEFAULT_NODE_INFO_BROADCAST_SECS,
+    ));
+    info!("Router initialized");
+}
+
+async fn initialize_channels() {
+    // Replaced by the saved channels in `load_settings`
+    let mut channels = ChannelSet::with_default_channel();
+    channels.set_modem_preset(LORA_MODEM_PRESET);
+    *CHANNELS.lock().await = Some(channels);
+    info!("Channels initialized");
+}
+
+/// LoRa settings the radio starts with
+fn default_lora_settings() -> LoraSettings {
+    LoraSettings {
+        use_preset: true,
+        modem_preset: LORA_MODEM_PRESET,
+        region: LORA_REGION,
+        hop_limit: u32::from(HOP_LIMIT),
+        tx_enabled: true,
+        ..Default::default()
+    }
+}
+
+/// Radio parameters for our LoRa settings and primary channel
+async fn radio_params() -> Result<RadioParams, RadioConfigError> {
+    let lora = SETTINGS
+        .lock()
+        .await
+        .as_ref()
+        .map_or_else(default_lora_settings, |settings| settings.lora);
+    let channels = CHANNELS.lock().await.clone().unwrap_or_default();
+    radio_params_for(&lora, &channels)
+}
+
+/// Radio parameters for LoRa settings and the primary channel of `channels`
+///
+/// An unnamed primary channel takes the name of the preset in `lora`.
+fn radio_params_for(lora: &LoraSettings, channels: &ChannelSet) -> Result<RadioParams, RadioConfigError> {
+    let channel_name = match channels.primary() {
+        Some(channel) if !channel.is_unnamed() => channel.name(),
+        _ => meshtassy_net::channel_url::preset_channel_name(lora.modem_preset),
+    };
+    RadioParams::from_settings(lora, channel_name)
+}
+
+/// The radio's modulation parameters for `modem`, `None` if the radio does not support them
+fn lora_modulation(modem: &ModemSettings) -> Option<(SpreadingFactor, Bandwidth, CodingRate)> {
+    let spreading_factor = match modem.spreading_factor {
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        12 => SpreadingFactor::_12,
+        _ => return None,
+    };
+    let bandwidth = match modem.bandwidth_hz {
+        31_250 => Bandwidth::_31KHz,
+        62_500 => Bandwidth::_62KHz,
+        125_000 => Bandwidth::_125KHz,
+        250_000 => Bandwidth::_250KHz,
+        500_000 => Bandwidth::_500KHz,
+        _ => return None,
+    };
+    let coding_rate = match modem.coding_rate {
+        5 => CodingRate::_4_5,
+        6 => CodingRate::_4_6,
+        7 => CodingRate::_4_7,
+        8 => CodingRate::_4_8,
+        _ => return None,
+    };
+    Some((spreading_factor, bandwidth, coding_rate))
+}
+
+fn initialize_airtime(radio_params: &RadioParams) {
+    AIRTIME.lock(|cell| {
+        *cell.borrow_mut() = Some((AirtimeTracker::for_radio(radio_params), radio_params.modem));
+    });
+    info!("Airtime initialized, duty cycle {}%", radio_params.duty_cycle_percent);
+}
+
+/// Follow a radio change, the airtime already used still counts against the new duty cycle
+fn update_airtime(radio_params: &RadioParams) {
+    AIRTIME.lock(|cell| {
+        if let Some((tracker, modem)) = cell.borrow_mut().as_mut() {
+            tracker.set_duty_cycle_percent(radio_params.duty_cycle_percent);
+            *modem = radio_params.modem;
+        }
+    });
+}
+
+async fn initialize_admin(secret: [u8; 32]) {
+    *ADMIN.lock().await = Some(AdminModule::new(node_num(), secret));
+
+    let owner = NODE_INFO.lock().await.as_ref().map(|module| module.user().clone());
+    let channels = CHANNELS.lock().await.clone();
+    if let (Some(owner), Some(channels)) = (owner, channels) {
+        *SETTINGS.lock().await = Some(Settings {
+            owner,
+            channels,
+            lora: default_lora_settings(),
+            device_role: config::device_config::Role::Client,
+            admin_keys: heapless::Vec::new(),
+            admin_channel_enabled: false,
+        });
+    }
+    info!("Admin initialized");
+}
+
+/// Apply the settings saved by `Settings::save` over the defaults
+///
+/// Channels come first, the LoRa settings are checked against the primary channel.
+async fn load_settings() {
+    let mut storage_guard = STORAGE.lock().await;
+    let Some(storage) = storage_guard.as_mut() else {
+        return;
+    };
+    let mut settings_guard = SETTINGS.lock().await;
+    let Some(settings) = settings_guard.as_mut() else {
+        return;
+    };
+    let mut buffer = [0u8; MAX_RECORD_LEN];
+
+    if let Ok(Some(len)) = storage.load(Record::Channels, &mut buffer) {
+        match meshtastic_protobufs::meshtastic::ChannelFile::decode(&buffer[..len]) {
+            Ok(file) => {
+                let mut channels = ChannelSet::new();
+                channels.set_modem_preset(settings.lora.modem_preset);
+                for channel in file.channels.iter().flatten() {
+                    match meshtassy_net::channel::Channel::from_protobuf(&channel) {
+                        Ok(channel) if channel.is_enabled() => channels.set(channel),
+                        Ok(_) => {}
+                        Err(err) => warn!("Saved channel {} is not valid: {}", channel.index, err),
+                    }
+                }
+                if channels.primary().is_some() {
+                    settings.channels = channels.clone();
+                    *CHANNELS.lock().await = Some(channels);
+                }
+            }
+            Err(_) => warn!("Saved channels are not valid, using the defaults"),
+        }
+    }
+
+    if let Ok(Some(len)) = storage.load(Record::Config, &mut buffer) {
+        match meshtastic_protobufs::meshtastic::LocalConfig::decode(&buffer[..len]) {
+            Ok(local_config) => {
+                let sections = [
+                    local_config.device.map(config::PayloadVariant::Device),
+                    local_config.position.map(config::PayloadVariant::Position),
+                    local_config.lora.map(config::PayloadVariant::Lora),
+                    local_config.security.map(config::PayloadVariant::Security),
+                ];
+                for section in sections.into_iter().flatten() {
+                    let section = meshtastic_protobufs::meshtastic::Config {
+                        payload_variant: Some(section),
+                        ..Default::default()
+                    };
+                    if let Err(err) = settings.set_config(&section) {
+                        warn!("Saved configuration not applied: {}", err);
+                    }
+                }
+            }
+            Err(_) => warn!("Saved configuration is not valid, using the defaults"),
+        }
+    }
+
+    if let Ok(Some(len)) = storage.load(Record::Owner, &mut buffer) {
+        match User::decode(&buffer[..len]) {
+            Ok(user) => {
+                let saved = meshtassy_net::node_database::User::from_protobuf(&user);
+                let mut owner = settings.owner();
+                owner.long_name = saved.long_name;
+                owner.short_name = saved.short_name;
+                owner.hw_model = saved.hw_model;
+                owner.is_licensed = saved.is_licensed;
+                owner.role = saved.role;
+                settings.set_owner(owner);
+            }
+            Err(_) => warn!("Saved owner is not valid, using the defaults"),
+        }
+    }
+
+    // The admin module starts without keys, hand it the saved ones. Admin
+    // messages lock ADMIN before SETTINGS, so let go of the settings first.
+    let admin_keys = settings.admin_keys.clone();
+    let admin_channel_enabled = settings.admin_channel_enabled;
+    drop(settings_guard);
+    drop(storage_guard);
+    if let Some(admin) = ADMIN.lock().await.as_mut() {
+        for key in admin_keys {
+            admin.add_admin_key(key);
+        }
+        admin.set_admin_channel_enabled(admin_channel_enabled);
+    }
+
+    // The radio is set up from the loaded settings, there is nothing to reconfigure
+    RADIO_PARAMS.reset();
+    info!("Settings loaded");
+}
+
+/// Erase the settings saved by `Settings::save`, the defaults apply on the next boot
+async fn forget_settings() {
+    let mut storage_guard = STORAGE.lock().await;
+    let Some(storage) = storage_guard.as_mut() else {
+        return;
+    };
+    for record in [Record::Owner, Record::Config, Record::Channels] {
+        if let Err(err) = storage.remove(record) {
+            warn!("Could not erase {}: {}", record, err);
+        }
+    }
+}
+
+/// Share the board's RNG with every task
+fn initialize_rng(rng: boards::BoardRng) {
+    RNG.lock(|cell| *cell.borrow_mut() = Some(rng));
+}
+
+/// Fill `bytes` from the board's RNG
+fn fill_random(bytes: &mut [u8]) {
+    RNG.lock(|cell| {
+        if let Some(rng) = cell.borrow_mut().as_mut() {
+            rng.fill_bytes(bytes);
+        }
+    });
+}
+
+/// The board's RNG for the code that takes a `RngCore`
+struct SharedRng;
+
+impl rand_core::RngCore for SharedRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_random(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        fill_random(dest);
+        Ok(())
+    }
+}
+
+impl rand_core::CryptoRng for SharedRng {}
+
+/// Derive our node number from the chip's unique ID, so it survives reboots and reflashing
+fn initialize_node_num(unique_id: &[u8]) {
+    let node_num = node_num_from_unique_id(unique_id);
+    NODE_NUM.store(node_num, Ordering::Relaxed);
+    info!("Node number: 0x{:08X}", node_num);
+}
+
+/// Our node number, see `initialize_node_num`
+fn node_num() -> u32 {
+    NODE_NUM.load(Ordering::Relaxed)
+}
+
+/// Keep records in the last pages of the flash, which memory.x leaves to us
+async fn initialize_storage(flash: boards::BoardFlash) {
+    let offset = boards::FLASH_SIZE as u32 - FlashStore::<boards::BoardFlash>::area_len();
+    match FlashStore::new(flash, offset) {
+        Ok(storage) => *STORAGE.lock().await = Some(storage),
+        Err(err) => error!("Flash storage unavailable, settings will not persist: {}", err),
+    }
+}
+
+/// Load our keypair from flash, only a first boot calls `generate` and saves the new keypair
+///
+/// Our public key must not change across reboots: peers would flag us as a
+/// possible impersonator and could no longer reach us with direct messages.
+async fn initialize_keypair(generate: impl FnOnce() -> Keypair) {
+    let mut storage_guard = STORAGE.lock().await;
+    let mut bytes = [0u8; KEYPAIR_LEN];
+    let loaded = match storage_guard.as_mut() {
+        Some(storage) => storage.load(Record::Keypair, &mut bytes),
+        None => Err(StorageError::Flash),
+    };
+    let keypair = match loaded.map(|len| len.map(|len| Keypair::from_bytes(&bytes[..len]))) {
+        Ok(Some(Ok(keypair))) => {
+            info!("Loaded PKI keypair from flash");
+            keypair
+        }
+        // Keep what is stored, it may read fine on the next boot
+        Err(err) => {
+            warn!("Could not read the PKI keypair, using a temporary one: {}", err);
+            generate()
+        }
+        // First boot, or a stored keypair that is not valid
+        Ok(_) => {
+            let keypair = generate();
+            if let Some(storage) = storage_guard.as_mut() {
+                if let Err(err) = storage.save(Record::Keypair, &keypair.to_bytes()) {
+                    warn!("Could not save the PKI keypair: {}", err);
+                }
+            }
+            keypair
+        }
+    };
+    info!("PKI public key: {:02X}", keypair.public_key());
+    *KEYPAIR.lock().await = Some(keypair);
+}
+
+/// Start our packet IDs at `seed`, like Meshtastic's `generatePacketId`
+///
+/// Counting from 1 after every reboot would repeat IDs our neighbors still
+/// remember, and they would drop our packets as duplicates.
+async fn seed_packet_ids(seed: u32) {
+    *PACKET_ID_COUNTER.lock().await = (seed & 0x7FFFFFFF).max(1);
+}
+
 /// Get the next packet ID for USB serial communication
 /// This ensures we never overflow by wrapping at a reasonable value
 async fn get_next_packet_id() -> u32 {
@@ -821,6 +2761,14 @@ async fn get_next_packet_id() -> u32 {
     current_id
 }
 
+/// Non-blocking variant of `get_next_packet_id` for the receive path
+fn try_next_packet_id() -> Option<u32> {
+    let mut counter_guard = PACKET_ID_COUNTER.try_lock().ok()?;
+    let current_id = *counter_guard;
+    *counter_guard = if current_id >= 0x7FFFFFFF { 1 } else { current_id + 1 };
+    Some(current_id)
+}
+
 /// Create a FromRadio packet containing MyNodeInfo with hardcoded values
 /// This demonstrates how to construct a basic MyNodeInfo packet for device identification
 fn create_my_node_info_packet(packet_id: u32) -> FromRadio<'static> {
@@ -828,7 +2776,7 @@ fn create_my_node_info_packet(packet_id: u32) -> FromRadio<'static> {
         id: packet_id,
         payload_variant: Some(
             meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::MyInfo(MyNodeInfo {
-                my_node_num: 0xDEADBEEF, // Hardcoded node number - should be unique device ID
+                my_node_num: node_num(),
                 reboot_count: 42,        // Number of reboots (hardcoded for demo)
                 min_app_version: 30200,  // Minimum app version (3.2.0)
                 device_id: b"EMBASSY_RP2040", // 16-byte device identifier
@@ -841,29 +2789,41 @@ fn create_my_node_info_packet(packet_id: u32) -> FromRadio<'static> {
 }
 
 /// Create a FromRadio packet containing NodeInfo for our own node
-fn create_node_info_packet(packet_id: u32) -> FromRadio<'static> {
-    use meshtastic_protobufs::meshtastic::{config, HardwareModel};
+fn create_node_info_packet<'a>(
+    packet_id: u32,
+    id: &'a str,
+    owner: &'a meshtassy_net::node_database::User,
+) -> FromRadio<'a> {
+    use meshtastic_protobufs::meshtastic::DeviceMetrics;
 
     let user = User {
-        id: "!deadbeef", // Use the same node ID as in MyNodeInfo
-        long_name: "Embassy RP2040",
-        short_name: "ERP2",
+        id,
+        long_name: &owner.long_name,
+        short_name: &owner.short_name,
         macaddr: &[], // Deprecated field
-        hw_model: femtopb::EnumValue::Known(HardwareModel::Unset),
-        is_licensed: false,
-        role: femtopb::EnumValue::Known(config::device_config::Role::Client),
-        public_key: &[], // No public key for now
+        hw_model: owner.hw_model,
+        is_licensed: owner.is_licensed,
+        role: owner.role,
+        public_key: owner.public_key.as_ref().map_or(&[], |key| key.as_slice()),
         is_unmessagable: Some(false),
         unknown_fields: Default::default(),
     };
 
+    let (channel_utilization, air_util_tx) = airtime_metrics();
     let node_info = NodeInfo {
-        num: 0xDEADBEEF, // Same as MyNodeInfo.my_node_num
+        num: node_num(), // Same as MyNodeInfo.my_node_num
         user: Some(user),
         position: None, // No position info for now
         snr: 0.0,
         last_heard: 0, // Current timestamp would be better
-        device_metrics: None,
+        device_metrics: Some(DeviceMetrics {
+            battery_level: None,
+            voltage: None,
+            channel_utilization: Some(channel_utilization),
+            air_util_tx: Some(air_util_tx),
+            uptime_seconds: Some(Instant::now().as_secs() as u32),
+            unknown_fields: Default::default(),
+        }),
         channel: 0,
         via_mqtt: false,
         hops_away: Some(0), // We are 0 hops from ourselves
@@ -896,30 +2856,18 @@ fn create_config_complete_packet(packet_id: u32, config_complete_id: u32) -> Fro
     }
 }
 
-/// Create a FromRadio packet containing Config with minimal placeholder data
-fn create_config_packet(packet_id: u32) -> FromRadio<'static> {
-    use meshtastic_protobufs::meshtastic::{config, Config};
+/// Config sections sent to the client on a config request, the ones `Settings::config` has
+const CLIENT_CONFIGS: [ConfigType; 3] = [
+    ConfigType::DeviceConfig,
+    ConfigType::PositionConfig,
+    ConfigType::LoraConfig,
+];
 
-    let device_config = config::DeviceConfig {
-        role: femtopb::EnumValue::Known(config::device_config::Role::Client),
-        serial_enabled: false,
-        button_gpio: 0,
-        buzzer_gpio: 0,
-        rebroadcast_mode: femtopb::EnumValue::Known(config::device_config::RebroadcastMode::All),
-        node_info_broadcast_secs: 900,
-        double_tap_as_button_press: false,
-        is_managed: false,
-        disable_triple_click: false,
-        tzdef: "",
-        led_heartbeat_disabled: false,
-        unknown_fields: Default::default(),
-    };
-
-    let config = Config {
-        payload_variant: Some(config::PayloadVariant::Device(device_config)),
-        unknown_fields: Default::default(),
-    };
+/// Module config sections sent to the client on a config request
+const CLIENT_MODULE_CONFIGS: [ModuleConfigType; 1] = [ModuleConfigType::NeighborinfoConfig];
 
+/// Create a FromRadio packet containing a config section
+fn create_config_packet(packet_id: u32, config: meshtastic_protobufs::meshtastic::Config<'_>) -> FromRadio<'_> {
     FromRadio {
         id: packet_id,
         payload_variant: Some(
@@ -929,67 +2877,119 @@ fn create_config_packet(packet_id: u32) -> FromRadio<'static> {
     }
 }
 
-/// Create a FromRadio packet containing ModuleConfig with minimal placeholder data
-fn create_module_config_packet(packet_id: u32) -> FromRadio<'static> {
-    use meshtastic_protobufs::meshtastic::{module_config, ModuleConfig};
-
-    let mqtt_config = module_config::MqttConfig {
-        enabled: false,
-        address: "",
-        username: "",
-        password: "",
-        encryption_enabled: false,
-        json_enabled: false,
-        tls_enabled: false,
-        root: "",
-        proxy_to_client_enabled: false,
-        map_reporting_enabled: false,
-        map_report_settings: None,
+/// Create a FromRadio packet containing a module config section
+fn create_module_config_packet(
+    packet_id: u32,
+    module_config: meshtastic_protobufs::meshtastic::ModuleConfig<'_>,
+) -> FromRadio<'_> {
+    FromRadio {
+        id: packet_id,
+        payload_variant: Some(
+            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::ModuleConfig(module_config),
+        ),
         unknown_fields: Default::default(),
-    };
+    }
+}
 
-    let module_config = ModuleConfig {
-        payload_variant: Some(module_config::PayloadVariant::Mqtt(mqtt_config)),
+/// Create a FromRadio packet containing one of our channels
+fn create_channel_packet(packet_id: u32, channel: &meshtassy_net::channel::Channel) -> FromRadio<'_> {
+    FromRadio {
+        id: packet_id,
+        payload_variant: Some(
+            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::Channel(channel.to_protobuf()),
+        ),
         unknown_fields: Default::default(),
+    }
+}
+
+/// Create a FromRadio packet carrying an admin response to our client
+fn create_admin_response_packet(packet_id: u32, response: &OwnedData) -> FromRadio<'_> {
+    use meshtastic_protobufs::meshtastic::{Data, MeshPacket, mesh_packet};
+
+    let mesh_packet = MeshPacket {
+        from: node_num(),
+        to: node_num(),
+        id: packet_id,
+        payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+            portnum: response.portnum,
+            payload: &response.payload[..response.payload_len],
+            request_id: response.request_id,
+            ..Default::default()
+        })),
+        ..Default::default()
     };
 
     FromRadio {
         id: packet_id,
         payload_variant: Some(
-            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::ModuleConfig(
-                module_config,
-            ),
+            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::Packet(mesh_packet),
         ),
         unknown_fields: Default::default(),
     }
 }
 
-/// Create a FromRadio packet containing Channel with minimal placeholder data
-fn create_channel_packet(packet_id: u32) -> FromRadio<'static> {
-    use meshtastic_protobufs::meshtastic::{channel, Channel, ChannelSettings};
-
-    let channel_settings = ChannelSettings {
-        channel_num: 0, // Deprecated but required
-        psk: &[0x01],   // Default AES key
-        name: "LongFast",
-        id: 0,
-        uplink_enabled: false,
-        downlink_enabled: false,
-        module_settings: None,
-        unknown_fields: Default::default(),
+/// Queue a packet the client asked us to send, returns the queue status to report back
+async fn queue_client_packet(
+    mesh_packet: &meshtastic_protobufs::meshtastic::MeshPacket<'_>,
+    data: &meshtastic_protobufs::meshtastic::Data<'_>,
+    packet_id: u32,
+) -> meshtastic_protobufs::meshtastic::QueueStatus<'static> {
+    let status = |res| TX_QUEUE.lock(|queue| queue.borrow().status(res, packet_id));
+    let femtopb::EnumValue::Known(portnum) = data.portnum else {
+        return status(ERRNO_UNKNOWN);
+    };
+    let Ok(mut payload) = OwnedData::new(portnum, data.payload) else {
+        return status(ERRNO_UNKNOWN);
+    };
+    payload.want_response = data.want_response;
+    payload.request_id = data.request_id;
+    payload.reply_id = data.reply_id;
+    payload.emoji = data.emoji;
+
+    let hop_limit = match mesh_packet.hop_limit {
+        0 => HOP_LIMIT,
+        hop_limit => hop_limit.min(7) as u8,
+    };
+    let header = Header::new(
+        if mesh_packet.to == 0 { 0xFFFFFFFF } else { mesh_packet.to },
+        node_num(),
+        packet_id,
+        HeaderFlags {
+            hop_limit,
+            want_ack: mesh_packet.want_ack,
+            via_mqtt: false,
+            hop_start: hop_limit,
+        },
+        0,
+        0,
+        node_num() as u8,
+    );
+    let packet = DecodedPacket::new(header, payload);
+    let priority = match mesh_packet.priority {
+        femtopb::EnumValue::Known(Priority::Unset) | femtopb::EnumValue::Unknown(_) => {
+            tx_queue::default_priority(&packet)
+        }
+        femtopb::EnumValue::Known(priority) => priority,
     };
 
-    let channel = Channel {
-        index: 0,
-        settings: Some(channel_settings),
-        role: femtopb::EnumValue::Known(channel::Role::Primary),
-        unknown_fields: Default::default(),
+    let result = if mesh_packet.pki_encrypted {
+        queue_pki_packet(packet, priority).await
+    } else {
+        queue_packet(packet, Some(mesh_packet.channel as u8), priority)
     };
+    info!("Queued packet 0x{:08X} from client: {:?}", packet_id, result);
+    status(result.map_or(ERRNO_UNKNOWN, |result| result.error_code()))
+}
 
+/// Tell the client how full the TX queue is after it sent a packet
+fn create_queue_status_packet(
+    packet_id: u32,
+    status: meshtastic_protobufs::meshtastic::QueueStatus<'static>,
+) -> FromRadio<'static> {
     FromRadio {
         id: packet_id,
         payload_variant: Some(
-            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::Channel(channel),
+            meshtastic_protobufs::meshtastic::from_radio::PayloadVariant::QueueStatus(status),
         ),
         unknown_fields: Default::default(),
     }
@@ -1026,7 +3026,7 @@ fn create_node_info_packet_from_db(
             hw_model: db_user.hw_model,
             is_licensed: db_user.is_licensed,
             role: db_user.role,
-            public_key: &[], // No public key for now
+            public_key: db_user.public_key.as_ref().map_or(&[], |key| &key[..]),
             is_unmessagable: Some(false),
             unknown_fields: Default::default(),
         }
@@ -1100,11 +3100,11 @@ fn create_node_info_packet_from_db(
 
 /// Create a generic FromRadio packet containing a MeshPacket for any supported packet type
 /// This function handles all packet types that should be forwarded as FromRadio::Packet
-fn create_mesh_packet_from_data(packet_id: u32, packet: &DecodedPacket) -> Option<FromRadio> {
+fn create_mesh_packet_from_data(packet_id: u32, packet: &PacketHandle<'_>) -> Option<FromRadio> {
     use meshtastic_protobufs::meshtastic::{mesh_packet, MeshPacket};
 
-    // Get the owned data from the received packet
-    let Ok(owned_data) = packet.data() else {
+    // Get the Data message straight from the pooled buffer
+    let Ok(data) = packet.data_message() else {
         return None;
     };
 
@@ -1145,11 +3145,11 @@ fn create_mesh_packet_from_data(packet_id: u32, packet: &DecodedPacket) -> Optio
         payload_variant: Some(mesh_packet::PayloadVariant::Decoded(
             meshtastic_protobufs::meshtastic::Data {
                 portnum: packet.port_num(),
-                payload: &owned_data.payload[..owned_data.payload_len],
+                payload: data.payload,
                 want_response: false,
                 dest: packet.header.destination,
                 source: packet.header.source,
-                request_id: 0,
+                request_id: data.request_id,
                 reply_id: 0,
                 emoji: 0,
                 bitfield: None,